@@ -0,0 +1,465 @@
+use std::fs::File;
+use std::io::{self, Read};
+use std::path::PathBuf;
+
+use xmas_elf::header;
+use zip::{CompressionMethod, ZipArchive};
+
+use crate::core::rudroid::Emulator;
+
+// chunk types of the binary xml format AndroidManifest.xml is compiled to
+const RES_STRING_POOL_TYPE  : u16 = 0x0001;
+const RES_XML_TYPE          : u16 = 0x0003;
+const RES_XML_START_ELEMENT : u16 = 0x0102;
+const UTF8_FLAG             : u32 = 1 << 8;
+const TYPE_STRING           : u8  = 0x03;
+const NO_ENTRY              : u32 = 0xffff_ffff;
+
+const PAGE_SIZE             : u64 = 0x1000;
+
+/// A shared library under `lib/<abi>/` in the APK.
+#[derive(Debug, Clone)]
+pub struct ApkLibrary {
+    pub name        : String,
+    // zip entry name, `lib/arm64-v8a/libfoo.so`
+    pub entry       : String,
+    // offset of the library in the APK
+    pub data_start  : u64,
+    pub size        : u64,
+    // stored and page aligned, the linker can map it straight out of the APK
+    pub mappable    : bool,
+}
+
+/// An APK opened for its native libraries.
+#[derive(Debug, Clone)]
+pub struct Apk {
+    pub path        : String,
+    pub package     : Option<String>,
+    pub abi         : String,
+    pub libraries   : Vec<ApkLibrary>,
+}
+
+/// ABIs able to run on `machine`, preferred first.
+fn abis(machine: header::Machine) -> &'static [&'static str] {
+    match machine {
+        header::Machine::AArch64 => &["arm64-v8a"],
+        header::Machine::Arm     => &["armeabi-v7a", "armeabi"],
+        header::Machine::X86_64  => &["x86_64"],
+        header::Machine::X86     => &["x86"],
+        _ => &[],
+    }
+}
+
+/// Directory name the package manager extracts an ABI's libraries to.
+fn abi_dir(abi: &str) -> &str {
+    match abi {
+        "arm64-v8a" => "arm64",
+        "armeabi-v7a" | "armeabi" => "arm",
+        abi => abi,
+    }
+}
+
+impl Apk {
+    /// Read the APK's central directory and manifest, keeping the libraries of the first ABI
+    /// in it that `machine` runs.
+    pub fn open(path: &str, machine: header::Machine) -> io::Result<Apk> {
+        let mut zip = ZipArchive::new(File::open(path)?)?;
+
+        let names: Vec<String> = zip.file_names().map(String::from).collect();
+        let abi = abis(machine).iter()
+            .find(|abi| names.iter().any(|name| name.starts_with(&format!("lib/{}/", abi)) && name.ends_with(".so")))
+            .map(|abi| String::from(*abi))
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, format!("{} has no libraries for {:?}", path, machine)))?;
+
+        let mut libraries = Vec::new();
+        for index in 0..zip.len() {
+            let file = zip.by_index_raw(index)?;
+            let name = match file.name().strip_prefix(&format!("lib/{}/", abi)) {
+                Some(name) if name.ends_with(".so") && !name.contains('/') => String::from(name),
+                _ => continue,
+            };
+
+            libraries.push(ApkLibrary {
+                name        : name,
+                entry       : String::from(file.name()),
+                data_start  : file.data_start(),
+                size        : file.size(),
+                mappable    : file.compression() == CompressionMethod::Stored && file.data_start() % PAGE_SIZE == 0,
+            });
+        }
+
+        let package = match zip.by_name("AndroidManifest.xml") {
+            Ok(mut manifest) => {
+                let mut data = Vec::new();
+                manifest.read_to_end(&mut data)?;
+                manifest_package(&data).filter(|package| valid_package(package))
+            },
+            Err(_) => None,
+        };
+
+        Ok(Apk {
+            path        : String::from(path),
+            package     : package,
+            abi         : abi,
+            libraries   : libraries,
+        })
+    }
+
+    /// Package name from the manifest, the file name of the APK without a usable one.
+    pub fn package_name(&self) -> String {
+        match &self.package {
+            Some(package) => package.clone(),
+            None => self.path.rsplit('/').next().unwrap().trim_end_matches(".apk")
+                .chars()
+                .map(|c| if c.is_ascii_alphanumeric() || c == '_' || c == '-' { c } else { '_' })
+                .collect(),
+        }
+    }
+
+    pub fn library(&self, name: &str) -> Option<&ApkLibrary> {
+        self.libraries.iter().find(|library| library.name == name)
+    }
+
+    /// `/data/app/<package>`, where the guest sees the APK installed.
+    pub fn app_dir(&self) -> String {
+        format!("/data/app/{}", self.package_name())
+    }
+
+    /// Guest path of the APK itself.
+    pub fn base_apk(&self) -> String {
+        format!("{}/base.apk", self.app_dir())
+    }
+
+    /// Search path for the linker, the extracted libraries and the ones inside the APK.
+    pub fn library_path(&self) -> String {
+        format!("{}/lib/{}:{}!/lib/{}", self.app_dir(), abi_dir(&self.abi), self.base_apk(), self.abi)
+    }
+
+    /// Decompressed copy of `library`.
+    pub fn read_library(&self, library: &ApkLibrary) -> io::Result<Vec<u8>> {
+        let mut zip = ZipArchive::new(File::open(&self.path)?)?;
+        let mut file = zip.by_name(&library.entry)?;
+
+        let mut data = Vec::with_capacity(library.size as usize);
+        file.read_to_end(&mut data)?;
+        Ok(data)
+    }
+
+    /// Host directory holding the extracted libraries and the app's data directory.
+    pub fn scratch_dir(&self) -> PathBuf {
+        std::env::temp_dir().join(format!("rudroid-apk-{}", self.package_name()))
+    }
+
+    /// Extract every library to `<scratch>/lib` and create the data directory and cmdline.
+    pub fn extract(&self) -> io::Result<()> {
+        let scratch = self.scratch_dir();
+        std::fs::create_dir_all(scratch.join("lib"))?;
+        std::fs::create_dir_all(scratch.join("data"))?;
+
+        for library in self.libraries.iter() {
+            std::fs::write(scratch.join("lib").join(&library.name), self.read_library(library)?)?;
+        }
+
+        let mut cmdline = self.package_name().into_bytes();
+        cmdline.push(0);
+        std::fs::write(scratch.join("cmdline"), cmdline)
+    }
+
+    /// Library starting at `offset` in the APK.
+    pub fn library_at(&self, offset: u64) -> Option<&ApkLibrary> {
+        self.libraries.iter().find(|library| library.data_start == offset)
+    }
+}
+
+fn read_u16(data: &[u8], offset: usize) -> Option<u16> {
+    Some(u16::from_le_bytes([*data.get(offset)?, *data.get(offset + 1)?]))
+}
+
+fn read_u32(data: &[u8], offset: usize) -> Option<u32> {
+    Some(u32::from_le_bytes([*data.get(offset)?, *data.get(offset + 1)?, *data.get(offset + 2)?, *data.get(offset + 3)?]))
+}
+
+/// String `index` of the string pool chunk at `pool`.
+fn pool_string(data: &[u8], pool: usize, index: u32) -> Option<String> {
+    let header_size = read_u16(data, pool + 2)? as usize;
+    let count       = read_u32(data, pool + 8)?;
+    let flags       = read_u32(data, pool + 16)?;
+    let strings     = read_u32(data, pool + 20)? as usize;
+
+    if index >= count {
+        return None;
+    }
+
+    let mut offset = pool + strings + read_u32(data, pool + header_size + 4 * index as usize)? as usize;
+    if flags & UTF8_FLAG != 0 {
+        // length in characters then in bytes, each one or two bytes long
+        for _ in 0..2 {
+            offset += if *data.get(offset)? & 0x80 != 0 { 2 } else { 1 };
+        }
+        let end = offset + data.get(offset..)?.iter().position(|byte| *byte == 0)?;
+        String::from_utf8(data[offset..end].to_vec()).ok()
+    }
+    else {
+        let mut len = read_u16(data, offset)? as usize;
+        offset += 2;
+        if len & 0x8000 != 0 {
+            len = ((len & 0x7fff) << 16) | read_u16(data, offset)? as usize;
+            offset += 2;
+        }
+        let units = (0..len).map(|n| read_u16(data, offset + 2 * n)).collect::<Option<Vec<u16>>>()?;
+        String::from_utf16(&units).ok()
+    }
+}
+
+/// Whether `package` is a Java package name, it ends up in host and guest paths.
+fn valid_package(package: &str) -> bool {
+    package.split('.').all(|part| !part.is_empty() && part.chars().all(|c| c.is_ascii_alphanumeric() || c == '_'))
+}
+
+/// `package` attribute of `<manifest>` in a binary AndroidManifest.xml.
+fn manifest_package(data: &[u8]) -> Option<String> {
+    if read_u16(data, 0)? != RES_XML_TYPE {
+        return None;
+    }
+
+    let mut pool = None;
+    let mut chunk = read_u16(data, 2)? as usize;
+    while chunk + 8 <= data.len() {
+        let kind        = read_u16(data, chunk)?;
+        let header_size = read_u16(data, chunk + 2)? as usize;
+        let size        = read_u32(data, chunk + 4)? as usize;
+
+        match kind {
+            RES_STRING_POOL_TYPE => pool = Some(chunk),
+            RES_XML_START_ELEMENT => {
+                let pool = pool?;
+                // ResXMLTree_attrExt follows the node header
+                let ext = chunk + header_size;
+                if pool_string(data, pool, read_u32(data, ext + 4)?)?.as_str() != "manifest" {
+                    return None;
+                }
+
+                let start = read_u16(data, ext + 8)? as usize;
+                let attribute_size = read_u16(data, ext + 10)? as usize;
+                let count = read_u16(data, ext + 12)? as usize;
+
+                for n in 0..count {
+                    let attribute = ext + start + n * attribute_size;
+                    if pool_string(data, pool, read_u32(data, attribute + 4)?).as_deref() != Some("package") {
+                        continue;
+                    }
+
+                    let raw = read_u32(data, attribute + 8)?;
+                    if raw != NO_ENTRY {
+                        return pool_string(data, pool, raw);
+                    }
+                    if *data.get(attribute + 15)? == TYPE_STRING {
+                        return pool_string(data, pool, read_u32(data, attribute + 16)?);
+                    }
+                    return None;
+                }
+                return None;
+            },
+            _ => {},
+        }
+
+        if size == 0 {
+            break;
+        }
+        chunk += size;
+    }
+    None
+}
+
+impl<D> Emulator<D> {
+    /// Make the APK look installed: the APK and its extracted libraries under
+    /// `/data/app/<package>`, a data directory and the package name in `/proc/self/cmdline`,
+    /// served from the scratch directory of `Apk::extract`.
+    pub fn install_apk(&mut self, apk: &Apk) {
+        let package = apk.package_name();
+        let scratch = apk.scratch_dir();
+        let lib_dir = scratch.join("lib").to_string_lossy().into_owned();
+        let data_dir = scratch.join("data").to_string_lossy().into_owned();
+
+        self.filesystem.mount(&apk.base_apk(), &apk.path);
+        self.filesystem.mount(&format!("{}/lib/{}", apk.app_dir(), abi_dir(&apk.abi)), &lib_dir);
+        // libraries mapped from the APK are known by their zip path, the extracted copies
+        // stand in for them when reading symbols
+        self.filesystem.mount(&format!("{}!/lib/{}", apk.base_apk(), apk.abi), &lib_dir);
+        self.filesystem.mount(&format!("/data/data/{}", package), &data_dir);
+        self.filesystem.mount(&format!("/data/user/0/{}", package), &data_dir);
+        self.filesystem.mount("/proc/self/cmdline", &scratch.join("cmdline").to_string_lossy());
+
+        self.debug_print(format!("installed {} as {} ({}, {} libraries)", apk.path, package, apk.abi, apk.libraries.len()));
+        self.apk = Some(apk.clone());
+    }
+
+    /// Guest path of the image at `offset` in the file known as `path`: the zip path for a
+    /// library inside the installed APK, `path` itself otherwise.
+    pub fn embedded_image_path(&self, path: &str, offset: u64) -> String {
+        match &self.apk {
+            Some(apk) if offset != 0 && path == apk.base_apk() => match apk.library_at(offset) {
+                Some(library) => format!("{}!/{}", path, library.entry),
+                None => format!("{}@{:#x}", path, offset),
+            },
+            _ if offset != 0 => format!("{}@{:#x}", path, offset),
+            _ => String::from(path),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn push_u16(out: &mut Vec<u8>, value: u16) {
+        out.extend_from_slice(&value.to_le_bytes());
+    }
+
+    fn push_u32(out: &mut Vec<u8>, value: u32) {
+        out.extend_from_slice(&value.to_le_bytes());
+    }
+
+    fn string_pool(strings: &[&str], utf8: bool) -> Vec<u8> {
+        let mut data = Vec::new();
+        let mut offsets = Vec::new();
+        for string in strings {
+            offsets.push(data.len() as u32);
+            if utf8 {
+                for len in &[string.chars().count(), string.len()] {
+                    if *len > 0x7f {
+                        data.push(0x80 | (len >> 8) as u8);
+                    }
+                    data.push(*len as u8);
+                }
+                data.extend_from_slice(string.as_bytes());
+                data.push(0);
+            }
+            else {
+                let units: Vec<u16> = string.encode_utf16().collect();
+                push_u16(&mut data, units.len() as u16);
+                units.iter().for_each(|unit| push_u16(&mut data, *unit));
+                push_u16(&mut data, 0);
+            }
+        }
+
+        let strings_start = 28 + 4 * strings.len() as u32;
+        let mut pool = Vec::new();
+        push_u16(&mut pool, RES_STRING_POOL_TYPE);
+        push_u16(&mut pool, 28);
+        push_u32(&mut pool, strings_start + data.len() as u32);
+        push_u32(&mut pool, strings.len() as u32);
+        push_u32(&mut pool, 0);
+        push_u32(&mut pool, if utf8 { UTF8_FLAG } else { 0 });
+        push_u32(&mut pool, strings_start);
+        push_u32(&mut pool, 0);
+        offsets.iter().for_each(|offset| push_u32(&mut pool, *offset));
+        pool.extend_from_slice(&data);
+        pool
+    }
+
+    // attributes as (name, raw value, typed string value)
+    fn start_element(name: u32, attributes: &[(u32, u32, u32)]) -> Vec<u8> {
+        let mut chunk = Vec::new();
+        push_u16(&mut chunk, RES_XML_START_ELEMENT);
+        push_u16(&mut chunk, 16);
+        push_u32(&mut chunk, 16 + 20 + 20 * attributes.len() as u32);
+        push_u32(&mut chunk, 1);
+        push_u32(&mut chunk, NO_ENTRY);
+
+        push_u32(&mut chunk, NO_ENTRY);
+        push_u32(&mut chunk, name);
+        push_u16(&mut chunk, 20);
+        push_u16(&mut chunk, 20);
+        push_u16(&mut chunk, attributes.len() as u16);
+        push_u16(&mut chunk, 0);
+        push_u16(&mut chunk, 0);
+        push_u16(&mut chunk, 0);
+
+        for (name, raw, typed) in attributes {
+            push_u32(&mut chunk, NO_ENTRY);
+            push_u32(&mut chunk, *name);
+            push_u32(&mut chunk, *raw);
+            push_u16(&mut chunk, 8);
+            chunk.push(0);
+            chunk.push(TYPE_STRING);
+            push_u32(&mut chunk, *typed);
+        }
+        chunk
+    }
+
+    fn manifest(pool: Vec<u8>, element: Vec<u8>) -> Vec<u8> {
+        let mut data = Vec::new();
+        push_u16(&mut data, RES_XML_TYPE);
+        push_u16(&mut data, 8);
+        push_u32(&mut data, 8 + (pool.len() + element.len()) as u32);
+        data.extend_from_slice(&pool);
+        data.extend_from_slice(&element);
+        data
+    }
+
+    #[test]
+    fn package_from_raw_value() {
+        let pool = string_pool(&["versionCode", "package", "manifest", "com.example.app"], false);
+        let data = manifest(pool, start_element(2, &[(0, NO_ENTRY, 0), (1, 3, 3)]));
+        assert_eq!(manifest_package(&data).as_deref(), Some("com.example.app"));
+    }
+
+    #[test]
+    fn package_from_typed_value_utf8() {
+        let pool = string_pool(&["manifest", "package", "org.exämple"], true);
+        let data = manifest(pool, start_element(0, &[(1, NO_ENTRY, 2)]));
+        assert_eq!(manifest_package(&data).as_deref(), Some("org.exämple"));
+    }
+
+    #[test]
+    fn pool_string_long_lengths() {
+        let long = "x".repeat(300);
+        for utf8 in &[true, false] {
+            let pool = string_pool(&["a", &long], *utf8);
+            assert_eq!(pool_string(&pool, 0, 0).as_deref(), Some("a"));
+            assert_eq!(pool_string(&pool, 0, 1).as_deref(), Some(long.as_str()));
+            assert_eq!(pool_string(&pool, 0, 2), None);
+        }
+    }
+
+    #[test]
+    fn package_names_for_paths() {
+        assert!(valid_package("com.example.app"));
+        assert!(valid_package("org.example_2"));
+        for bad in &["../../home/u", "com/example", "..", ".app", "com..app", "", "app.", "com.exämple"] {
+            assert!(!valid_package(bad), "{}", bad);
+        }
+
+        let apk = Apk { path: String::from("/tmp/x/../my app.apk"), package: None, abi: String::from("arm64-v8a"), libraries: Vec::new() };
+        assert_eq!(apk.package_name(), "my_app");
+    }
+
+    #[test]
+    fn no_package() {
+        let pool = string_pool(&["application", "package", "com.example"], false);
+        assert_eq!(manifest_package(&manifest(pool, start_element(0, &[(1, 2, 2)]))), None);
+
+        let pool = string_pool(&["manifest", "versionName"], false);
+        assert_eq!(manifest_package(&manifest(pool, start_element(0, &[(1, NO_ENTRY, 0)]))), None);
+
+        // the element refers to a string past the pool
+        let pool = string_pool(&["manifest"], false);
+        assert_eq!(manifest_package(&manifest(pool, start_element(7, &[]))), None);
+
+        assert_eq!(manifest_package(b"PK\x03\x04"), None);
+        assert_eq!(manifest_package(&[]), None);
+    }
+
+    #[test]
+    fn truncated_manifests_do_not_panic() {
+        for utf8 in &[true, false] {
+            let pool = string_pool(&["manifest", "package", "com.example"], *utf8);
+            let data = manifest(pool, start_element(0, &[(1, 2, 2)]));
+            assert_eq!(manifest_package(&data).as_deref(), Some("com.example"));
+            for len in 0..data.len() {
+                let _ = manifest_package(&data[..len]);
+            }
+        }
+    }
+}
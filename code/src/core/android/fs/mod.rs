@@ -40,7 +40,9 @@ pub struct oFile {
 pub struct FsScheme
 {
     pub rootfs        : String,
-    pub open_files    : HashMap<RawFd, oFile>
+    pub open_files    : HashMap<RawFd, oFile>,
+    // guest paths backed by host files or directories outside the rootfs
+    pub mounts        : HashMap<String, String>,
 }
 
 impl oFile {
@@ -65,9 +67,22 @@ impl FsScheme {
         FsScheme {
             rootfs      : rootfs.clone(),
             open_files  : HashMap::new(),
+            mounts      : HashMap::new(),
         }
     }
 
+    /// Serve `guest`, and everything below it for a directory, from `host`.
+    pub fn mount(&mut self, guest: &str, host: &str) {
+        self.mounts.insert(String::from(guest.trim_end_matches('/')), String::from(host));
+    }
+
+    fn mounted_path(&self, path: &str) -> Option<String> {
+        self.mounts.iter()
+            .filter(|(guest, _)| path == guest.as_str() || (path.starts_with(guest.as_str()) && path[guest.len()..].starts_with('/')))
+            .max_by_key(|(guest, _)| guest.len())
+            .map(|(guest, host)| format!("{}{}", host, &path[guest.len()..]))
+    }
+
     pub fn check_for_traversal(&self, path: &str) {
         //! FIX ME. i'm just checking for ../ == ParentDir
         let path = path::Path::new(path);
@@ -180,6 +195,10 @@ impl FsScheme {
     }
 
     pub fn change_path_if_special(&self, path: &str) -> String {
+        if let Some(host) = self.mounted_path(path) {
+            return host;
+        }
+
         if self.is_driver_io(path) {
             return String::from(path);
         }
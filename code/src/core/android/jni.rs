@@ -0,0 +1,1217 @@
+use std::collections::{BTreeMap, HashMap};
+
+use crate::utilities;
+use crate::core::rudroid::Emulator;
+use crate::core::unicorn::ffi;
+use crate::core::unicorn::arch::arm64::RegisterARM64;
+use crate::core::unicorn::unicorn_const::Protection;
+
+// guest memory holding the JavaVM, the JNIEnv, their function tables and what native code gets
+// handed (string chars, array elements)
+const JNI_BASE          : u64 = 0x6000_0000_0000;
+const JNI_SIZE          : usize = 0x100_0000;
+const VM_OFFSET         : u64 = 0x0;
+const ENV_OFFSET        : u64 = 0x10;
+const VM_TABLE_OFFSET   : u64 = 0x1000;
+const ENV_TABLE_OFFSET  : u64 = 0x2000;
+// one `ret` per table entry, a code hook over them runs the Rust side
+const VM_STUBS_OFFSET   : u64 = 0x3000;
+const ENV_STUBS_OFFSET  : u64 = 0x4000;
+const HEAP_OFFSET       : u64 = 0x10000;
+
+// references and ids are opaque to native code, these ranges are never mapped
+const REF_BASE          : u64 = 0x7e00_0000_0000;
+const METHOD_BASE       : u64 = 0x7e10_0000_0000;
+const FIELD_BASE        : u64 = 0x7e20_0000_0000;
+
+const RET               : [u8; 4] = [0xc0, 0x03, 0x5f, 0xd6];
+
+const JNI_VERSION_1_6   : u64 = 0x10006;
+const JNI_ABORT         : u64 = 2;
+
+const VM_FUNCTIONS: &[&str] = &[
+    "reserved0", "reserved1", "reserved2",
+    "DestroyJavaVM", "AttachCurrentThread", "DetachCurrentThread", "GetEnv", "AttachCurrentThreadAsDaemon",
+];
+
+const CALL_TYPES: &[&str] = &["Object", "Boolean", "Byte", "Char", "Short", "Int", "Long", "Float", "Double", "Void"];
+// primitive array types and their element size
+const PRIMITIVES: &[(&str, usize)] = &[
+    ("Boolean", 1), ("Byte", 1), ("Char", 2), ("Short", 2), ("Int", 4), ("Long", 8), ("Float", 4), ("Double", 8),
+];
+
+/// Names of the JNINativeInterface entries, in table order.
+fn env_functions() -> Vec<String> {
+    let mut names: Vec<String> = Vec::new();
+    let mut push = |list: &[&str]| names.extend(list.iter().map(|name| String::from(*name)));
+
+    push(&["reserved0", "reserved1", "reserved2", "reserved3", "GetVersion", "DefineClass", "FindClass",
+           "FromReflectedMethod", "FromReflectedField", "ToReflectedMethod", "GetSuperclass", "IsAssignableFrom",
+           "ToReflectedField", "Throw", "ThrowNew", "ExceptionOccurred", "ExceptionDescribe", "ExceptionClear",
+           "FatalError", "PushLocalFrame", "PopLocalFrame", "NewGlobalRef", "DeleteGlobalRef", "DeleteLocalRef",
+           "IsSameObject", "NewLocalRef", "EnsureLocalCapacity", "AllocObject", "NewObject", "NewObjectV",
+           "NewObjectA", "GetObjectClass", "IsInstanceOf", "GetMethodID"]);
+
+    let calls = |prefix: &str| -> Vec<String> {
+        CALL_TYPES.iter()
+            .flat_map(|kind| ["", "V", "A"].iter().map(move |variant| format!("{}{}Method{}", prefix, kind, variant)))
+            .collect()
+    };
+    let fields = |prefix: &str| -> Vec<String> {
+        CALL_TYPES[..9].iter().map(|kind| format!("{}{}Field", prefix, kind)).collect()
+    };
+    let arrays = |prefix: &str, suffix: &str| -> Vec<String> {
+        PRIMITIVES.iter().map(|(kind, _)| format!("{}{}{}", prefix, kind, suffix)).collect()
+    };
+
+    names.extend(calls("Call"));
+    names.extend(calls("CallNonvirtual"));
+    names.push(String::from("GetFieldID"));
+    names.extend(fields("Get"));
+    names.extend(fields("Set"));
+    names.push(String::from("GetStaticMethodID"));
+    names.extend(calls("CallStatic"));
+    names.push(String::from("GetStaticFieldID"));
+    names.extend(fields("GetStatic"));
+    names.extend(fields("SetStatic"));
+
+    let mut push = |list: &[&str]| names.extend(list.iter().map(|name| String::from(*name)));
+    push(&["NewString", "GetStringLength", "GetStringChars", "ReleaseStringChars", "NewStringUTF",
+           "GetStringUTFLength", "GetStringUTFChars", "ReleaseStringUTFChars", "GetArrayLength", "NewObjectArray",
+           "GetObjectArrayElement", "SetObjectArrayElement"]);
+
+    names.extend(arrays("New", "Array"));
+    names.extend(arrays("Get", "ArrayElements"));
+    names.extend(arrays("Release", "ArrayElements"));
+    names.extend(arrays("Get", "ArrayRegion"));
+    names.extend(arrays("Set", "ArrayRegion"));
+
+    let mut push = |list: &[&str]| names.extend(list.iter().map(|name| String::from(*name)));
+    push(&["RegisterNatives", "UnregisterNatives", "MonitorEnter", "MonitorExit", "GetJavaVM", "GetStringRegion",
+           "GetStringUTFRegion", "GetPrimitiveArrayCritical", "ReleasePrimitiveArrayCritical", "GetStringCritical",
+           "ReleaseStringCritical", "NewWeakGlobalRef", "DeleteWeakGlobalRef", "ExceptionCheck",
+           "NewDirectByteBuffer", "GetDirectBufferAddress", "GetDirectBufferCapacity", "GetObjectRefType",
+           "GetModule"]);
+    names
+}
+
+/// An argument for a native method called from Rust.
+#[derive(Debug, Clone)]
+pub enum JValue {
+    Boolean(bool),
+    Int(i32),
+    Long(i64),
+    Float(f32),
+    Double(f64),
+    // a reference as returned by the new_java_* methods
+    Object(u64),
+    String(String),
+    Bytes(Vec<u8>),
+}
+
+#[derive(Debug, Clone)]
+enum JavaObject {
+    Class(String),
+    Object(String),
+    String(String),
+    // primitive array, e.g. `[B`
+    Array(String, usize, Vec<u8>),
+    ObjectArray(String, Vec<u64>),
+    DirectBuffer(u64, u64),
+}
+
+#[derive(Debug, Clone)]
+struct Member {
+    class       : String,
+    name        : String,
+    signature   : String,
+    is_static   : bool,
+}
+
+/// A native registered with RegisterNatives.
+#[derive(Debug, Clone)]
+pub struct RegisteredNative {
+    pub class       : String,
+    pub name        : String,
+    pub signature   : String,
+    pub address     : u64,
+}
+
+/// A Java method called from native code, handed to the implementation registered for it.
+#[derive(Debug, Clone)]
+pub struct JavaCall {
+    pub class       : String,
+    pub name        : String,
+    pub signature   : String,
+    // the object, or the class for static methods
+    pub this        : u64,
+    // integers and references as is, float and double as the bits of a double
+    pub args        : Vec<u64>,
+}
+
+pub type JavaMethod<D> = Box<dyn FnMut(&mut Emulator<D>, &JavaCall) -> u64>;
+
+/// What a trapped JNI function did, before a Java method implementation had its say.
+struct Trap {
+    name        : String,
+    args        : Vec<String>,
+    value       : u64,
+    // 'F' or 'D' when the value is returned in s0 or d0
+    float       : Option<char>,
+    java_call   : Option<(JavaCall, char)>,
+}
+
+/// Objects, ids and natives of the Java side, kept with emulator snapshots.
+#[derive(Debug, Clone)]
+pub struct JniState {
+    natives         : Vec<RegisteredNative>,
+    heap_next       : u64,
+    allocated       : HashMap<u64, u64>,
+    free_blocks     : BTreeMap<u64, u64>,
+    objects         : Vec<JavaObject>,
+    classes         : HashMap<String, u64>,
+    methods         : Vec<Member>,
+    fields          : Vec<Member>,
+    field_values    : HashMap<(u64, u64), u64>,
+    pinned          : HashMap<u64, u64>,
+}
+
+/// Fake JavaVM and JNIEnv. Every function table entry points at a `ret` the trap hook sits on,
+/// so native code calls into Rust and returns normally.
+pub struct Jni<D> {
+    pub trace       : bool,
+    pub natives     : Vec<RegisteredNative>,
+
+    env_functions   : Vec<String>,
+    heap_next       : u64,
+    // blocks handed to native code and the ones released below `heap_next`, address -> size
+    allocated       : HashMap<u64, u64>,
+    free_blocks     : BTreeMap<u64, u64>,
+    objects         : Vec<JavaObject>,
+    classes         : HashMap<String, u64>,
+    methods         : Vec<Member>,
+    fields          : Vec<Member>,
+    field_values    : HashMap<(u64, u64), u64>,
+    // guest copy of array elements -> the array, written back on release
+    pinned          : HashMap<u64, u64>,
+    implementations : HashMap<String, JavaMethod<D>>,
+    hook            : Option<ffi::uc_hook>,
+}
+
+impl<D> Jni<D> {
+    fn new() -> Jni<D> {
+        Jni {
+            trace           : false,
+            natives         : Vec::new(),
+            env_functions   : env_functions(),
+            heap_next       : JNI_BASE + HEAP_OFFSET,
+            allocated       : HashMap::new(),
+            free_blocks     : BTreeMap::new(),
+            objects         : Vec::new(),
+            classes         : HashMap::new(),
+            methods         : Vec::new(),
+            fields          : Vec::new(),
+            field_values    : HashMap::new(),
+            pinned          : HashMap::new(),
+            implementations : HashMap::new(),
+            hook            : None,
+        }
+    }
+
+    pub fn save_state(&self) -> JniState {
+        JniState {
+            natives         : self.natives.clone(),
+            heap_next       : self.heap_next,
+            allocated       : self.allocated.clone(),
+            free_blocks     : self.free_blocks.clone(),
+            objects         : self.objects.clone(),
+            classes         : self.classes.clone(),
+            methods         : self.methods.clone(),
+            fields          : self.fields.clone(),
+            field_values    : self.field_values.clone(),
+            pinned          : self.pinned.clone(),
+        }
+    }
+
+    pub fn restore_state(&mut self, state: &JniState) {
+        self.natives        = state.natives.clone();
+        self.heap_next      = state.heap_next;
+        self.allocated      = state.allocated.clone();
+        self.free_blocks    = state.free_blocks.clone();
+        self.objects        = state.objects.clone();
+        self.classes        = state.classes.clone();
+        self.methods        = state.methods.clone();
+        self.fields         = state.fields.clone();
+        self.field_values   = state.field_values.clone();
+        self.pinned         = state.pinned.clone();
+    }
+
+    fn new_object(&mut self, object: JavaObject) -> u64 {
+        self.objects.push(object);
+        REF_BASE + 8 * (self.objects.len() as u64 - 1)
+    }
+
+    fn object(&self, reference: u64) -> Option<&JavaObject> {
+        if reference < REF_BASE || reference % 8 != 0 {
+            return None;
+        }
+        self.objects.get(((reference - REF_BASE) / 8) as usize)
+    }
+
+    fn object_mut(&mut self, reference: u64) -> Option<&mut JavaObject> {
+        if reference < REF_BASE || reference % 8 != 0 {
+            return None;
+        }
+        self.objects.get_mut(((reference - REF_BASE) / 8) as usize)
+    }
+
+    fn find_class(&mut self, name: &str) -> u64 {
+        if let Some(class) = self.classes.get(name) {
+            return *class;
+        }
+        let class = self.new_object(JavaObject::Class(String::from(name)));
+        self.classes.insert(String::from(name), class);
+        class
+    }
+
+    fn class_name(&self, reference: u64) -> String {
+        match self.object(reference) {
+            Some(JavaObject::Class(name)) => name.clone(),
+            Some(JavaObject::Object(class)) => class.clone(),
+            Some(JavaObject::String(_)) => String::from("java/lang/String"),
+            Some(JavaObject::Array(class, _, _)) | Some(JavaObject::ObjectArray(class, _)) => class.clone(),
+            Some(JavaObject::DirectBuffer(_, _)) => String::from("java/nio/DirectByteBuffer"),
+            None => String::from("java/lang/Object"),
+        }
+    }
+
+    fn describe(&self, reference: u64) -> String {
+        match self.object(reference) {
+            _ if reference == 0 => String::from("NULL"),
+            Some(JavaObject::String(string)) => format!("{:#x} {:?}", reference, string),
+            Some(JavaObject::Class(name)) => format!("{:#x} {{{}}}", reference, name),
+            Some(JavaObject::Array(class, size, data)) => format!("{:#x} {{{} len {}}}", reference, class, data.len() / size),
+            Some(_) => format!("{:#x} {{{}}}", reference, self.class_name(reference)),
+            None => format!("{:#x}", reference),
+        }
+    }
+
+    /// A copy of `data` in guest memory, 0 (NULL, out of memory) once the region is full.
+    fn alloc<T>(&mut self, emu: &mut Emulator<T>, data: &[u8]) -> u64 {
+        let size = (data.len().max(1) as u64 + 0xf) & !0xf;
+        let free = self.free_blocks.iter().find(|(_, free)| **free >= size).map(|(address, free)| (*address, *free));
+        let address = match free {
+            Some((address, free)) => {
+                self.free_blocks.remove(&address);
+                if free > size {
+                    self.free_blocks.insert(address + size, free - size);
+                }
+                address
+            },
+            None if self.heap_next + size <= JNI_BASE + JNI_SIZE as u64 => {
+                self.heap_next += size;
+                self.heap_next - size
+            },
+            None => {
+                utilities::log("jni: out of guest memory for strings and arrays", utilities::DebugLevel::ERROR);
+                return 0;
+            },
+        };
+
+        self.allocated.insert(address, size);
+        emu.mem_write(address, data).unwrap();
+        address
+    }
+
+    /// Give back a block from `alloc`, merged with the free ones next to it.
+    fn dealloc(&mut self, address: u64) {
+        let (mut address, mut size) = match self.allocated.remove(&address) {
+            Some(size) => (address, size),
+            None => return,
+        };
+
+        if let Some(next) = self.free_blocks.remove(&(address + size)) {
+            size += next;
+        }
+        let previous = self.free_blocks.range(..address).next_back().map(|(address, size)| (*address, *size));
+        if let Some((previous, previous_size)) = previous {
+            if previous + previous_size == address {
+                self.free_blocks.remove(&previous);
+                address = previous;
+                size += previous_size;
+            }
+        }
+
+        if address + size == self.heap_next {
+            self.heap_next = address;
+        } else {
+            self.free_blocks.insert(address, size);
+        }
+    }
+
+    fn new_member(members: &mut Vec<Member>, base: u64, member: Member) -> u64 {
+        let index = match members.iter().position(|known| known.class == member.class && known.name == member.name && known.signature == member.signature) {
+            Some(index) => index,
+            None => {
+                members.push(member);
+                members.len() - 1
+            }
+        };
+        base + 8 * index as u64
+    }
+
+    fn member(members: &[Member], base: u64, id: u64) -> Option<&Member> {
+        if id < base {
+            return None;
+        }
+        members.get(((id - base) / 8) as usize)
+    }
+
+    /// Run the JNIEnv function `index` with the arguments in x0-x7.
+    fn env_call(&mut self, emu: &mut Emulator<D>, index: usize) -> Trap {
+        let name = self.env_functions.get(index).cloned().unwrap_or_else(|| format!("function{}", index));
+        let arg = |n: usize| -> u64 { emu.reg_read(RegisterARM64::x(n)).unwrap() };
+        let string = |address: u64| -> String { emu.read_c_string(address, 0x1000).unwrap_or_default() };
+
+        let mut trap = Trap {
+            name        : name.clone(),
+            args        : Vec::new(),
+            value       : 0,
+            float       : None,
+            java_call   : None,
+        };
+
+        if let Some((kind, variant, offset)) = parse_call(&name) {
+            let (this, method_id) = (arg(1), arg(offset - 1));
+            let method = match Jni::<D>::member(&self.methods, METHOD_BASE, method_id) {
+                Some(method) => method.clone(),
+                None => {
+                    trap.args = vec![self.describe(this), format!("{:#x}", method_id)];
+                    return trap;
+                }
+            };
+
+            let args = self.call_arguments(emu, &method.signature, variant, offset);
+            trap.args.push(self.describe(this));
+            trap.args.push(format!("{}.{}{}", method.class, method.name, method.signature));
+            trap.args.extend(args.iter().zip(parameter_kinds(&method.signature)).map(|(value, kind)| self.format_value(kind, *value)));
+
+            trap.float = Some(kind).filter(|kind| *kind == 'F' || *kind == 'D');
+            let class = if method.is_static { method.class.clone() } else { self.class_name(this) };
+            trap.java_call = Some((JavaCall {
+                class       : class,
+                name        : method.name,
+                signature   : method.signature,
+                this        : this,
+                args        : args,
+            }, kind));
+            return trap;
+        }
+
+        if let Some((is_static, set, kind)) = parse_field(&name) {
+            let (object, field_id) = (arg(1), arg(2));
+            let field = Jni::<D>::member(&self.fields, FIELD_BASE, field_id).map(|field| format!("{}.{}", field.class, field.name)).unwrap_or_default();
+            let key = (if is_static { 0 } else { object }, field_id);
+
+            trap.args = vec![self.describe(object), field];
+            if set {
+                let value = match kind {
+                    // not variadic, a jfloat comes in s0
+                    'F' => (read_float_register(emu, 0) as f64).to_bits(),
+                    'D' => read_double_register(emu, 0).to_bits(),
+                    _ => arg(3),
+                };
+                trap.args.push(self.format_value(kind, value));
+                self.field_values.insert(key, value);
+            }
+            else {
+                trap.value = self.field_values.get(&key).cloned().unwrap_or(0);
+                trap.float = Some(kind).filter(|kind| *kind == 'F' || *kind == 'D');
+            }
+            return trap;
+        }
+
+        if let Some((kind, size, operation)) = parse_array(&name) {
+            trap.value = self.array_call(emu, &mut trap.args, kind, size, operation);
+            return trap;
+        }
+
+        trap.value = match name.as_str() {
+            "GetVersion" => JNI_VERSION_1_6,
+            "FindClass" => {
+                let class = string(arg(1));
+                trap.args.push(format!("{:?}", class));
+                self.find_class(&class)
+            },
+            "GetSuperclass" => {
+                trap.args.push(self.describe(arg(1)));
+                self.find_class("java/lang/Object")
+            },
+            "IsAssignableFrom" | "IsInstanceOf" => {
+                trap.args = vec![self.describe(arg(1)), self.describe(arg(2))];
+                1
+            },
+            "Throw" => {
+                trap.args.push(self.describe(arg(1)));
+                0
+            },
+            "ThrowNew" => {
+                trap.args = vec![self.describe(arg(1)), format!("{:?}", string(arg(2)))];
+                0
+            },
+            "FatalError" => {
+                let message = string(arg(1));
+                utilities::log(&format!("jni: FatalError({:?})", message), utilities::DebugLevel::ERROR);
+                trap.args.push(format!("{:?}", message));
+                0
+            },
+            "ExceptionOccurred" | "ExceptionCheck" | "ExceptionDescribe" | "ExceptionClear" => 0,
+            "PushLocalFrame" | "EnsureLocalCapacity" => 0,
+            "MonitorEnter" | "MonitorExit" => {
+                trap.args.push(self.describe(arg(1)));
+                0
+            },
+            "PopLocalFrame" | "NewGlobalRef" | "NewLocalRef" | "NewWeakGlobalRef" => {
+                trap.args.push(self.describe(arg(1)));
+                arg(1)
+            },
+            "DeleteGlobalRef" | "DeleteLocalRef" | "DeleteWeakGlobalRef" => {
+                trap.args.push(self.describe(arg(1)));
+                0
+            },
+            "IsSameObject" => {
+                trap.args = vec![self.describe(arg(1)), self.describe(arg(2))];
+                (arg(1) == arg(2)) as u64
+            },
+            "GetObjectRefType" => {
+                trap.args.push(self.describe(arg(1)));
+                // JNILocalRefType
+                1
+            },
+            "AllocObject" => {
+                trap.args.push(self.describe(arg(1)));
+                let class = self.class_name(arg(1));
+                self.new_object(JavaObject::Object(class))
+            },
+            "NewObject" | "NewObjectV" | "NewObjectA" => {
+                let (class, method_id) = (arg(1), arg(2));
+                trap.args.push(self.describe(class));
+                if let Some(method) = Jni::<D>::member(&self.methods, METHOD_BASE, method_id).cloned() {
+                    let variant = name.chars().last().filter(|c| *c == 'V' || *c == 'A').unwrap_or(' ');
+                    let args = self.call_arguments(emu, &method.signature, variant, 3);
+                    trap.args.push(format!("{}{}", method.name, method.signature));
+                    trap.args.extend(args.iter().zip(parameter_kinds(&method.signature)).map(|(value, kind)| self.format_value(kind, *value)));
+                }
+                let class = self.class_name(class);
+                self.new_object(JavaObject::Object(class))
+            },
+            "GetObjectClass" => {
+                trap.args.push(self.describe(arg(1)));
+                let class = self.class_name(arg(1));
+                self.find_class(&class)
+            },
+            "GetMethodID" | "GetStaticMethodID" | "GetFieldID" | "GetStaticFieldID" => {
+                let member = Member {
+                    class       : self.class_name(arg(1)),
+                    name        : string(arg(2)),
+                    signature   : string(arg(3)),
+                    is_static   : name.contains("Static"),
+                };
+                trap.args = vec![self.describe(arg(1)), format!("{:?}", member.name), format!("{:?}", member.signature)];
+                if name.ends_with("MethodID") {
+                    Jni::<D>::new_member(&mut self.methods, METHOD_BASE, member)
+                }
+                else {
+                    Jni::<D>::new_member(&mut self.fields, FIELD_BASE, member)
+                }
+            },
+            "NewStringUTF" => {
+                let value = string(arg(1));
+                trap.args.push(format!("{:?}", value));
+                self.new_object(JavaObject::String(value))
+            },
+            "NewString" => {
+                let len = arg(2) as usize;
+                let units: Vec<u16> = emu.mem_read_as_vec(arg(1), len * 2).unwrap_or_default()
+                    .chunks_exact(2).map(|unit| u16::from_le_bytes([unit[0], unit[1]])).collect();
+                let value = String::from_utf16_lossy(&units);
+                trap.args.push(format!("{:?}", value));
+                self.new_object(JavaObject::String(value))
+            },
+            "GetStringLength" | "GetStringUTFLength" => {
+                trap.args.push(self.describe(arg(1)));
+                match self.object(arg(1)) {
+                    Some(JavaObject::String(value)) if name == "GetStringLength" => value.encode_utf16().count() as u64,
+                    Some(JavaObject::String(value)) => value.len() as u64,
+                    _ => 0,
+                }
+            },
+            "GetStringUTFChars" | "GetStringChars" | "GetStringCritical" => {
+                trap.args.push(self.describe(arg(1)));
+                let value = match self.object(arg(1)) {
+                    Some(JavaObject::String(value)) => value.clone(),
+                    _ => String::new(),
+                };
+
+                let mut data: Vec<u8> = if name == "GetStringUTFChars" {
+                    value.into_bytes()
+                } else {
+                    value.encode_utf16().flat_map(|unit| unit.to_le_bytes().to_vec()).collect()
+                };
+                data.extend_from_slice(&[0, 0]);
+
+                if arg(2) != 0 {
+                    emu.mem_write(arg(2), &[1]).unwrap();
+                }
+                self.alloc(emu, &data)
+            },
+            "ReleaseStringUTFChars" | "ReleaseStringChars" | "ReleaseStringCritical" => {
+                trap.args = vec![self.describe(arg(1)), format!("{:#x}", arg(2))];
+                self.dealloc(arg(2));
+                0
+            },
+            "GetStringUTFRegion" | "GetStringRegion" => {
+                let (start, len, buffer) = (arg(2) as usize, arg(3) as usize, arg(4));
+                trap.args = vec![self.describe(arg(1)), format!("{}", start), format!("{}", len), format!("{:#x}", buffer)];
+                if let Some(JavaObject::String(value)) = self.object(arg(1)) {
+                    let units: Vec<u16> = value.encode_utf16().skip(start).take(len).collect();
+                    let data: Vec<u8> = if name == "GetStringUTFRegion" {
+                        let mut data = String::from_utf16_lossy(&units).into_bytes();
+                        data.push(0);
+                        data
+                    } else {
+                        units.iter().flat_map(|unit| unit.to_le_bytes().to_vec()).collect()
+                    };
+                    emu.mem_write(buffer, &data).unwrap();
+                }
+                0
+            },
+            "GetArrayLength" => {
+                trap.args.push(self.describe(arg(1)));
+                match self.object(arg(1)) {
+                    Some(JavaObject::Array(_, size, data)) => (data.len() / size) as u64,
+                    Some(JavaObject::ObjectArray(_, items)) => items.len() as u64,
+                    _ => 0,
+                }
+            },
+            "NewObjectArray" => {
+                let (len, class, initial) = (arg(1) as usize, self.class_name(arg(2)), arg(3));
+                trap.args = vec![format!("{}", len), self.describe(arg(2)), self.describe(initial)];
+                self.new_object(JavaObject::ObjectArray(format!("[L{};", class), vec![initial; len]))
+            },
+            "GetObjectArrayElement" => {
+                trap.args = vec![self.describe(arg(1)), format!("{}", arg(2))];
+                match self.object(arg(1)) {
+                    Some(JavaObject::ObjectArray(_, items)) => items.get(arg(2) as usize).cloned().unwrap_or(0),
+                    _ => 0,
+                }
+            },
+            "SetObjectArrayElement" => {
+                trap.args = vec![self.describe(arg(1)), format!("{}", arg(2)), self.describe(arg(3))];
+                let (index, value) = (arg(2) as usize, arg(3));
+                if let Some(JavaObject::ObjectArray(_, items)) = self.object_mut(arg(1)) {
+                    if index < items.len() {
+                        items[index] = value;
+                    }
+                }
+                0
+            },
+            "GetPrimitiveArrayCritical" => {
+                trap.args.push(self.describe(arg(1)));
+                self.pin_array(emu, arg(1), arg(2))
+            },
+            "ReleasePrimitiveArrayCritical" => {
+                trap.args = vec![self.describe(arg(1)), format!("{:#x}", arg(2)), format!("{}", arg(3))];
+                self.release_array(emu, arg(2), arg(3));
+                0
+            },
+            "RegisterNatives" => {
+                let (class, methods, count) = (self.class_name(arg(1)), arg(2), arg(3));
+                trap.args = vec![self.describe(arg(1)), format!("{:#x}", methods), format!("{}", count)];
+
+                for n in 0..count {
+                    // JNINativeMethod { const char* name; const char* signature; void* fnPtr; }
+                    let entry = emu.mem_read_as_vec(methods + 24 * n, 24).unwrap_or_else(|_| vec![0; 24]);
+                    let native = RegisteredNative {
+                        class       : class.clone(),
+                        name        : string(emu.unpack_64(&entry[0..8])),
+                        signature   : string(emu.unpack_64(&entry[8..16])),
+                        address     : emu.unpack_64(&entry[16..24]),
+                    };
+                    trap.args.push(format!("{}{} -> {}", native.name, native.signature, emu.symbolize(native.address)));
+
+                    self.natives.retain(|known| !(known.class == native.class && known.name == native.name && known.signature == native.signature));
+                    self.natives.push(native);
+                }
+                0
+            },
+            "UnregisterNatives" => {
+                trap.args.push(self.describe(arg(1)));
+                let class = self.class_name(arg(1));
+                self.natives.retain(|native| native.class != class);
+                0
+            },
+            "GetJavaVM" => {
+                emu.mem_write(arg(1), &(JNI_BASE + VM_OFFSET).to_le_bytes()).unwrap();
+                0
+            },
+            "NewDirectByteBuffer" => {
+                trap.args = vec![format!("{:#x}", arg(1)), format!("{}", arg(2))];
+                self.new_object(JavaObject::DirectBuffer(arg(1), arg(2)))
+            },
+            "GetDirectBufferAddress" | "GetDirectBufferCapacity" => {
+                trap.args.push(self.describe(arg(1)));
+                match self.object(arg(1)) {
+                    Some(JavaObject::DirectBuffer(address, _)) if name == "GetDirectBufferAddress" => *address,
+                    Some(JavaObject::DirectBuffer(_, capacity)) => *capacity,
+                    _ => 0,
+                }
+            },
+            _ => {
+                utilities::log(&format!("jni: {} is not implemented", name), utilities::DebugLevel::ERROR);
+                0
+            }
+        };
+
+        trap
+    }
+
+    fn array_call(&mut self, emu: &mut Emulator<D>, trace_args: &mut Vec<String>, kind: &str, size: usize, operation: &str) -> u64 {
+        let arg = |n: usize| -> u64 { emu.reg_read(RegisterARM64::x(n)).unwrap() };
+        let array = arg(1);
+
+        match operation {
+            "New" => {
+                let len = arg(1) as usize;
+                trace_args.push(format!("{}", len));
+                self.new_object(JavaObject::Array(format!("[{}", type_char(kind)), size, vec![0; len * size]))
+            },
+            "GetElements" => {
+                trace_args.push(self.describe(array));
+                self.pin_array(emu, array, arg(2))
+            },
+            "ReleaseElements" => {
+                trace_args.extend(vec![self.describe(array), format!("{:#x}", arg(2)), format!("{}", arg(3))]);
+                self.release_array(emu, arg(2), arg(3));
+                0
+            },
+            _ => {
+                let (start, len, buffer) = (arg(2) as usize * size, arg(3) as usize * size, arg(4));
+                trace_args.extend(vec![self.describe(array), format!("{}", arg(2)), format!("{}", arg(3)), format!("{:#x}", buffer)]);
+
+                let data = match self.object_mut(array) {
+                    Some(JavaObject::Array(_, _, data)) if start + len <= data.len() => data,
+                    _ => return 0,
+                };
+
+                if operation == "GetRegion" {
+                    emu.mem_write(buffer, &data[start..start + len]).unwrap();
+                }
+                else if let Ok(bytes) = emu.mem_read_as_vec(buffer, len) {
+                    data[start..start + len].copy_from_slice(&bytes);
+                }
+                0
+            }
+        }
+    }
+
+    /// Copy an array's elements into guest memory, they are copied back on release.
+    fn pin_array(&mut self, emu: &mut Emulator<D>, array: u64, is_copy: u64) -> u64 {
+        let data = match self.object(array) {
+            Some(JavaObject::Array(_, _, data)) => data.clone(),
+            _ => return 0,
+        };
+
+        if is_copy != 0 {
+            emu.mem_write(is_copy, &[1]).unwrap();
+        }
+
+        let elements = self.alloc(emu, &data);
+        if elements != 0 {
+            self.pinned.insert(elements, array);
+        }
+        elements
+    }
+
+    fn release_array(&mut self, emu: &mut Emulator<D>, elements: u64, mode: u64) {
+        let array = match self.pinned.get(&elements) {
+            Some(array) => *array,
+            None => return,
+        };
+
+        if mode != JNI_ABORT {
+            if let Some(JavaObject::Array(_, _, data)) = self.object_mut(array) {
+                if let Ok(bytes) = emu.mem_read_as_vec(elements, data.len()) {
+                    *data = bytes;
+                }
+            }
+        }
+
+        // JNI_COMMIT keeps the copy around
+        if mode != 1 {
+            self.pinned.remove(&elements);
+            self.dealloc(elements);
+        }
+    }
+
+    /// Arguments of a Call*Method, from registers and the stack, a va_list or a jvalue array.
+    fn call_arguments(&self, emu: &Emulator<D>, signature: &str, variant: char, first: usize) -> Vec<u64> {
+        let kinds = parameter_kinds(signature);
+        let read = |address: u64| -> u64 { emu.mem_read_as_vec(address, 8).map(|data| emu.unpack_64(&data)).unwrap_or(0) };
+        let mut args = Vec::new();
+
+        match variant {
+            'A' => {
+                let values = emu.reg_read(RegisterARM64::x(first)).unwrap();
+                for (n, kind) in kinds.iter().enumerate() {
+                    let value = read(values + 8 * n as u64);
+                    // a jvalue float holds the f32 itself
+                    args.push(if *kind == 'F' { (f32::from_bits(value as u32) as f64).to_bits() } else { value });
+                }
+            },
+            'V' => {
+                // struct va_list { void* __stack; void* __gr_top; void* __vr_top; int __gr_offs; int __vr_offs; }
+                let va_list = emu.reg_read(RegisterARM64::x(first)).unwrap();
+                let mut stack = read(va_list);
+                let (gr_top, vr_top) = (read(va_list + 8), read(va_list + 16));
+                let offsets = read(va_list + 24);
+                let (mut gr_offs, mut vr_offs) = (offsets as i32 as i64, (offsets >> 32) as i32 as i64);
+
+                for kind in kinds.iter() {
+                    let float = *kind == 'F' || *kind == 'D';
+                    let address = if float && vr_offs < 0 {
+                        vr_offs += 16;
+                        (vr_top as i64 + vr_offs - 16) as u64
+                    } else if !float && gr_offs < 0 {
+                        gr_offs += 8;
+                        (gr_top as i64 + gr_offs - 8) as u64
+                    } else {
+                        stack += 8;
+                        stack - 8
+                    };
+                    args.push(read(address));
+                }
+            },
+            _ => {
+                let sp = emu.reg_read(RegisterARM64::SP as i32).unwrap();
+                let (mut int, mut float, mut stack) = (first, 0, 0);
+                for kind in kinds.iter() {
+                    // variadic floats are promoted to double
+                    if (*kind == 'F' || *kind == 'D') && float < 8 {
+                        args.push(read_double_register(emu, float).to_bits());
+                        float += 1;
+                    } else if *kind != 'F' && *kind != 'D' && int < 8 {
+                        args.push(emu.reg_read(RegisterARM64::x(int)).unwrap());
+                        int += 1;
+                    } else {
+                        args.push(read(sp + 8 * stack));
+                        stack += 1;
+                    }
+                }
+            }
+        }
+
+        args
+    }
+
+    fn format_value(&self, kind: char, value: u64) -> String {
+        match kind {
+            'Z' => format!("{}", value & 0xff != 0),
+            'B' => format!("{}", value as i8),
+            'C' => format!("{:?}", std::char::from_u32(value as u16 as u32).unwrap_or('?')),
+            'S' => format!("{}", value as i16),
+            'I' => format!("{}", value as i32),
+            'J' => format!("{}", value as i64),
+            'F' | 'D' => format!("{}", f64::from_bits(value)),
+            'V' => String::from("void"),
+            _ => self.describe(value),
+        }
+    }
+}
+
+/// `CallStaticIntMethodV` -> (return type, variant, index of the method id argument + 1).
+fn parse_call(name: &str) -> Option<(char, char, usize)> {
+    let (rest, first) = if let Some(rest) = name.strip_prefix("CallNonvirtual") {
+        (rest, 4)
+    } else if let Some(rest) = name.strip_prefix("CallStatic") {
+        (rest, 3)
+    } else {
+        (name.strip_prefix("Call")?, 3)
+    };
+
+    let index = rest.find("Method")?;
+    let variant = match &rest[index + 6..] {
+        "" => ' ',
+        "V" => 'V',
+        "A" => 'A',
+        _ => return None,
+    };
+    Some((type_char(&rest[..index]), variant, first))
+}
+
+/// `GetStaticIntField` -> (static, set, type).
+fn parse_field(name: &str) -> Option<(bool, bool, char)> {
+    let rest = name.strip_suffix("Field")?;
+    let (set, rest) = match rest.strip_prefix("Get") {
+        Some(rest) => (false, rest),
+        None => (true, rest.strip_prefix("Set")?),
+    };
+    let (is_static, kind) = match rest.strip_prefix("Static") {
+        Some(kind) => (true, kind),
+        None => (false, rest),
+    };
+    if !CALL_TYPES[..9].contains(&kind) {
+        return None;
+    }
+    Some((is_static, set, type_char(kind)))
+}
+
+/// `GetByteArrayRegion` -> ("Byte", element size, "GetRegion").
+fn parse_array(name: &str) -> Option<(&'static str, usize, &'static str)> {
+    for (kind, size) in PRIMITIVES.iter() {
+        for (prefix, suffix, operation) in [("New", "Array", "New"), ("Get", "ArrayElements", "GetElements"), ("Release", "ArrayElements", "ReleaseElements"),
+                                            ("Get", "ArrayRegion", "GetRegion"), ("Set", "ArrayRegion", "SetRegion")].iter() {
+            if name.len() == prefix.len() + kind.len() + suffix.len() && name.starts_with(prefix) && name.ends_with(suffix) && name[prefix.len()..].starts_with(kind) {
+                return Some((kind, *size, operation));
+            }
+        }
+    }
+    None
+}
+
+fn type_char(kind: &str) -> char {
+    match kind {
+        "Boolean" => 'Z',
+        "Byte" => 'B',
+        "Char" => 'C',
+        "Short" => 'S',
+        "Int" => 'I',
+        "Long" => 'J',
+        "Float" => 'F',
+        "Double" => 'D',
+        "Void" => 'V',
+        _ => 'L',
+    }
+}
+
+/// Type of every parameter in a method signature, `L` for objects and arrays.
+fn parameter_kinds(signature: &str) -> Vec<char> {
+    let parameters = signature.trim_start_matches('(').split(')').next().unwrap_or("");
+    let mut kinds = Vec::new();
+    let mut chars = parameters.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        match c {
+            '[' => {
+                while chars.peek() == Some(&'[') {
+                    chars.next();
+                }
+                if chars.next() == Some('L') {
+                    while chars.next().map_or(false, |c| c != ';') {}
+                }
+                kinds.push('L');
+            },
+            'L' => {
+                while chars.next().map_or(false, |c| c != ';') {}
+                kinds.push('L');
+            },
+            _ => kinds.push(c),
+        }
+    }
+    kinds
+}
+
+fn read_double_register<D>(emu: &Emulator<D>, n: usize) -> f64 {
+    match emu.reg_read_long(RegisterARM64::v(n)) {
+        Ok(value) if value.len() >= 8 => f64::from_le_bytes([value[0], value[1], value[2], value[3], value[4], value[5], value[6], value[7]]),
+        _ => 0.0,
+    }
+}
+
+fn read_float_register<D>(emu: &Emulator<D>, n: usize) -> f32 {
+    match emu.reg_read_long(RegisterARM64::v(n)) {
+        Ok(value) if value.len() >= 4 => f32::from_le_bytes([value[0], value[1], value[2], value[3]]),
+        _ => 0.0,
+    }
+}
+
+fn write_float_register<D>(emu: &Emulator<D>, n: usize, value: f32) {
+    let mut bytes = vec![0u8; 16];
+    bytes[..4].copy_from_slice(&value.to_le_bytes());
+    emu.reg_write_long(RegisterARM64::v(n), bytes.into_boxed_slice()).unwrap();
+}
+
+fn write_double_register<D>(emu: &Emulator<D>, n: usize, value: f64) {
+    let mut bytes = vec![0u8; 16];
+    bytes[..8].copy_from_slice(&value.to_le_bytes());
+    emu.reg_write_long(RegisterARM64::v(n), bytes.into_boxed_slice()).unwrap();
+}
+
+/// `Java_com_example_Foo_bar` -> `com/example/Foo`. Escapes other than `_1` are not decoded.
+fn class_of_native(symbol: &str) -> String {
+    let mangled = symbol.trim_start_matches("Java_").replace("_1", "\u{0}");
+    let class = match mangled.rfind('_') {
+        Some(index) => &mangled[..index],
+        None => "",
+    };
+    class.replace('_', "/").replace('\u{0}', "_")
+}
+
+impl<D> Emulator<D> {
+    /// Map the JavaVM and JNIEnv into the guest. Called by everything JNI, once is enough.
+    pub fn jni_init(&mut self) {
+        if self.jni.is_some() {
+            return;
+        }
+
+        let jni: Jni<D> = Jni::new();
+        self.mmu_map(JNI_BASE, JNI_SIZE, Protection::ALL, "[jni]", self.null_mut());
+
+        // JavaVM and JNIEnv are pointers to their function tables
+        self.mem_write(JNI_BASE + VM_OFFSET, &(JNI_BASE + VM_TABLE_OFFSET).to_le_bytes()).unwrap();
+        self.mem_write(JNI_BASE + ENV_OFFSET, &(JNI_BASE + ENV_TABLE_OFFSET).to_le_bytes()).unwrap();
+
+        for (table, stubs, count) in [(VM_TABLE_OFFSET, VM_STUBS_OFFSET, VM_FUNCTIONS.len()), (ENV_TABLE_OFFSET, ENV_STUBS_OFFSET, jni.env_functions.len())].iter() {
+            for n in 0..*count as u64 {
+                let stub = JNI_BASE + stubs + 4 * n;
+                self.mem_write(stub, &RET).unwrap();
+                self.mem_write(JNI_BASE + table + 8 * n, &stub.to_le_bytes()).unwrap();
+            }
+        }
+
+        self.jni = Some(jni);
+
+        let hook = self.add_code_hook(JNI_BASE + VM_STUBS_OFFSET, JNI_BASE + HEAP_OFFSET - 1, |emu: &mut Emulator<D>, address: u64, _size: u32| {
+            emu.jni_trap(address);
+        }).expect("failed to add jni hook");
+        self.jni.as_mut().unwrap().hook = Some(hook);
+    }
+
+    /// Put the Java side back to a snapshot's, dropping it when the snapshot was taken before
+    /// `jni_init`. Guest memory is restored separately, after this.
+    pub fn jni_restore(&mut self, state: Option<&JniState>) {
+        match state {
+            Some(state) => {
+                self.jni_init();
+                self.jni.as_mut().unwrap().restore_state(state);
+            },
+            None => {
+                if let Some(mut jni) = self.jni.take() {
+                    if let Some(hook) = jni.hook.take() {
+                        self.remove_hook(hook).unwrap();
+                    }
+                }
+            },
+        }
+    }
+
+    pub fn java_vm(&mut self) -> u64 {
+        self.jni_init();
+        JNI_BASE + VM_OFFSET
+    }
+
+    pub fn jni_env(&mut self) -> u64 {
+        self.jni_init();
+        JNI_BASE + ENV_OFFSET
+    }
+
+    /// Log every JNI call, jnitrace style.
+    pub fn set_jni_trace(&mut self, trace: bool) {
+        self.jni_init();
+        self.jni.as_mut().unwrap().trace = trace;
+    }
+
+    /// Implement the Java method `class.name` (`com/example/Foo`, `getKey`) for native code
+    /// calling it through Call*Method. Unimplemented methods return 0, or a new object when
+    /// they return one.
+    pub fn jni_implement<F>(&mut self, class: &str, name: &str, implementation: F)
+        where F: FnMut(&mut Emulator<D>, &JavaCall) -> u64 + 'static
+    {
+        self.jni_init();
+        self.jni.as_mut().unwrap().implementations.insert(format!("{}.{}", class, name), Box::new(implementation));
+    }
+
+    pub fn find_java_class(&mut self, name: &str) -> u64 {
+        self.jni_init();
+        self.jni.as_mut().unwrap().find_class(name)
+    }
+
+    pub fn new_java_string(&mut self, value: &str) -> u64 {
+        self.jni_init();
+        self.jni.as_mut().unwrap().new_object(JavaObject::String(String::from(value)))
+    }
+
+    pub fn new_java_bytes(&mut self, value: &[u8]) -> u64 {
+        self.jni_init();
+        self.jni.as_mut().unwrap().new_object(JavaObject::Array(String::from("[B"), 1, value.to_vec()))
+    }
+
+    pub fn java_string(&self, reference: u64) -> Option<String> {
+        match self.jni.as_ref()?.object(reference) {
+            Some(JavaObject::String(value)) => Some(value.clone()),
+            _ => None,
+        }
+    }
+
+    pub fn java_bytes(&self, reference: u64) -> Option<Vec<u8>> {
+        match self.jni.as_ref()?.object(reference) {
+            Some(JavaObject::Array(_, _, data)) => Some(data.clone()),
+            _ => None,
+        }
+    }
+
+    /// Run `JNI_OnLoad(vm, NULL)` of `module`, None when it has none.
+    pub fn jni_on_load(&mut self, module: &str) -> Option<u64> {
+        let module = self.find_module(module)?.clone();
+        let address = self.module_symbol(&module, "JNI_OnLoad")?;
+
+        let vm = self.java_vm();
+        let version = self.call_function(address, &[vm, 0]);
+        self.debug_print(format!("JNI_OnLoad of {} returned {:#x}", module.name, version));
+        Some(version)
+    }
+
+    /// Call a native method: a `Java_*` export of `module`, or `com/example/Foo.bar` as
+    /// registered with RegisterNatives. `this` is the class for static methods.
+    pub fn call_native(&mut self, module: &str, method: &str, this: Option<u64>, args: &[JValue]) -> Option<u64> {
+        self.jni_init();
+
+        let (address, class) = if method.starts_with("Java_") {
+            let module = self.find_module(module)?.clone();
+            (self.module_symbol(&module, method)?, class_of_native(method))
+        } else {
+            let index = method.rfind('.')?;
+            let (class, name) = (&method[..index], &method[index + 1..]);
+            let native = self.jni.as_ref().unwrap().natives.iter().find(|native| native.class == class && native.name == name)?;
+            (native.address, String::from(class))
+        };
+
+        let this = match this {
+            Some(this) => this,
+            None => self.find_java_class(&class),
+        };
+
+        let mut ints = vec![self.jni_env(), this];
+        let mut floats = Vec::new();
+        for arg in args {
+            match arg {
+                JValue::Boolean(value) => ints.push(*value as u64),
+                JValue::Int(value) => ints.push(*value as u32 as u64),
+                JValue::Long(value) => ints.push(*value as u64),
+                // natives are not variadic, a jfloat stays single precision
+                JValue::Float(_) | JValue::Double(_) => floats.push(arg.clone()),
+                JValue::Object(value) => ints.push(*value),
+                JValue::String(value) => ints.push(self.new_java_string(value)),
+                JValue::Bytes(value) => ints.push(self.new_java_bytes(value)),
+            }
+        }
+
+        for (n, value) in floats.iter().take(8).enumerate() {
+            match value {
+                JValue::Float(value) => write_float_register(self, n, *value),
+                JValue::Double(value) => write_double_register(self, n, *value),
+                _ => unreachable!(),
+            }
+        }
+
+        Some(self.call_function(address, &ints))
+    }
+
+    fn jni_trap(&mut self, address: u64) {
+        let mut jni = match self.jni.take() {
+            Some(jni) => jni,
+            None => return,
+        };
+
+        let caller = self.reg_read(RegisterARM64::LR as i32).unwrap();
+        let offset = address - JNI_BASE;
+
+        let mut trap = if offset >= ENV_STUBS_OFFSET {
+            jni.env_call(self, ((offset - ENV_STUBS_OFFSET) / 4) as usize)
+        } else {
+            self.vm_call(((offset - VM_STUBS_OFFSET) / 4) as usize)
+        };
+        let interface = if offset >= ENV_STUBS_OFFSET { "JNIEnv" } else { "JavaVM" };
+
+        self.jni = Some(jni);
+
+        let mut kind = None;
+        if let Some((call, return_kind)) = trap.java_call.take() {
+            kind = Some(return_kind);
+            trap.value = self.call_java(&call, return_kind);
+        }
+
+        let jni = self.jni.as_ref().unwrap();
+        if jni.trace {
+            let value = match kind {
+                Some('V') => String::from("void"),
+                Some(kind) => jni.format_value(kind, trap.value),
+                None if trap.float.is_some() => format!("{}", f64::from_bits(trap.value)),
+                None => jni.describe(trap.value),
+            };
+            println!("[jni] {}  {}->{}({}) = {}", self.symbolize(caller), interface, trap.name, trap.args.join(", "), value);
+        }
+
+        match trap.float {
+            Some('F') => write_float_register(self, 0, f64::from_bits(trap.value) as f32),
+            Some(_) => write_double_register(self, 0, f64::from_bits(trap.value)),
+            None => self.reg_write(RegisterARM64::X0 as i32, trap.value).unwrap(),
+        }
+    }
+
+    fn vm_call(&mut self, index: usize) -> Trap {
+        let name = VM_FUNCTIONS.get(index).cloned().unwrap_or("unknown");
+        let env = JNI_BASE + ENV_OFFSET;
+
+        let mut trap = Trap {
+            name        : String::from(name),
+            args        : Vec::new(),
+            value       : 0,
+            float       : None,
+            java_call   : None,
+        };
+
+        match name {
+            "GetEnv" | "AttachCurrentThread" | "AttachCurrentThreadAsDaemon" => {
+                let out = self.reg_read(RegisterARM64::X1 as i32).unwrap();
+                trap.args.push(format!("{:#x}", out));
+                if name == "GetEnv" {
+                    trap.args.push(format!("{:#x}", self.reg_read(RegisterARM64::X2 as i32).unwrap()));
+                }
+                self.mem_write(out, &env.to_le_bytes()).unwrap();
+            },
+            "DestroyJavaVM" | "DetachCurrentThread" => {},
+            _ => utilities::log(&format!("jni: JavaVM {} is not implemented", name), utilities::DebugLevel::ERROR),
+        }
+
+        trap
+    }
+
+    /// Result of a Java method called from native code.
+    fn call_java(&mut self, call: &JavaCall, return_kind: char) -> u64 {
+        let key = format!("{}.{}", call.class, call.name);
+        let implementation = self.jni.as_mut().unwrap().implementations.remove(&key);
+
+        if let Some(mut implementation) = implementation {
+            let value = implementation(self, call);
+            self.jni.as_mut().unwrap().implementations.entry(key).or_insert(implementation);
+            return value;
+        }
+
+        if return_kind != 'L' {
+            return 0;
+        }
+
+        // native code rarely checks for null, hand out an empty object of the declared type
+        let returns = call.signature.rsplit(')').next().unwrap_or("");
+        let jni = self.jni.as_mut().unwrap();
+        match returns {
+            "Ljava/lang/String;" => jni.new_object(JavaObject::String(String::new())),
+            _ if returns.starts_with('L') => {
+                let class = String::from(returns.trim_start_matches('L').trim_end_matches(';'));
+                jni.new_object(JavaObject::Object(class))
+            },
+            _ => 0,
+        }
+    }
+}
@@ -1,2 +1,4 @@
+pub mod apk;
 pub mod fs;
+pub mod jni;
 pub mod syscalls;
\ No newline at end of file
@@ -57,6 +57,13 @@ impl<D> Emulator<D> {
 
             self.add_mapinfo(map_info);
             self.write(mmap_base, &data);
+
+            // library segments are always placed with MAP_FIXED, other file mappings are the
+            // linker peeking at headers
+            if is_fixed {
+                let path = String::from(mem_info);
+                self.track_module_mapping(&path, mmap_base, off, &data);
+            }
         }
 
         self.set_return_val(mmap_base);
@@ -82,6 +89,7 @@ impl<D> Emulator<D> {
         }
         
         let aligned = ((len + 0x1000 - 1) / 0x1000) * 0x1000;
+        self.track_module_unmapping(address, aligned);
         self.mmu_unmap(address, aligned as usize);
         // self.munmap(address, len, aligned);
         self.set_return_val(0);
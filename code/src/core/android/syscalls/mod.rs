@@ -12,6 +12,27 @@ mod ioctl;
 
 use crate::{core::{rudroid::Emulator, unicorn::arch::arm64::RegisterARM64}, utilities};
 
+/// Number of syscalls kept around for crash reports.
+pub const SYSCALL_HISTORY_SIZE: usize = 32;
+
+#[derive(Debug, Clone)]
+pub struct SyscallRecord {
+    pub pc      : u64,
+    pub name    : String,
+    pub args    : [u64; 6],
+    pub ret     : Option<u64>,
+}
+
+impl std::fmt::Display for SyscallRecord {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let args = self.args.iter().map(|a| format!("{:#x}", a)).collect::<Vec<_>>().join(", ");
+        match self.ret {
+            Some(ret) => write!(f, "pc {:016x}  {}({}) = {:#x}", self.pc, self.name, args, ret),
+            None      => write!(f, "pc {:016x}  {}({}) = ?", self.pc, self.name, args),
+        }
+    }
+}
+
 pub fn get_syscall(uc: &mut Emulator<i64>) -> syscalls::Syscalls {
     // syscall_num = UC_ARM64_REG_X8
     let syscall = uc.reg_read(RegisterARM64::X8 as i32).unwrap();
@@ -30,7 +51,9 @@ impl<D> Emulator<D> {
             utilities::draw_line();
             self.debug_print(format!("got syscall: {:?}", syscall));
         }
-        
+
+        self.record_syscall(&syscall);
+
         match syscall {
             
             syscalls::Syscalls::__NR3264_mmap =>
@@ -139,6 +162,19 @@ impl<D> Emulator<D> {
                 self.sys_ioctl();
             },
 
+            syscalls::Syscalls::__NR_kill => {
+                self.sys_kill();
+            },
+            syscalls::Syscalls::__NR_tkill => {
+                self.sys_tkill();
+            },
+            syscalls::Syscalls::__NR_tgkill => {
+                self.sys_tgkill();
+            },
+            syscalls::Syscalls::__NR_rt_tgsigqueueinfo => {
+                self.sys_rt_tgsigqueueinfo();
+            },
+
             syscalls::Syscalls::__NR_exit_group => {
                 self.sys_exit_group();
             },
@@ -147,6 +183,33 @@ impl<D> Emulator<D> {
                 panic!("Syscall {:?} not implemented yet!", syscall);
             }
         }; 
+
+        let ret = self.reg_read(RegisterARM64::X0 as i32).unwrap();
+        if let Some(record) = self.syscall_history.back_mut() {
+            record.ret = Some(ret);
+        }
+    }
+
+    fn record_syscall(&mut self, syscall: &syscalls::Syscalls) {
+        let name = format!("{:?}", syscall);
+        let name = name.trim_start_matches("__NR3264_").trim_start_matches("__NR_");
+
+        let mut args = [0u64; 6];
+        for (i, arg) in args.iter_mut().enumerate() {
+            *arg = self.get_arg(i as i32);
+        }
+
+        let record = SyscallRecord {
+            pc      : self.reg_read(RegisterARM64::PC as i32).unwrap(),
+            name    : String::from(name),
+            args    : args,
+            ret     : None,
+        };
+
+        if self.syscall_history.len() == SYSCALL_HISTORY_SIZE {
+            self.syscall_history.pop_front();
+        }
+        self.syscall_history.push_back(record);
     }
 
     pub fn empty_syscall_return(&mut self) {
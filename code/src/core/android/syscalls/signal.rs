@@ -1,4 +1,18 @@
+use crate::core::crash;
+use crate::core::debugger;
+use crate::core::pid;
 use crate::core::rudroid::Emulator;
+use crate::core::unicorn::arch::arm64::RegisterARM64;
+
+const SIG_DFL       : u64 = 0;
+const SIGKILL       : i32 = 9;
+// SIGCHLD, SIGCONT, SIGSTOP, SIGTSTP, SIGTTIN, SIGTTOU, SIGURG, SIGWINCH
+const DEFAULT_IGNORE_OR_STOP: &[i32] = &[17, 18, 19, 20, 21, 22, 23, 28];
+
+/// The tid `sys_set_tid_address` hands out, what bionic caches for gettid().
+fn own_tid() -> i32 {
+    std::process::id() as i32
+}
 
 impl<D> Emulator<D> {
     pub fn sys_sigaltstack(&mut self) {
@@ -63,10 +77,74 @@ impl<D> Emulator<D> {
         self.set_return_val(0);
     }
 
+    pub fn sys_kill(&mut self) {
+        // sys_kill(pid_t pid, int sig)
+        let target  = self.get_arg(0) as i32;
+        let sig     = self.get_arg(1) as i32;
+        self.send_signal(target == pid as i32 || target == 0 || target == -1, sig);
+    }
+
+    pub fn sys_tkill(&mut self) {
+        // sys_tkill(pid_t pid, int sig)
+        let tid     = self.get_arg(0) as i32;
+        let sig     = self.get_arg(1) as i32;
+        self.send_signal(tid == own_tid() || tid == pid as i32, sig);
+    }
+
+    pub fn sys_tgkill(&mut self) {
+        // sys_tgkill(pid_t tgid, pid_t pid, int sig)
+        let tgid    = self.get_arg(0) as i32;
+        let sig     = self.get_arg(2) as i32;
+        self.send_signal(tgid == pid as i32, sig);
+    }
+
+    pub fn sys_rt_tgsigqueueinfo(&mut self) {
+        // sys_rt_tgsigqueueinfo(pid_t tgid, pid_t pid, int sig, siginfo_t __user *uinfo), how newer
+        // bionic raises
+        let tgid    = self.get_arg(0) as i32;
+        let sig     = self.get_arg(2) as i32;
+        self.send_signal(tgid == pid as i32, sig);
+    }
+
+    /// A signal to ourselves that would kill the process stops the emulation, the fault is
+    /// left in `last_fault` for whoever started it. There is no signal delivery, a signal with
+    /// a handler or one that is ignored is dropped.
+    fn send_signal(&mut self, to_self: bool, sig: i32) {
+        self.set_return_val(0);
+        if sig == 0 || !to_self || !self.signal_is_fatal(sig) {
+            return;
+        }
+
+        let pc = self.reg_read(RegisterARM64::PC as i32).unwrap();
+        self.debug_print(format!("signal {} sent to self at {:#x}", sig, pc));
+        self.last_fault = Some(crash::Fault::from_signal(sig, pc));
+        self.emu_stop().unwrap();
+    }
+
+    /// Whether `sig` terminates the process: its default action is to and `rt_sigaction` did
+    /// not install a handler or SIG_IGN.
+    fn signal_is_fatal(&self, sig: i32) -> bool {
+        if DEFAULT_IGNORE_OR_STOP.contains(&sig) {
+            return false;
+        }
+        if sig == SIGKILL {
+            return true;
+        }
+
+        // sa_handler leads the kernel's struct sigaction
+        match self.sigmap.get(&(sig as u64)) {
+            Some(action) => self.unpack_64(&action[..8]) == SIG_DFL,
+            None => true,
+        }
+    }
+
     pub fn sys_exit_group(&mut self) {
         // sys_exit_group(int error_code)
         let error_code = self.get_arg(0);
         self.debug_print(format!("sys_exit_group code: {}", error_code));
+        self.debug_trap(debugger::StopReason::Exited(error_code));
+        self.finish_traces();
+        self.stop_alloc_stats();
         self.emu_stop();
         std::process::exit(1);
     }    
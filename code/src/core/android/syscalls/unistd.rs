@@ -4,8 +4,7 @@ use crate::core::rudroid::Emulator;
 
 impl<D> Emulator<D> {
     pub fn sys_getpid(&mut self) {
-        let pid = 1337;
-        self.set_return_val(pid as u64);
+        self.set_return_val(crate::core::pid as u64);
     }
 
     pub fn sys_set_tid_address(&mut self) {
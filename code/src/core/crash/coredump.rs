@@ -0,0 +1,303 @@
+use std::fs;
+use std::path::Path;
+use xmas_elf::header;
+
+use super::Fault;
+use crate::core::pid;
+use crate::core::{uid, gid};
+use crate::core::mmu::MapInfo;
+use crate::core::rudroid::Emulator;
+use crate::core::unicorn::arch::arm64::RegisterARM64;
+use crate::core::unicorn::unicorn_const::Protection;
+
+const PAGE_SIZE     : u64 = 0x1000;
+
+const ET_CORE       : u16 = 4;
+const EM_AARCH64    : u16 = 183;
+const PT_LOAD       : u32 = 1;
+const PT_NOTE       : u32 = 4;
+
+const PF_X          : u32 = 1;
+const PF_W          : u32 = 2;
+const PF_R          : u32 = 4;
+
+const NT_PRSTATUS   : u32 = 1;
+const NT_FPREGSET   : u32 = 2;
+const NT_PRPSINFO   : u32 = 3;
+const NT_AUXV       : u32 = 6;
+const NT_FILE       : u32 = 0x4649_4c45;
+
+const ELF_HEADER_SIZE   : u64 = 64;
+const PROGRAM_HEADER_SIZE : u64 = 56;
+
+// linux ELF core files as written by fs/binfmt_elf.c, loadable by gdb-multiarch and lldb
+impl<D> Emulator<D> {
+    /// Write an ELF core file of the current emulator state to `path`.
+    ///
+    /// `fault` fills in the signal information of NT_PRSTATUS, pass `None` for dumps taken on demand.
+    pub fn write_core_dump(&self, path: &str, fault: Option<&Fault>) -> std::io::Result<()> {
+        let segments = self.core_segments();
+        let notes = self.core_notes(fault);
+
+        let phnum       = segments.len() as u64 + 1;
+        let notes_off   = ELF_HEADER_SIZE + phnum * PROGRAM_HEADER_SIZE;
+        let mut data_off = self.align_size(notes_off + notes.len() as u64, PAGE_SIZE);
+
+        let mut core: Vec<u8> = Vec::new();
+        core.extend_from_slice(&self.core_elf_header(phnum as u16));
+        core.extend_from_slice(&self.core_program_header(PT_NOTE, 0, notes_off, 0, notes.len() as u64, 0));
+
+        for segment in segments.iter() {
+            let size = segment.memory_end - segment.memory_start;
+            core.extend_from_slice(&self.core_program_header(PT_LOAD, core_flags(segment.memory_perms), data_off, segment.memory_start, size, PAGE_SIZE));
+            data_off += size;
+        }
+
+        core.extend_from_slice(&notes);
+        core.resize(self.align_size(core.len() as u64, PAGE_SIZE) as usize, 0);
+
+        for segment in segments.iter() {
+            core.extend_from_slice(&self.core_segment_data(segment));
+        }
+
+        if let Some(dir) = Path::new(path).parent() {
+            if !dir.as_os_str().is_empty() {
+                fs::create_dir_all(dir)?;
+            }
+        }
+        fs::write(path, core)
+    }
+
+    /// Write a core file for `fault` next to the tombstones and return its path.
+    pub fn write_crash_core_dump(&self, fault: &Fault) -> std::io::Result<String> {
+        let mut index = 0;
+        let path = loop {
+            let path = format!("{}/core_{:02}", self.tombstone_dir, index);
+            if !Path::new(&path).exists() {
+                break path;
+            }
+            index += 1;
+        };
+
+        self.write_core_dump(&path, Some(fault))?;
+        Ok(path)
+    }
+
+    /// Memory map flattened into non overlapping ranges, file mmaps placed inside
+    /// `[syscall_mmap]` regions would otherwise show up twice.
+    fn core_segments(&self) -> Vec<MapInfo> {
+        let mut segments: Vec<MapInfo> = Vec::new();
+
+        for map_info in self.sorted_mappings() {
+            let mut segment = map_info.clone();
+            if let Some(last) = segments.last() {
+                segment.memory_start = std::cmp::max(segment.memory_start, last.memory_end);
+            }
+            if segment.memory_start < segment.memory_end {
+                segments.push(segment);
+            }
+        }
+
+        segments
+    }
+
+    fn core_segment_data(&self, segment: &MapInfo) -> Vec<u8> {
+        let mut data = Vec::with_capacity((segment.memory_end - segment.memory_start) as usize);
+        let mut address = segment.memory_start;
+
+        // unmapped holes left behind by munmap are dumped as zeroes
+        while address < segment.memory_end {
+            let len = std::cmp::min(PAGE_SIZE, segment.memory_end - address) as usize;
+            match self.mem_read_as_vec(address, len) {
+                Ok(page) => data.extend_from_slice(&page),
+                Err(_) => data.extend(std::iter::repeat(0).take(len)),
+            }
+            address += len as u64;
+        }
+
+        data
+    }
+
+    fn core_elf_header(&self, phnum: u16) -> Vec<u8> {
+        let mut ehdr: Vec<u8> = vec![0x7f, b'E', b'L', b'F', 2];
+        ehdr.push(match self.endian {
+            header::Data::BigEndian => 2,
+            _ => 1,
+        });
+        ehdr.push(1);                                           // EI_VERSION
+        ehdr.resize(16, 0);
+
+        ehdr.extend_from_slice(&self.pack_16(ET_CORE));
+        ehdr.extend_from_slice(&self.pack_16(EM_AARCH64));
+        ehdr.extend_from_slice(&self.pack_32(1));               // e_version
+        ehdr.extend_from_slice(&self.pack_64(0));               // e_entry
+        ehdr.extend_from_slice(&self.pack_64(ELF_HEADER_SIZE)); // e_phoff
+        ehdr.extend_from_slice(&self.pack_64(0));               // e_shoff
+        ehdr.extend_from_slice(&self.pack_32(0));               // e_flags
+        ehdr.extend_from_slice(&self.pack_16(ELF_HEADER_SIZE as u16));
+        ehdr.extend_from_slice(&self.pack_16(PROGRAM_HEADER_SIZE as u16));
+        ehdr.extend_from_slice(&self.pack_16(phnum));
+        ehdr.extend_from_slice(&self.pack_16(0));               // e_shentsize
+        ehdr.extend_from_slice(&self.pack_16(0));               // e_shnum
+        ehdr.extend_from_slice(&self.pack_16(0));               // e_shstrndx
+        ehdr
+    }
+
+    fn core_program_header(&self, p_type: u32, flags: u32, offset: u64, vaddr: u64, size: u64, align: u64) -> Vec<u8> {
+        let mut phdr: Vec<u8> = Vec::new();
+        phdr.extend_from_slice(&self.pack_32(p_type));
+        phdr.extend_from_slice(&self.pack_32(flags));
+        phdr.extend_from_slice(&self.pack_64(offset));
+        phdr.extend_from_slice(&self.pack_64(vaddr));
+        phdr.extend_from_slice(&self.pack_64(0));               // p_paddr
+        phdr.extend_from_slice(&self.pack_64(size));            // p_filesz
+        phdr.extend_from_slice(&self.pack_64(size));            // p_memsz
+        phdr.extend_from_slice(&self.pack_64(align));
+        phdr
+    }
+
+    fn core_notes(&self, fault: Option<&Fault>) -> Vec<u8> {
+        let mut notes: Vec<u8> = Vec::new();
+        notes.extend_from_slice(&self.core_note(NT_PRSTATUS, &self.core_prstatus(fault)));
+        notes.extend_from_slice(&self.core_note(NT_PRPSINFO, &self.core_prpsinfo()));
+        notes.extend_from_slice(&self.core_note(NT_FPREGSET, &self.core_fpregset()));
+
+        let mut auxv: Vec<u8> = Vec::new();
+        for (key, value) in self.auxv.iter() {
+            auxv.extend_from_slice(&self.pack_64(*key));
+            auxv.extend_from_slice(&self.pack_64(*value));
+        }
+        notes.extend_from_slice(&self.core_note(NT_AUXV, &auxv));
+        notes.extend_from_slice(&self.core_note(NT_FILE, &self.core_file_note()));
+        notes
+    }
+
+    fn core_note(&self, n_type: u32, desc: &[u8]) -> Vec<u8> {
+        let name = b"CORE\0";
+        let mut note: Vec<u8> = Vec::new();
+        note.extend_from_slice(&self.pack_32(name.len() as u32));
+        note.extend_from_slice(&self.pack_32(desc.len() as u32));
+        note.extend_from_slice(&self.pack_32(n_type));
+        note.extend_from_slice(name);
+        note.resize(self.align_size(note.len() as u64, 4) as usize, 0);
+        note.extend_from_slice(desc);
+        note.resize(self.align_size(note.len() as u64, 4) as usize, 0);
+        note
+    }
+
+    // struct elf_prstatus
+    fn core_prstatus(&self, fault: Option<&Fault>) -> Vec<u8> {
+        let (signo, code) = match fault {
+            Some(fault) => (fault.signal, fault.code),
+            None => (0, 0),
+        };
+
+        let mut prstatus: Vec<u8> = Vec::new();
+        prstatus.extend_from_slice(&self.pack_32(signo as u32));    // si_signo
+        prstatus.extend_from_slice(&self.pack_32(code as u32));     // si_code
+        prstatus.extend_from_slice(&self.pack_32(0));               // si_errno
+        prstatus.extend_from_slice(&self.pack_16(signo as u16));    // pr_cursig
+        prstatus.resize(16, 0);
+        prstatus.extend_from_slice(&self.pack_64(0));               // pr_sigpend
+        prstatus.extend_from_slice(&self.pack_64(0));               // pr_sighold
+        prstatus.extend_from_slice(&self.pack_32(pid));             // pr_pid
+        prstatus.extend_from_slice(&self.pack_32(1));               // pr_ppid
+        prstatus.extend_from_slice(&self.pack_32(pid));             // pr_pgrp
+        prstatus.extend_from_slice(&self.pack_32(pid));             // pr_sid
+        prstatus.resize(prstatus.len() + 4 * 16, 0);                // pr_utime, pr_stime, pr_cutime, pr_cstime
+
+        // struct user_pt_regs
+        for n in 0..31 {
+            prstatus.extend_from_slice(&self.pack_64(self.reg_read(RegisterARM64::x(n)).unwrap_or(0)));
+        }
+        prstatus.extend_from_slice(&self.pack_64(self.reg_read(RegisterARM64::SP as i32).unwrap_or(0)));
+        prstatus.extend_from_slice(&self.pack_64(self.reg_read(RegisterARM64::PC as i32).unwrap_or(0)));
+        prstatus.extend_from_slice(&self.pack_64(self.reg_read(RegisterARM64::NZCV as i32).unwrap_or(0)));
+
+        prstatus.extend_from_slice(&self.pack_32(1));               // pr_fpvalid
+        prstatus.resize(392, 0);
+        prstatus
+    }
+
+    // struct elf_prpsinfo
+    fn core_prpsinfo(&self) -> Vec<u8> {
+        let name = Path::new(&self.elf_path).file_name().map(|n| n.to_string_lossy().to_string()).unwrap_or_default();
+        let mut psargs = self.elf_path.clone();
+        for arg in self.args.iter() {
+            psargs.push(' ');
+            psargs.push_str(arg);
+        }
+
+        let mut prpsinfo: Vec<u8> = vec![b'R', b'R', 0, 0];         // pr_state, pr_sname, pr_zomb, pr_nice
+        prpsinfo.resize(8, 0);
+        prpsinfo.extend_from_slice(&self.pack_64(0));               // pr_flag
+        prpsinfo.extend_from_slice(&self.pack_32(uid));
+        prpsinfo.extend_from_slice(&self.pack_32(gid));
+        prpsinfo.extend_from_slice(&self.pack_32(pid));             // pr_pid
+        prpsinfo.extend_from_slice(&self.pack_32(1));               // pr_ppid
+        prpsinfo.extend_from_slice(&self.pack_32(pid));             // pr_pgrp
+        prpsinfo.extend_from_slice(&self.pack_32(pid));             // pr_sid
+        prpsinfo.extend_from_slice(&fixed_str(&name, 16));          // pr_fname
+        prpsinfo.extend_from_slice(&fixed_str(&psargs, 80));        // pr_psargs
+        prpsinfo
+    }
+
+    // struct user_fpsimd_state
+    fn core_fpregset(&self) -> Vec<u8> {
+        let mut fpregset: Vec<u8> = Vec::new();
+        for n in 0..32 {
+            let mut vreg = self.reg_read_long(RegisterARM64::v(n)).map(|v| v.to_vec()).unwrap_or_default();
+            vreg.resize(16, 0);
+            fpregset.extend_from_slice(&vreg);
+        }
+        fpregset.extend_from_slice(&self.pack_32(0));               // fpsr
+        fpregset.extend_from_slice(&self.pack_32(0));               // fpcr
+        fpregset.resize(528, 0);
+        fpregset
+    }
+
+    fn core_file_note(&self) -> Vec<u8> {
+        let files: Vec<MapInfo> = self.sorted_mappings().into_iter()
+            .filter(|map_info| map_info.description.starts_with('/'))
+            .collect();
+
+        let mut note: Vec<u8> = Vec::new();
+        note.extend_from_slice(&self.pack_64(files.len() as u64));
+        note.extend_from_slice(&self.pack_64(PAGE_SIZE));
+
+        for map_info in files.iter() {
+            let page_offset = (map_info.memory_start - self.module_base(&map_info.description)) / PAGE_SIZE;
+            note.extend_from_slice(&self.pack_64(map_info.memory_start));
+            note.extend_from_slice(&self.pack_64(map_info.memory_end));
+            note.extend_from_slice(&self.pack_64(page_offset));
+        }
+
+        for map_info in files.iter() {
+            note.extend_from_slice(map_info.description.as_bytes());
+            note.push(0);
+        }
+
+        note
+    }
+}
+
+fn core_flags(perms: Protection) -> u32 {
+    let mut flags = 0;
+    if perms.contains(Protection::READ) {
+        flags |= PF_R;
+    }
+    if perms.contains(Protection::WRITE) {
+        flags |= PF_W;
+    }
+    if perms.contains(Protection::EXEC) {
+        flags |= PF_X;
+    }
+    flags
+}
+
+fn fixed_str(value: &str, len: usize) -> Vec<u8> {
+    let mut bytes = value.as_bytes().to_vec();
+    bytes.truncate(len - 1);
+    bytes.resize(len, 0);
+    bytes
+}
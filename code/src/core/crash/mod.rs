@@ -0,0 +1,121 @@
+pub mod coredump;
+pub mod tombstone;
+pub mod triage;
+
+use super::unicorn::unicorn_const::{uc_error, MemType};
+
+pub const SIGILL    : i32 = 4;
+pub const SIGTRAP   : i32 = 5;
+pub const SIGABRT   : i32 = 6;
+pub const SIGBUS    : i32 = 7;
+pub const SIGSEGV   : i32 = 11;
+
+pub const SEGV_MAPERR   : i32 = 1;
+pub const SEGV_ACCERR   : i32 = 2;
+pub const ILL_ILLOPC    : i32 = 1;
+pub const BUS_ADRALN    : i32 = 1;
+pub const SI_USER       : i32 = 0;
+pub const SI_TKILL      : i32 = -6;
+
+/// What the faulting instruction was doing with `fault_addr`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Access {
+    Read,
+    Write,
+    Fetch,
+    // not a memory access, e.g. an abort
+    None,
+}
+
+/// A fatal fault raised by the guest, described the way the kernel would deliver it.
+#[derive(Debug, Clone)]
+pub struct Fault {
+    pub signal      : i32,
+    pub code        : i32,
+    pub fault_addr  : u64,
+    pub cause       : String,
+    pub access      : Access,
+}
+
+impl Fault {
+    /// Fault reported by one of the invalid memory hooks.
+    pub fn from_mem_type(mem_type: MemType, address: u64) -> Fault {
+        let (signal, code, cause, access) = match mem_type {
+            MemType::READ_UNMAPPED  => (SIGSEGV, SEGV_MAPERR, "read from unmapped memory", Access::Read),
+            MemType::WRITE_UNMAPPED => (SIGSEGV, SEGV_MAPERR, "write to unmapped memory", Access::Write),
+            MemType::FETCH_UNMAPPED => (SIGSEGV, SEGV_MAPERR, "fetch from unmapped memory", Access::Fetch),
+            MemType::READ_PROT      => (SIGSEGV, SEGV_ACCERR, "read from non-readable memory", Access::Read),
+            MemType::WRITE_PROT     => (SIGSEGV, SEGV_ACCERR, "write to read-only memory", Access::Write),
+            MemType::FETCH_PROT     => (SIGSEGV, SEGV_ACCERR, "fetch from non-executable memory", Access::Fetch),
+            _                       => (SIGSEGV, SEGV_MAPERR, "invalid memory access", Access::None),
+        };
+
+        Fault {
+            signal      : signal,
+            code        : code,
+            fault_addr  : address,
+            cause       : String::from(cause),
+            access      : access,
+        }
+    }
+
+    /// Fault derived from the error `emu_start` returned, when no memory hook caught it first.
+    pub fn from_uc_error(err: uc_error, pc: u64) -> Fault {
+        let (signal, code, cause, access) = match err {
+            uc_error::READ_UNMAPPED     => (SIGSEGV, SEGV_MAPERR, "read from unmapped memory", Access::Read),
+            uc_error::WRITE_UNMAPPED    => (SIGSEGV, SEGV_MAPERR, "write to unmapped memory", Access::Write),
+            uc_error::FETCH_UNMAPPED    => (SIGSEGV, SEGV_MAPERR, "fetch from unmapped memory", Access::Fetch),
+            uc_error::READ_PROT         => (SIGSEGV, SEGV_ACCERR, "read from non-readable memory", Access::Read),
+            uc_error::WRITE_PROT        => (SIGSEGV, SEGV_ACCERR, "write to read-only memory", Access::Write),
+            uc_error::FETCH_PROT        => (SIGSEGV, SEGV_ACCERR, "fetch from non-executable memory", Access::Fetch),
+            uc_error::READ_UNALIGNED    => (SIGBUS,  BUS_ADRALN,  "unaligned memory access", Access::Read),
+            uc_error::WRITE_UNALIGNED   => (SIGBUS,  BUS_ADRALN,  "unaligned memory access", Access::Write),
+            uc_error::FETCH_UNALIGNED   => (SIGBUS,  BUS_ADRALN,  "unaligned memory access", Access::Fetch),
+            uc_error::INSN_INVALID      => (SIGILL,  ILL_ILLOPC,  "invalid instruction", Access::None),
+            uc_error::EXCEPTION         => (SIGILL,  ILL_ILLOPC,  "unhandled cpu exception", Access::None),
+            _                           => (SIGABRT, SI_USER,     "emulation aborted", Access::None),
+        };
+
+        Fault {
+            signal      : signal,
+            code        : code,
+            fault_addr  : pc,
+            cause       : format!("{} ({:?})", cause, err),
+            access      : access,
+        }
+    }
+
+    /// The guest sending itself a fatal signal, abort() and friends.
+    pub fn from_signal(signal: i32, pc: u64) -> Fault {
+        Fault {
+            signal      : signal,
+            code        : SI_TKILL,
+            fault_addr  : pc,
+            cause       : format!("signal {} sent by the process itself", signal),
+            access      : Access::None,
+        }
+    }
+
+    pub fn signal_name(&self) -> &'static str {
+        match self.signal {
+            SIGILL  => "SIGILL",
+            SIGTRAP => "SIGTRAP",
+            SIGABRT => "SIGABRT",
+            SIGBUS  => "SIGBUS",
+            SIGSEGV => "SIGSEGV",
+            _       => "UNKNOWN",
+        }
+    }
+
+    pub fn code_name(&self) -> &'static str {
+        match (self.signal, self.code) {
+            (SIGSEGV, SEGV_MAPERR)  => "SEGV_MAPERR",
+            (SIGSEGV, SEGV_ACCERR)  => "SEGV_ACCERR",
+            (SIGILL, ILL_ILLOPC)    => "ILL_ILLOPC",
+            (SIGBUS, BUS_ADRALN)    => "BUS_ADRALN",
+            (_, SI_USER)            => "SI_USER",
+            (_, SI_TKILL)           => "SI_TKILL",
+            _                       => "UNKNOWN",
+        }
+    }
+}
@@ -0,0 +1,229 @@
+use std::fs;
+use std::path::Path;
+use std::fmt::Write;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use super::{Fault, SIGSEGV};
+use crate::core::pid;
+use crate::core::uid;
+use crate::core::rudroid::Emulator;
+use crate::core::unicorn::arch::arm64::RegisterARM64;
+
+const MAX_FRAMES        : usize = 64;
+const MEMORY_DUMP_SIZE  : u64   = 0x100;
+
+// debuggerd style crash reports, see system/core/debuggerd/libdebuggerd/tombstone.cpp
+impl<D> Emulator<D> {
+    /// Render a tombstone for `fault` from the current emulator state.
+    pub fn tombstone(&self, fault: &Fault) -> String {
+        let mut out = String::new();
+        let name = Path::new(&self.elf_path).file_name().map(|n| n.to_string_lossy().to_string()).unwrap_or_default();
+
+        writeln!(out, "*** *** *** *** *** *** *** *** *** *** *** *** *** *** *** ***").unwrap();
+        writeln!(out, "Build fingerprint: 'rudroid'").unwrap();
+        writeln!(out, "Revision: '0'").unwrap();
+        writeln!(out, "ABI: 'arm64'").unwrap();
+        writeln!(out, "Timestamp: {}", format_timestamp(SystemTime::now())).unwrap();
+        writeln!(out, "pid: {}, tid: {}, name: {}  >>> {} <<<", pid, pid, name, self.elf_path).unwrap();
+        writeln!(out, "uid: {}", uid).unwrap();
+        writeln!(out, "signal {} ({}), code {} ({}), fault addr {:#x}", fault.signal, fault.signal_name(), fault.code, fault.code_name(), fault.fault_addr).unwrap();
+
+        if fault.signal == SIGSEGV && fault.fault_addr < 0x1000 {
+            writeln!(out, "Cause: null pointer dereference").unwrap();
+        }
+        else {
+            writeln!(out, "Cause: {}", fault.cause).unwrap();
+        }
+
+        self.tombstone_registers(&mut out);
+        self.tombstone_backtrace(&mut out);
+        self.tombstone_heap(&mut out);
+        self.tombstone_memory(&mut out);
+        self.tombstone_memory_map(&mut out, fault.fault_addr);
+        self.tombstone_syscalls(&mut out);
+
+        out
+    }
+
+    /// Write a tombstone for `fault` into `tombstone_dir` and return its path.
+    pub fn write_tombstone(&self, fault: &Fault) -> std::io::Result<String> {
+        fs::create_dir_all(&self.tombstone_dir)?;
+
+        let mut index = 0;
+        let path = loop {
+            let path = format!("{}/tombstone_{:02}", self.tombstone_dir, index);
+            if !Path::new(&path).exists() {
+                break path;
+            }
+            index += 1;
+        };
+
+        fs::write(&path, self.tombstone(fault))?;
+        Ok(path)
+    }
+
+    /// Module a guest address belongs to, and the address relative to the module's base.
+    pub fn module_offset(&self, address: u64) -> Option<(String, u64)> {
+        let map_info = self.find_mapping(address)?;
+        let base = self.module_base(&map_info.description);
+        Some((map_info.description.clone(), address - base))
+    }
+
+    fn tombstone_registers(&self, out: &mut String) {
+        let mut line = String::new();
+        for n in 0..30 {
+            let value = self.reg_read(RegisterARM64::x(n)).unwrap_or(0);
+            let name = format!("x{}", n);
+            write!(line, "  {:<3} {:016x}", name, value).unwrap();
+            if n % 4 == 3 || n == 29 {
+                writeln!(out, "  {}", line).unwrap();
+                line.clear();
+            }
+        }
+
+        let lr  = self.reg_read(RegisterARM64::LR as i32).unwrap_or(0);
+        let sp  = self.reg_read(RegisterARM64::SP as i32).unwrap_or(0);
+        let pc  = self.reg_read(RegisterARM64::PC as i32).unwrap_or(0);
+        let pst = self.reg_read(RegisterARM64::NZCV as i32).unwrap_or(0);
+        writeln!(out, "    lr  {:016x}  sp  {:016x}  pc  {:016x}  pst {:016x}", lr, sp, pc, pst).unwrap();
+        writeln!(out).unwrap();
+
+        for n in (0..32).step_by(2) {
+            let v0 = self.reg_read_long(RegisterARM64::v(n)).map(|v| simd_value(&v)).unwrap_or(0);
+            let v1 = self.reg_read_long(RegisterARM64::v(n + 1)).map(|v| simd_value(&v)).unwrap_or(0);
+            writeln!(out, "    {:<3} {:032x}  {:<3} {:032x}", format!("v{}", n), v0, format!("v{}", n + 1), v1).unwrap();
+        }
+    }
+
+    fn tombstone_backtrace(&self, out: &mut String) {
+        writeln!(out).unwrap();
+        writeln!(out, "backtrace:").unwrap();
+
+        for (i, frame) in self.backtrace(MAX_FRAMES).iter().enumerate() {
+            match &frame.info {
+                Some(info) => {
+                    write!(out, "      #{:02} pc {:016x}  {}", i, info.module_offset, info.module).unwrap();
+                    if let Some((name, offset)) = &info.symbol {
+                        write!(out, " ({}+{})", name, offset).unwrap();
+                    }
+                    writeln!(out).unwrap();
+                },
+                None => writeln!(out, "      #{:02} pc {:016x}  <unknown>", i, frame.pc).unwrap(),
+            }
+        }
+    }
+
+    fn tombstone_heap(&self, out: &mut String) {
+        if let Some(error) = self.heap.as_ref().and_then(|heap| heap.last_error.as_ref()) {
+            writeln!(out).unwrap();
+            write!(out, "{}", self.heap_report(error)).unwrap();
+        }
+    }
+
+    fn tombstone_memory(&self, out: &mut String) {
+        let mut registers: Vec<(String, u64)> = (0..30)
+            .map(|n| (format!("x{}", n), self.reg_read(RegisterARM64::x(n)).unwrap_or(0)))
+            .collect();
+        registers.push((String::from("lr"), self.reg_read(RegisterARM64::LR as i32).unwrap_or(0)));
+        registers.push((String::from("sp"), self.reg_read(RegisterARM64::SP as i32).unwrap_or(0)));
+        registers.push((String::from("pc"), self.reg_read(RegisterARM64::PC as i32).unwrap_or(0)));
+
+        for (name, value) in registers {
+            let map_info = match self.find_mapping(value) {
+                Some(map_info) => map_info,
+                None => continue,
+            };
+
+            let start = std::cmp::max((value & !0xf).saturating_sub(MEMORY_DUMP_SIZE / 2), map_info.memory_start);
+            let end   = std::cmp::min(start + MEMORY_DUMP_SIZE, map_info.memory_end);
+            let data  = match self.mem_read_as_vec(start, (end - start) as usize) {
+                Ok(data) => data,
+                Err(_) => continue,
+            };
+
+            writeln!(out).unwrap();
+            writeln!(out, "memory near {} ({}):", name, map_info.description).unwrap();
+            for (i, line) in data.chunks(16).enumerate() {
+                let mut words = String::new();
+                for word in line.chunks(8) {
+                    let mut padded = [0u8; 8];
+                    padded[..word.len()].copy_from_slice(word);
+                    write!(words, " {:016x}", self.unpack_64(&padded)).unwrap();
+                }
+                let ascii: String = line.iter().map(|b| if (0x20..0x7f).contains(b) { *b as char } else { '.' }).collect();
+                writeln!(out, "    {:016x}{}  {}", start + (i * 16) as u64, words, ascii).unwrap();
+            }
+        }
+    }
+
+    fn tombstone_memory_map(&self, out: &mut String, fault_addr: u64) {
+        let mappings = self.sorted_mappings();
+        let mut fault_shown = false;
+
+        writeln!(out).unwrap();
+        writeln!(out, "memory map ({} entries):", mappings.len()).unwrap();
+
+        for map_info in mappings.iter() {
+            if !fault_shown && fault_addr < map_info.memory_start {
+                writeln!(out, "--->Fault address falls at {} between mapped regions", split_address(fault_addr)).unwrap();
+                fault_shown = true;
+            }
+
+            let prefix = if fault_addr >= map_info.memory_start && fault_addr < map_info.memory_end {
+                fault_shown = true;
+                "--->"
+            }
+            else {
+                "    "
+            };
+
+            writeln!(out, "{}{}-{} {}  {:>8x}  {}", prefix,
+                split_address(map_info.memory_start),
+                split_address(map_info.memory_end - 1),
+                map_info.memory_perms,
+                map_info.memory_end - map_info.memory_start,
+                map_info.description).unwrap();
+        }
+
+        if !fault_shown {
+            writeln!(out, "--->Fault address falls at {} after any mapped regions", split_address(fault_addr)).unwrap();
+        }
+    }
+
+    fn tombstone_syscalls(&self, out: &mut String) {
+        writeln!(out).unwrap();
+        writeln!(out, "last {} syscalls:", self.syscall_history.len()).unwrap();
+        for record in self.syscall_history.iter() {
+            writeln!(out, "    {}", record).unwrap();
+        }
+    }
+}
+
+fn simd_value(bytes: &[u8]) -> u128 {
+    let mut value = [0u8; 16];
+    value[..bytes.len()].copy_from_slice(bytes);
+    u128::from_le_bytes(value)
+}
+
+fn split_address(address: u64) -> String {
+    format!("{:08x}'{:08x}", address >> 32, address & 0xffff_ffff)
+}
+
+fn format_timestamp(time: SystemTime) -> String {
+    let secs = time.duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+    let days = (secs / 86400) as i64;
+    let rem  = secs % 86400;
+
+    // civil from days, http://howardhinnant.github.io/date_algorithms.html
+    let z   = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp  = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year  = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    format!("{:04}-{:02}-{:02} {:02}:{:02}:{:02}+0000", year, month, day, rem / 3600, (rem % 3600) / 60, rem % 60)
+}
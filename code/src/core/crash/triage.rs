@@ -0,0 +1,273 @@
+use std::collections::HashMap;
+use std::fmt::Write;
+
+use super::{Access, Fault, SIGABRT, SIGILL, SEGV_ACCERR};
+use crate::utilities::Fnv;
+use crate::core::heap::HeapErrorKind;
+use crate::core::rudroid::Emulator;
+use crate::core::symbols::unwind::Frame;
+use crate::core::unicorn::arch::arm64::RegisterARM64;
+
+/// Frames hashed into a bucket unless asked otherwise.
+pub const DEFAULT_FRAMES    : usize = 5;
+
+// addresses this low come from a null pointer
+const NULL_PAGE             : u64 = 0x1000;
+const MAX_FRAMES            : usize = 64;
+
+// the way down to the abort, skipped so a crash is bucketed by who asked for it
+const FATAL_FUNCTIONS       : &[&str] = &[
+    "abort", "raise", "kill", "tkill", "tgkill", "pthread_kill",
+    "__stack_chk_fail", "__fortify_fatal", "__fortify_chk_fail",
+    "__assert", "__assert2", "async_safe_fatal_no_abort", "async_safe_fatal_va_list",
+    // where the guarded heap stops on a bad free
+    "free", "realloc",
+];
+
+/// What went wrong, most severe first.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum CrashKind {
+    // the pc went somewhere that is not code
+    PcControl,
+    // caught by the guarded heap
+    UseAfterFree,
+    DoubleFree,
+    HeapOverflow,
+    // __stack_chk_fail found a smashed canary
+    StackSmash,
+    WildWrite,
+    WriteReadOnly,
+    WildRead,
+    InvalidFree,
+    NullDeref,
+    IllegalInstruction,
+    // abort(), a failed assertion or a fortify check
+    Abort,
+    Unknown,
+}
+
+impl CrashKind {
+    pub fn name(&self) -> &'static str {
+        match self {
+            CrashKind::PcControl            => "pc-control",
+            CrashKind::UseAfterFree         => "use-after-free",
+            CrashKind::DoubleFree           => "double-free",
+            CrashKind::HeapOverflow         => "heap-overflow",
+            CrashKind::StackSmash           => "stack-smash",
+            CrashKind::WildWrite            => "wild-write",
+            CrashKind::WriteReadOnly        => "write-read-only",
+            CrashKind::WildRead             => "wild-read",
+            CrashKind::InvalidFree          => "invalid-free",
+            CrashKind::NullDeref            => "null-deref",
+            CrashKind::IllegalInstruction   => "illegal-instruction",
+            CrashKind::Abort                => "abort",
+            CrashKind::Unknown              => "unknown",
+        }
+    }
+
+    /// A rough guess in the spirit of !exploitable.
+    pub fn exploitability(&self) -> &'static str {
+        match self {
+            CrashKind::PcControl |
+            CrashKind::UseAfterFree |
+            CrashKind::DoubleFree |
+            CrashKind::HeapOverflow |
+            CrashKind::StackSmash |
+            CrashKind::WildWrite            => "EXPLOITABLE",
+            CrashKind::WriteReadOnly |
+            CrashKind::InvalidFree          => "PROBABLY_EXPLOITABLE",
+            CrashKind::NullDeref |
+            CrashKind::Abort                => "PROBABLY_NOT_EXPLOITABLE",
+            CrashKind::WildRead |
+            CrashKind::IllegalInstruction |
+            CrashKind::Unknown              => "UNKNOWN",
+        }
+    }
+}
+
+impl std::fmt::Display for CrashKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+/// A classified crash, `bucket` the same for crashes with the same kind and top frames.
+#[derive(Debug, Clone)]
+pub struct Crash {
+    pub kind    : CrashKind,
+    pub bucket  : u64,
+    pub pc      : u64,
+    pub fault   : Fault,
+    // symbolized top frames, the abort machinery left out
+    pub frames  : Vec<String>,
+}
+
+/// One unique crash and the smallest input seen reaching it.
+#[derive(Debug, Clone)]
+pub struct Bucket {
+    pub crash       : Crash,
+    pub count       : u64,
+    pub reproducer  : Vec<u8>,
+    // where the reproducer was saved, if it was
+    pub path        : Option<String>,
+}
+
+/// Crashes of a campaign sorted into buckets.
+pub struct Triage {
+    pub depth   : usize,
+    pub crashes : u64,
+    buckets     : HashMap<u64, Bucket>,
+}
+
+impl Triage {
+    pub fn new(depth: usize) -> Triage {
+        Triage {
+            depth   : depth,
+            crashes : 0,
+            buckets : HashMap::new(),
+        }
+    }
+
+    /// Count `crash`, true when it is the first of its bucket. The smallest input is kept.
+    pub fn add(&mut self, crash: &Crash, input: &[u8]) -> bool {
+        self.crashes += 1;
+        match self.buckets.get_mut(&crash.bucket) {
+            Some(bucket) => {
+                bucket.count += 1;
+                if input.len() < bucket.reproducer.len() {
+                    bucket.reproducer = input.to_vec();
+                }
+                false
+            },
+            None => {
+                self.buckets.insert(crash.bucket, Bucket {
+                    crash       : crash.clone(),
+                    count       : 1,
+                    reproducer  : input.to_vec(),
+                    path        : None,
+                });
+                true
+            },
+        }
+    }
+
+    pub fn bucket(&self, bucket: u64) -> Option<&Bucket> {
+        self.buckets.get(&bucket)
+    }
+
+    pub fn bucket_mut(&mut self, bucket: u64) -> Option<&mut Bucket> {
+        self.buckets.get_mut(&bucket)
+    }
+
+    /// Most severe first, then the most frequent.
+    pub fn buckets(&self) -> Vec<&Bucket> {
+        let mut buckets: Vec<&Bucket> = self.buckets.values().collect();
+        buckets.sort_by_key(|bucket| (bucket.crash.kind, std::cmp::Reverse(bucket.count), bucket.crash.bucket));
+        buckets
+    }
+
+    pub fn report(&self) -> String {
+        let mut out = String::new();
+        writeln!(out, "{} crashes in {} unique buckets, {} frames each", self.crashes, self.buckets.len(), self.depth).unwrap();
+
+        for (n, bucket) in self.buckets().iter().enumerate() {
+            let crash = &bucket.crash;
+            writeln!(out).unwrap();
+            writeln!(out, "#{} bucket {:016x}  {}  {}  ({} crashes)", n + 1, crash.bucket, crash.kind, crash.kind.exploitability(), bucket.count).unwrap();
+            writeln!(out, "    {} ({}), {} at {:#x}", crash.fault.signal_name(), crash.fault.code_name(), crash.fault.cause, crash.fault.fault_addr).unwrap();
+            match &bucket.path {
+                Some(path) => writeln!(out, "    reproducer: {} ({} bytes)", path, bucket.reproducer.len()).unwrap(),
+                None => writeln!(out, "    reproducer: {} bytes", bucket.reproducer.len()).unwrap(),
+            }
+            for (i, frame) in crash.frames.iter().enumerate() {
+                writeln!(out, "      #{:02} {}", i, frame).unwrap();
+            }
+        }
+        out
+    }
+}
+
+impl<D> Emulator<D> {
+    /// Classify the crash the emulator is stopped at and compute its bucket from the top
+    /// `depth` frames.
+    pub fn classify_crash(&self, fault: &Fault, depth: usize) -> Crash {
+        let pc = self.reg_read(RegisterARM64::PC as i32).unwrap_or(0);
+        let frames = self.backtrace(MAX_FRAMES);
+
+        let function = |frame: &Frame| frame.info.as_ref()
+            .and_then(|info| info.symbol.as_ref())
+            .map(|(name, _)| name.clone());
+        let stack_smash = frames.iter().any(|frame| function(frame).as_deref() == Some("__stack_chk_fail"));
+
+        // the guarded heap knows better than the fault, when it was the one catching it
+        let heap_error = self.heap.as_ref()
+            .and_then(|heap| heap.last_error.as_ref())
+            .filter(|error| error.address == fault.fault_addr)
+            .map(|error| error.kind);
+
+        let kind = if let Some(error) = heap_error {
+            match error {
+                HeapErrorKind::Overflow |
+                HeapErrorKind::Underflow    => CrashKind::HeapOverflow,
+                HeapErrorKind::UseAfterFree => CrashKind::UseAfterFree,
+                HeapErrorKind::DoubleFree   => CrashKind::DoubleFree,
+                HeapErrorKind::InvalidFree  => CrashKind::InvalidFree,
+            }
+        }
+        else if stack_smash {
+            CrashKind::StackSmash
+        }
+        else if fault.access == Access::Fetch {
+            if fault.fault_addr < NULL_PAGE { CrashKind::NullDeref } else { CrashKind::PcControl }
+        }
+        else {
+            match fault.access {
+                _ if fault.signal == SIGABRT        => CrashKind::Abort,
+                _ if fault.signal == SIGILL         => CrashKind::IllegalInstruction,
+                Access::Read | Access::Write if fault.fault_addr < NULL_PAGE => CrashKind::NullDeref,
+                Access::Write if fault.code == SEGV_ACCERR => CrashKind::WriteReadOnly,
+                Access::Write                       => CrashKind::WildWrite,
+                Access::Read                        => CrashKind::WildRead,
+                _                                   => CrashKind::Unknown,
+            }
+        };
+
+        // the frame at a wild pc changes with every input, the caller does not
+        let skip = frames.iter()
+            .take_while(|frame| {
+                function(frame).map(|name| FATAL_FUNCTIONS.contains(&name.as_str())).unwrap_or(false)
+                    || (kind == CrashKind::PcControl && frame.info.is_none())
+            })
+            .count();
+
+        let mut hash = Fnv::new();
+        hash.write_str(kind.name());
+
+        let mut names = Vec::new();
+        for frame in frames.iter().skip(skip).take(depth) {
+            match &frame.info {
+                Some(info) => {
+                    names.push(info.to_string());
+                    // by function, a slightly different spot in it is the same bug
+                    hash.write_str(info.module_name());
+                    match &info.symbol {
+                        Some((name, _)) => hash.write_str(name),
+                        None => hash.write_u64(info.module_offset),
+                    }
+                },
+                None => {
+                    names.push(format!("{:#x}", frame.pc));
+                    hash.write_u64(frame.pc);
+                },
+            }
+        }
+
+        Crash {
+            kind    : kind,
+            bucket  : hash.state,
+            pc      : pc,
+            fault   : fault.clone(),
+            frames  : names,
+        }
+    }
+}
@@ -0,0 +1,547 @@
+use std::io::{self, Read, Write};
+use std::net::TcpListener;
+use std::os::unix::net::UnixListener;
+
+use super::{Frontend, Resume, StopReason, WatchKind};
+use crate::utilities;
+use crate::core::rudroid::Emulator;
+use crate::core::unicorn::arch::arm64::RegisterARM64;
+
+// x0-x30, sp, pc, cpsr, v0-v31, fpsr, fpcr, matching gdb's aarch64 core and fpu features
+const REG_SP        : usize = 31;
+const REG_PC        : usize = 32;
+const REG_CPSR      : usize = 33;
+const REG_V0        : usize = 34;
+const REG_FPSR      : usize = 66;
+const REG_FPCR      : usize = 67;
+const NUM_REGS      : usize = 68;
+
+const SIGTRAP       : u8 = 5;
+const THREAD_ID     : u64 = 1;
+
+trait Connection: Read + Write {}
+impl<T: Read + Write> Connection for T {}
+
+/// GDB remote serial protocol stub, see https://sourceware.org/gdb/onlinedocs/gdb/Remote-Protocol.html
+///
+/// The stub only gets to talk to gdb while the guest is stopped, interrupting a running
+/// guest with ^C is not supported.
+pub struct GdbStub {
+    conn        : Box<dyn Connection>,
+    input       : Vec<u8>,
+    input_pos   : usize,
+    no_ack      : bool,
+    // gdb sent c/s and waits for a stop reply
+    resumed     : bool,
+    last_stop   : String,
+}
+
+enum Action {
+    Reply(String),
+    Resume(Resume),
+}
+
+impl GdbStub {
+    /// Wait for gdb to connect on a TCP port (`1234`) or a unix socket path.
+    pub fn listen(address: &str) -> io::Result<GdbStub> {
+        let conn: Box<dyn Connection> = match address.parse::<u16>() {
+            Ok(port) => {
+                let listener = TcpListener::bind(("127.0.0.1", port))?;
+                utilities::log(&format!("waiting for gdb on 127.0.0.1:{}", port), utilities::DebugLevel::INFO);
+                let (stream, peer) = listener.accept()?;
+                stream.set_nodelay(true)?;
+                utilities::log(&format!("gdb connected from {}", peer), utilities::DebugLevel::INFO);
+                Box::new(stream)
+            },
+            Err(_) => {
+                let listener = UnixListener::bind(address)?;
+                utilities::log(&format!("waiting for gdb on {}", address), utilities::DebugLevel::INFO);
+                let (stream, _) = listener.accept()?;
+                utilities::log("gdb connected", utilities::DebugLevel::INFO);
+                Box::new(stream)
+            }
+        };
+
+        Ok(GdbStub {
+            conn        : conn,
+            input       : Vec::new(),
+            input_pos   : 0,
+            no_ack      : false,
+            resumed     : false,
+            last_stop   : format!("S{:02x}", SIGTRAP),
+        })
+    }
+
+    fn read_byte(&mut self) -> io::Result<u8> {
+        if self.input_pos >= self.input.len() {
+            let mut buf = [0u8; 4096];
+            let len = self.conn.read(&mut buf)?;
+            if len == 0 {
+                return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "gdb disconnected"));
+            }
+            self.input = buf[..len].to_vec();
+            self.input_pos = 0;
+        }
+
+        self.input_pos += 1;
+        Ok(self.input[self.input_pos - 1])
+    }
+
+    fn read_packet(&mut self) -> io::Result<String> {
+        loop {
+            // skip acks and ^C, we are stopped already
+            while self.read_byte()? != b'$' {}
+
+            let mut data = Vec::new();
+            loop {
+                match self.read_byte()? {
+                    b'#' => break,
+                    c => data.push(c),
+                }
+            }
+
+            let checksum = [self.read_byte()?, self.read_byte()?];
+            let checksum = u8::from_str_radix(&String::from_utf8_lossy(&checksum), 16).unwrap_or(0);
+            let expected = data.iter().fold(0u8, |sum, c| sum.wrapping_add(*c));
+
+            if self.no_ack {
+                return Ok(String::from_utf8_lossy(&data).to_string());
+            }
+
+            if checksum == expected {
+                self.conn.write_all(b"+")?;
+                return Ok(String::from_utf8_lossy(&data).to_string());
+            }
+            self.conn.write_all(b"-")?;
+        }
+    }
+
+    fn send_packet(&mut self, data: &str) -> io::Result<()> {
+        let checksum = data.bytes().fold(0u8, |sum, c| sum.wrapping_add(c));
+        let packet = format!("${}#{:02x}", data, checksum);
+
+        loop {
+            self.conn.write_all(packet.as_bytes())?;
+            self.conn.flush()?;
+
+            if self.no_ack {
+                return Ok(());
+            }
+
+            match self.read_byte()? {
+                b'-' => continue,
+                _ => return Ok(()),
+            }
+        }
+    }
+
+    fn stop_reply(reason: &StopReason) -> String {
+        match reason {
+            StopReason::Attached | StopReason::Step | StopReason::Breakpoint(_) => {
+                format!("T{:02x}thread:{:x};", SIGTRAP, THREAD_ID)
+            },
+            StopReason::Watchpoint(kind, address) => {
+                let watch = match kind {
+                    WatchKind::Write  => "watch",
+                    WatchKind::Read   => "rwatch",
+                    WatchKind::Access => "awatch",
+                };
+                format!("T{:02x}{}:{:x};thread:{:x};", SIGTRAP, watch, address, THREAD_ID)
+            },
+            StopReason::Signal(fault) => {
+                format!("T{:02x}thread:{:x};", fault.signal, THREAD_ID)
+            },
+            StopReason::Exited(code) => {
+                format!("W{:02x}", code & 0xff)
+            },
+        }
+    }
+
+    fn serve<D>(&mut self, emu: &mut Emulator<D>) -> io::Result<Resume> {
+        loop {
+            let packet = self.read_packet()?;
+            emu.debug_print(format!("gdb <- {}", packet));
+
+            match self.handle_packet(emu, &packet) {
+                Action::Reply(reply) => {
+                    self.send_packet(&reply)?;
+                    // the OK itself is still acked, the packets after it are not
+                    if packet == "QStartNoAckMode" {
+                        self.no_ack = true;
+                    }
+                },
+                Action::Resume(resume) => {
+                    return Ok(resume);
+                }
+            }
+        }
+    }
+
+    fn handle_packet<D>(&mut self, emu: &mut Emulator<D>, packet: &str) -> Action {
+        let (command, args) = packet.split_at(packet.chars().next().map_or(0, char::len_utf8));
+
+        let reply = match command {
+            "?" => self.last_stop.clone(),
+            "g" => {
+                let mut regs = String::new();
+                for n in 0..NUM_REGS {
+                    regs.push_str(&to_hex(&read_register(emu, n)));
+                }
+                regs
+            },
+            "G" => {
+                let data = match from_hex(args) {
+                    Some(data) => data,
+                    None => return Action::Reply(String::from("E01")),
+                };
+                let mut offset = 0;
+                for n in 0..NUM_REGS {
+                    let size = register_size(n);
+                    if offset + size > data.len() {
+                        break;
+                    }
+                    write_register(emu, n, &data[offset..offset + size]);
+                    offset += size;
+                }
+                String::from("OK")
+            },
+            "p" => {
+                match usize::from_str_radix(args, 16) {
+                    Ok(n) if n < NUM_REGS => to_hex(&read_register(emu, n)),
+                    _ => String::from("E00"),
+                }
+            },
+            "P" => {
+                let mut parts = args.splitn(2, '=');
+                let n = parts.next().and_then(|n| usize::from_str_radix(n, 16).ok());
+                let value = match from_hex(parts.next().unwrap_or("")) {
+                    Some(value) => value,
+                    None => return Action::Reply(String::from("E01")),
+                };
+                match n {
+                    Some(n) if n < NUM_REGS && value.len() == register_size(n) => {
+                        write_register(emu, n, &value);
+                        String::from("OK")
+                    },
+                    _ => String::from("E00"),
+                }
+            },
+            "m" => {
+                match parse_address_length(args) {
+                    Some((address, len)) => {
+                        match emu.mem_read_as_vec(address, len as usize) {
+                            Ok(data) => to_hex(&data),
+                            Err(_) => String::from("E14"),
+                        }
+                    },
+                    None => String::from("E00"),
+                }
+            },
+            "M" => {
+                let mut parts = args.splitn(2, ':');
+                let target = parts.next().and_then(parse_address_length);
+                let data = from_hex(parts.next().unwrap_or(""));
+                match (target, data) {
+                    (Some((address, len)), Some(data)) if data.len() as u64 == len => {
+                        match emu.mem_write(address, &data) {
+                            Ok(_) => String::from("OK"),
+                            Err(_) => String::from("E14"),
+                        }
+                    },
+                    (None, _) => String::from("E00"),
+                    _ => String::from("E01"),
+                }
+            },
+            "c" | "s" => {
+                if let Ok(address) = u64::from_str_radix(args, 16) {
+                    emu.reg_write(RegisterARM64::PC as i32, address).unwrap();
+                }
+                return Action::Resume(if command == "c" { Resume::Continue } else { Resume::Step });
+            },
+            "C" | "S" => {
+                // signals can not be delivered to the guest, resume without one
+                return Action::Resume(if command == "C" { Resume::Continue } else { Resume::Step });
+            },
+            "Z" | "z" => {
+                let mut parts = args.split(',');
+                let kind = parts.next().unwrap_or("");
+                let address = parts.next().and_then(|a| u64::from_str_radix(a, 16).ok());
+                let len = parts.next().and_then(|l| u64::from_str_radix(l, 16).ok()).unwrap_or(1);
+
+                match (kind, address) {
+                    ("0", Some(address)) | ("1", Some(address)) => {
+                        if command == "Z" {
+                            emu.add_breakpoint(address);
+                        }
+                        else {
+                            emu.remove_breakpoint(address);
+                        }
+                        String::from("OK")
+                    },
+                    ("2", Some(address)) | ("3", Some(address)) | ("4", Some(address)) => {
+                        let kind = match kind {
+                            "2" => WatchKind::Write,
+                            "3" => WatchKind::Read,
+                            _   => WatchKind::Access,
+                        };
+                        if command == "Z" {
+                            emu.add_watchpoint(kind, address, len);
+                        }
+                        else {
+                            emu.remove_watchpoint(kind, address, len);
+                        }
+                        String::from("OK")
+                    },
+                    _ => String::new(),
+                }
+            },
+            "H" | "T" => String::from("OK"),
+            "D" => {
+                let _ = self.send_packet("OK");
+                return Action::Resume(Resume::Detach);
+            },
+            "k" => {
+                utilities::log("killed by gdb", utilities::DebugLevel::INFO);
+                std::process::exit(0);
+            },
+            "v" => return self.handle_v_packet(packet),
+            "q" | "Q" => self.handle_query(emu, packet),
+            _ => String::new(),
+        };
+
+        Action::Reply(reply)
+    }
+
+    fn handle_v_packet(&mut self, packet: &str) -> Action {
+        if packet == "vCont?" {
+            return Action::Reply(String::from("vCont;c;C;s;S"));
+        }
+
+        // only one thread, the first action is the one that applies to it
+        if let Some(actions) = packet.strip_prefix("vCont;") {
+            return match actions.chars().next() {
+                Some('s') | Some('S') => Action::Resume(Resume::Step),
+                _ => Action::Resume(Resume::Continue),
+            };
+        }
+
+        Action::Reply(String::new())
+    }
+
+    fn handle_query<D>(&mut self, emu: &mut Emulator<D>, packet: &str) -> String {
+        let name = packet.split(|c| c == ':' || c == ',').next().unwrap_or("");
+
+        match name {
+            "qSupported" => {
+                String::from("PacketSize=4000;qXfer:features:read+;qXfer:libraries-svr4:read+;QStartNoAckMode+;vContSupported+")
+            },
+            "QStartNoAckMode" => String::from("OK"),
+            "qAttached" => String::from("1"),
+            "qC" => format!("QC{:x}", THREAD_ID),
+            "qfThreadInfo" => format!("m{:x}", THREAD_ID),
+            "qsThreadInfo" => String::from("l"),
+            "qThreadExtraInfo" => to_hex(b"main"),
+            "qSymbol" => String::from("OK"),
+            "qXfer" => {
+                let parts: Vec<&str> = packet.splitn(5, ':').collect();
+                if parts.len() != 5 || parts[2] != "read" {
+                    return String::new();
+                }
+
+                let document = match (parts[1], parts[3]) {
+                    ("features", "target.xml") => target_xml(),
+                    ("libraries-svr4", _) => libraries_svr4(emu),
+                    _ => return String::from("E00"),
+                };
+
+                match parse_address_length(parts[4]) {
+                    Some((offset, len)) => xfer_chunk(document.as_bytes(), offset as usize, len as usize),
+                    None => String::from("E00"),
+                }
+            },
+            _ => String::new(),
+        }
+    }
+}
+
+impl<D> Frontend<D> for GdbStub {
+    fn on_stop(&mut self, emu: &mut Emulator<D>, reason: &StopReason) -> Resume {
+        self.last_stop = GdbStub::stop_reply(reason);
+
+        if self.resumed || matches!(reason, StopReason::Exited(_)) {
+            if self.send_packet(&self.last_stop.clone()).is_err() {
+                return Resume::Detach;
+            }
+        }
+
+        if let StopReason::Exited(_) = reason {
+            return Resume::Detach;
+        }
+
+        let resume = match self.serve(emu) {
+            Ok(resume) => resume,
+            Err(e) => {
+                utilities::log(&format!("gdb connection lost: {}", e), utilities::DebugLevel::ERROR);
+                Resume::Detach
+            }
+        };
+
+        // emulation can not go on after a fatal fault, let gdb know the guest is gone
+        if let StopReason::Signal(fault) = reason {
+            if resume != Resume::Detach {
+                let _ = self.send_packet(&format!("X{:02x}", fault.signal));
+            }
+            return Resume::Detach;
+        }
+
+        self.resumed = resume != Resume::Detach;
+        resume
+    }
+}
+
+impl<D> Emulator<D> {
+    /// Serve the gdb remote protocol on `address`, a TCP port or a unix socket path.
+    ///
+    /// Blocks until gdb connects, the guest then stops at its first instruction.
+    pub fn attach_gdb(&mut self, address: &str) -> io::Result<()> {
+        let stub = GdbStub::listen(address)?;
+        self.attach_debugger(Box::new(stub));
+        Ok(())
+    }
+}
+
+fn register_size(n: usize) -> usize {
+    match n {
+        REG_CPSR | REG_FPSR | REG_FPCR => 4,
+        n if n >= REG_V0 && n < REG_V0 + 32 => 16,
+        _ => 8,
+    }
+}
+
+fn read_register<D>(emu: &Emulator<D>, n: usize) -> Vec<u8> {
+    match n {
+        0..=30  => emu.reg_read(RegisterARM64::x(n)).unwrap_or(0).to_le_bytes().to_vec(),
+        REG_SP  => emu.reg_read(RegisterARM64::SP as i32).unwrap_or(0).to_le_bytes().to_vec(),
+        REG_PC  => emu.reg_read(RegisterARM64::PC as i32).unwrap_or(0).to_le_bytes().to_vec(),
+        REG_CPSR => (emu.reg_read(RegisterARM64::NZCV as i32).unwrap_or(0) as u32).to_le_bytes().to_vec(),
+        n if n >= REG_V0 && n < REG_V0 + 32 => {
+            let mut value = emu.reg_read_long(RegisterARM64::v(n - REG_V0)).map(|v| v.to_vec()).unwrap_or_default();
+            value.resize(16, 0);
+            value
+        },
+        // unicorn does not expose fpsr and fpcr
+        _ => vec![0; register_size(n)],
+    }
+}
+
+fn write_register<D>(emu: &mut Emulator<D>, n: usize, value: &[u8]) {
+    let mut raw = [0u8; 8];
+    let len = std::cmp::min(value.len(), 8);
+    raw[..len].copy_from_slice(&value[..len]);
+    let raw = u64::from_le_bytes(raw);
+
+    match n {
+        0..=30  => { emu.reg_write(RegisterARM64::x(n), raw).unwrap(); },
+        REG_SP  => { emu.reg_write(RegisterARM64::SP as i32, raw).unwrap(); },
+        REG_PC  => { emu.reg_write(RegisterARM64::PC as i32, raw).unwrap(); },
+        REG_CPSR => { emu.reg_write(RegisterARM64::NZCV as i32, raw).unwrap(); },
+        n if n >= REG_V0 && n < REG_V0 + 32 => {
+            emu.reg_write_long(RegisterARM64::v(n - REG_V0), value.to_vec().into_boxed_slice()).unwrap();
+        },
+        _ => {}
+    }
+}
+
+fn target_xml() -> String {
+    let mut xml = String::from("<?xml version=\"1.0\"?>\n<!DOCTYPE target SYSTEM \"gdb-target.dtd\">\n<target version=\"1.0\">\n");
+    xml.push_str("<architecture>aarch64</architecture>\n");
+
+    xml.push_str("<feature name=\"org.gnu.gdb.aarch64.core\">\n");
+    for n in 0..31 {
+        xml.push_str(&format!("<reg name=\"x{}\" bitsize=\"64\" type=\"int\" regnum=\"{}\"/>\n", n, n));
+    }
+    xml.push_str(&format!("<reg name=\"sp\" bitsize=\"64\" type=\"data_ptr\" regnum=\"{}\"/>\n", REG_SP));
+    xml.push_str(&format!("<reg name=\"pc\" bitsize=\"64\" type=\"code_ptr\" regnum=\"{}\"/>\n", REG_PC));
+    xml.push_str(&format!("<reg name=\"cpsr\" bitsize=\"32\" type=\"int\" regnum=\"{}\"/>\n", REG_CPSR));
+    xml.push_str("</feature>\n");
+
+    xml.push_str("<feature name=\"org.gnu.gdb.aarch64.fpu\">\n");
+    for n in 0..32 {
+        xml.push_str(&format!("<reg name=\"v{}\" bitsize=\"128\" type=\"uint128\" regnum=\"{}\"/>\n", n, REG_V0 + n));
+    }
+    xml.push_str(&format!("<reg name=\"fpsr\" bitsize=\"32\" type=\"int\" regnum=\"{}\"/>\n", REG_FPSR));
+    xml.push_str(&format!("<reg name=\"fpcr\" bitsize=\"32\" type=\"int\" regnum=\"{}\"/>\n", REG_FPCR));
+    xml.push_str("</feature>\n");
+
+    xml.push_str("</target>\n");
+    xml
+}
+
+fn libraries_svr4<D>(emu: &Emulator<D>) -> String {
+    let mut xml = String::from("<library-list-svr4 version=\"1.0\">");
+    for module in emu.modules.iter().filter(|module| module.path != emu.elf_path) {
+        xml.push_str(&format!("<library name=\"{}\" lm=\"{:#x}\" l_addr=\"{:#x}\" l_ld=\"0x0\"/>", module.path, module.base, module.bias));
+    }
+    xml.push_str("</library-list-svr4>");
+    xml
+}
+
+fn xfer_chunk(document: &[u8], offset: usize, len: usize) -> String {
+    if offset >= document.len() {
+        return String::from("l");
+    }
+
+    let end = std::cmp::min(offset + len, document.len());
+    let marker = if end == document.len() { 'l' } else { 'm' };
+
+    let mut reply = String::new();
+    reply.push(marker);
+    for c in document[offset..end].iter() {
+        match c {
+            b'$' | b'#' | b'}' | b'*' => {
+                reply.push('}');
+                reply.push((c ^ 0x20) as char);
+            },
+            _ => reply.push(*c as char),
+        }
+    }
+    reply
+}
+
+fn parse_address_length(args: &str) -> Option<(u64, u64)> {
+    let mut parts = args.splitn(2, ',');
+    let address = u64::from_str_radix(parts.next()?, 16).ok()?;
+    let len = u64::from_str_radix(parts.next()?, 16).ok()?;
+    Some((address, len))
+}
+
+fn to_hex(data: &[u8]) -> String {
+    data.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// None unless every character is part of a hex byte.
+fn from_hex(data: &str) -> Option<Vec<u8>> {
+    let digit = |c: u8| (c as char).to_digit(16).map(|digit| digit as u8);
+    let data = data.as_bytes();
+    if data.len() % 2 != 0 {
+        return None;
+    }
+    data.chunks(2).map(|pair| Some(digit(pair[0])? << 4 | digit(pair[1])?)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn from_hex_bytes() {
+        assert_eq!(from_hex("00ff7F"), Some(vec![0, 0xff, 0x7f]));
+        assert_eq!(from_hex(""), Some(Vec::new()));
+        assert_eq!(from_hex("abc"), None);
+        assert_eq!(from_hex("zz"), None);
+        assert_eq!(from_hex("+1"), None);
+        // a multi-byte character straddling a pair
+        assert_eq!(from_hex("0é0"), None);
+        assert_eq!(from_hex("éé"), None);
+    }
+}
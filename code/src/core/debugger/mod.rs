@@ -0,0 +1,245 @@
+pub mod gdbstub;
+pub mod repl;
+
+use std::collections::HashMap;
+
+use super::crash::Fault;
+use super::rudroid::Emulator;
+use super::unicorn::ffi;
+use super::unicorn::arch::arm64::RegisterARM64;
+use super::unicorn::unicorn_const::HookType;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum WatchKind {
+    Write,
+    Read,
+    Access,
+}
+
+#[derive(Debug, Clone)]
+pub enum StopReason {
+    /// first instruction after a debugger got attached
+    Attached,
+    Step,
+    Breakpoint(u64),
+    Watchpoint(WatchKind, u64),
+    Signal(Fault),
+    Exited(u64),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Resume {
+    Continue,
+    Step,
+    Detach,
+}
+
+/// Something driving the emulator while it is stopped, e.g. a gdb connection.
+///
+/// `on_stop` runs from inside the unicorn hook that trapped, so registers and memory can be
+/// inspected and modified before execution resumes.
+pub trait Frontend<D> {
+    fn on_stop(&mut self, emu: &mut Emulator<D>, reason: &StopReason) -> Resume;
+}
+
+struct Watchpoint {
+    kind    : WatchKind,
+    address : u64,
+    len     : u64,
+    hooks   : Vec<ffi::uc_hook>,
+}
+
+pub struct Debugger<D> {
+    pub frontend    : Option<Box<dyn Frontend<D>>>,
+    pub stepping    : bool,
+
+    breakpoints     : HashMap<u64, ffi::uc_hook>,
+    watchpoints     : Vec<Watchpoint>,
+    step_hook       : Option<ffi::uc_hook>,
+    attaching       : bool,
+
+    // the code hooks of the instruction we stopped at may still run after we resumed,
+    // they must not trap again for the same instruction
+    resumed_from    : Option<u64>,
+    resumed_seen    : bool,
+}
+
+impl<D> Debugger<D> {
+    pub fn new() -> Debugger<D> {
+        Debugger {
+            frontend        : None,
+            stepping        : false,
+            breakpoints     : HashMap::new(),
+            watchpoints     : Vec::new(),
+            step_hook       : None,
+            attaching       : false,
+            resumed_from    : None,
+            resumed_seen    : false,
+        }
+    }
+
+    pub fn is_attached(&self) -> bool {
+        self.frontend.is_some()
+    }
+
+    pub fn breakpoints(&self) -> Vec<u64> {
+        let mut breakpoints: Vec<u64> = self.breakpoints.keys().cloned().collect();
+        breakpoints.sort();
+        breakpoints
+    }
+}
+
+impl<D> Emulator<D> {
+    /// Attach `frontend` and stop at the next instruction.
+    pub fn attach_debugger(&mut self, frontend: Box<dyn Frontend<D>>) {
+        self.debugger.frontend = Some(frontend);
+        self.debugger.stepping = true;
+        self.debugger.attaching = true;
+        self.arm_step_hook();
+    }
+
+    /// Remove every breakpoint and watchpoint and let the guest run freely.
+    pub fn detach_debugger(&mut self) {
+        for address in self.debugger.breakpoints() {
+            self.remove_breakpoint(address);
+        }
+
+        for watchpoint in self.debugger.watchpoints.drain(..).collect::<Vec<_>>() {
+            for hook in watchpoint.hooks {
+                self.remove_hook(hook).unwrap();
+            }
+        }
+
+        if let Some(hook) = self.debugger.step_hook.take() {
+            self.remove_hook(hook).unwrap();
+        }
+
+        self.debugger.stepping = false;
+        self.debugger.frontend = None;
+    }
+
+    pub fn add_breakpoint(&mut self, address: u64) {
+        if self.debugger.breakpoints.contains_key(&address) {
+            return;
+        }
+
+        let hook = self.add_code_hook(address, address, |emu: &mut Emulator<D>, address: u64, _size: u32| {
+            // while stepping the step hook reports breakpoints itself
+            if emu.debugger.stepping || emu.debugger.resumed_from == Some(address) {
+                return;
+            }
+            emu.debug_trap(StopReason::Breakpoint(address));
+        }).expect("failed to add breakpoint hook");
+
+        self.debugger.breakpoints.insert(address, hook);
+    }
+
+    pub fn remove_breakpoint(&mut self, address: u64) -> bool {
+        match self.debugger.breakpoints.remove(&address) {
+            Some(hook) => {
+                self.remove_hook(hook).unwrap();
+                true
+            },
+            None => false,
+        }
+    }
+
+    pub fn add_watchpoint(&mut self, kind: WatchKind, address: u64, len: u64) {
+        let hook_types = match kind {
+            WatchKind::Write  => vec![HookType::MEM_WRITE],
+            WatchKind::Read   => vec![HookType::MEM_READ],
+            WatchKind::Access => vec![HookType::MEM_READ, HookType::MEM_WRITE],
+        };
+
+        let mut hooks = Vec::new();
+        for hook_type in hook_types {
+            let hook = self.add_mem_hook(hook_type, address, address + len.max(1) - 1, move |emu: &mut Emulator<D>, _mem_type, address: u64, _size: usize, _value: i64| {
+                emu.debug_trap(StopReason::Watchpoint(kind, address));
+            }).expect("failed to add watchpoint hook");
+            hooks.push(hook);
+        }
+
+        self.debugger.watchpoints.push(Watchpoint {
+            kind    : kind,
+            address : address,
+            len     : len,
+            hooks   : hooks,
+        });
+    }
+
+    pub fn remove_watchpoint(&mut self, kind: WatchKind, address: u64, len: u64) -> bool {
+        let index = self.debugger.watchpoints.iter().position(|w| w.kind == kind && w.address == address && w.len == len);
+        match index {
+            Some(index) => {
+                let watchpoint = self.debugger.watchpoints.remove(index);
+                for hook in watchpoint.hooks {
+                    self.remove_hook(hook).unwrap();
+                }
+                true
+            },
+            None => false,
+        }
+    }
+
+    /// Hand control to the attached frontend until it resumes execution.
+    pub fn debug_trap(&mut self, reason: StopReason) {
+        let mut frontend = match self.debugger.frontend.take() {
+            Some(frontend) => frontend,
+            None => return,
+        };
+
+        let resume = frontend.on_stop(self, &reason);
+        self.debugger.frontend = Some(frontend);
+
+        if resume == Resume::Detach {
+            self.detach_debugger();
+            return;
+        }
+
+        self.debugger.resumed_from = match reason {
+            StopReason::Attached | StopReason::Step | StopReason::Breakpoint(_) => {
+                Some(self.reg_read(RegisterARM64::PC as i32).unwrap())
+            },
+            _ => None,
+        };
+        self.debugger.resumed_seen = false;
+        self.debugger.stepping = resume == Resume::Step;
+        self.arm_step_hook();
+    }
+
+    /// The step hook runs on every instruction, it traps while stepping and otherwise only
+    /// lives until the instruction we resumed from has been left.
+    fn arm_step_hook(&mut self) {
+        if self.debugger.step_hook.is_some() {
+            return;
+        }
+
+        let hook = self.add_code_hook(1, 0, |emu: &mut Emulator<D>, address: u64, _size: u32| {
+            if let Some(pc) = emu.debugger.resumed_from {
+                if pc == address && !emu.debugger.resumed_seen {
+                    emu.debugger.resumed_seen = true;
+                    return;
+                }
+                emu.debugger.resumed_from = None;
+            }
+
+            if emu.debugger.stepping {
+                if emu.debugger.attaching {
+                    emu.debugger.attaching = false;
+                    emu.debug_trap(StopReason::Attached);
+                }
+                else if emu.debugger.breakpoints.contains_key(&address) {
+                    emu.debug_trap(StopReason::Breakpoint(address));
+                }
+                else {
+                    emu.debug_trap(StopReason::Step);
+                }
+            }
+            else if let Some(hook) = emu.debugger.step_hook.take() {
+                emu.remove_hook(hook).unwrap();
+            }
+        }).expect("failed to add step hook");
+
+        self.debugger.step_hook = Some(hook);
+    }
+}
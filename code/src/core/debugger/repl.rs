@@ -0,0 +1,592 @@
+use std::io::{self, Write};
+use std::collections::HashMap;
+
+use capstone::prelude::*;
+
+use super::{Frontend, Resume, StopReason, WatchKind};
+use crate::utilities;
+use crate::core::rudroid::Emulator;
+use crate::core::snapshot::Snapshot;
+use crate::core::symbols::unwind::MAX_FRAMES;
+use crate::core::unicorn::arch::arm64::RegisterARM64;
+
+const CONTEXT_STACK     : u64 = 8;
+const CONTEXT_CODE      : usize = 8;
+const CONTEXT_FRAMES    : usize = 6;
+const TELESCOPE_DEPTH   : usize = 4;
+const SEARCH_CHUNK      : usize = 0x10000;
+
+const HELP: &str = "\
+c, continue             resume execution
+s, si, step             execute one instruction
+n, ni, next             step over calls
+finish                  run until the current function returns
+b, break <loc>          set a breakpoint, <loc> is an address, $reg, symbol or lib.so!symbol (+offset)
+d, delete <loc>         remove a breakpoint
+watch|rwatch|awatch <loc> [len]
+                        stop on write, read or any access
+info b                  list breakpoints
+regs, context           show the context view
+p, print <expr>         evaluate an expression
+set <reg> <expr>        modify a register
+setmem <loc> <hex>      write bytes to memory
+x <loc> [len]           hexdump memory
+tele <loc> [count]      telescope memory
+stack [count]           telescope the stack
+disas [loc] [count]     disassemble
+bt, backtrace           show the call stack
+vmmap [filter]          show the memory map
+search <string>         search mapped memory for a string
+search -x <hex>         search mapped memory for bytes
+snapshot [name]         save the emulator state
+restore [name]          go back to a saved state
+detach                  let the guest run without the debugger
+q, quit                 exit rudroid
+an empty line repeats the last command";
+
+/// Interactive debugger on the terminal, started with `rudroid debug <elf> <rootfs>`.
+pub struct Repl {
+    last_command    : String,
+    // temporary breakpoint used by next and finish, with the stack pointer of the frame it
+    // belongs to and whether the user already had a breakpoint there
+    temporary       : Option<(u64, u64, bool)>,
+    snapshots       : HashMap<String, Snapshot>,
+}
+
+impl Repl {
+    pub fn new() -> Repl {
+        Repl {
+            last_command    : String::new(),
+            temporary       : None,
+            snapshots       : HashMap::new(),
+        }
+    }
+
+    fn read_command(&mut self) -> Option<String> {
+        print!("rudroid> ");
+        io::stdout().flush().unwrap();
+
+        let mut line = String::new();
+        match io::stdin().read_line(&mut line) {
+            Ok(0) | Err(_) => None,
+            Ok(_) => {
+                let line = line.trim().to_string();
+                if line.is_empty() {
+                    return Some(self.last_command.clone());
+                }
+                self.last_command = line.clone();
+                Some(line)
+            }
+        }
+    }
+
+    fn set_temporary<D>(&mut self, emu: &mut Emulator<D>, address: u64) {
+        let sp = emu.reg_read(RegisterARM64::SP as i32).unwrap();
+        let existing = emu.debugger.breakpoints().contains(&address);
+        emu.add_breakpoint(address);
+        self.temporary = Some((address, sp, existing));
+    }
+
+    fn execute<D>(&mut self, emu: &mut Emulator<D>, line: &str) -> Option<Resume> {
+        let args: Vec<&str> = line.split_whitespace().collect();
+        if args.is_empty() {
+            return None;
+        }
+
+        match args[0] {
+            "c" | "continue" => return Some(Resume::Continue),
+            "s" | "si" | "step" => return Some(Resume::Step),
+            "n" | "ni" | "next" => {
+                let pc = emu.reg_read(RegisterARM64::PC as i32).unwrap();
+                match disassemble(emu, pc, 1).first() {
+                    Some((_, _, mnemonic, _)) if mnemonic == "bl" || mnemonic == "blr" => {
+                        self.set_temporary(emu, pc + 4);
+                        return Some(Resume::Continue);
+                    },
+                    _ => return Some(Resume::Step),
+                }
+            },
+            "finish" => {
+                let lr = emu.reg_read(RegisterARM64::LR as i32).unwrap();
+                self.set_temporary(emu, lr);
+                return Some(Resume::Continue);
+            },
+            "b" | "break" => {
+                match args.get(1).map(|loc| evaluate(emu, loc)) {
+                    Some(Ok(address)) => {
+                        emu.add_breakpoint(address);
+                        println!("breakpoint at {:#x} {}", address, describe(emu, address));
+                    },
+                    Some(Err(e)) => println!("{}", e),
+                    None => println!("usage: break <loc>"),
+                }
+            },
+            "d" | "delete" => {
+                match args.get(1).map(|loc| evaluate(emu, loc)) {
+                    Some(Ok(address)) => {
+                        if !emu.remove_breakpoint(address) {
+                            println!("no breakpoint at {:#x}", address);
+                        }
+                    },
+                    Some(Err(e)) => println!("{}", e),
+                    None => println!("usage: delete <loc>"),
+                }
+            },
+            "watch" | "rwatch" | "awatch" => {
+                let kind = match args[0] {
+                    "watch"  => WatchKind::Write,
+                    "rwatch" => WatchKind::Read,
+                    _        => WatchKind::Access,
+                };
+                let len = match args.get(2).map(|len| evaluate(emu, len)) {
+                    Some(Ok(len)) => len,
+                    _ => 8,
+                };
+                match args.get(1).map(|loc| evaluate(emu, loc)) {
+                    Some(Ok(address)) => {
+                        emu.add_watchpoint(kind, address, len);
+                        println!("{} {:#x}-{:#x}", args[0], address, address + len);
+                    },
+                    Some(Err(e)) => println!("{}", e),
+                    None => println!("usage: {} <loc> [len]", args[0]),
+                }
+            },
+            "info" if args.get(1) == Some(&"b") || args.get(1) == Some(&"breakpoints") => {
+                for (i, address) in emu.debugger.breakpoints().iter().enumerate() {
+                    println!("{:>3}  {:#018x}  {}", i, address, describe(emu, *address));
+                }
+            },
+            "regs" | "context" => context(emu),
+            "p" | "print" => {
+                match args.get(1).map(|expr| evaluate(emu, expr)) {
+                    Some(Ok(value)) => println!("{:#x} ({}) {}", value, value as i64, describe(emu, value)),
+                    Some(Err(e)) => println!("{}", e),
+                    None => println!("usage: print <expr>"),
+                }
+            },
+            "set" => {
+                let register = args.get(1).and_then(|name| register_id(name.trim_start_matches('$')));
+                match (register, args.get(2).map(|expr| evaluate(emu, expr))) {
+                    (Some(register), Some(Ok(value))) => {
+                        emu.reg_write(register, value).unwrap();
+                    },
+                    (_, Some(Err(e))) => println!("{}", e),
+                    _ => println!("usage: set <reg> <expr>"),
+                }
+            },
+            "setmem" => {
+                let data = args.get(2).map(|hex| parse_hex_bytes(hex));
+                match (args.get(1).map(|loc| evaluate(emu, loc)), data) {
+                    (Some(Ok(address)), Some(Some(data))) => {
+                        if emu.mem_write(address, &data).is_err() {
+                            println!("cannot write to {:#x}", address);
+                        }
+                    },
+                    (Some(Err(e)), _) => println!("{}", e),
+                    _ => println!("usage: setmem <loc> <hex bytes>"),
+                }
+            },
+            "x" => {
+                let len = match args.get(2).map(|len| evaluate(emu, len)) {
+                    Some(Ok(len)) => len,
+                    _ => 0x40,
+                };
+                match args.get(1).map(|loc| evaluate(emu, loc)) {
+                    Some(Ok(address)) => hexdump(emu, address, len as usize),
+                    Some(Err(e)) => println!("{}", e),
+                    None => println!("usage: x <loc> [len]"),
+                }
+            },
+            "tele" | "telescope" | "stack" => {
+                let (address, count) = if args[0] == "stack" {
+                    (Ok(emu.reg_read(RegisterARM64::SP as i32).unwrap()), args.get(1))
+                }
+                else {
+                    match args.get(1) {
+                        Some(loc) => (evaluate(emu, loc), args.get(2)),
+                        None => {
+                            println!("usage: tele <loc> [count]");
+                            return None;
+                        }
+                    }
+                };
+                let count = match count.map(|count| evaluate(emu, count)) {
+                    Some(Ok(count)) => count,
+                    _ => 10,
+                };
+                match address {
+                    Ok(address) => telescope(emu, address, count),
+                    Err(e) => println!("{}", e),
+                }
+            },
+            "disas" | "disassemble" => {
+                let address = match args.get(1).map(|loc| evaluate(emu, loc)) {
+                    Some(Ok(address)) => address,
+                    Some(Err(e)) => {
+                        println!("{}", e);
+                        return None;
+                    },
+                    None => emu.reg_read(RegisterARM64::PC as i32).unwrap(),
+                };
+                let count = match args.get(2).map(|count| evaluate(emu, count)) {
+                    Some(Ok(count)) => count as usize,
+                    _ => 16,
+                };
+                print_disassembly(emu, address, count);
+            },
+            "bt" | "backtrace" => backtrace(emu, MAX_FRAMES),
+            "vmmap" => vmmap(emu, args.get(1).cloned()),
+            "search" => {
+                let pattern = match args.get(1) {
+                    Some(&"-x") => args.get(2).and_then(|hex| parse_hex_bytes(hex)),
+                    Some(_) => Some(line.splitn(2, ' ').nth(1).unwrap().trim().trim_matches('"').as_bytes().to_vec()),
+                    None => None,
+                };
+                match pattern {
+                    Some(pattern) if !pattern.is_empty() => search(emu, &pattern),
+                    _ => println!("usage: search <string> | search -x <hex bytes>"),
+                }
+            },
+            "snapshot" => {
+                let name = String::from(args.get(1).cloned().unwrap_or("default"));
+                let snapshot = emu.take_snapshot();
+                println!("snapshot {}: {} regions, {:#x} bytes", name, snapshot.regions.len(), snapshot.size());
+                self.snapshots.insert(name, snapshot);
+            },
+            "restore" => {
+                let name = args.get(1).cloned().unwrap_or("default");
+                match self.snapshots.get(name) {
+                    Some(snapshot) => {
+                        emu.restore_snapshot(snapshot);
+                        context(emu);
+                    },
+                    None => println!("no snapshot {}", name),
+                }
+            },
+            "detach" => return Some(Resume::Detach),
+            "q" | "quit" | "exit" => std::process::exit(0),
+            "h" | "help" => println!("{}", HELP),
+            command => println!("unknown command {}, try help", command),
+        }
+
+        None
+    }
+}
+
+impl<D> Frontend<D> for Repl {
+    fn on_stop(&mut self, emu: &mut Emulator<D>, reason: &StopReason) -> Resume {
+        if let Some((address, sp, existing)) = self.temporary {
+            let current_sp = emu.reg_read(RegisterARM64::SP as i32).unwrap();
+            match reason {
+                // recursion hit the return address of a deeper frame, keep going
+                StopReason::Breakpoint(pc) if *pc == address && current_sp < sp && !existing => {
+                    return Resume::Continue;
+                },
+                _ => {
+                    if !existing {
+                        emu.remove_breakpoint(address);
+                    }
+                    self.temporary = None;
+                }
+            }
+        }
+
+        match reason {
+            StopReason::Attached => utilities::context_title(Some("attached")),
+            StopReason::Step => {},
+            StopReason::Breakpoint(address) => {
+                utilities::context_title(Some(&format!("breakpoint {:#x}", address)));
+            },
+            StopReason::Watchpoint(kind, address) => {
+                utilities::context_title(Some(&format!("{:?} watchpoint {:#x}", kind, address)));
+            },
+            StopReason::Signal(fault) => {
+                utilities::context_title(Some(&format!("{} ({}) at {:#x}: {}", fault.signal_name(), fault.code_name(), fault.fault_addr, fault.cause)));
+            },
+            StopReason::Exited(code) => {
+                utilities::context_title(Some(&format!("exited with code {}", code)));
+                return Resume::Detach;
+            },
+        }
+
+        context(emu);
+
+        loop {
+            let line = match self.read_command() {
+                Some(line) => line,
+                None => return Resume::Detach,
+            };
+
+            if let Some(resume) = self.execute(emu, &line) {
+                return resume;
+            }
+        }
+    }
+}
+
+fn register_id(name: &str) -> Option<i32> {
+    match name {
+        "pc"            => Some(RegisterARM64::PC as i32),
+        "sp"            => Some(RegisterARM64::SP as i32),
+        "lr" | "x30"    => Some(RegisterARM64::LR as i32),
+        "fp" | "x29"    => Some(RegisterARM64::FP as i32),
+        "nzcv" | "cpsr" => Some(RegisterARM64::NZCV as i32),
+        _ => {
+            let n = name.strip_prefix('x')?.parse::<usize>().ok()?;
+            if n < 29 { Some(RegisterARM64::x(n)) } else { None }
+        }
+    }
+}
+
+/// `<term>[+-<term>]...` where a term is a number, `$reg`, `symbol` or `lib.so!symbol`.
+fn evaluate<D>(emu: &Emulator<D>, expr: &str) -> Result<u64, String> {
+    let mut value: u64 = 0;
+    let mut negate = false;
+    let mut term = String::new();
+
+    for c in expr.chars().chain(std::iter::once('\0')) {
+        if c == '+' || c == '-' || c == '\0' {
+            if !term.is_empty() {
+                let v = evaluate_term(emu, &term)?;
+                value = if negate { value.wrapping_sub(v) } else { value.wrapping_add(v) };
+                term.clear();
+            }
+            negate = c == '-';
+        }
+        else {
+            term.push(c);
+        }
+    }
+
+    Ok(value)
+}
+
+fn evaluate_term<D>(emu: &Emulator<D>, term: &str) -> Result<u64, String> {
+    if let Some(name) = term.strip_prefix('$') {
+        return match register_id(name) {
+            Some(register) => Ok(emu.reg_read(register).unwrap()),
+            None => Err(format!("unknown register {}", name)),
+        };
+    }
+
+    if let Some(hex) = term.strip_prefix("0x") {
+        return u64::from_str_radix(hex, 16).map_err(|_| format!("invalid number {}", term));
+    }
+
+    if let Ok(value) = term.parse::<u64>() {
+        return Ok(value);
+    }
+
+    emu.resolve_symbol(term).ok_or(format!("no symbol {}", term))
+}
+
+fn parse_hex_bytes(hex: &str) -> Option<Vec<u8>> {
+    let hex = hex.trim_start_matches("0x");
+    if hex.len() % 2 != 0 {
+        return None;
+    }
+    // from_str_radix would take a sign, and a pair may split a multi-byte character
+    (0..hex.len()).step_by(2)
+        .map(|i| hex.get(i..i + 2).filter(|pair| pair.bytes().all(|c| c.is_ascii_hexdigit())).and_then(|pair| u8::from_str_radix(pair, 16).ok()))
+        .collect()
+}
+
+/// `<libfoo.so!func+0x1c> [perms]` for addresses inside a mapping.
+fn describe<D>(emu: &Emulator<D>, address: u64) -> String {
+    match emu.find_mapping(address) {
+        Some(map_info) => format!("<{}> [{}]", emu.symbolize(address), map_info.memory_perms),
+        None => String::new(),
+    }
+}
+
+fn disassemble<D>(emu: &Emulator<D>, address: u64, count: usize) -> Vec<(u64, Vec<u8>, String, String)> {
+    let code = match emu.mem_read_as_vec(address, count * 4) {
+        Ok(code) => code,
+        Err(_) => return Vec::new(),
+    };
+
+    let cs = Capstone::new()
+        .arm64()
+        .mode(arch::arm64::ArchMode::Arm)
+        .build()
+        .expect("failed to create capstone for ARM64");
+
+    let insns = match cs.disasm_count(&code, address, count) {
+        Ok(insns) => insns,
+        Err(_) => return Vec::new(),
+    };
+
+    insns.iter().map(|i| {
+        (i.address(), i.bytes().to_vec(), i.mnemonic().unwrap_or("").to_string(), i.op_str().unwrap_or("").to_string())
+    }).collect()
+}
+
+fn print_disassembly<D>(emu: &Emulator<D>, address: u64, count: usize) {
+    let pc = emu.reg_read(RegisterARM64::PC as i32).unwrap();
+    let breakpoints = emu.debugger.breakpoints();
+
+    for (address, bytes, mnemonic, op_str) in disassemble(emu, address, count) {
+        let marker = if address == pc { "->" } else if breakpoints.contains(&address) { " *" } else { "  " };
+        let location = match emu.symbol_info(address) {
+            Some(info) => info.to_string(),
+            None => String::new(),
+        };
+        let hex: String = bytes.iter().map(|b| format!("{:02x}", b)).collect();
+        println!("{} {:#018x} {:<24} {}  {:<8} {}", marker, address, location, hex, mnemonic, op_str);
+    }
+}
+
+fn context<D>(emu: &Emulator<D>) {
+    utilities::context_title(Some("registers"));
+    for n in (0..29).step_by(4) {
+        let mut line = String::new();
+        for n in n..std::cmp::min(n + 4, 29) {
+            line.push_str(&format!("$x{:<3}: {:#018x}   ", n, emu.reg_read(RegisterARM64::x(n)).unwrap()));
+        }
+        println!("{}", line);
+    }
+    let fp   = emu.reg_read(RegisterARM64::FP as i32).unwrap();
+    let lr   = emu.reg_read(RegisterARM64::LR as i32).unwrap();
+    let sp   = emu.reg_read(RegisterARM64::SP as i32).unwrap();
+    let pc   = emu.reg_read(RegisterARM64::PC as i32).unwrap();
+    let nzcv = emu.reg_read(RegisterARM64::NZCV as i32).unwrap();
+    println!("$fp  : {:#018x}   $lr  : {:#018x}   $sp  : {:#018x}   $pc  : {:#018x}", fp, lr, sp, pc);
+    println!("$nzcv: [{}{}{}{}]",
+        if nzcv & (1 << 31) != 0 { "N" } else { "n" },
+        if nzcv & (1 << 30) != 0 { "Z" } else { "z" },
+        if nzcv & (1 << 29) != 0 { "C" } else { "c" },
+        if nzcv & (1 << 28) != 0 { "V" } else { "v" });
+
+    utilities::context_title(Some("stack"));
+    telescope(emu, sp, CONTEXT_STACK);
+
+    utilities::context_title(Some("code"));
+    print_disassembly(emu, pc.saturating_sub(8), CONTEXT_CODE);
+
+    utilities::context_title(Some("trace"));
+    backtrace(emu, CONTEXT_FRAMES);
+
+    utilities::draw_line();
+}
+
+fn backtrace<D>(emu: &Emulator<D>, max_frames: usize) {
+    for (i, frame) in emu.backtrace(max_frames).iter().enumerate() {
+        println!("#{:<2} {}", i, frame);
+    }
+}
+
+fn vmmap<D>(emu: &Emulator<D>, filter: Option<&str>) {
+    println!("{:<18} {:<18} {:<5} {}", "start", "end", "perms", "path");
+    for map_info in emu.sorted_mappings() {
+        if filter.map_or(true, |filter| map_info.description.contains(filter)) {
+            println!("{:#018x} {:#018x} {:<5} {}", map_info.memory_start, map_info.memory_end, map_info.memory_perms, map_info.description);
+        }
+    }
+}
+
+fn hexdump<D>(emu: &Emulator<D>, address: u64, len: usize) {
+    let data = match emu.mem_read_as_vec(address, len) {
+        Ok(data) => data,
+        Err(_) => {
+            println!("cannot read {:#x} bytes at {:#x}", len, address);
+            return;
+        }
+    };
+
+    for (i, line) in data.chunks(16).enumerate() {
+        let hex: Vec<String> = line.iter().map(|b| format!("{:02x}", b)).collect();
+        let ascii: String = line.iter().map(|b| if b.is_ascii_graphic() || *b == b' ' { *b as char } else { '.' }).collect();
+        println!("{:#018x}  {:<48} {}", address + (i * 16) as u64, hex.join(" "), ascii);
+    }
+}
+
+fn telescope<D>(emu: &Emulator<D>, address: u64, count: u64) {
+    let sp = emu.reg_read(RegisterARM64::SP as i32).unwrap();
+    let fp = emu.reg_read(RegisterARM64::FP as i32).unwrap();
+
+    for i in 0..count {
+        let slot = address + i * 8;
+        let mut line = format!("{:#018x}|+{:#06x}: ", slot, i * 8);
+
+        let mut value = slot;
+        for depth in 0..TELESCOPE_DEPTH {
+            let next = match emu.mem_read_as_vec(value, 8) {
+                Ok(data) => emu.unpack_64(&data),
+                Err(_) => {
+                    if depth == 0 {
+                        line.push_str("<unreadable>");
+                    }
+                    break;
+                }
+            };
+
+            line.push_str(&format!("{}{:#x}", if depth == 0 { "" } else { " -> " }, next));
+
+            if emu.find_mapping(next).is_none() {
+                break;
+            }
+            if let Some(string) = read_string(emu, next) {
+                line.push_str(&format!(" -> \"{}\"", string));
+                break;
+            }
+            value = next;
+        }
+
+        if slot == sp {
+            line.push_str("  <- $sp");
+        }
+        if slot == fp {
+            line.push_str("  <- $fp");
+        }
+        println!("{}", line);
+    }
+}
+
+/// Printable C string of at least 4 characters at `address`.
+fn read_string<D>(emu: &Emulator<D>, address: u64) -> Option<String> {
+    let data = emu.mem_read_as_vec(address, 64).ok()?;
+    let len = data.iter().position(|b| !(b.is_ascii_graphic() || *b == b' ')).unwrap_or(data.len());
+    if len < 4 || (len < data.len() && data[len] != 0) {
+        return None;
+    }
+    Some(String::from_utf8_lossy(&data[..len]).to_string())
+}
+
+fn search<D>(emu: &Emulator<D>, pattern: &[u8]) {
+    // file backed mmaps overlap the region they were placed in, search each byte once
+    let mut searched_until = 0;
+
+    for map_info in emu.sorted_mappings() {
+        let mut address = std::cmp::max(map_info.memory_start, searched_until);
+        searched_until = std::cmp::max(searched_until, map_info.memory_end);
+
+        while address < map_info.memory_end {
+            // overlap the chunks so matches crossing a boundary are found
+            let len = std::cmp::min(SEARCH_CHUNK as u64 + pattern.len() as u64 - 1, map_info.memory_end - address) as usize;
+            if let Ok(data) = emu.mem_read_as_vec(address, len) {
+                for offset in 0..data.len().saturating_sub(pattern.len() - 1) {
+                    if offset < SEARCH_CHUNK && data[offset..].starts_with(pattern) {
+                        let found = address + offset as u64;
+                        println!("{:#018x} {}", found, describe(emu, found));
+                    }
+                }
+            }
+            address += SEARCH_CHUNK as u64;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hex_bytes() {
+        assert_eq!(parse_hex_bytes("0x41ff"), Some(vec![0x41, 0xff]));
+        assert_eq!(parse_hex_bytes("DEAD"), Some(vec![0xde, 0xad]));
+        assert_eq!(parse_hex_bytes("abc"), None);
+        assert_eq!(parse_hex_bytes("+f"), None);
+        assert_eq!(parse_hex_bytes("aé"), None);
+        assert_eq!(parse_hex_bytes("ééé"), None);
+    }
+}
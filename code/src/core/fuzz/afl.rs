@@ -0,0 +1,172 @@
+use std::fs;
+use std::io::{self, Read};
+
+use crate::utilities;
+use crate::core::crash;
+use crate::core::rudroid::Emulator;
+use crate::core::unicorn::arch::arm64::RegisterARM64;
+
+use super::{FuzzOptions, RunResult};
+use super::coverage::MAP_SIZE;
+
+// afl-fuzz reads commands from the first and statuses from the second
+const FORKSRV_FD        : i32 = 198;
+const SHM_ENV_VAR       : &str = "__AFL_SHM_ID";
+const MAP_SIZE_ENV_VAR  : &str = "AFL_MAP_SIZE";
+
+// AFL++ forkserver options in the hello message
+const FS_OPT_ENABLED    : i32 = 0x8000_0001u32 as i32;
+const FS_OPT_MAPSIZE    : i32 = 0x4000_0000;
+const FS_OPT_MAX_MAPSIZE: usize = 1 << 23;
+
+/// afl-fuzz's coverage map, None when not started by afl-fuzz.
+fn afl_shm() -> io::Result<Option<(*mut u8, usize)>> {
+    let id = match std::env::var(SHM_ENV_VAR) {
+        Ok(id) => id.parse::<i32>().map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, format!("bad {}: {}", SHM_ENV_VAR, id)))?,
+        Err(_) => return Ok(None),
+    };
+
+    // edges are masked into the map, rounded down so they stay inside the segment afl-fuzz made
+    let size = std::env::var(MAP_SIZE_ENV_VAR).ok()
+        .and_then(|size| size.parse::<usize>().ok())
+        .filter(|size| *size != 0)
+        .map(|size| if size.is_power_of_two() { size } else { size.next_power_of_two() >> 1 })
+        .unwrap_or(MAP_SIZE);
+
+    let map = unsafe { libc::shmat(id, std::ptr::null(), 0) };
+    if map as isize == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(Some((map as *mut u8, size)))
+}
+
+/// The hello message, telling afl-fuzz how much of its map is used.
+fn hello(map_size: Option<usize>) -> i32 {
+    match map_size {
+        Some(size) if size > 1 && size <= FS_OPT_MAX_MAPSIZE => FS_OPT_ENABLED | FS_OPT_MAPSIZE | ((size as i32 - 1) << 1),
+        _ => 0,
+    }
+}
+
+fn read_input(input_file: Option<&str>) -> io::Result<Vec<u8>> {
+    match input_file {
+        Some(path) => fs::read(path),
+        None => {
+            let mut input = Vec::new();
+            io::stdin().read_to_end(&mut input)?;
+            Ok(input)
+        }
+    }
+}
+
+fn write_status(value: i32) -> bool {
+    unsafe { libc::write(FORKSRV_FD + 1, &value as *const i32 as *const libc::c_void, 4) == 4 }
+}
+
+fn read_command() -> bool {
+    let mut value = 0i32;
+    unsafe { libc::read(FORKSRV_FD, &mut value as *mut i32 as *mut libc::c_void, 4) == 4 }
+}
+
+/// Leave the way a native process would have, so afl-fuzz sees the signal in the wait status.
+fn exit_like(result: &RunResult) -> ! {
+    unsafe {
+        if let RunResult::Crashed(fault) = result {
+            libc::signal(fault.signal, libc::SIG_DFL);
+            libc::raise(fault.signal);
+        }
+        libc::_exit(0);
+    }
+}
+
+impl<D> Emulator<D> {
+    /// Serve afl-fuzz: attach its coverage map, then fork a copy of the emulator for every input
+    /// read from `input_file` (`@@`) or stdin and hand it to `run`. Crashes end the child with
+    /// the fault's signal. Without afl-fuzz around the input is run once.
+    pub fn afl_forkserver<F>(&mut self, input_file: Option<&str>, mut run: F) -> io::Result<()>
+        where F: FnMut(&mut Emulator<D>, &[u8]) -> RunResult
+    {
+        self.start_edge_coverage();
+        let shm = afl_shm()?;
+        if let Some((map, size)) = shm {
+            self.edges.as_mut().unwrap().attach_shared(map, size);
+        }
+
+        // failing when afl-fuzz did not open the pipes
+        if !write_status(hello(shm.map(|(_, size)| size))) {
+            let input = read_input(input_file)?;
+            let result = run(self, &input);
+            match &result {
+                RunResult::Crashed(fault) => {
+                    let pc = self.reg_read(RegisterARM64::PC as i32).unwrap_or(0);
+                    utilities::log(&format!("{} ({}) at {}", fault.signal_name(), fault.cause, self.symbolize(pc)), utilities::DebugLevel::ERROR);
+                    print!("{}", self.tombstone(fault));
+                },
+                RunResult::Returned(value) => println!("[afl] returned {:#x}", value),
+                RunResult::TimedOut => println!("[afl] timed out"),
+            }
+            self.stop_edge_coverage();
+            exit_like(&result);
+        }
+
+        loop {
+            // afl-fuzz closing the pipe is the end of the campaign
+            if !read_command() {
+                unsafe { libc::_exit(0) };
+            }
+
+            let child = unsafe { libc::fork() };
+            if child < 0 {
+                return Err(io::Error::last_os_error());
+            }
+
+            if child == 0 {
+                unsafe {
+                    libc::close(FORKSRV_FD);
+                    libc::close(FORKSRV_FD + 1);
+                }
+                let input = read_input(input_file).unwrap_or_default();
+                let result = run(self, &input);
+                exit_like(&result);
+            }
+
+            let mut status = 0;
+            if !write_status(child) || unsafe { libc::waitpid(child, &mut status, 0) } < 0 || !write_status(status) {
+                return Err(io::Error::last_os_error());
+            }
+        }
+    }
+
+    /// `afl_forkserver` for code that is not a function to call: `place_input` writes the input
+    /// into guest memory and registers, false skips it, then emulation runs from `begin` until
+    /// `until`.
+    pub fn afl_fuzz<F>(&mut self, input_file: Option<&str>, begin: u64, until: u64, mut place_input: F) -> io::Result<()>
+        where F: FnMut(&mut Emulator<D>, &[u8]) -> bool
+    {
+        self.afl_forkserver(input_file, move |emu: &mut Emulator<D>, input: &[u8]| {
+            if !place_input(emu, input) {
+                return RunResult::Returned(0);
+            }
+
+            emu.last_fault = None;
+            match emu.emu_start(begin, until, 0, 0) {
+                Ok(_) if emu.last_fault.is_some() => RunResult::Crashed(emu.last_fault.take().unwrap()),
+                Ok(_) => RunResult::Returned(emu.reg_read(RegisterARM64::X0 as i32).unwrap()),
+                Err(error) => {
+                    let pc = emu.reg_read(RegisterARM64::PC as i32).unwrap_or(0);
+                    RunResult::Crashed(emu.last_fault.take().unwrap_or_else(|| crash::Fault::from_uc_error(error, pc)))
+                },
+            }
+        })
+    }
+
+    /// The `fuzz` target driven by afl-fuzz, arguments placed by the template. Hangs are left
+    /// to afl-fuzz's own timeout.
+    pub fn afl_fuzz_target(&mut self, options: &FuzzOptions) -> io::Result<()> {
+        let address = self.resolve_target(&options.target)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, format!("fuzz target {} not found", options.target)))?;
+
+        let target = self.fuzz_target(address, &options.args, options.max_len, 0);
+        self.afl_forkserver(options.input_file.as_deref(), |emu: &mut Emulator<D>, input: &[u8]| emu.run_target(&target, input))
+    }
+}
@@ -0,0 +1,474 @@
+use std::collections::{HashMap, HashSet};
+
+use crate::core::rudroid::Emulator;
+use crate::core::unicorn::ffi;
+use crate::core::unicorn::arch::arm64::RegisterARM64;
+
+use super::{FuzzTarget, RunResult};
+use super::mutator::Mutator;
+
+// bytes of a memcmp/strcmp operand kept, longer magic values are rare
+const MAX_BYTES         : usize = 32;
+const MAX_ENTRIES       : usize = 4096;
+// distinct operand pairs kept per compare, a loop over a table would drown the rest
+const MAX_PER_SITE      : usize = 16;
+const MAX_CANDIDATES    : usize = 256;
+
+/// Both sides of one comparison.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Operands {
+    // CMP, SUBS or CCMP, `size` bytes wide
+    Int { size: usize, lhs: u64, rhs: u64 },
+    // memcmp, strcmp or strncmp
+    Bytes(Vec<u8>, Vec<u8>),
+}
+
+/// Operands of the comparisons one run went through, by the pc they were made at.
+pub struct CmpLog {
+    pub entries : Vec<(u64, Operands)>,
+    seen        : HashSet<(u64, Operands)>,
+    per_site    : HashMap<u64, usize>,
+    hook        : Option<ffi::uc_hook>,
+    listeners   : Vec<usize>,
+}
+
+impl CmpLog {
+    pub fn new() -> CmpLog {
+        CmpLog {
+            entries     : Vec::new(),
+            seen        : HashSet::new(),
+            per_site    : HashMap::new(),
+            hook        : None,
+            listeners   : Vec::new(),
+        }
+    }
+
+    pub fn record(&mut self, site: u64, operands: Operands) {
+        // nothing to solve when both sides already agree
+        let equal = match &operands {
+            Operands::Int { lhs, rhs, .. } => lhs == rhs,
+            Operands::Bytes(lhs, rhs) => lhs == rhs,
+        };
+        if equal || self.entries.len() >= MAX_ENTRIES {
+            return;
+        }
+
+        let count = self.per_site.entry(site).or_insert(0);
+        if *count >= MAX_PER_SITE {
+            return;
+        }
+        if self.seen.insert((site, operands.clone())) {
+            *count += 1;
+            self.entries.push((site, operands));
+        }
+    }
+
+    /// Copies of `input` with the bytes one side of a comparison came from replaced by the
+    /// other side, and the dictionary tokens worth keeping.
+    pub fn input_to_state(&self, input: &[u8], max_len: usize) -> (Vec<Vec<u8>>, Vec<Vec<u8>>) {
+        let mut candidates = Vec::new();
+        let mut seen = HashSet::new();
+        let mut tokens = Vec::new();
+
+        for (_, operands) in self.entries.iter() {
+            let mut pairs = Vec::new();
+            match operands {
+                Operands::Int { size, lhs, rhs } => {
+                    for (pattern, replacement) in &[(*lhs, *rhs), (*rhs, *lhs)] {
+                        // a byte loaded into a w register compares as 4 bytes
+                        for width in [8, 4, 2, 1].iter().cloned().filter(|width| *width <= *size) {
+                            if width != *size && (pattern >> (width * 8) != 0 || replacement >> (width * 8) != 0) {
+                                continue;
+                            }
+                            let pattern = pattern.to_le_bytes()[..width].to_vec();
+                            let replacement = replacement.to_le_bytes()[..width].to_vec();
+                            if width > 1 {
+                                let reversed = |bytes: &Vec<u8>| bytes.iter().rev().cloned().collect::<Vec<u8>>();
+                                pairs.push((reversed(&pattern), reversed(&replacement)));
+                            }
+                            pairs.push((pattern, replacement));
+                        }
+                    }
+                },
+                Operands::Bytes(lhs, rhs) => {
+                    pairs.push((lhs.clone(), rhs.clone()));
+                    pairs.push((rhs.clone(), lhs.clone()));
+                    tokens.extend([lhs, rhs].iter().filter(|token| token.len() >= 2).map(|token| token.to_vec()));
+                },
+            }
+
+            for (pattern, replacement) in pairs.iter().filter(|(pattern, _)| !pattern.is_empty()) {
+                let mut found = false;
+                for at in (0..input.len()).filter(|at| input[*at..].starts_with(pattern)) {
+                    found = true;
+                    let mut candidate = input.to_vec();
+                    let end = at + replacement.len();
+                    if end > candidate.len() {
+                        candidate.resize(end, 0);
+                    }
+                    candidate[at..end].copy_from_slice(replacement);
+                    candidate.truncate(max_len);
+
+                    if candidate != input && seen.insert(candidate.clone()) {
+                        candidates.push(candidate);
+                        if candidates.len() >= MAX_CANDIDATES {
+                            return (candidates, tokens);
+                        }
+                    }
+                }
+
+                // a value the input controls, likely to matter elsewhere too
+                if found && replacement.len() >= 2 {
+                    tokens.push(replacement.clone());
+                }
+            }
+        }
+
+        (candidates, tokens)
+    }
+}
+
+/// Second operand of a compare, before the registers are read.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Rhs {
+    Immediate(u64),
+    // register, shift type (lsl, lsr, asr) and amount
+    Shifted(u32, u32, u32),
+    // register, extend option and left shift
+    Extended(u32, u32, u32),
+    Register(u32),
+}
+
+/// A decoded CMP, SUBS or CCMP, `lhs` being sp rather than xzr when `lhs_sp` is set.
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Compare {
+    size    : usize,
+    lhs     : u32,
+    lhs_sp  : bool,
+    rhs     : Rhs,
+}
+
+impl Compare {
+    /// None for anything that is not a compare.
+    fn decode(insn: u32) -> Option<Compare> {
+        let size = if insn >> 31 == 1 { 8 } else { 4 };
+        let rn = (insn >> 5) & 31;
+        let rm = (insn >> 16) & 31;
+
+        let (lhs_sp, rhs) = if insn & 0x7f80_0000 == 0x7100_0000 {
+            // SUBS immediate, CMP Rn, #imm
+            let imm = ((insn >> 10) & 0xfff) as u64;
+            (true, Rhs::Immediate(if insn & (1 << 22) != 0 { imm << 12 } else { imm }))
+        }
+        else if insn & 0x7f20_0000 == 0x6b00_0000 {
+            // SUBS shifted register, ror is not allocated
+            let shift = (insn >> 22) & 3;
+            if shift == 3 {
+                return None;
+            }
+            (false, Rhs::Shifted(rm, shift, (insn >> 10) & 0x3f))
+        }
+        else if insn & 0x7fe0_0000 == 0x6b20_0000 {
+            // SUBS extended register
+            (true, Rhs::Extended(rm, (insn >> 13) & 7, (insn >> 10) & 7))
+        }
+        else if insn & 0x7fe0_0c10 == 0x7a40_0000 {
+            // CCMP register, recorded whether or not the condition held
+            (false, Rhs::Register(rm))
+        }
+        else if insn & 0x7fe0_0c10 == 0x7a40_0800 {
+            // CCMP immediate, imm5 where Rm would be
+            (false, Rhs::Immediate(rm as u64))
+        }
+        else {
+            return None;
+        };
+
+        Some(Compare {
+            size    : size,
+            lhs     : rn,
+            lhs_sp  : lhs_sp,
+            rhs     : rhs,
+        })
+    }
+
+    /// Both operands, registers read through `register(n, sp)`.
+    fn operands<F: Fn(u32, bool) -> u64>(&self, register: F) -> Operands {
+        let mask = if self.size == 8 { u64::MAX } else { 0xffff_ffff };
+        let rhs = match self.rhs {
+            Rhs::Immediate(imm) => imm,
+            Rhs::Shifted(rm, shift, amount) => {
+                let value = register(rm, false) & mask;
+                match shift {
+                    0 => value << amount,
+                    1 => value >> amount,
+                    _ if self.size == 4 => ((value as u32 as i32) >> amount) as u32 as u64,
+                    _ => ((value as i64) >> amount) as u64,
+                }
+            },
+            Rhs::Extended(rm, option, amount) => {
+                let value = register(rm, false);
+                let extended = match option {
+                    0 => value as u8 as u64,
+                    1 => value as u16 as u64,
+                    2 => value as u32 as u64,
+                    4 => value as i8 as i64 as u64,
+                    5 => value as i16 as i64 as u64,
+                    6 => value as i32 as i64 as u64,
+                    _ => value,
+                };
+                extended << amount
+            },
+            Rhs::Register(rm) => register(rm, false),
+        };
+
+        Operands::Int { size: self.size, lhs: register(self.lhs, self.lhs_sp) & mask, rhs: rhs & mask }
+    }
+}
+
+impl<D> Emulator<D> {
+    /// Record the operands of CMP, SUBS and CCMP between `begin` and `end` (everything for
+    /// 1, 0) and of libc's memcmp, strcmp and strncmp into `self.cmplog`.
+    pub fn start_cmplog(&mut self, begin: u64, end: u64) {
+        self.stop_cmplog();
+
+        let mut cmplog = CmpLog::new();
+        cmplog.listeners.push(self.hook_symbol_enter("libc.so", "memcmp", |emu, call| {
+            let len = (call.arg(2) as usize).min(MAX_BYTES);
+            emu.record_bytes(call.caller, call.arg(0), call.arg(1), len, false);
+        }));
+        cmplog.listeners.push(self.hook_symbol_enter("libc.so", "strcmp", |emu, call| {
+            emu.record_bytes(call.caller, call.arg(0), call.arg(1), MAX_BYTES, true);
+        }));
+        cmplog.listeners.push(self.hook_symbol_enter("libc.so", "strncmp", |emu, call| {
+            let len = (call.arg(2) as usize).min(MAX_BYTES);
+            emu.record_bytes(call.caller, call.arg(0), call.arg(1), len, true);
+        }));
+        self.cmplog = Some(cmplog);
+
+        let hook = self.add_code_hook(begin, end, |emu: &mut Emulator<D>, address: u64, size: u32| {
+            if size != 4 {
+                return;
+            }
+            let insn = match emu.mem_read_as_vec(address, 4) {
+                Ok(bytes) => u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]),
+                Err(_) => return,
+            };
+            if let Some(compare) = Compare::decode(insn) {
+                let operands = compare.operands(|n, sp| emu.cmp_register(n, sp));
+                if let Some(cmplog) = emu.cmplog.as_mut() {
+                    cmplog.record(address, operands);
+                }
+            }
+        }).expect("failed to add cmplog hook");
+
+        self.cmplog.as_mut().unwrap().hook = Some(hook);
+    }
+
+    pub fn stop_cmplog(&mut self) -> Option<CmpLog> {
+        let mut cmplog = self.cmplog.take()?;
+        if let Some(hook) = cmplog.hook.take() {
+            self.remove_hook(hook).unwrap();
+        }
+        for id in cmplog.listeners.drain(..) {
+            self.unhook_symbol(id);
+        }
+        Some(cmplog)
+    }
+
+    /// Run `input` once with comparisons recorded, add their operands to the dictionary and
+    /// return the inputs that might get past them.
+    pub fn cmplog_stage(&mut self, target: &FuzzTarget, input: &[u8], range: (u64, u64), mutator: &mut Mutator) -> Vec<Vec<u8>> {
+        self.start_cmplog(range.0, range.1);
+        let result = self.run_target(target, input);
+        let cmplog = self.stop_cmplog().unwrap();
+
+        if let RunResult::Crashed(_) = result {
+            return Vec::new();
+        }
+
+        let (candidates, tokens) = cmplog.input_to_state(input, target.max_len);
+        for token in tokens {
+            mutator.add_token(token);
+        }
+        candidates
+    }
+
+    /// x`n`, register 31 being sp or xzr depending on the encoding.
+    fn cmp_register(&self, n: u32, sp: bool) -> u64 {
+        match n {
+            31 if sp => self.reg_read(RegisterARM64::SP as i32).unwrap(),
+            31 => 0,
+            n => self.reg_read(RegisterARM64::x(n as usize)).unwrap(),
+        }
+    }
+
+    fn record_bytes(&mut self, site: u64, lhs: u64, rhs: u64, len: usize, string: bool) {
+        let lhs = self.cmp_bytes(lhs, len, string);
+        let rhs = self.cmp_bytes(rhs, len, string);
+        if let (Some(lhs), Some(rhs), Some(cmplog)) = (lhs, rhs, self.cmplog.as_mut()) {
+            cmplog.record(site, Operands::Bytes(lhs, rhs));
+        }
+    }
+
+    /// Up to `len` bytes at `address`, cut at the NUL for a string.
+    fn cmp_bytes(&self, address: u64, len: usize, string: bool) -> Option<Vec<u8>> {
+        let mut bytes = Vec::new();
+        while bytes.len() < len {
+            let byte = self.mem_read_as_vec(address + bytes.len() as u64, 1).ok()?[0];
+            if string && byte == 0 {
+                break;
+            }
+            bytes.push(byte);
+        }
+        Some(bytes)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // x0 = 0x4d, x1 = 0x41, x2 = 0xffff_ffff_ffff_fff0, sp = 0x7000
+    fn register(n: u32, sp: bool) -> u64 {
+        match n {
+            0 => 0x4d,
+            1 => 0x41,
+            2 => 0xffff_ffff_ffff_fff0,
+            31 if sp => 0x7000,
+            _ => 0,
+        }
+    }
+
+    fn int(size: usize, lhs: u64, rhs: u64) -> Operands {
+        Operands::Int { size: size, lhs: lhs, rhs: rhs }
+    }
+
+    #[test]
+    fn decode_immediate() {
+        // cmp w0, #0x4d
+        let compare = Compare::decode(0x7101_341f).unwrap();
+        assert_eq!(compare, Compare { size: 4, lhs: 0, lhs_sp: true, rhs: Rhs::Immediate(0x4d) });
+        assert_eq!(compare.operands(register), int(4, 0x4d, 0x4d));
+
+        // cmp x1, #1, lsl #12
+        assert_eq!(Compare::decode(0xf140_043f).unwrap().rhs, Rhs::Immediate(0x1000));
+        // cmp sp, #16
+        assert_eq!(Compare::decode(0xf100_43ff).unwrap().operands(register), int(8, 0x7000, 16));
+        // cmn w0, #1 is an ADDS
+        assert_eq!(Compare::decode(0x3100_041f), None);
+    }
+
+    #[test]
+    fn decode_shifted_register() {
+        // cmp x1, x2
+        let compare = Compare::decode(0xeb02_003f).unwrap();
+        assert_eq!(compare, Compare { size: 8, lhs: 1, lhs_sp: false, rhs: Rhs::Shifted(2, 0, 0) });
+        assert_eq!(compare.operands(register), int(8, 0x41, 0xffff_ffff_ffff_fff0));
+
+        // cmp w1, w2, lsl #3
+        assert_eq!(Compare::decode(0x6b02_0c3f).unwrap().operands(register), int(4, 0x41, 0xffff_ff80));
+        // cmp x1, x2, lsr #4
+        assert_eq!(Compare::decode(0xeb42_103f).unwrap().operands(register), int(8, 0x41, 0x0fff_ffff_ffff_ffff));
+        // cmp w1, w2, asr #31
+        assert_eq!(Compare::decode(0x6b82_7c3f).unwrap().operands(register), int(4, 0x41, 0xffff_ffff));
+        // ror is not allocated for SUBS
+        assert_eq!(Compare::decode(0x6bc2_003f), None);
+    }
+
+    #[test]
+    fn decode_extended_register() {
+        // cmp w1, w2, uxtb
+        let compare = Compare::decode(0x6b22_003f).unwrap();
+        assert_eq!(compare.rhs, Rhs::Extended(2, 0, 0));
+        assert_eq!(compare.operands(register), int(4, 0x41, 0xf0));
+
+        // cmp x1, w2, sxtw #2
+        assert_eq!(Compare::decode(0xeb22_c83f).unwrap().operands(register), int(8, 0x41, 0xffff_ffff_ffff_ffc0));
+        // cmp sp, x2
+        let compare = Compare::decode(0xeb22_63ff).unwrap();
+        assert!(compare.lhs_sp);
+        assert_eq!(compare.operands(register), int(8, 0x7000, 0xffff_ffff_ffff_fff0));
+    }
+
+    #[test]
+    fn decode_ccmp() {
+        // ccmp w0, #5, #0, ne
+        assert_eq!(Compare::decode(0x7a45_1800).unwrap().operands(register), int(4, 0x4d, 5));
+        // ccmp x0, x1, #4, eq
+        assert_eq!(Compare::decode(0xfa41_0004).unwrap().operands(register), int(8, 0x4d, 0x41));
+    }
+
+    #[test]
+    fn decode_other_instructions() {
+        // nop, adds x0, x1, x2, tst w0, #1, add x0, x1, #1
+        for insn in &[0xd503_201f, 0xab02_0020, 0x7200_001f, 0x9100_0420] {
+            assert_eq!(Compare::decode(*insn), None, "{:#x}", insn);
+        }
+    }
+
+    #[test]
+    fn record_skips_equal_and_duplicates() {
+        let mut cmplog = CmpLog::new();
+        cmplog.record(0x1000, int(4, 1, 1));
+        cmplog.record(0x1000, Operands::Bytes(b"abc".to_vec(), b"abc".to_vec()));
+        cmplog.record(0x1000, int(4, 1, 2));
+        cmplog.record(0x1000, int(4, 1, 2));
+        cmplog.record(0x1004, int(4, 1, 2));
+        assert_eq!(cmplog.entries, vec![(0x1000, int(4, 1, 2)), (0x1004, int(4, 1, 2))]);
+    }
+
+    #[test]
+    fn record_caps_per_site() {
+        let mut cmplog = CmpLog::new();
+        for rhs in 0..MAX_PER_SITE as u64 * 2 {
+            cmplog.record(0x1000, int(8, 0x100, rhs));
+        }
+        cmplog.record(0x2000, int(8, 0x100, 0));
+        assert_eq!(cmplog.entries.len(), MAX_PER_SITE + 1);
+        assert_eq!(cmplog.entries.last(), Some(&(0x2000, int(8, 0x100, 0))));
+    }
+
+    #[test]
+    fn input_to_state_narrows_small_values() {
+        // a byte compared in a w register
+        let mut cmplog = CmpLog::new();
+        cmplog.record(0x1000, int(4, 0x41, 0x4d));
+        let (candidates, tokens) = cmplog.input_to_state(b"xxAyy", 64);
+        assert_eq!(candidates, vec![b"xxMyy".to_vec()]);
+        assert!(tokens.is_empty());
+    }
+
+    #[test]
+    fn input_to_state_both_byte_orders() {
+        let mut cmplog = CmpLog::new();
+        cmplog.record(0x1000, int(4, 0x1122_3344, 0x5566_7788));
+        let input = [0x44, 0x33, 0x22, 0x11, 0, 0x11, 0x22, 0x33, 0x44];
+        let (candidates, tokens) = cmplog.input_to_state(&input, 64);
+
+        assert_eq!(candidates.len(), 2);
+        assert!(candidates.contains(&vec![0x88, 0x77, 0x66, 0x55, 0, 0x11, 0x22, 0x33, 0x44]));
+        assert!(candidates.contains(&vec![0x44, 0x33, 0x22, 0x11, 0, 0x55, 0x66, 0x77, 0x88]));
+        assert!(tokens.contains(&vec![0x88, 0x77, 0x66, 0x55]));
+        assert!(tokens.contains(&vec![0x55, 0x66, 0x77, 0x88]));
+    }
+
+    #[test]
+    fn input_to_state_bytes() {
+        let mut cmplog = CmpLog::new();
+        cmplog.record(0x1000, Operands::Bytes(b"AB".to_vec(), b"ABCD".to_vec()));
+
+        // the replacement runs past the end of the input
+        let (candidates, tokens) = cmplog.input_to_state(b"xxAB", 64);
+        assert_eq!(candidates, vec![b"xxABCD".to_vec()]);
+        assert!(tokens.contains(&b"AB".to_vec()));
+        assert!(tokens.contains(&b"ABCD".to_vec()));
+
+        let (candidates, _) = cmplog.input_to_state(b"xxAB", 5);
+        assert_eq!(candidates, vec![b"xxABC".to_vec()]);
+
+        // nothing the input controls
+        let (candidates, _) = cmplog.input_to_state(b"zzzz", 64);
+        assert!(candidates.is_empty());
+    }
+}
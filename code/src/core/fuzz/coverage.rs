@@ -0,0 +1,137 @@
+use crate::core::rudroid::Emulator;
+use crate::core::unicorn::ffi;
+
+pub const MAP_SIZE: usize = 1 << 16;
+
+/// AFL style edge coverage, a hit counter for every (previous block, block) pair hashed into
+/// `MAP_SIZE` bytes.
+pub struct EdgeMap {
+    map         : Vec<u8>,
+    // afl-fuzz's shared memory map, used instead of `map` once attached
+    shared      : Option<(*mut u8, usize)>,
+    prev        : usize,
+    hook        : Option<ffi::uc_hook>,
+}
+
+impl EdgeMap {
+    pub fn new() -> EdgeMap {
+        EdgeMap {
+            map     : vec![0; MAP_SIZE],
+            shared  : None,
+            prev    : 0,
+            hook    : None,
+        }
+    }
+
+    /// Count into `size` bytes at `map` from now on, `size` a power of two. The memory has to
+    /// outlive the map, afl's shared memory stays attached until the process exits.
+    pub fn attach_shared(&mut self, map: *mut u8, size: usize) {
+        self.shared = Some((map, size));
+        self.prev = 0;
+    }
+
+    pub fn hits(&self) -> &[u8] {
+        match self.shared {
+            Some((map, size)) => unsafe { std::slice::from_raw_parts(map, size) },
+            None => &self.map,
+        }
+    }
+
+    fn hits_mut(&mut self) -> &mut [u8] {
+        match self.shared {
+            Some((map, size)) => unsafe { std::slice::from_raw_parts_mut(map, size) },
+            None => &mut self.map,
+        }
+    }
+
+    /// Forget the last run.
+    pub fn reset(&mut self) {
+        self.hits_mut().iter_mut().for_each(|count| *count = 0);
+        self.prev = 0;
+    }
+
+    fn record(&mut self, address: u64) {
+        let prev = self.prev;
+        let map = self.hits_mut();
+        // the block id afl-qemu uses
+        let block = (((address >> 4) ^ (address << 8)) as usize) & (map.len() - 1);
+        let edge = block ^ prev;
+        map[edge] = map[edge].wrapping_add(1);
+        // shifted so A -> B and B -> A are different edges
+        self.prev = block >> 1;
+    }
+
+    pub fn edge_count(&self) -> usize {
+        self.hits().iter().filter(|count| **count != 0).count()
+    }
+}
+
+/// Hit counts folded into AFL's buckets, a loop running a few more times is not new coverage.
+fn bucket(count: u8) -> u8 {
+    match count {
+        0           => 0,
+        1           => 1,
+        2           => 2,
+        3           => 4,
+        4..=7       => 8,
+        8..=15      => 16,
+        16..=31     => 32,
+        32..=127    => 64,
+        _           => 128,
+    }
+}
+
+/// Edges and hit count buckets any run has reached so far.
+pub struct Virgin {
+    bits    : Vec<u8>,
+}
+
+impl Virgin {
+    pub fn new() -> Virgin {
+        Virgin {
+            bits    : vec![0; MAP_SIZE],
+        }
+    }
+
+    /// Add the edges of a run, true when it reached a new edge or hit count bucket.
+    pub fn merge(&mut self, edges: &EdgeMap) -> bool {
+        let mut new = false;
+        for (seen, count) in self.bits.iter_mut().zip(edges.hits().iter()) {
+            let bucket = bucket(*count);
+            if bucket & !*seen != 0 {
+                *seen |= bucket;
+                new = true;
+            }
+        }
+        new
+    }
+
+    pub fn edge_count(&self) -> usize {
+        self.bits.iter().filter(|bits| **bits != 0).count()
+    }
+}
+
+impl<D> Emulator<D> {
+    /// Count edges between basic blocks into `self.edges`.
+    pub fn start_edge_coverage(&mut self) {
+        self.stop_edge_coverage();
+
+        self.edges = Some(EdgeMap::new());
+
+        let hook = self.add_block_hook(1, 0, |emu: &mut Emulator<D>, address: u64, _size: u32| {
+            if let Some(edges) = emu.edges.as_mut() {
+                edges.record(address);
+            }
+        }).expect("failed to add edge coverage hook");
+
+        self.edges.as_mut().unwrap().hook = Some(hook);
+    }
+
+    pub fn stop_edge_coverage(&mut self) -> Option<EdgeMap> {
+        let mut edges = self.edges.take()?;
+        if let Some(hook) = edges.hook.take() {
+            self.remove_hook(hook).unwrap();
+        }
+        Some(edges)
+    }
+}
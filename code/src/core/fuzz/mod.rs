@@ -0,0 +1,378 @@
+pub mod afl;
+pub mod cmplog;
+pub mod coverage;
+pub mod mutator;
+pub mod triage;
+
+use std::fs;
+use std::io;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use crate::utilities;
+use crate::core::crash;
+use crate::core::crash::triage::{Triage, DEFAULT_FRAMES};
+use crate::core::rudroid::Emulator;
+use crate::core::unicorn::Context;
+use crate::core::snapshot::Snapshot;
+use crate::core::unicorn::arch::arm64::RegisterARM64;
+use crate::core::unicorn::unicorn_const::Protection;
+use crate::core::loaders::elfRunner::TRAMPOLINE_RETURN;
+
+use coverage::Virgin;
+use mutator::Mutator;
+
+// where the input is written, the end of the region is left unmapped so reads past the input
+// fault instead of hitting stale bytes
+const INPUT_BASE        : u64 = 0x7100_0000_0000;
+const STATUS_INTERVAL   : Duration = Duration::from_secs(5);
+
+/// What the target function gets in one argument register.
+#[derive(Debug, Clone, PartialEq)]
+pub enum FuzzArg {
+    // pointer to the input bytes
+    Input,
+    // pointer to the input followed by a NUL
+    CString,
+    // input length
+    Length,
+    // the emulated JNIEnv
+    JniEnv,
+    Value(u64),
+}
+
+impl FuzzArg {
+    /// `input,len`, `env,0,cstr`, ...: one of input (or @@), cstr, len, env or a number per
+    /// argument register.
+    pub fn parse_template(template: &str) -> Option<Vec<FuzzArg>> {
+        let mut args = Vec::new();
+        for arg in template.split(',').map(|arg| arg.trim()).filter(|arg| !arg.is_empty()) {
+            args.push(match arg {
+                "input" | "@@"  => FuzzArg::Input,
+                "cstr"          => FuzzArg::CString,
+                "len"           => FuzzArg::Length,
+                "env"           => FuzzArg::JniEnv,
+                "null"          => FuzzArg::Value(0),
+                value if value.starts_with("0x") => FuzzArg::Value(u64::from_str_radix(&value[2..], 16).ok()?),
+                value => FuzzArg::Value(value.parse::<i64>().ok()? as u64),
+            });
+        }
+
+        if args.len() > 8 {
+            return None;
+        }
+        Some(args)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct FuzzOptions {
+    // symbol, `lib.so!symbol` or hex address
+    pub target        : String,
+    pub args          : Vec<FuzzArg>,
+    // seeds are read from here and new inputs written back
+    pub corpus        : String,
+    pub crashes       : String,
+    pub dictionary    : Option<String>,
+    pub max_len       : usize,
+    // milliseconds
+    pub timeout       : u64,
+    // 0 runs until interrupted
+    pub iterations    : u64,
+    pub seed          : Option<u64>,
+    // let afl-fuzz drive the target, reading the input from `input_file` or stdin
+    pub afl           : bool,
+    pub input_file    : Option<String>,
+    // top frames a crash bucket is made of
+    pub triage_frames : usize,
+    // solve comparisons against the input and collect dictionary tokens from them
+    pub cmplog        : bool,
+}
+
+impl FuzzOptions {
+    pub fn new(target: &str) -> FuzzOptions {
+        FuzzOptions {
+            target        : String::from(target),
+            args          : vec![FuzzArg::Input, FuzzArg::Length],
+            corpus        : String::from("corpus"),
+            crashes       : String::from("crashes"),
+            dictionary    : None,
+            max_len       : 0x1000,
+            timeout       : 1000,
+            iterations    : 0,
+            seed          : None,
+            afl           : false,
+            input_file    : None,
+            triage_frames : DEFAULT_FRAMES,
+            cmplog        : false,
+        }
+    }
+}
+
+/// How one run of the target ended.
+#[derive(Debug, Clone)]
+pub enum RunResult {
+    Returned(u64),
+    Crashed(crash::Fault),
+    TimedOut,
+}
+
+/// A function set up to be called over and over with fresh inputs.
+pub struct FuzzTarget {
+    pub address     : u64,
+    pub args        : Vec<FuzzArg>,
+    pub max_len     : usize,
+    // microseconds, 0 for none
+    pub timeout     : u64,
+    input_size      : u64,
+    trampoline      : u64,
+    // registers every run starts from
+    context         : Context,
+    // the whole state every run starts from, registers only without one
+    pub snapshot    : Option<Snapshot>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct FuzzStats {
+    pub execs       : u64,
+    pub corpus      : usize,
+    pub edges       : usize,
+    pub crashes     : usize,
+    pub buckets     : usize,
+    pub hangs       : usize,
+    pub tokens      : usize,
+}
+
+impl std::fmt::Display for FuzzStats {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "execs {}, corpus {}, edges {}, crashes {} in {} buckets, hangs {}, dictionary {}", self.execs, self.corpus, self.edges, self.crashes, self.buckets, self.hangs, self.tokens)
+    }
+}
+
+impl<D> Emulator<D> {
+    /// Address of a fuzz target given as a hex address or a symbol.
+    pub fn resolve_target(&self, target: &str) -> Option<u64> {
+        if target.starts_with("0x") {
+            return u64::from_str_radix(&target[2..], 16).ok();
+        }
+        self.resolve_symbol(target)
+    }
+
+    /// Map the input buffer and remember the current registers as the start of every run.
+    pub fn fuzz_target(&mut self, address: u64, args: &[FuzzArg], max_len: usize, timeout_ms: u64) -> FuzzTarget {
+        let trampoline = self.trampoline();
+
+        // room for the NUL of a cstr argument
+        let input_size = self.uc_align_up(max_len as u64 + 1);
+        self.mmu_map(INPUT_BASE, input_size as usize, Protection::READ | Protection::WRITE, "[fuzz_input]", self.null_mut());
+
+        if args.contains(&FuzzArg::JniEnv) {
+            self.jni_init();
+        }
+
+        FuzzTarget {
+            address     : address,
+            args        : args.to_vec(),
+            max_len     : max_len,
+            timeout     : timeout_ms * 1000,
+            input_size  : input_size,
+            trampoline  : trampoline,
+            context     : self.context_init().expect("failed to save the cpu context"),
+            snapshot    : None,
+        }
+    }
+
+    /// Call the target once with `input`.
+    pub fn run_target(&mut self, target: &FuzzTarget, input: &[u8]) -> RunResult {
+        match &target.snapshot {
+            Some(snapshot) => self.restore_snapshot(snapshot),
+            None => self.context_restore(&target.context).unwrap(),
+        }
+
+        let input = &input[..input.len().min(target.max_len)];
+        let mut data = input.to_vec();
+        if target.args.contains(&FuzzArg::CString) {
+            data.push(0);
+        }
+        let address = INPUT_BASE + target.input_size - data.len() as u64;
+        self.mem_write(address, &data).unwrap();
+
+        for (n, arg) in target.args.iter().enumerate() {
+            let value = match arg {
+                FuzzArg::Input | FuzzArg::CString => address,
+                FuzzArg::Length => input.len() as u64,
+                FuzzArg::JniEnv => self.jni_env(),
+                FuzzArg::Value(value) => *value,
+            };
+            self.reg_write(RegisterARM64::x(n), value).unwrap();
+        }
+
+        self.last_fault = None;
+        match self.try_call_me(target.address, target.trampoline, target.timeout) {
+            // abort() and friends
+            Ok(_) if self.last_fault.is_some() => RunResult::Crashed(self.last_fault.take().unwrap()),
+            Ok(_) => {
+                let pc = self.reg_read(RegisterARM64::PC as i32).unwrap();
+                if pc == target.trampoline + TRAMPOLINE_RETURN {
+                    RunResult::Returned(self.reg_read(RegisterARM64::X0 as i32).unwrap())
+                }
+                else {
+                    RunResult::TimedOut
+                }
+            },
+            Err(error) => {
+                let pc = self.reg_read(RegisterARM64::PC as i32).unwrap_or(0);
+                RunResult::Crashed(self.last_fault.take().unwrap_or_else(|| crash::Fault::from_uc_error(error, pc)))
+            },
+        }
+    }
+
+    /// Coverage guided fuzzing of one function: mutate corpus entries, keep the ones reaching
+    /// new edges and save crashing and hanging inputs with a report next to them. Crashes are
+    /// also bucketed, with a minimized reproducer per bucket and a triage report. With cmplog
+    /// every new corpus entry is run once more recording comparisons, and the inputs solving
+    /// them are tried before going back to mutations.
+    pub fn fuzz(&mut self, options: &FuzzOptions) -> io::Result<FuzzStats> {
+        let address = self.resolve_target(&options.target)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, format!("fuzz target {} not found", options.target)))?;
+
+        fs::create_dir_all(&options.corpus)?;
+        fs::create_dir_all(&options.crashes)?;
+
+        let seed = options.seed.unwrap_or_else(|| SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_nanos() as u64);
+        let mut mutator = Mutator::new(seed, options.max_len);
+        if let Some(path) = &options.dictionary {
+            let count = mutator.load_dictionary(path)?;
+            self.debug_print(format!("{} dictionary tokens from {}", count, path));
+        }
+
+        let mut seeds = Vec::new();
+        let mut entries: Vec<_> = fs::read_dir(&options.corpus)?.filter_map(|entry| entry.ok()).map(|entry| entry.path()).collect();
+        entries.sort();
+        for path in entries.iter().filter(|path| path.is_file()) {
+            let mut data = fs::read(path)?;
+            data.truncate(options.max_len);
+            seeds.push(data);
+        }
+        if seeds.is_empty() {
+            // havoc grows inputs, any start will do
+            seeds.push(vec![0; 8]);
+        }
+        // popped from the back
+        seeds.reverse();
+
+        utilities::context_title(Some(&format!("Fuzzing {} at {:#x}, seed {:#x}", options.target, address, seed)));
+
+        let mut target = self.fuzz_target(address, &options.args, options.max_len, options.timeout);
+        // globals and heap the target changed would leak into the next run
+        target.snapshot = Some(self.take_snapshot());
+        self.start_edge_coverage();
+        // compares in the target's module, libc's own would only be noise
+        let cmplog_range = self.module_at(address).map(|module| (module.base, module.end() - 1)).unwrap_or((1, 0));
+
+        let mut virgin = Virgin::new();
+        let mut crash_virgin = Virgin::new();
+        let mut hang_virgin = Virgin::new();
+        let mut corpus: Vec<Vec<u8>> = Vec::new();
+        let mut pending: Vec<Vec<u8>> = Vec::new();
+        let mut stats = FuzzStats::default();
+        let mut triage = Triage::new(options.triage_frames);
+
+        let started = Instant::now();
+        let mut last_status = started;
+        let mut queue = 0;
+
+        loop {
+            // seeds first, in order, then cmplog's candidates and mutations of the corpus
+            let (input, is_seed) = match seeds.pop() {
+                Some(seed) => (seed, true),
+                None => {
+                    if options.iterations != 0 && stats.execs >= options.iterations {
+                        break;
+                    }
+                    match pending.pop() {
+                        Some(candidate) => (candidate, false),
+                        None => {
+                            queue = (queue + 1) % corpus.len().max(1);
+                            let parent = corpus.get(queue).cloned().unwrap_or_default();
+                            (mutator.mutate(&parent, &corpus), false)
+                        },
+                    }
+                }
+            };
+
+            self.edges.as_mut().unwrap().reset();
+            let result = self.run_target(&target, &input);
+            stats.execs += 1;
+
+            let edges = self.edges.take().unwrap();
+            match &result {
+                RunResult::Returned(_) => {
+                    if virgin.merge(&edges) || is_seed {
+                        if !is_seed {
+                            fs::write(format!("{}/id_{:06}_edges_{}", options.corpus, corpus.len(), edges.edge_count()), &input)?;
+                        }
+                        if options.cmplog {
+                            pending.extend(self.cmplog_stage(&target, &input, cmplog_range, &mut mutator));
+                        }
+                        corpus.push(input);
+                    }
+                },
+                RunResult::Crashed(fault) => {
+                    let crash = self.classify_crash(fault, options.triage_frames);
+
+                    // one input per crashing path, not per execution
+                    if crash_virgin.merge(&edges) {
+                        let name = format!("{}/id_{:06}_{}_pc_{:x}", options.crashes, stats.crashes, fault.signal_name(), self.reg_read(RegisterARM64::PC as i32).unwrap_or(0));
+                        fs::write(&name, &input)?;
+                        fs::write(format!("{}.txt", name), self.tombstone(fault))?;
+                        utilities::log(&format!("{} ({}) at {}, saved {}", fault.signal_name(), fault.cause, self.symbolize(self.reg_read(RegisterARM64::PC as i32).unwrap_or(0)), name), utilities::DebugLevel::ERROR);
+                        stats.crashes += 1;
+                    }
+
+                    if triage.add(&crash, &input) {
+                        let reproducer = self.minimize_crash(&target, &input, crash.bucket, options.triage_frames);
+                        triage.bucket_mut(crash.bucket).unwrap().reproducer = reproducer;
+                        self.save_bucket(&mut triage, &target, crash.bucket, &options.crashes)?;
+                        utilities::log(&format!("new crash bucket {:016x}: {} ({})", crash.bucket, crash.kind, crash.kind.exploitability()), utilities::DebugLevel::ERROR);
+                        stats.buckets += 1;
+                    }
+                },
+                RunResult::TimedOut => {
+                    if hang_virgin.merge(&edges) {
+                        let pc = self.reg_read(RegisterARM64::PC as i32).unwrap_or(0);
+                        let name = format!("{}/hang_{:06}_pc_{:x}", options.crashes, stats.hangs, pc);
+                        fs::write(&name, &input)?;
+                        fs::write(format!("{}.txt", name), format!("timed out after {} ms at {}\n", options.timeout, self.symbolize(pc)))?;
+                        stats.hangs += 1;
+                    }
+                },
+            }
+            self.edges = Some(edges);
+
+            if last_status.elapsed() >= STATUS_INTERVAL {
+                last_status = Instant::now();
+                stats.corpus = corpus.len();
+                stats.edges = virgin.edge_count();
+                stats.tokens = mutator.dictionary.len();
+                println!("[fuzz] {}, {:.0} execs/s", stats, stats.execs as f64 / started.elapsed().as_secs_f64());
+            }
+        }
+
+        self.stop_edge_coverage();
+
+        // smaller inputs may have reached a bucket since it was saved
+        for bucket in triage.buckets() {
+            if let Some(path) = &bucket.path {
+                fs::write(path, &bucket.reproducer)?;
+            }
+        }
+        if stats.buckets != 0 {
+            fs::write(format!("{}/{}", options.crashes, triage::REPORT_NAME), triage.report())?;
+        }
+
+        stats.corpus = corpus.len();
+        stats.edges = virgin.edge_count();
+        stats.tokens = mutator.dictionary.len();
+        Ok(stats)
+    }
+}
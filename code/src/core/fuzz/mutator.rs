@@ -0,0 +1,345 @@
+use std::fs;
+use std::io;
+
+const INTERESTING_8 : &[i8]  = &[-128, -1, 0, 1, 16, 32, 64, 100, 127];
+const INTERESTING_16: &[i16] = &[-32768, -129, 128, 255, 256, 512, 1000, 1024, 4096, 32767];
+const INTERESTING_32: &[i32] = &[-2147483648, -100663046, -32769, 32768, 65535, 65536, 100663045, 2147483647];
+
+const ARITH_MAX     : u64 = 35;
+const HAVOC_STACK   : u64 = 7;
+// tokens cmplog may add on top of the user's dictionary
+const AUTO_TOKENS   : usize = 512;
+
+/// xorshift64*, plenty for picking mutations.
+pub struct Rng {
+    state   : u64,
+}
+
+impl Rng {
+    pub fn new(seed: u64) -> Rng {
+        Rng { state: seed | 1 }
+    }
+
+    pub fn next(&mut self) -> u64 {
+        self.state ^= self.state >> 12;
+        self.state ^= self.state << 25;
+        self.state ^= self.state >> 27;
+        self.state.wrapping_mul(0x2545_f491_4f6c_dd1d)
+    }
+
+    /// Uniform-ish in `0..limit`, 0 for an empty range.
+    pub fn below(&mut self, limit: usize) -> usize {
+        if limit == 0 { 0 } else { (self.next() % limit as u64) as usize }
+    }
+}
+
+/// AFL's havoc and splice stages plus dictionary tokens.
+pub struct Mutator {
+    pub rng         : Rng,
+    pub dictionary  : Vec<Vec<u8>>,
+    pub max_len     : usize,
+    auto_tokens     : usize,
+}
+
+impl Mutator {
+    pub fn new(seed: u64, max_len: usize) -> Mutator {
+        Mutator {
+            rng         : Rng::new(seed),
+            dictionary  : Vec::new(),
+            max_len     : max_len,
+            auto_tokens : 0,
+        }
+    }
+
+    /// Read an AFL dictionary, `name="value"` or bare `"value"` lines with `\xNN` escapes.
+    /// Returns the number of tokens added.
+    pub fn load_dictionary(&mut self, path: &str) -> io::Result<usize> {
+        let text = fs::read_to_string(path)?;
+        let mut count = 0;
+
+        for (number, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let value = match (line.find('"'), line.rfind('"')) {
+                (Some(start), Some(end)) if end > start => &line[start + 1..end],
+                _ => return Err(io::Error::new(io::ErrorKind::InvalidData, format!("{}:{}: expected a quoted value", path, number + 1))),
+            };
+
+            let token = unescape(value).ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, format!("{}:{}: bad escape", path, number + 1)))?;
+            if !token.is_empty() {
+                self.dictionary.push(token);
+                count += 1;
+            }
+        }
+
+        Ok(count)
+    }
+
+    /// Add a token found while running the target, true when it was new.
+    pub fn add_token(&mut self, token: Vec<u8>) -> bool {
+        if token.is_empty() || self.auto_tokens >= AUTO_TOKENS || self.dictionary.contains(&token) {
+            return false;
+        }
+        self.dictionary.push(token);
+        self.auto_tokens += 1;
+        true
+    }
+
+    /// A mutated copy of `input`, sometimes spliced with another entry of `corpus` first.
+    pub fn mutate(&mut self, input: &[u8], corpus: &[Vec<u8>]) -> Vec<u8> {
+        let mut data = if corpus.len() > 1 && self.rng.below(4) == 0 {
+            let other = &corpus[self.rng.below(corpus.len())];
+            self.splice(input, other)
+        }
+        else {
+            input.to_vec()
+        };
+
+        self.havoc(&mut data);
+        data
+    }
+
+    /// Head of `a` and tail of `b`, cut somewhere in the middle.
+    pub fn splice(&mut self, a: &[u8], b: &[u8]) -> Vec<u8> {
+        if a.len() < 2 || b.len() < 2 {
+            return a.to_vec();
+        }
+
+        let cut = 1 + self.rng.below(a.len().min(b.len()) - 1);
+        let mut data = a[..cut].to_vec();
+        data.extend_from_slice(&b[cut..]);
+        data.truncate(self.max_len);
+        data
+    }
+
+    /// A stack of 2 to 128 random mutations.
+    pub fn havoc(&mut self, data: &mut Vec<u8>) {
+        let stack = 1 << (1 + self.rng.below(HAVOC_STACK as usize));
+        for _ in 0..stack {
+            self.mutate_once(data);
+        }
+        data.truncate(self.max_len);
+    }
+
+    fn mutate_once(&mut self, data: &mut Vec<u8>) {
+        // only growing mutations make sense on an empty input
+        let kinds = if data.is_empty() { 1 } else { 13 };
+
+        match self.rng.below(kinds) {
+            0 => {
+                // insert a dictionary token, or a block of one random byte
+                let at = self.rng.below(data.len() + 1);
+                let block = self.block(data.len());
+                data.splice(at..at, block);
+            },
+            1 => {
+                let bit = self.rng.below(data.len() * 8);
+                data[bit / 8] ^= 0x80 >> (bit % 8);
+            },
+            2 => {
+                let at = self.rng.below(data.len());
+                data[at] = INTERESTING_8[self.rng.below(INTERESTING_8.len())] as u8;
+            },
+            3 if data.len() >= 2 => {
+                let value = INTERESTING_16[self.rng.below(INTERESTING_16.len())];
+                let at = self.rng.below(data.len() - 1);
+                let bytes = if self.rng.below(2) == 0 { value.to_le_bytes() } else { value.to_be_bytes() };
+                data[at..at + 2].copy_from_slice(&bytes);
+            },
+            4 if data.len() >= 4 => {
+                let value = INTERESTING_32[self.rng.below(INTERESTING_32.len())];
+                let at = self.rng.below(data.len() - 3);
+                let bytes = if self.rng.below(2) == 0 { value.to_le_bytes() } else { value.to_be_bytes() };
+                data[at..at + 4].copy_from_slice(&bytes);
+            },
+            5 => {
+                let at = self.rng.below(data.len());
+                let delta = 1 + self.rng.below(ARITH_MAX as usize) as u8;
+                data[at] = if self.rng.below(2) == 0 { data[at].wrapping_add(delta) } else { data[at].wrapping_sub(delta) };
+            },
+            6 if data.len() >= 4 => {
+                let at = self.rng.below(data.len() - 3);
+                let delta = 1 + self.rng.below(ARITH_MAX as usize) as u32;
+                let mut value = [0u8; 4];
+                value.copy_from_slice(&data[at..at + 4]);
+                let value = if self.rng.below(2) == 0 { u32::from_le_bytes(value).wrapping_add(delta) } else { u32::from_le_bytes(value).wrapping_sub(delta) };
+                data[at..at + 4].copy_from_slice(&value.to_le_bytes());
+            },
+            7 => {
+                let at = self.rng.below(data.len());
+                data[at] ^= 1 + self.rng.below(255) as u8;
+            },
+            8 if data.len() >= 2 => {
+                // delete a block, never the whole input
+                let len = 1 + self.rng.below(data.len() - 1);
+                let at = self.rng.below(data.len() - len + 1);
+                data.drain(at..at + len);
+            },
+            9 => {
+                // clone a block somewhere else
+                let len = 1 + self.rng.below(data.len());
+                let from = self.rng.below(data.len() - len + 1);
+                let at = self.rng.below(data.len() + 1);
+                let block = data[from..from + len].to_vec();
+                data.splice(at..at, block);
+            },
+            10 => {
+                // overwrite with a block copied from elsewhere in the input
+                let len = 1 + self.rng.below(data.len());
+                let from = self.rng.below(data.len() - len + 1);
+                let to = self.rng.below(data.len() - len + 1);
+                data.copy_within(from..from + len, to);
+            },
+            11 | 12 if !self.dictionary.is_empty() => {
+                let token = self.dictionary[self.rng.below(self.dictionary.len())].clone();
+                if token.len() <= data.len() && self.rng.below(2) == 0 {
+                    let at = self.rng.below(data.len() - token.len() + 1);
+                    data[at..at + token.len()].copy_from_slice(&token);
+                }
+                else {
+                    let at = self.rng.below(data.len() + 1);
+                    data.splice(at..at, token);
+                }
+            },
+            _ => {
+                let at = self.rng.below(data.len());
+                data[at] = self.rng.next() as u8;
+            },
+        }
+    }
+
+    /// Something to insert: a dictionary token or a run of one byte.
+    fn block(&mut self, len: usize) -> Vec<u8> {
+        if !self.dictionary.is_empty() && self.rng.below(2) == 0 {
+            return self.dictionary[self.rng.below(self.dictionary.len())].clone();
+        }
+
+        let size = 1 + self.rng.below(len.max(16).min(self.max_len.max(1)));
+        vec![self.rng.next() as u8; size]
+    }
+}
+
+/// `\xNN`, `\\` and `\"` escapes of a dictionary value.
+fn unescape(value: &str) -> Option<Vec<u8>> {
+    let bytes = value.as_bytes();
+    let mut out = Vec::new();
+    let mut n = 0;
+
+    while n < bytes.len() {
+        if bytes[n] != b'\\' {
+            out.push(bytes[n]);
+            n += 1;
+            continue;
+        }
+
+        match bytes.get(n + 1)? {
+            b'x' => {
+                out.push(u8::from_str_radix(value.get(n + 2..n + 4)?, 16).ok()?);
+                n += 4;
+            },
+            escaped => {
+                out.push(*escaped);
+                n += 2;
+            },
+        }
+    }
+
+    Some(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unescape_values() {
+        assert_eq!(unescape("GIF89a"), Some(b"GIF89a".to_vec()));
+        assert_eq!(unescape("\\x7fELF"), Some(b"\x7fELF".to_vec()));
+        assert_eq!(unescape("a\\\\b\\\"c"), Some(b"a\\b\"c".to_vec()));
+        assert_eq!(unescape("\\xff\\x00"), Some(vec![0xff, 0]));
+        assert_eq!(unescape(""), Some(Vec::new()));
+    }
+
+    #[test]
+    fn unescape_rejects_bad_escapes() {
+        assert_eq!(unescape("\\"), None);
+        assert_eq!(unescape("\\x"), None);
+        assert_eq!(unescape("\\x4"), None);
+        assert_eq!(unescape("\\xzz"), None);
+        // not a char boundary
+        assert_eq!(unescape("\\xé"), None);
+    }
+
+    #[test]
+    fn load_dictionary_formats() {
+        let path = std::env::temp_dir().join(format!("rudroid_dict_{}", std::process::id()));
+        fs::write(&path, "# comment\n\nmagic=\"MZ\"\n\"\\x7fELF\"\nempty=\"\"\n").unwrap();
+
+        let mut mutator = Mutator::new(1, 64);
+        let count = mutator.load_dictionary(path.to_str().unwrap()).unwrap();
+        assert_eq!(count, 2);
+        assert_eq!(mutator.dictionary, vec![b"MZ".to_vec(), b"\x7fELF".to_vec()]);
+
+        fs::write(&path, "broken\n").unwrap();
+        assert!(mutator.load_dictionary(path.to_str().unwrap()).is_err());
+        fs::write(&path, "\"\\x1\"\n").unwrap();
+        assert!(mutator.load_dictionary(path.to_str().unwrap()).is_err());
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn havoc_stays_within_max_len() {
+        for seed in 0..200 {
+            let mut mutator = Mutator::new(seed, 32);
+            mutator.dictionary.push(vec![0x41; 40]);
+            for len in 0..40 {
+                let mut data = vec![seed as u8; len];
+                mutator.havoc(&mut data);
+                assert!(data.len() <= 32);
+            }
+        }
+    }
+
+    #[test]
+    fn mutate_from_empty() {
+        let mut mutator = Mutator::new(7, 16);
+        for _ in 0..1000 {
+            let data = mutator.mutate(&[], &[]);
+            assert!(data.len() <= 16);
+        }
+    }
+
+    #[test]
+    fn splice_cut_points() {
+        let mut mutator = Mutator::new(3, 64);
+        let (a, b) = (vec![0xaa; 10], vec![0xbb; 6]);
+        for _ in 0..1000 {
+            let data = mutator.splice(&a, &b);
+            // a head of 1..=5 bytes of `a`, the rest from `b`
+            assert_eq!(data.len(), 6);
+            let cut = data.iter().position(|byte| *byte == 0xbb).unwrap();
+            assert!(cut >= 1 && cut < 6);
+            assert!(data[..cut].iter().all(|byte| *byte == 0xaa));
+            assert!(data[cut..].iter().all(|byte| *byte == 0xbb));
+        }
+
+        // too short to cut
+        assert_eq!(mutator.splice(&[1], &b), vec![1]);
+        assert_eq!(mutator.splice(&a, &[2]), a);
+    }
+
+    #[test]
+    fn add_token_dedups_and_caps() {
+        let mut mutator = Mutator::new(1, 64);
+        assert!(mutator.add_token(b"abc".to_vec()));
+        assert!(!mutator.add_token(b"abc".to_vec()));
+        assert!(!mutator.add_token(Vec::new()));
+        for n in 0..AUTO_TOKENS as u32 * 2 {
+            mutator.add_token(n.to_le_bytes().to_vec());
+        }
+        assert_eq!(mutator.dictionary.len(), AUTO_TOKENS);
+    }
+}
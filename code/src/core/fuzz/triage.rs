@@ -0,0 +1,118 @@
+use std::fs;
+use std::io;
+
+use crate::utilities;
+use crate::core::rudroid::Emulator;
+use crate::core::crash::triage::{Crash, Triage};
+
+use super::{FuzzOptions, FuzzTarget, RunResult};
+
+// executions spent shrinking one reproducer
+const MINIMIZE_RUNS     : usize = 1024;
+pub const REPORT_NAME   : &str = "triage.txt";
+
+impl<D> Emulator<D> {
+    /// Run `input` and classify the crash it ends in, None when it does not crash.
+    pub fn run_classified(&mut self, target: &FuzzTarget, input: &[u8], depth: usize) -> Option<Crash> {
+        match self.run_target(target, input) {
+            RunResult::Crashed(fault) => Some(self.classify_crash(&fault, depth)),
+            _ => None,
+        }
+    }
+
+    /// Shrink `input` while it still crashes into `bucket`, dropping blocks of halving size.
+    pub fn minimize_crash(&mut self, target: &FuzzTarget, input: &[u8], bucket: u64, depth: usize) -> Vec<u8> {
+        let mut best = input.to_vec();
+        let mut block = (best.len() / 2).max(1);
+        let mut runs = 0;
+
+        while !best.is_empty() && runs < MINIMIZE_RUNS {
+            let mut at = 0;
+            while at < best.len() && runs < MINIMIZE_RUNS {
+                let mut candidate = best[..at].to_vec();
+                candidate.extend_from_slice(&best[(at + block).min(best.len())..]);
+                runs += 1;
+
+                // the next block moved to `at` when this one could go
+                if self.run_classified(target, &candidate, depth).map(|crash| crash.bucket) == Some(bucket) {
+                    best = candidate;
+                }
+                else {
+                    at += block;
+                }
+            }
+
+            if block == 1 {
+                break;
+            }
+            block /= 2;
+        }
+
+        best
+    }
+
+    /// Write the reproducer of `bucket` into `dir` with a tombstone of it, and the report next
+    /// to it.
+    pub fn save_bucket(&mut self, triage: &mut Triage, target: &FuzzTarget, bucket: u64, dir: &str) -> io::Result<()> {
+        let (reproducer, kind) = match triage.bucket(bucket) {
+            Some(saved) => (saved.reproducer.clone(), saved.crash.kind),
+            None => return Ok(()),
+        };
+
+        let name = format!("{}/bucket_{:016x}_{}", dir, bucket, kind);
+        fs::write(&name, &reproducer)?;
+        // run once more for the state to report
+        if let RunResult::Crashed(fault) = self.run_target(target, &reproducer) {
+            fs::write(format!("{}.txt", name), self.tombstone(&fault))?;
+        }
+
+        triage.bucket_mut(bucket).unwrap().path = Some(name);
+        fs::write(format!("{}/{}", dir, REPORT_NAME), triage.report())
+    }
+
+    /// Run every input in `dir`, e.g. afl-fuzz's crashes, through the fuzz target and bucket
+    /// the crashes. Reproducers, their tombstones and the report go to `options.crashes`.
+    pub fn triage_crashes(&mut self, options: &FuzzOptions, dir: &str) -> io::Result<Triage> {
+        let address = self.resolve_target(&options.target)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, format!("fuzz target {} not found", options.target)))?;
+        fs::create_dir_all(&options.crashes)?;
+
+        let mut entries: Vec<_> = fs::read_dir(dir)?.filter_map(|entry| entry.ok()).map(|entry| entry.path()).collect();
+        entries.sort();
+
+        let mut target = self.fuzz_target(address, &options.args, options.max_len, options.timeout);
+        target.snapshot = Some(self.take_snapshot());
+
+        let mut triage = Triage::new(options.triage_frames);
+        let mut buckets = Vec::new();
+        for path in entries.iter().filter(|path| path.is_file()) {
+            // our own reports and reproducers, afl's README.txt
+            let name = path.file_name().unwrap().to_string_lossy();
+            if name.starts_with("bucket_") || name.ends_with(".txt") {
+                continue;
+            }
+
+            let input = fs::read(path)?;
+            match self.run_classified(&target, &input, options.triage_frames) {
+                Some(crash) => {
+                    if triage.add(&crash, &input) {
+                        buckets.push(crash.bucket);
+                    }
+                },
+                None => self.debug_print(format!("{} does not crash", path.display())),
+            }
+        }
+
+        // from the smallest input of each
+        for bucket in buckets {
+            let reproducer = triage.bucket(bucket).unwrap().reproducer.clone();
+            let reproducer = self.minimize_crash(&target, &reproducer, bucket, options.triage_frames);
+            triage.bucket_mut(bucket).unwrap().reproducer = reproducer;
+            self.save_bucket(&mut triage, &target, bucket, &options.crashes)?;
+        }
+        fs::write(format!("{}/{}", options.crashes, REPORT_NAME), triage.report())?;
+
+        utilities::log(&format!("{} crashes in {} buckets, report in {}/{}", triage.crashes, triage.buckets().len(), options.crashes, REPORT_NAME), utilities::DebugLevel::INFO);
+        Ok(triage)
+    }
+}
@@ -0,0 +1,426 @@
+pub mod stats;
+
+use std::collections::{BTreeMap, VecDeque};
+use std::fmt::Write;
+
+use super::crash::{self, Access, Fault};
+use super::mmu::MapInfo;
+use super::pid;
+use super::rudroid::Emulator;
+use super::unicorn::arch::arm64::RegisterARM64;
+use super::unicorn::unicorn_const::Protection;
+
+// address space handed out once and never reused, so stale pointers keep faulting
+const HEAP_BASE         : u64 = 0x7200_0000_0000;
+const HEAP_SIZE         : u64 = 0x100_0000_0000;
+const PAGE_SIZE         : u64 = 0x1000;
+const MIN_ALIGN         : u64 = 16;
+// freed bytes kept inaccessible before their pages are unmapped
+const QUARANTINE_SIZE   : u64 = 64 << 20;
+// fills the bytes around an allocation that no guard page covers, checked on free
+const REDZONE_BYTE      : u8 = 0xfa;
+const REDZONE_CHECK     : u64 = 16;
+const TRACE_FRAMES      : usize = 16;
+const HEAP_NAME         : &str = "[guarded heap]";
+
+const ENOMEM            : u64 = 12;
+const EINVAL            : u64 = 22;
+
+/// Memory errors the guarded heap catches.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum HeapErrorKind {
+    Overflow,
+    Underflow,
+    UseAfterFree,
+    DoubleFree,
+    // free of a pointer malloc never returned
+    InvalidFree,
+}
+
+impl HeapErrorKind {
+    /// ASan's name for it.
+    pub fn name(&self) -> &'static str {
+        match self {
+            HeapErrorKind::Overflow |
+            HeapErrorKind::Underflow    => "heap-buffer-overflow",
+            HeapErrorKind::UseAfterFree => "heap-use-after-free",
+            HeapErrorKind::DoubleFree   => "attempting double-free",
+            HeapErrorKind::InvalidFree  => "attempting free on address which was not malloc()-ed",
+        }
+    }
+}
+
+/// One allocation: pages `start..end` mapped, `size` bytes at `ptr` handed out and the page
+/// after `end` left unmapped.
+#[derive(Debug, Clone)]
+pub struct Chunk {
+    pub start       : u64,
+    pub end         : u64,
+    pub ptr         : u64,
+    pub size        : u64,
+    pub freed       : bool,
+    // return addresses of the malloc and free calls, innermost first
+    pub alloc_trace : Vec<u64>,
+    pub free_trace  : Vec<u64>,
+}
+
+#[derive(Debug, Clone)]
+pub struct HeapError {
+    pub kind    : HeapErrorKind,
+    pub address : u64,
+    pub access  : Access,
+    // bytes accessed, 0 when not known
+    pub size    : usize,
+    pub trace   : Vec<u64>,
+    pub chunk   : Option<Chunk>,
+}
+
+impl HeapError {
+    pub fn summary(&self) -> String {
+        match self.access {
+            Access::Read => format!("{}, READ of size {} at {:#x}", self.kind.name(), self.size, self.address),
+            Access::Write => format!("{}, WRITE of size {} at {:#x}", self.kind.name(), self.size, self.address),
+            _ => format!("{} on {:#x}", self.kind.name(), self.address),
+        }
+    }
+}
+
+/// Allocator state, restored with snapshots.
+#[derive(Debug, Clone)]
+pub struct GuardedHeap {
+    next                : u64,
+    chunks              : BTreeMap<u64, Chunk>,
+    quarantine          : VecDeque<u64>,
+    quarantine_bytes    : u64,
+    pub quarantine_size : u64,
+    // what stopped the emulation, for the tombstone and triage
+    pub last_error      : Option<HeapError>,
+    hooks               : Vec<usize>,
+}
+
+impl GuardedHeap {
+    /// The chunk `address` belongs to, its guard page included.
+    pub fn chunk_at(&self, address: u64) -> Option<&Chunk> {
+        self.chunks.range(..=address).next_back()
+            .map(|(_, chunk)| chunk)
+            .filter(|chunk| address < chunk.end + PAGE_SIZE)
+    }
+
+    pub fn contains(&self, address: u64) -> bool {
+        address >= HEAP_BASE && address < HEAP_BASE + HEAP_SIZE
+    }
+}
+
+impl<D> Emulator<D> {
+    /// Serve libc's malloc family from guarded chunks: each allocation ends at an unmapped page,
+    /// freed chunks stay inaccessible for a while and misuse is reported like ASan would.
+    /// Best enabled before the linker runs so every allocation goes through it.
+    pub fn enable_guarded_heap(&mut self) {
+        if self.heap.is_some() {
+            return;
+        }
+
+        let mut hooks = Vec::new();
+        hooks.push(self.replace_symbol("libc.so", "malloc", |emu, call| emu.heap_alloc(call.arg(0), MIN_ALIGN)));
+        hooks.push(self.replace_symbol("libc.so", "calloc", |emu, call| {
+            match call.arg(0).checked_mul(call.arg(1)) {
+                // fresh pages are zeroed already
+                Some(size) => emu.heap_alloc(size, MIN_ALIGN),
+                None => 0,
+            }
+        }));
+        hooks.push(self.replace_symbol("libc.so", "realloc", |emu, call| emu.heap_realloc(call.arg(0), call.arg(1))));
+        hooks.push(self.replace_symbol("libc.so", "free", |emu, call| {
+            emu.heap_free(call.arg(0));
+            0
+        }));
+        hooks.push(self.replace_symbol("libc.so", "memalign", |emu, call| emu.heap_alloc(call.arg(1), call.arg(0).max(MIN_ALIGN))));
+        hooks.push(self.replace_symbol("libc.so", "aligned_alloc", |emu, call| emu.heap_alloc(call.arg(1), call.arg(0).max(MIN_ALIGN))));
+        hooks.push(self.replace_symbol("libc.so", "posix_memalign", |emu, call| {
+            let (memptr, align, size) = (call.arg(0), call.arg(1), call.arg(2));
+            if !align.is_power_of_two() || align % 8 != 0 {
+                return EINVAL;
+            }
+            match emu.heap_alloc(size, align.max(MIN_ALIGN)) {
+                0 => ENOMEM,
+                ptr => {
+                    let bytes = emu.pack_64(ptr);
+                    emu.mem_write(memptr, &bytes).unwrap();
+                    0
+                },
+            }
+        }));
+        hooks.push(self.replace_symbol("libc.so", "malloc_usable_size", |emu, call| {
+            emu.heap.as_ref()
+                .and_then(|heap| heap.chunk_at(call.arg(0)))
+                .filter(|chunk| chunk.ptr == call.arg(0) && !chunk.freed)
+                .map(|chunk| chunk.size)
+                .unwrap_or(0)
+        }));
+
+        self.heap = Some(GuardedHeap {
+            next                : HEAP_BASE + PAGE_SIZE,
+            chunks              : BTreeMap::new(),
+            quarantine          : VecDeque::new(),
+            quarantine_bytes    : 0,
+            quarantine_size     : QUARANTINE_SIZE,
+            last_error          : None,
+            hooks               : hooks,
+        });
+    }
+
+    pub fn disable_guarded_heap(&mut self) {
+        if let Some(heap) = self.heap.take() {
+            for id in heap.hooks {
+                self.unhook_symbol(id);
+            }
+        }
+    }
+
+    fn heap_trace(&self) -> Vec<u64> {
+        self.backtrace(TRACE_FRAMES).iter().map(|frame| frame.pc).collect()
+    }
+
+    /// `size` bytes aligned to `align` and ending as close to the guard page as that allows,
+    /// 0 when the arena is used up, unicorn can not back the chunk or `align` is absurd.
+    pub fn heap_alloc(&mut self, size: u64, align: u64) -> u64 {
+        let next = match self.heap.as_ref() {
+            Some(heap) if size < HEAP_SIZE => heap.next,
+            _ => return 0,
+        };
+        let align = match align.checked_next_power_of_two() {
+            Some(align) if align < HEAP_SIZE => align,
+            _ => return 0,
+        };
+
+        // past a page the chunk starts at the aligned pointer instead, what is skipped stays unmapped
+        let start = (next + align.max(PAGE_SIZE) - 1) & !(align.max(PAGE_SIZE) - 1);
+        let pages = (size.max(1) + PAGE_SIZE - 1) & !(PAGE_SIZE - 1);
+        let ptr = (start + pages - size) & !(align - 1);
+
+        if start + pages + PAGE_SIZE > HEAP_BASE + HEAP_SIZE {
+            return 0;
+        }
+        if self.mem_map(start, pages as usize, Protection::READ | Protection::WRITE).is_err() {
+            return 0;
+        }
+        // one map per chunk, the arena itself is mostly unmapped guard pages
+        self.add_mapinfo(MapInfo {
+            memory_start    : start,
+            memory_end      : start + pages,
+            memory_perms    : Protection::READ | Protection::WRITE,
+            description     : String::from(HEAP_NAME),
+        });
+
+        // everything around the allocation the guard page does not catch
+        let redzone = vec![REDZONE_BYTE; (start + pages - (ptr + size)) as usize];
+        self.mem_write(ptr + size, &redzone).unwrap();
+        let before = (ptr - start).min(REDZONE_CHECK);
+        self.mem_write(ptr - before, &vec![REDZONE_BYTE; before as usize]).unwrap();
+
+        let trace = self.heap_trace();
+        // frame 0 is malloc itself
+        let caller = trace.iter().skip(1).cloned().collect();
+        self.with_alloc_stats(|stats| stats.record_alloc(ptr, size, caller));
+
+        let heap = self.heap.as_mut().unwrap();
+        heap.next = start + pages + PAGE_SIZE;
+        heap.chunks.insert(start, Chunk {
+            start       : start,
+            end         : start + pages,
+            ptr         : ptr,
+            size        : size,
+            freed       : false,
+            alloc_trace : trace,
+            free_trace  : Vec::new(),
+        });
+        ptr
+    }
+
+    /// Put the chunk at `ptr` into quarantine. Pointers from before the heap was enabled are
+    /// left alone, there is no one to give them back to.
+    pub fn heap_free(&mut self, ptr: u64) {
+        let heap = match self.heap.as_ref() {
+            Some(heap) if ptr != 0 && heap.contains(ptr) => heap,
+            _ => return,
+        };
+
+        let chunk = match heap.chunk_at(ptr) {
+            Some(chunk) if chunk.ptr == ptr && chunk.freed => return self.heap_abort(HeapErrorKind::DoubleFree, ptr, Some(chunk.clone())),
+            Some(chunk) if chunk.ptr == ptr => chunk.clone(),
+            chunk => return self.heap_abort(HeapErrorKind::InvalidFree, ptr, chunk.cloned()),
+        };
+
+        // small overflows that stayed short of the guard page
+        let after = self.mem_read_as_vec(chunk.ptr + chunk.size, (chunk.end - chunk.ptr - chunk.size) as usize).unwrap();
+        if let Some(offset) = after.iter().position(|byte| *byte != REDZONE_BYTE) {
+            return self.heap_abort(HeapErrorKind::Overflow, chunk.ptr + chunk.size + offset as u64, Some(chunk));
+        }
+        let before = (chunk.ptr - chunk.start).min(REDZONE_CHECK);
+        let under = self.mem_read_as_vec(chunk.ptr - before, before as usize).unwrap();
+        if let Some(offset) = under.iter().position(|byte| *byte != REDZONE_BYTE) {
+            return self.heap_abort(HeapErrorKind::Underflow, chunk.ptr - before + offset as u64, Some(chunk));
+        }
+
+        self.mem_protect(chunk.start, (chunk.end - chunk.start) as usize, Protection::NONE).unwrap();
+        if let Some(map_info) = self.map_infos.get_mut(&chunk.start) {
+            map_info.memory_perms = Protection::NONE;
+        }
+        self.with_alloc_stats(|stats| stats.record_free(ptr));
+
+        let trace = self.heap_trace();
+        let heap = self.heap.as_mut().unwrap();
+        let saved = heap.chunks.get_mut(&chunk.start).unwrap();
+        saved.freed = true;
+        saved.free_trace = trace;
+        heap.quarantine.push_back(chunk.start);
+        heap.quarantine_bytes += chunk.end - chunk.start;
+
+        // the oldest freed chunks go for good, accesses to them are plain wild ones from now on
+        let mut evicted = Vec::new();
+        while heap.quarantine_bytes > heap.quarantine_size {
+            let start = match heap.quarantine.pop_front() {
+                Some(start) => start,
+                None => break,
+            };
+            let old = heap.chunks.remove(&start).unwrap();
+            heap.quarantine_bytes -= old.end - old.start;
+            evicted.push(old);
+        }
+        for old in evicted {
+            self.map_infos.remove(&old.start);
+            self.mem_unmap(old.start, (old.end - old.start) as usize).unwrap();
+        }
+    }
+
+    pub fn heap_realloc(&mut self, ptr: u64, size: u64) -> u64 {
+        if ptr == 0 {
+            return self.heap_alloc(size, MIN_ALIGN);
+        }
+        if size == 0 {
+            self.heap_free(ptr);
+            return 0;
+        }
+
+        let old = match self.heap.as_ref().and_then(|heap| heap.chunk_at(ptr)) {
+            Some(chunk) if chunk.ptr == ptr && chunk.freed => {
+                let chunk = chunk.clone();
+                self.heap_abort(HeapErrorKind::UseAfterFree, ptr, Some(chunk));
+                return 0;
+            },
+            Some(chunk) if chunk.ptr == ptr => chunk.size,
+            // not ours, copy what is there up to the end of its page
+            _ => size.min(PAGE_SIZE - (ptr & (PAGE_SIZE - 1))),
+        };
+
+        let new = self.heap_alloc(size, MIN_ALIGN);
+        if new == 0 {
+            return 0;
+        }
+        if let Ok(data) = self.mem_read_as_vec(ptr, old.min(size) as usize) {
+            self.mem_write(new, &data).unwrap();
+        }
+        self.heap_free(ptr);
+        new
+    }
+
+    /// Stop with the misuse of `address` found in free or realloc.
+    fn heap_abort(&mut self, kind: HeapErrorKind, address: u64, chunk: Option<Chunk>) {
+        let error = HeapError {
+            kind    : kind,
+            address : address,
+            access  : Access::None,
+            size    : 0,
+            trace   : self.heap_trace(),
+            chunk   : chunk,
+        };
+
+        let pc = self.reg_read(RegisterARM64::PC as i32).unwrap();
+        let mut fault = Fault::from_signal(crash::SIGABRT, pc);
+        fault.fault_addr = address;
+        fault.cause = error.summary();
+
+        self.heap.as_mut().unwrap().last_error = Some(error);
+        self.last_fault = Some(fault);
+        self.emu_stop().unwrap();
+    }
+
+    /// Tell what a faulting access to the heap hit, remembered as the heap's last error.
+    pub fn heap_fault(&mut self, fault: &Fault, size: usize) -> Option<HeapError> {
+        let heap = self.heap.as_ref()?;
+        if fault.access != Access::Read && fault.access != Access::Write || !heap.contains(fault.fault_addr) {
+            return None;
+        }
+
+        let chunk = heap.chunk_at(fault.fault_addr)?.clone();
+        let kind = if chunk.freed {
+            HeapErrorKind::UseAfterFree
+        }
+        else if fault.fault_addr < chunk.ptr {
+            HeapErrorKind::Underflow
+        }
+        else {
+            HeapErrorKind::Overflow
+        };
+
+        let error = HeapError {
+            kind    : kind,
+            address : fault.fault_addr,
+            access  : fault.access,
+            size    : size,
+            trace   : self.heap_trace(),
+            chunk   : Some(chunk),
+        };
+        self.heap.as_mut().unwrap().last_error = Some(error.clone());
+        Some(error)
+    }
+
+    /// ASan style description of `error`, with where the chunk was allocated and freed.
+    pub fn heap_report(&self, error: &HeapError) -> String {
+        let mut out = String::new();
+        let pc = error.trace.first().cloned().unwrap_or(0);
+        writeln!(out, "=={}==ERROR: {} on address {:#x} at pc {:#x}", pid, error.kind.name(), error.address, pc).unwrap();
+        match error.access {
+            Access::Read => writeln!(out, "READ of size {} at {:#x} thread T0", error.size, error.address).unwrap(),
+            Access::Write => writeln!(out, "WRITE of size {} at {:#x} thread T0", error.size, error.address).unwrap(),
+            _ => {},
+        }
+        self.heap_report_trace(&mut out, &error.trace);
+
+        let chunk = match &error.chunk {
+            Some(chunk) => chunk,
+            None => return out,
+        };
+
+        let end = chunk.ptr + chunk.size;
+        let location = if error.address < chunk.ptr {
+            format!("{} bytes before", chunk.ptr - error.address)
+        }
+        else if error.address >= end {
+            format!("{} bytes after", error.address - end)
+        }
+        else {
+            format!("{} bytes inside of", error.address - chunk.ptr)
+        };
+        writeln!(out).unwrap();
+        writeln!(out, "{:#x} is located {} {}-byte region [{:#x},{:#x})", error.address, location, chunk.size, chunk.ptr, end).unwrap();
+
+        if chunk.freed {
+            writeln!(out, "freed by thread T0 here:").unwrap();
+            self.heap_report_trace(&mut out, &chunk.free_trace);
+            writeln!(out).unwrap();
+            writeln!(out, "previously allocated by thread T0 here:").unwrap();
+        }
+        else {
+            writeln!(out, "allocated by thread T0 here:").unwrap();
+        }
+        self.heap_report_trace(&mut out, &chunk.alloc_trace);
+        out
+    }
+
+    fn heap_report_trace(&self, out: &mut String, trace: &[u64]) {
+        for (i, pc) in trace.iter().enumerate() {
+            writeln!(out, "    #{} {:#x} in {}", i, pc, self.symbolize(*pc)).unwrap();
+        }
+    }
+}
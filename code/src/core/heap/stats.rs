@@ -0,0 +1,321 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Write;
+use std::fs;
+
+use crate::core::rudroid::Emulator;
+
+// frames kept per allocation, the first CALL_SITE_FRAMES of them group the leaks
+const TRACE_FRAMES      : usize = 8;
+const CALL_SITE_FRAMES  : usize = 4;
+
+/// An allocation the guest has not freed yet.
+#[derive(Debug, Clone)]
+pub struct Allocation {
+    pub size    : u64,
+    // return address into the caller first
+    pub trace   : Vec<u64>,
+}
+
+/// Outstanding allocations sharing the same top frames.
+#[derive(Debug, Clone)]
+pub struct CallSite {
+    pub trace   : Vec<u64>,
+    pub count   : u64,
+    pub bytes   : u64,
+}
+
+/// Allocations seen through libc's malloc family.
+pub struct AllocStats {
+    pub live            : HashMap<u64, Allocation>,
+    pub live_bytes      : u64,
+    pub peak_bytes      : u64,
+    pub allocations     : u64,
+    pub frees           : u64,
+    // power of two size class -> allocations
+    pub size_classes    : BTreeMap<u64, u64>,
+    // JSON report instead of the text one
+    pub output          : Option<String>,
+    hooks               : Vec<usize>,
+}
+
+impl AllocStats {
+    pub fn record_alloc(&mut self, ptr: u64, size: u64, trace: Vec<u64>) {
+        if ptr == 0 {
+            return;
+        }
+
+        self.allocations += 1;
+        *self.size_classes.entry(size_class(size)).or_insert(0) += 1;
+
+        if let Some(old) = self.live.insert(ptr, Allocation { size: size, trace: trace }) {
+            // freed behind our back, e.g. by code calling the allocator directly
+            self.live_bytes -= old.size;
+        }
+        self.live_bytes += size;
+        self.peak_bytes = self.peak_bytes.max(self.live_bytes);
+    }
+
+    pub fn record_free(&mut self, ptr: u64) {
+        if let Some(old) = self.live.remove(&ptr) {
+            self.frees += 1;
+            self.live_bytes -= old.size;
+        }
+    }
+
+    /// Outstanding allocations grouped by call site, most bytes first.
+    pub fn call_sites(&self) -> Vec<CallSite> {
+        let mut sites: HashMap<&[u64], CallSite> = HashMap::new();
+        for allocation in self.live.values() {
+            let key = &allocation.trace[..allocation.trace.len().min(CALL_SITE_FRAMES)];
+            let site = sites.entry(key).or_insert_with(|| CallSite {
+                trace   : key.to_vec(),
+                count   : 0,
+                bytes   : 0,
+            });
+            site.count += 1;
+            site.bytes += allocation.size;
+        }
+
+        let mut sites: Vec<CallSite> = sites.into_iter().map(|(_, site)| site).collect();
+        sites.sort_by(|a, b| b.bytes.cmp(&a.bytes).then(b.count.cmp(&a.count)).then(a.trace.cmp(&b.trace)));
+        sites
+    }
+}
+
+/// The power of two a size rounds up to, 0 for empty allocations.
+fn size_class(size: u64) -> u64 {
+    if size == 0 { 0 } else { size.next_power_of_two() }
+}
+
+fn json_string(value: &str) -> String {
+    let mut out = String::from("\"");
+    for c in value.chars() {
+        match c {
+            '"'  => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+impl<D> Emulator<D> {
+    /// Track every allocation made through libc's allocator until the guest exits, then report
+    /// what was never freed, on stdout or as JSON into `output`. With the guarded heap on its
+    /// allocations are tracked directly.
+    pub fn start_alloc_stats(&mut self, output: Option<&str>) {
+        self.stop_alloc_stats();
+
+        let mut hooks = Vec::new();
+        if self.heap.is_none() {
+            hooks.push(self.hook_symbol("libc.so", "malloc", |_, _| {}, |emu, call| {
+                let trace = emu.caller_trace();
+                emu.with_alloc_stats(|stats| stats.record_alloc(call.retval, call.arg(0), trace));
+            }));
+            hooks.push(self.hook_symbol("libc.so", "calloc", |_, _| {}, |emu, call| {
+                let trace = emu.caller_trace();
+                let size = call.arg(0).wrapping_mul(call.arg(1));
+                emu.with_alloc_stats(|stats| stats.record_alloc(call.retval, size, trace));
+            }));
+            hooks.push(self.hook_symbol("libc.so", "realloc", |_, _| {}, |emu, call| {
+                // a failed realloc leaves the old block alone
+                if call.retval == 0 && call.arg(1) != 0 {
+                    return;
+                }
+                let trace = emu.caller_trace();
+                emu.with_alloc_stats(|stats| {
+                    stats.record_free(call.arg(0));
+                    stats.record_alloc(call.retval, call.arg(1), trace);
+                });
+            }));
+            hooks.push(self.hook_symbol_enter("libc.so", "free", |emu, call| {
+                emu.with_alloc_stats(|stats| stats.record_free(call.arg(0)));
+            }));
+            for symbol in &["memalign", "aligned_alloc"] {
+                hooks.push(self.hook_symbol("libc.so", symbol, |_, _| {}, |emu, call| {
+                    let trace = emu.caller_trace();
+                    emu.with_alloc_stats(|stats| stats.record_alloc(call.retval, call.arg(1), trace));
+                }));
+            }
+            hooks.push(self.hook_symbol("libc.so", "posix_memalign", |_, _| {}, |emu, call| {
+                if call.retval != 0 {
+                    return;
+                }
+                let ptr = emu.get_pointer_at(call.arg(0));
+                let trace = emu.caller_trace();
+                emu.with_alloc_stats(|stats| stats.record_alloc(ptr, call.arg(2), trace));
+            }));
+        }
+
+        self.alloc_stats = Some(AllocStats {
+            live            : HashMap::new(),
+            live_bytes      : 0,
+            peak_bytes      : 0,
+            allocations     : 0,
+            frees           : 0,
+            size_classes    : BTreeMap::new(),
+            output          : output.map(String::from),
+            hooks           : hooks,
+        });
+    }
+
+    /// Stop tracking and report, once.
+    pub fn stop_alloc_stats(&mut self) {
+        let stats = match self.alloc_stats.take() {
+            Some(stats) => stats,
+            None => return,
+        };
+        for id in stats.hooks.iter() {
+            self.unhook_symbol(*id);
+        }
+
+        match &stats.output {
+            Some(path) => {
+                if let Err(e) = fs::write(path, self.alloc_stats_json(&stats)) {
+                    self.debug_print(format!("failed to write allocation stats to {}: {}", path, e));
+                }
+            },
+            None => print!("{}", self.alloc_stats_report(&stats)),
+        }
+    }
+
+    pub(crate) fn with_alloc_stats<F: FnOnce(&mut AllocStats)>(&mut self, f: F) {
+        if let Some(stats) = self.alloc_stats.as_mut() {
+            f(stats);
+        }
+    }
+
+    /// Backtrace from a function's return address, i.e. at on_leave.
+    fn caller_trace(&self) -> Vec<u64> {
+        self.backtrace(TRACE_FRAMES).iter().map(|frame| frame.pc).collect()
+    }
+
+    pub fn alloc_stats_report(&self, stats: &AllocStats) -> String {
+        let mut out = String::new();
+        let sites = stats.call_sites();
+
+        writeln!(out, "allocations: {}, frees: {}, peak heap usage: {} bytes", stats.allocations, stats.frees, stats.peak_bytes).unwrap();
+        writeln!(out, "leaked: {} bytes in {} allocations from {} call sites", stats.live_bytes, stats.live.len(), sites.len()).unwrap();
+
+        for site in sites.iter() {
+            writeln!(out).unwrap();
+            writeln!(out, "{} bytes in {} allocations from:", site.bytes, site.count).unwrap();
+            for (i, pc) in site.trace.iter().enumerate() {
+                writeln!(out, "    #{} {:#x} in {}", i, pc, self.symbolize(*pc)).unwrap();
+            }
+        }
+
+        writeln!(out).unwrap();
+        writeln!(out, "allocations per size class:").unwrap();
+        for (class, count) in stats.size_classes.iter() {
+            writeln!(out, "    <= {:>10}: {}", class, count).unwrap();
+        }
+        out
+    }
+
+    pub fn alloc_stats_json(&self, stats: &AllocStats) -> String {
+        let mut out = String::new();
+        let frames = |trace: &[u64]| trace.iter()
+            // as strings, JSON numbers lose precision past 2^53
+            .map(|pc| format!("{{\"pc\": \"{:#x}\", \"symbol\": {}}}", pc, json_string(&self.symbolize(*pc))))
+            .collect::<Vec<_>>()
+            .join(", ");
+
+        writeln!(out, "{{").unwrap();
+        writeln!(out, "  \"allocations\": {},", stats.allocations).unwrap();
+        writeln!(out, "  \"frees\": {},", stats.frees).unwrap();
+        writeln!(out, "  \"peak_bytes\": {},", stats.peak_bytes).unwrap();
+        writeln!(out, "  \"leaked_bytes\": {},", stats.live_bytes).unwrap();
+        writeln!(out, "  \"leaked_allocations\": {},", stats.live.len()).unwrap();
+
+        let sites: Vec<String> = stats.call_sites().iter()
+            .map(|site| format!("    {{\"bytes\": {}, \"count\": {}, \"frames\": [{}]}}", site.bytes, site.count, frames(&site.trace)))
+            .collect();
+        writeln!(out, "  \"leaks\": [\n{}\n  ],", sites.join(",\n")).unwrap();
+
+        let classes: Vec<String> = stats.size_classes.iter()
+            .map(|(class, count)| format!("\"{}\": {}", class, count))
+            .collect();
+        writeln!(out, "  \"size_classes\": {{{}}}", classes.join(", ")).unwrap();
+        writeln!(out, "}}").unwrap();
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn stats() -> AllocStats {
+        AllocStats {
+            live            : HashMap::new(),
+            live_bytes      : 0,
+            peak_bytes      : 0,
+            allocations     : 0,
+            frees           : 0,
+            size_classes    : BTreeMap::new(),
+            output          : None,
+            hooks           : Vec::new(),
+        }
+    }
+
+    #[test]
+    fn size_classes() {
+        assert_eq!(size_class(0), 0);
+        assert_eq!(size_class(1), 1);
+        assert_eq!(size_class(16), 16);
+        assert_eq!(size_class(17), 32);
+        assert_eq!(size_class(4097), 8192);
+    }
+
+    #[test]
+    fn json_strings() {
+        assert_eq!(json_string("libc.so!malloc+0x1c"), "\"libc.so!malloc+0x1c\"");
+        assert_eq!(json_string("a\"b\\c"), "\"a\\\"b\\\\c\"");
+        assert_eq!(json_string("\n\u{1}"), "\"\\u000a\\u0001\"");
+        assert_eq!(json_string("é"), "\"é\"");
+    }
+
+    #[test]
+    fn alloc_and_free() {
+        let mut stats = stats();
+        stats.record_alloc(0x1000, 100, vec![1]);
+        stats.record_alloc(0x2000, 50, vec![1]);
+        stats.record_alloc(0, 10, vec![1]);
+        assert_eq!((stats.allocations, stats.live_bytes, stats.peak_bytes), (2, 150, 150));
+
+        stats.record_free(0x1000);
+        stats.record_free(0x1000);
+        stats.record_free(0x3000);
+        assert_eq!((stats.frees, stats.live_bytes, stats.peak_bytes), (1, 50, 150));
+
+        // reused without a free we saw
+        stats.record_alloc(0x2000, 20, vec![1]);
+        assert_eq!(stats.live_bytes, 20);
+        assert_eq!(stats.size_classes.get(&64), Some(&1));
+        assert_eq!(stats.size_classes.get(&128), Some(&1));
+    }
+
+    #[test]
+    fn call_sites_grouped_by_top_frames() {
+        let mut stats = stats();
+        // the same first four frames, called from different places
+        stats.record_alloc(0x1000, 10, vec![1, 2, 3, 4, 5]);
+        stats.record_alloc(0x2000, 10, vec![1, 2, 3, 4, 6]);
+        stats.record_alloc(0x3000, 100, vec![7, 8]);
+        stats.record_alloc(0x4000, 5, vec![9]);
+        stats.record_alloc(0x5000, 5, vec![10]);
+
+        let sites = stats.call_sites();
+        let summary: Vec<(Vec<u64>, u64, u64)> = sites.iter().map(|site| (site.trace.clone(), site.count, site.bytes)).collect();
+        assert_eq!(summary, vec![
+            (vec![7, 8], 1, 100),
+            (vec![1, 2, 3, 4], 2, 20),
+            // ties ordered by trace
+            (vec![9], 1, 5),
+            (vec![10], 1, 5),
+        ]);
+    }
+}
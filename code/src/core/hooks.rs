@@ -1,6 +1,7 @@
 use capstone::prelude::*;
 
 use super::android;
+use super::crash;
 use super::rudroid;
 use super::unicorn::arch::arm64;
 use crate::utilities;
@@ -12,17 +13,28 @@ pub fn add_hooks(emu: &mut rudroid::Emulator<i64>) {
     
     emu.add_mem_hook(unicorn_const::HookType::MEM_FETCH_UNMAPPED, 1, 0, callback_mem_error).unwrap();
     emu.add_mem_hook(unicorn_const::HookType::MEM_READ_UNMAPPED, 1, 0, callback_mem_error).unwrap();
+    emu.add_mem_hook(unicorn_const::HookType::MEM_WRITE_UNMAPPED, 1, 0, callback_mem_error).unwrap();
+    emu.add_mem_hook(unicorn_const::HookType::MEM_PROT, 1, 0, callback_mem_error).unwrap();
 }
 
 // hooks
 pub fn callback(uc: &mut rudroid::Emulator<i64>, address: u64, size: u32) {
     // dump_context(uc, address, size as usize);
-    println!("addr: {:x}", address);
+    println!("addr: {}", uc.symbolize(address));
 }
 
 pub fn callback_mem_error(uc: &mut rudroid::Emulator<i64>, memtype: unicorn_const::MemType, address: u64, size: usize, value: i64) {
-    println!("callback_mem_error {:x}", address);
-    dump_context(uc, address, size);
+    // remembered for the tombstone written once emu_start returns
+    let mut fault = crash::Fault::from_mem_type(memtype, address);
+    if let Some(error) = uc.heap_fault(&fault, size) {
+        fault.cause = error.summary();
+    }
+    uc.last_fault = Some(fault);
+
+    if uc.debug {
+        println!("callback_mem_error {:?} {:x}", memtype, address);
+        dump_context(uc, address, size);
+    }
 }
 
 pub fn callback_mem_rw(uc: &mut rudroid::Emulator<i64>, memtype: unicorn_const::MemType, address: u64, size: usize, value: i64) {
@@ -0,0 +1,392 @@
+use std::collections::HashMap;
+
+use super::modules::Module;
+use super::rudroid::Emulator;
+use super::unicorn::ffi;
+use super::unicorn::arch::arm64::RegisterARM64;
+
+pub type InvocationCallback<D> = Box<dyn FnMut(&mut Emulator<D>, &mut Invocation)>;
+pub type ReplacementCallback<D> = Box<dyn FnMut(&mut Emulator<D>, &mut Invocation) -> u64>;
+
+/// One call of a hooked function, handed to `on_enter` and, for the same call, `on_leave`.
+#[derive(Debug, Clone)]
+pub struct Invocation {
+    pub module      : String,
+    pub symbol      : String,
+    pub function    : u64,
+    // return address, the instruction after the call
+    pub caller      : u64,
+    pub sp          : u64,
+    // x0-x7 on entry
+    pub args        : [u64; 8],
+    // x0 when the function returned, valid in on_leave
+    pub retval      : u64,
+}
+
+impl Invocation {
+    fn new<D>(emu: &Emulator<D>, module: &str, symbol: &str, function: u64) -> Invocation {
+        let mut args = [0u64; 8];
+        for (n, arg) in args.iter_mut().enumerate() {
+            *arg = emu.reg_read(RegisterARM64::x(n)).unwrap();
+        }
+
+        Invocation {
+            module      : String::from(module),
+            symbol      : String::from(symbol),
+            function    : function,
+            caller      : emu.reg_read(RegisterARM64::LR as i32).unwrap(),
+            sp          : emu.reg_read(RegisterARM64::SP as i32).unwrap(),
+            args        : args,
+            retval      : 0,
+        }
+    }
+
+    pub fn arg(&self, n: usize) -> u64 {
+        self.args[n]
+    }
+
+    pub fn arg_i32(&self, n: usize) -> i32 {
+        self.args[n] as i32
+    }
+
+    pub fn arg_string<D>(&self, emu: &Emulator<D>, n: usize) -> Option<String> {
+        emu.read_c_string(self.args[n], 0x1000)
+    }
+
+    /// Change an argument before the function body runs, only meaningful in on_enter.
+    pub fn set_arg<D>(&mut self, emu: &mut Emulator<D>, n: usize, value: u64) {
+        self.args[n] = value;
+        emu.reg_write(RegisterARM64::x(n), value).unwrap();
+    }
+
+    /// Change what the caller gets back, only meaningful in on_leave.
+    pub fn set_retval<D>(&mut self, emu: &mut Emulator<D>, value: u64) {
+        self.retval = value;
+        emu.reg_write(RegisterARM64::X0 as i32, value).unwrap();
+    }
+}
+
+struct Listener<D> {
+    module          : String,
+    symbol          : String,
+    on_enter        : Option<InvocationCallback<D>>,
+    on_leave        : Option<InvocationCallback<D>>,
+    // runs instead of the guest function, its result goes to x0
+    replacement     : Option<ReplacementCallback<D>>,
+    // (module base, entry hook) for every loaded module the symbol was found in
+    hooks           : Vec<(u64, ffi::uc_hook)>,
+    module_callbacks: Vec<usize>,
+}
+
+/// Calls waiting for their on_leave, kept with snapshots.
+#[derive(Debug, Clone)]
+pub struct InterceptorState {
+    pending         : Vec<(usize, Invocation)>,
+}
+
+impl InterceptorState {
+    /// No call in flight.
+    pub fn new() -> InterceptorState {
+        InterceptorState {
+            pending         : Vec::new(),
+        }
+    }
+}
+
+/// Function hooks and replacements by symbol name, in the spirit of Frida's Interceptor.
+///
+/// on_enter runs at the first instruction of the function. on_leave runs when execution reaches
+/// the return address again with the stack pointer the call was made with, so recursive and
+/// nested calls are told apart. A replacement runs at the first instruction as well and returns
+/// straight to the caller, the guest function never executes.
+pub struct Interceptor<D> {
+    next_id         : usize,
+    listeners       : HashMap<usize, Listener<D>>,
+    // calls waiting for their on_leave, innermost last
+    pending         : Vec<(usize, Invocation)>,
+    // return address -> (hook, calls pending on it)
+    return_hooks    : HashMap<u64, (ffi::uc_hook, usize)>,
+}
+
+impl<D> Interceptor<D> {
+    pub fn new() -> Interceptor<D> {
+        Interceptor {
+            next_id         : 0,
+            listeners       : HashMap::new(),
+            pending         : Vec::new(),
+            return_hooks    : HashMap::new(),
+        }
+    }
+}
+
+impl<D> Emulator<D> {
+    /// Hook `symbol` exported by `module` (`libc.so` or a path suffix), now if the module is
+    /// loaded or as soon as it is. Returns an id for `unhook_symbol`.
+    pub fn hook_symbol<E, L>(&mut self, module: &str, symbol: &str, on_enter: E, on_leave: L) -> usize
+        where E: FnMut(&mut Emulator<D>, &mut Invocation) + 'static,
+              L: FnMut(&mut Emulator<D>, &mut Invocation) + 'static
+    {
+        self.add_listener(module, symbol, Some(Box::new(on_enter)), Some(Box::new(on_leave)), None)
+    }
+
+    /// Like `hook_symbol` without on_leave, no return address hook is installed.
+    pub fn hook_symbol_enter<E>(&mut self, module: &str, symbol: &str, on_enter: E) -> usize
+        where E: FnMut(&mut Emulator<D>, &mut Invocation) + 'static
+    {
+        self.add_listener(module, symbol, Some(Box::new(on_enter)), None, None)
+    }
+
+    /// Detour `symbol` exported by `module` to `replacement`: when the function is called the
+    /// closure runs instead, its result is returned in x0 and execution resumes at lr. Pending
+    /// until the module is loaded, undone with `unhook_symbol`.
+    pub fn replace_symbol<R>(&mut self, module: &str, symbol: &str, replacement: R) -> usize
+        where R: FnMut(&mut Emulator<D>, &mut Invocation) -> u64 + 'static
+    {
+        self.add_listener(module, symbol, None, None, Some(Box::new(replacement)))
+    }
+
+    /// Hook the function at `address`, for code without symbols. The hook goes away with the
+    /// module `address` is in.
+    pub fn hook_address<E, L>(&mut self, address: u64, on_enter: E, on_leave: L) -> usize
+        where E: FnMut(&mut Emulator<D>, &mut Invocation) + 'static,
+              L: FnMut(&mut Emulator<D>, &mut Invocation) + 'static
+    {
+        self.add_address_listener(address, Some(Box::new(on_enter)), Some(Box::new(on_leave)), None)
+    }
+
+    /// Detour the function at `address`, for code without symbols. Undone when the module
+    /// `address` is in is unloaded.
+    pub fn replace_address<R>(&mut self, address: u64, replacement: R) -> usize
+        where R: FnMut(&mut Emulator<D>, &mut Invocation) -> u64 + 'static
+    {
+        self.add_address_listener(address, None, None, Some(Box::new(replacement)))
+    }
+
+    fn add_address_listener(&mut self, address: u64, on_enter: Option<InvocationCallback<D>>, on_leave: Option<InvocationCallback<D>>,
+                            replacement: Option<ReplacementCallback<D>>) -> usize {
+        let id = self.interceptor.next_id;
+        self.interceptor.next_id += 1;
+
+        let module = self.module_at(address).map(|module| (module.name.clone(), module.base));
+        let (name, base) = module.unwrap_or((String::new(), 0));
+
+        let hook = self.add_code_hook(address, address, move |emu: &mut Emulator<D>, address: u64, _size: u32| {
+            emu.enter_listener(id, address);
+        }).expect("failed to add address hook");
+
+        self.interceptor.listeners.insert(id, Listener {
+            module          : name,
+            symbol          : format!("{:#x}", address),
+            on_enter        : on_enter,
+            on_leave        : on_leave,
+            replacement     : replacement,
+            hooks           : vec![(base, hook)],
+            module_callbacks: Vec::new(),
+        });
+
+        // whatever gets mapped there next is not the function that was hooked, base is 0 when
+        // the address is in no module
+        if base != 0 {
+            let unload = self.on_module_unload(move |emu: &mut Emulator<D>, module: &Module| {
+                if module.base == base {
+                    emu.unhook_symbol(id);
+                }
+            });
+            self.interceptor.listeners.get_mut(&id).unwrap().module_callbacks = vec![unload];
+        }
+        id
+    }
+
+    fn add_listener(&mut self, module: &str, symbol: &str, on_enter: Option<InvocationCallback<D>>, on_leave: Option<InvocationCallback<D>>,
+                    replacement: Option<ReplacementCallback<D>>) -> usize {
+        let id = self.interceptor.next_id;
+        self.interceptor.next_id += 1;
+
+        self.interceptor.listeners.insert(id, Listener {
+            module          : String::from(module),
+            symbol          : String::from(symbol),
+            on_enter        : on_enter,
+            on_leave        : on_leave,
+            replacement     : replacement,
+            hooks           : Vec::new(),
+            module_callbacks: Vec::new(),
+        });
+
+        let load = self.on_module_load(move |emu: &mut Emulator<D>, module: &Module| emu.attach_listener(id, module));
+        let unload = self.on_module_unload(move |emu: &mut Emulator<D>, module: &Module| emu.detach_listener(id, module.base));
+
+        if let Some(listener) = self.interceptor.listeners.get_mut(&id) {
+            listener.module_callbacks = vec![load, unload];
+        }
+        id
+    }
+
+    /// Remove a hook or replacement. Calls already entered get no on_leave.
+    pub fn unhook_symbol(&mut self, id: usize) {
+        let listener = match self.interceptor.listeners.remove(&id) {
+            Some(listener) => listener,
+            None => return,
+        };
+
+        for callback in listener.module_callbacks {
+            self.remove_module_callback(callback);
+        }
+        for (_, hook) in listener.hooks {
+            self.remove_hook(hook).unwrap();
+        }
+
+        let pending: Vec<u64> = self.interceptor.pending.iter()
+            .filter(|(listener, _)| *listener == id)
+            .map(|(_, invocation)| invocation.caller)
+            .collect();
+        self.interceptor.pending.retain(|(listener, _)| *listener != id);
+        for caller in pending {
+            self.release_return_hook(caller);
+        }
+    }
+
+    pub fn save_interceptor_state(&self) -> InterceptorState {
+        InterceptorState {
+            pending         : self.interceptor.pending.clone(),
+        }
+    }
+
+    /// Go back to the calls pending in `state`. Calls entered since never see their on_leave
+    /// and their return hooks go, so a later call from the same place is not mistaken for one.
+    pub fn restore_interceptor_state(&mut self, state: &InterceptorState) {
+        let stale: Vec<u64> = self.interceptor.pending.drain(..).map(|(_, invocation)| invocation.caller).collect();
+        for caller in stale {
+            self.release_return_hook(caller);
+        }
+
+        for (id, invocation) in state.pending.iter() {
+            // unhooked since the snapshot
+            if !self.interceptor.listeners.contains_key(id) {
+                continue;
+            }
+            self.hold_return_hook(invocation.caller);
+            self.interceptor.pending.push((*id, invocation.clone()));
+        }
+    }
+
+    fn attach_listener(&mut self, id: usize, module: &Module) {
+        let symbol = match self.interceptor.listeners.get(&id) {
+            Some(listener) if module.matches(&listener.module) => listener.symbol.clone(),
+            _ => return,
+        };
+
+        let address = match self.module_symbol(module, &symbol) {
+            Some(address) => address,
+            None => {
+                self.debug_print(format!("hook_symbol: {} not found in {}", symbol, module.path));
+                return;
+            }
+        };
+
+        let hook = self.add_code_hook(address, address, move |emu: &mut Emulator<D>, address: u64, _size: u32| {
+            emu.enter_listener(id, address);
+        }).expect("failed to add symbol hook");
+
+        self.debug_print(format!("hooked {}!{} at {:#x}", module.name, symbol, address));
+        self.interceptor.listeners.get_mut(&id).unwrap().hooks.push((module.base, hook));
+    }
+
+    fn detach_listener(&mut self, id: usize, base: u64) {
+        let hooks = match self.interceptor.listeners.get_mut(&id) {
+            Some(listener) => {
+                let (gone, kept) = listener.hooks.drain(..).partition(|(module_base, _)| *module_base == base);
+                listener.hooks = kept;
+                gone
+            },
+            None => Vec::new(),
+        };
+
+        for (_, hook) in hooks {
+            self.remove_hook(hook).unwrap();
+        }
+    }
+
+    fn enter_listener(&mut self, id: usize, address: u64) {
+        let (module, symbol, on_enter, replacement, has_leave) = match self.interceptor.listeners.get_mut(&id) {
+            Some(listener) => (listener.module.clone(), listener.symbol.clone(), listener.on_enter.take(), listener.replacement.take(), listener.on_leave.is_some()),
+            None => return,
+        };
+
+        let mut invocation = Invocation::new(self, &module, &symbol, address);
+
+        if let Some(mut replacement) = replacement {
+            invocation.retval = replacement(self, &mut invocation);
+            if let Some(listener) = self.interceptor.listeners.get_mut(&id) {
+                listener.replacement = Some(replacement);
+            }
+
+            self.reg_write(RegisterARM64::X0 as i32, invocation.retval).unwrap();
+            self.reg_write(RegisterARM64::PC as i32, invocation.caller).unwrap();
+            return;
+        }
+
+        if let Some(mut on_enter) = on_enter {
+            on_enter(self, &mut invocation);
+            // the callback may have unhooked itself
+            if let Some(listener) = self.interceptor.listeners.get_mut(&id) {
+                listener.on_enter = Some(on_enter);
+            }
+        }
+
+        if has_leave && self.interceptor.listeners.contains_key(&id) {
+            self.hold_return_hook(invocation.caller);
+            self.interceptor.pending.push((id, invocation));
+        }
+    }
+
+    fn leave_listeners(&mut self, address: u64) {
+        let sp = self.reg_read(RegisterARM64::SP as i32).unwrap();
+
+        // innermost first, one call may be pending for several listeners
+        while let Some(index) = self.interceptor.pending.iter().rposition(|(_, invocation)| invocation.caller == address && invocation.sp == sp) {
+            let (id, mut invocation) = self.interceptor.pending.remove(index);
+            self.release_return_hook(address);
+
+            invocation.retval = self.reg_read(RegisterARM64::X0 as i32).unwrap();
+
+            let on_leave = match self.interceptor.listeners.get_mut(&id) {
+                Some(listener) => listener.on_leave.take(),
+                None => None,
+            };
+
+            if let Some(mut on_leave) = on_leave {
+                on_leave(self, &mut invocation);
+                if let Some(listener) = self.interceptor.listeners.get_mut(&id) {
+                    listener.on_leave = Some(on_leave);
+                }
+            }
+        }
+    }
+
+    fn hold_return_hook(&mut self, address: u64) {
+        if let Some((_, count)) = self.interceptor.return_hooks.get_mut(&address) {
+            *count += 1;
+            return;
+        }
+
+        let hook = self.add_code_hook(address, address, |emu: &mut Emulator<D>, address: u64, _size: u32| {
+            emu.leave_listeners(address);
+        }).expect("failed to add return hook");
+        self.interceptor.return_hooks.insert(address, (hook, 1));
+    }
+
+    fn release_return_hook(&mut self, address: u64) {
+        let remove = match self.interceptor.return_hooks.get_mut(&address) {
+            Some((_, count)) => {
+                *count -= 1;
+                *count == 0
+            },
+            None => false,
+        };
+
+        if remove {
+            let (hook, _) = self.interceptor.return_hooks.remove(&address).unwrap();
+            self.remove_hook(hook).unwrap();
+        }
+    }
+}
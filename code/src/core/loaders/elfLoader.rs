@@ -153,6 +153,9 @@ impl<D> Emulator<D> {
             self.mmu_map( mem_e, (loaded_mem_end-mem_e) as usize, Protection::ALL, &desc, self.null_mut());
         }
 
+        let desc = self.elf_path.clone();
+        self.add_loaded_module(&desc, elf, load_address);
+
         self.elf_entry = elf.header.pt2.entry_point() + load_address;
         self.debug_print(format!("elf_entry {:x}", self.elf_entry));
 
@@ -213,6 +216,8 @@ impl<D> Emulator<D> {
                 };
             }
 
+            self.add_loaded_module(&interp_path, &interp_elf, interp_address);
+
             self.interp_address = interp_address;
             self.entry_point    = interp_elf.header.pt2.entry_point() + self.interp_address;
         }
@@ -323,7 +328,10 @@ impl<D> Emulator<D> {
         self.load_address = load_address;
     }
 
-    fn new_aux_ent(&self, key: u64, val: u64) -> Vec<u8> {
+    fn new_aux_ent(&mut self, key: u64, val: u64) -> Vec<u8> {
+        // kept around for NT_AUXV in core dumps
+        self.auxv.push((key, val));
+
         let mut aux: Vec<u8> = Vec::new();
         aux.extend_from_slice(&self.pack(key));
         aux.extend_from_slice(&self.pack(val));
@@ -7,9 +7,14 @@ use capstone::prelude::*;
 use keystone::keystone_const;
 use keystone::{Keystone, Arch as kArch};
 use super::super::unicorn::arch::arm64::RegisterARM64;
+use super::super::crash;
+use super::super::debugger;
 
 use capstone::prelude::*;
 
+// offset of the trampoline's final `ret`, where emulation stops once the callee returned
+pub const TRAMPOLINE_RETURN: u64 = 0x14;
+
 impl<D> rudroid::Emulator<D> {
     pub fn run_elf(&mut self) {
         utilities::context_title(Some("Emulating elf"));
@@ -30,31 +35,109 @@ impl<D> rudroid::Emulator<D> {
     }
 
     pub fn call_me(&mut self, func_addr: u64, emu_addr: u64) {
+        let res = self.try_call_me(func_addr, emu_addr, 0);
+        self.handle_emu_exception(res);
+    }
+
+    /// `call_me` handing the error back instead of reporting it. Stops after `timeout`
+    /// microseconds, 0 for none; the pc is `emu_addr + TRAMPOLINE_RETURN` when the call returned.
+    pub fn try_call_me(&mut self, func_addr: u64, emu_addr: u64, timeout: u64) -> Result<(), unicorn_const::uc_error> {
         //assumes arguments are already set
         self.reg_write(RegisterARM64::X12 as i32, func_addr); // function to emulate
         self.reg_write(RegisterARM64::LR as i32, 0);   //should return on 0
 
-        let res = self.emu_start(emu_addr, 0x14141415014, 0, 0);
-        self.handle_emu_exception(res);
+        self.emu_start(emu_addr, emu_addr + TRAMPOLINE_RETURN, timeout, 0)
+    }
+
+    /// The `fuzz_init` trampoline, created on first use.
+    pub fn trampoline(&mut self) -> u64 {
+        match self.call_trampoline {
+            Some(trampoline) => trampoline,
+            None => {
+                let trampoline = self.fuzz_init();
+                self.call_trampoline = Some(trampoline);
+                trampoline
+            }
+        }
+    }
+
+    /// Call the guest function at `address` on the current stack and return its x0. Arguments
+    /// past the eighth are passed on the stack.
+    pub fn call_function(&mut self, address: u64, args: &[u64]) -> u64 {
+        let trampoline = self.trampoline();
+
+        for (n, arg) in args.iter().take(8).enumerate() {
+            self.reg_write(RegisterARM64::x(n), *arg).unwrap();
+        }
+
+        let sp = self.reg_read(RegisterARM64::SP as i32).unwrap();
+        if args.len() > 8 {
+            // the trampoline pushes fp and lr before calling, the callee finds its stack
+            // arguments 16 bytes below the sp we start with
+            let stack_args = &args[8..];
+            let callee_sp = (sp - 0x100 - 8 * stack_args.len() as u64) & !0xf;
+            for (n, arg) in stack_args.iter().enumerate() {
+                self.mem_write(callee_sp + 8 * n as u64, &arg.to_le_bytes()).unwrap();
+            }
+            self.reg_write(RegisterARM64::SP as i32, callee_sp + 16).unwrap();
+        }
+
+        self.call_me(address, trampoline);
+
+        self.reg_write(RegisterARM64::SP as i32, sp).unwrap();
+        self.reg_read(RegisterARM64::X0 as i32).unwrap()
     }
 
     pub fn handle_emu_exception(&mut self, err: Result<(), unicorn_const::uc_error>) {
         // self.get_mapped();
 
-        match err.ok() {
-            Some(v) => {
+        match err {
+            // a signal sent to ourselves stops the emulation without an error
+            Ok(_) if self.last_fault.is_none() => {
 
             },
-            None => {
+            _ => {
+                let pc = self.reg_read(RegisterARM64::PC as i32).unwrap_or(0);
+
+                // the invalid memory hooks know the faulting address, unicorn's error does not
+                let fault = self.last_fault.take().unwrap_or_else(|| crash::Fault::from_uc_error(err.err().unwrap(), pc));
+
+                // let an attached debugger look at the faulting state first
+                self.debug_trap(debugger::StopReason::Signal(fault.clone()));
+
+                self.finish_traces();
                 self.display_mapped();
                 self.dump_context();
 
-                match err.err().unwrap() {
-                    unicorn_const::uc_error::FETCH_UNMAPPED => {
+                match self.write_tombstone(&fault) {
+                    Ok(path) => {
+                        utilities::log(&format!("{} ({}) at {:#x} in {}, tombstone written to {}", fault.signal_name(), fault.code_name(), fault.fault_addr, self.symbolize(pc), path), utilities::DebugLevel::ERROR);
+                    },
+                    Err(e) => {
+                        utilities::log(&format!("failed to write tombstone: {}", e), utilities::DebugLevel::ERROR);
+                    }
+                }
+
+                if self.core_dump_on_crash {
+                    match self.write_crash_core_dump(&fault) {
+                        Ok(path) => {
+                            utilities::log(&format!("core dump written to {}", path), utilities::DebugLevel::ERROR);
+                        },
+                        Err(e) => {
+                            utilities::log(&format!("failed to write core dump: {}", e), utilities::DebugLevel::ERROR);
+                        }
+                    }
+                }
+
+                match err {
+                    Err(unicorn_const::uc_error::FETCH_UNMAPPED) => {
                         panic!("- [handle_emu_exception] unicorn::unicorn_const::uc_error::FETCH_UNMAPPED");
                     },
-                    _ => {
-                        panic!("- [handle_emu_exception] {:?}", err);
+                    Err(error) => {
+                        panic!("- [handle_emu_exception] {:?}", error);
+                    },
+                    Ok(_) => {
+                        panic!("- [handle_emu_exception] {}", fault.signal_name());
                     }
                 }
             }
@@ -64,47 +147,47 @@ impl<D> rudroid::Emulator<D> {
     pub fn dump_context(&mut self) {
         utilities::draw_line();
     
-        let pc  = self.reg_read(RegisterARM64::PC  as i32).expect("failed to read r11"); 
+        let pc  = self.reg_read(RegisterARM64::PC  as i32).expect("failed to read PC"); 
         let sp  = self.reg_read(RegisterARM64::SP  as i32).expect("failed to read SP" );
         let lr  = self.reg_read(RegisterARM64::LR  as i32).expect("failed to read LR" );
-        let r0  = self.reg_read(RegisterARM64::X0  as i32).expect("failed to read r0" );
-        let r1  = self.reg_read(RegisterARM64::X1  as i32).expect("failed to read r1" );
-        let r2  = self.reg_read(RegisterARM64::X2  as i32).expect("failed to read r2" );
-        let r3  = self.reg_read(RegisterARM64::X3  as i32).expect("failed to read r3" );
-        let r4  = self.reg_read(RegisterARM64::X4  as i32).expect("failed to read r4" );
-        let r5  = self.reg_read(RegisterARM64::X5  as i32).expect("failed to read r5" );
-        let r6  = self.reg_read(RegisterARM64::X6  as i32).expect("failed to read r6" );
-        let r7  = self.reg_read(RegisterARM64::X7  as i32).expect("failed to read r7" );
-        let r8  = self.reg_read(RegisterARM64::X8  as i32).expect("failed to read r8" );
-        let r9  = self.reg_read(RegisterARM64::X9  as i32).expect("failed to read r9" );
-        let r10 = self.reg_read(RegisterARM64::X10 as i32).expect("failed to read r10");
-        let r11 = self.reg_read(RegisterARM64::X11 as i32).expect("failed to read r11");
-        let r12 = self.reg_read(RegisterARM64::X12 as i32).expect("failed to read r11");
-        let r13 = self.reg_read(RegisterARM64::X13 as i32).expect("failed to read r11");
-        let r14 = self.reg_read(RegisterARM64::X14 as i32).expect("failed to read r11");
-        let r15 = self.reg_read(RegisterARM64::X15 as i32).expect("failed to read r11");
-        let r18 = self.reg_read(RegisterARM64::X18 as i32).expect("failed to read r11");
-        let r19 = self.reg_read(RegisterARM64::X19 as i32).expect("failed to read r11");
-        let r20 = self.reg_read(RegisterARM64::X20 as i32).expect("failed to read r11");
-        let r21 = self.reg_read(RegisterARM64::X21 as i32).expect("failed to read r11");
-        let r22 = self.reg_read(RegisterARM64::X22 as i32).expect("failed to read r11");
-        let r23 = self.reg_read(RegisterARM64::X23 as i32).expect("failed to read r11");
-        let r24 = self.reg_read(RegisterARM64::X24 as i32).expect("failed to read r11");
-        let r25 = self.reg_read(RegisterARM64::X25 as i32).expect("failed to read r11");
-        let r26 = self.reg_read(RegisterARM64::X26 as i32).expect("failed to read r11");
-        let r27 = self.reg_read(RegisterARM64::X27 as i32).expect("failed to read r11");
-        let r28 = self.reg_read(RegisterARM64::X28 as i32).expect("failed to read r11");
+        let r0  = self.reg_read(RegisterARM64::X0  as i32).expect("failed to read x0" );
+        let r1  = self.reg_read(RegisterARM64::X1  as i32).expect("failed to read x1" );
+        let r2  = self.reg_read(RegisterARM64::X2  as i32).expect("failed to read x2" );
+        let r3  = self.reg_read(RegisterARM64::X3  as i32).expect("failed to read x3" );
+        let r4  = self.reg_read(RegisterARM64::X4  as i32).expect("failed to read x4" );
+        let r5  = self.reg_read(RegisterARM64::X5  as i32).expect("failed to read x5" );
+        let r6  = self.reg_read(RegisterARM64::X6  as i32).expect("failed to read x6" );
+        let r7  = self.reg_read(RegisterARM64::X7  as i32).expect("failed to read x7" );
+        let r8  = self.reg_read(RegisterARM64::X8  as i32).expect("failed to read x8" );
+        let r9  = self.reg_read(RegisterARM64::X9  as i32).expect("failed to read x9" );
+        let r10 = self.reg_read(RegisterARM64::X10 as i32).expect("failed to read x10");
+        let r11 = self.reg_read(RegisterARM64::X11 as i32).expect("failed to read x11");
+        let r12 = self.reg_read(RegisterARM64::X12 as i32).expect("failed to read x12");
+        let r13 = self.reg_read(RegisterARM64::X13 as i32).expect("failed to read x13");
+        let r14 = self.reg_read(RegisterARM64::X14 as i32).expect("failed to read x14");
+        let r15 = self.reg_read(RegisterARM64::X15 as i32).expect("failed to read x15");
+        let r18 = self.reg_read(RegisterARM64::X18 as i32).expect("failed to read x18");
+        let r19 = self.reg_read(RegisterARM64::X19 as i32).expect("failed to read x19");
+        let r20 = self.reg_read(RegisterARM64::X20 as i32).expect("failed to read x20");
+        let r21 = self.reg_read(RegisterARM64::X21 as i32).expect("failed to read x21");
+        let r22 = self.reg_read(RegisterARM64::X22 as i32).expect("failed to read x22");
+        let r23 = self.reg_read(RegisterARM64::X23 as i32).expect("failed to read x23");
+        let r24 = self.reg_read(RegisterARM64::X24 as i32).expect("failed to read x24");
+        let r25 = self.reg_read(RegisterARM64::X25 as i32).expect("failed to read x25");
+        let r26 = self.reg_read(RegisterARM64::X26 as i32).expect("failed to read x26");
+        let r27 = self.reg_read(RegisterARM64::X27 as i32).expect("failed to read x27");
+        let r28 = self.reg_read(RegisterARM64::X28 as i32).expect("failed to read x28");
         
-        let cpacr_el1 = self.reg_read(RegisterARM64::CPACR_EL1 as i32).expect("failed to read r11"); 
+        let cpacr_el1 = self.reg_read(RegisterARM64::CPACR_EL1 as i32).expect("failed to read CPACR_EL1"); 
         utilities::draw_line();
     
-        println!("$r0 : {:#016x}   $r1 : {:#016x}    $r2: {:#016x}    $r3: {:#016x}", r0, r1, r2, r3);
-        println!("$r4 : {:#016x}   $r5 : {:#016x}    $r6: {:#016x}    $r7: {:#016x}", r4, r5, r6, r7);
-        println!("$r8 : {:#016x}   $r9 : {:#016x}   $r10: {:#016x}   $r11: {:#016x}", r8, r9, r10, r11);
-        println!("$r12: {:#016x}   $r13: {:#016x}   $r14: {:#016x}   $r15: {:#016x}", r12, r13, r14, r15);
-        println!("$r18: {:#016x}   $r19: {:#016x}   $r20: {:#016x}   $r21: {:#016x}", r18, r19, r20, r21);
-        println!("$r22: {:#016x}   $r23: {:#016x}   $r24: {:#016x}   $r25: {:#016x}", r22, r23, r24, r25);
-        println!("$r26: {:#016x}   $r27: {:#016x}   $r28: {:#016x}   ", r26, r27, r28);
+        println!("$x0 : {:#016x}   $x1 : {:#016x}    $x2: {:#016x}    $x3: {:#016x}", r0, r1, r2, r3);
+        println!("$x4 : {:#016x}   $x5 : {:#016x}    $x6: {:#016x}    $x7: {:#016x}", r4, r5, r6, r7);
+        println!("$x8 : {:#016x}   $x9 : {:#016x}   $x10: {:#016x}   $x11: {:#016x}", r8, r9, r10, r11);
+        println!("$x12: {:#016x}   $x13: {:#016x}   $x14: {:#016x}   $x15: {:#016x}", r12, r13, r14, r15);
+        println!("$x18: {:#016x}   $x19: {:#016x}   $x20: {:#016x}   $x21: {:#016x}", r18, r19, r20, r21);
+        println!("$x22: {:#016x}   $x23: {:#016x}   $x24: {:#016x}   $x25: {:#016x}", r22, r23, r24, r25);
+        println!("$x26: {:#016x}   $x27: {:#016x}   $x28: {:#016x}   ", r26, r27, r28);
         println!("$sp : {:#016x}   $lr : {:#016x}    $pc: {:#016x}", sp, lr, pc);
         println!("$cpacr_el1: {:#016x} \n", cpacr_el1);
         
@@ -147,6 +147,56 @@ impl<D> Emulator<D> {
         mappings
     }
 
+    /// Returns the innermost mapping containing `address`.
+    ///
+    /// File backed mmaps are recorded on top of the `[syscall_mmap]` region they are placed in,
+    /// so the mapping with the highest start address wins.
+    pub fn find_mapping(&self, address: u64) -> Option<&MapInfo> {
+        self.map_infos.values()
+            .filter(|map_info| address >= map_info.memory_start && address < map_info.memory_end)
+            .max_by_key(|map_info| map_info.memory_start)
+    }
+
+    /// Lowest address mapped with `description`, i.e. the load base of a module.
+    pub fn module_base(&self, description: &str) -> u64 {
+        self.map_infos.values()
+            .filter(|map_info| map_info.description == description)
+            .map(|map_info| map_info.memory_start)
+            .min()
+            .unwrap_or(0)
+    }
+
+    /// Sorted copy of the memory map, as shown in `/proc/self/maps`.
+    pub fn sorted_mappings(&self) -> Vec<MapInfo> {
+        let mut mappings: Vec<MapInfo> = self.map_infos.values().cloned().collect();
+        mappings.sort_by_key(|map_info| map_info.memory_start);
+        mappings
+    }
+
+    /// NUL terminated string at `address`, cut at `max` bytes. None when unmapped.
+    pub fn read_c_string(&self, address: u64, max: usize) -> Option<String> {
+        let mut data = Vec::new();
+        while data.len() < max {
+            // read byte by byte once a chunk would run into unmapped memory
+            let chunk = std::cmp::min(64, max - data.len());
+            let position = address + data.len() as u64;
+            let bytes = match self.mem_read_as_vec(position, chunk).or_else(|_| self.mem_read_as_vec(position, 1)) {
+                Ok(bytes) => bytes,
+                Err(_) if data.is_empty() => return None,
+                Err(_) => break,
+            };
+
+            match bytes.iter().position(|b| *b == 0) {
+                Some(end) => {
+                    data.extend_from_slice(&bytes[..end]);
+                    return Some(String::from_utf8_lossy(&data).to_string());
+                },
+                None => data.extend_from_slice(&bytes),
+            }
+        }
+        Some(String::from_utf8_lossy(&data).to_string())
+    }
+
     pub fn display_mapped(&self) {
         let mut v: Vec<_> = Vec::new();
         for (addr, map_info) in self.map_infos.iter() {
@@ -306,6 +356,20 @@ impl<D> Emulator<D> {
         }
     }
     
+    pub fn pack_16(&self, value: u16) -> Vec<u8> {
+        match self.endian {
+            header::Data::BigEndian  => {
+                value.to_be_bytes().to_vec()
+            },
+            header::Data::LittleEndian => {
+                value.to_le_bytes().to_vec()
+            },
+            _ => {
+                panic!("what kiinda endian is this")
+            }
+        }
+    }
+
     pub fn pack_32(&self, value: u32) -> Vec<u8> {        
         match self.endian {
             header::Data::BigEndian  => {
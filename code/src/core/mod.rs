@@ -1,5 +1,14 @@
 pub mod mmu;
 pub mod hooks;
+pub mod crash;
+pub mod debugger;
+pub mod trace;
+pub mod symbols;
+pub mod modules;
+pub mod interceptor;
+pub mod fuzz;
+pub mod snapshot;
+pub mod heap;
 pub mod android;
 pub mod loaders;
 pub mod rudroid;
@@ -21,5 +30,6 @@ pub enum OS64 {
 }
 
 // [KERNEL]
+pub const pid : u32 = 1337;
 pub const uid : u32 = 0;
 pub const gid : u32 = 0;
\ No newline at end of file
@@ -0,0 +1,343 @@
+use xmas_elf::ElfFile;
+use xmas_elf::program;
+
+use crate::utilities;
+use super::rudroid::Emulator;
+use super::unicorn::unicorn_const::Protection;
+
+const ELF_MAGIC: &[u8] = b"\x7fELF";
+
+/// One PT_LOAD segment of a module, page aligned.
+#[derive(Debug, Clone)]
+pub struct Segment {
+    pub start   : u64,
+    pub end     : u64,
+    pub perms   : Protection,
+    // page aligned offset into the file
+    pub offset  : u64,
+}
+
+/// An ELF image mapped into the guest.
+#[derive(Debug, Clone)]
+pub struct Module {
+    pub name        : String,
+    // guest path, the host path for the main executable
+    pub path        : String,
+    pub base        : u64,
+    pub size        : u64,
+    // difference between guest addresses and the addresses in the file
+    pub bias        : u64,
+    pub segments    : Vec<Segment>,
+}
+
+impl Module {
+    fn from_elf(path: &str, elf: &ElfFile, bias: u64) -> Module {
+        let mut segments = Vec::new();
+        for header in elf.program_iter() {
+            if header.get_type() != Ok(program::Type::Load) {
+                continue;
+            }
+
+            segments.push(Segment {
+                start   : (bias + header.virtual_addr()) & !0xfff,
+                end     : (bias + header.virtual_addr() + header.mem_size() + 0xfff) & !0xfff,
+                perms   : utilities::to_uc_permissions(header.flags()),
+                offset  : header.offset() & !0xfff,
+            });
+        }
+
+        let base = segments.iter().map(|segment| segment.start).min().unwrap_or(bias);
+        let end  = segments.iter().map(|segment| segment.end).max().unwrap_or(bias);
+
+        Module {
+            name        : String::from(path.rsplit('/').next().unwrap()),
+            path        : String::from(path),
+            base        : base,
+            size        : end - base,
+            bias        : bias,
+            segments    : segments,
+        }
+    }
+
+    pub fn end(&self) -> u64 {
+        self.base + self.size
+    }
+
+    pub fn contains(&self, address: u64) -> bool {
+        address >= self.base && address < self.end()
+    }
+
+    /// `libc.so` matches the module name, anything with a slash the end of its path.
+    pub fn matches(&self, name: &str) -> bool {
+        if name.contains('/') { self.path.ends_with(name) } else { self.name == name }
+    }
+}
+
+// a library being mapped segment by segment
+#[derive(Debug, Clone)]
+struct PendingModule {
+    // the mapped file, the APK for a library stored in one
+    file        : String,
+    file_offset : u64,
+    module      : Module,
+    mapped      : Vec<bool>,
+}
+
+/// The registry's modules without its callbacks, kept with emulator snapshots.
+#[derive(Debug, Clone)]
+pub struct ModuleState {
+    modules     : Vec<Module>,
+    pending     : Vec<PendingModule>,
+}
+
+impl ModuleState {
+    /// Fully loaded `modules`, nothing half mapped.
+    pub fn new(modules: Vec<Module>) -> ModuleState {
+        ModuleState {
+            modules     : modules,
+            pending     : Vec::new(),
+        }
+    }
+
+    pub fn modules(&self) -> &[Module] {
+        &self.modules
+    }
+}
+
+pub type ModuleCallback<D> = Box<dyn FnMut(&mut Emulator<D>, &Module)>;
+
+/// Modules loaded into the guest, the executable and interpreter from the ELF loader and
+/// libraries as the linker maps their segments.
+pub struct ModuleRegistry<D> {
+    modules         : Vec<Module>,
+    // libraries with only some of their segments mapped so far
+    pending         : Vec<PendingModule>,
+
+    next_callback   : usize,
+    load_callbacks  : Vec<(usize, ModuleCallback<D>)>,
+    unload_callbacks: Vec<(usize, ModuleCallback<D>)>,
+    // ids removed while their callbacks were running
+    removed         : Vec<usize>,
+}
+
+impl<D> ModuleRegistry<D> {
+    pub fn new() -> ModuleRegistry<D> {
+        ModuleRegistry {
+            modules         : Vec::new(),
+            pending         : Vec::new(),
+            next_callback   : 0,
+            load_callbacks  : Vec::new(),
+            unload_callbacks: Vec::new(),
+            removed         : Vec::new(),
+        }
+    }
+
+    pub fn iter(&self) -> std::slice::Iter<Module> {
+        self.modules.iter()
+    }
+
+    pub fn find(&self, address: u64) -> Option<&Module> {
+        self.modules.iter().find(|module| module.contains(address))
+    }
+
+    pub fn by_name(&self, name: &str) -> Option<&Module> {
+        self.modules.iter().find(|module| module.matches(name))
+    }
+
+    pub fn save_state(&self) -> ModuleState {
+        ModuleState {
+            modules     : self.modules.clone(),
+            pending     : self.pending.clone(),
+        }
+    }
+
+    /// Go back to `state`, the load and unload callbacks do not run.
+    pub fn restore_state(&mut self, state: &ModuleState) {
+        self.modules = state.modules.clone();
+        self.pending = state.pending.clone();
+    }
+}
+
+impl<D> Emulator<D> {
+    /// Register an image the ELF loader mapped itself.
+    pub fn add_loaded_module(&mut self, path: &str, elf: &ElfFile, bias: u64) {
+        let module = Module::from_elf(path, elf, bias);
+        self.module_loaded(module);
+    }
+
+    /// Called for every file backed mmap. The linker maps each PT_LOAD of a library with
+    /// MAP_FIXED into a reserved region, the module is complete once all of them are in place.
+    /// Libraries stored in an APK are mapped from the APK at the offset of their zip entry.
+    pub fn track_module_mapping(&mut self, path: &str, address: u64, offset: u64, data: &[u8]) {
+        let found = self.modules.pending.iter().enumerate().find_map(|(index, pending)| {
+            if pending.file != path {
+                return None;
+            }
+            pending.module.segments.iter()
+                .position(|segment| pending.file_offset + segment.offset == offset && segment.start == address)
+                .map(|segment| (index, segment))
+        });
+
+        let (index, segment) = match found {
+            Some(found) => found,
+            None if data.starts_with(ELF_MAGIC) => {
+                // the same image mapped somewhere else, an earlier load attempt was abandoned
+                self.modules.pending.retain(|pending| !(pending.file == path && pending.file_offset == offset));
+
+                let pending = match self.pending_module(path, address, offset) {
+                    Some(pending) => pending,
+                    None => return,
+                };
+                let segment = match pending.module.segments.iter().position(|segment| segment.offset == 0 && segment.start == address) {
+                    Some(segment) => segment,
+                    None => return,
+                };
+                self.modules.pending.push(pending);
+                (self.modules.pending.len() - 1, segment)
+            },
+            None => return,
+        };
+
+        self.modules.pending[index].mapped[segment] = true;
+
+        if self.modules.pending[index].mapped.iter().all(|mapped| *mapped) {
+            let pending = self.modules.pending.remove(index);
+            self.module_loaded(pending.module);
+        }
+    }
+
+    /// Module for the ELF image at `offset` in `path`, placed so its first segment is at `address`.
+    fn pending_module(&self, path: &str, address: u64, offset: u64) -> Option<PendingModule> {
+        let file = std::fs::read(self.module_host_path(path)).ok()?;
+        let elf = ElfFile::new(file.get(offset as usize..)?).ok()?;
+
+        let first_vaddr = elf.program_iter()
+            .filter(|header| header.get_type() == Ok(program::Type::Load))
+            .map(|header| header.virtual_addr() & !0xfff)
+            .min()
+            .unwrap_or(0);
+
+        let module = Module::from_elf(&self.embedded_image_path(path, offset), &elf, address.wrapping_sub(first_vaddr));
+        Some(PendingModule {
+            file        : String::from(path),
+            file_offset : offset,
+            mapped      : vec![false; module.segments.len()],
+            module      : module,
+        })
+    }
+
+    /// Called for every munmap, modules whose base is inside the range are gone.
+    pub fn track_module_unmapping(&mut self, address: u64, len: u64) {
+        self.modules.pending.retain(|pending| !(pending.module.base >= address && pending.module.base < address + len));
+
+        let mut unloaded = Vec::new();
+        self.modules.modules.retain(|module| {
+            let inside = module.base >= address && module.base < address + len;
+            if inside {
+                unloaded.push(module.clone());
+            }
+            !inside
+        });
+
+        for module in unloaded {
+            self.debug_print(format!("module unloaded: {} {:#x}-{:#x}", module.path, module.base, module.end()));
+            self.symbolizer.clear();
+
+            let mut callbacks = std::mem::take(&mut self.modules.unload_callbacks);
+            for (_, callback) in callbacks.iter_mut() {
+                callback(self, &module);
+            }
+            callbacks.append(&mut self.modules.unload_callbacks);
+            self.modules.unload_callbacks = callbacks;
+            self.forget_removed_callbacks();
+        }
+    }
+
+    fn module_loaded(&mut self, module: Module) {
+        self.debug_print(format!("module loaded: {} {:#x}-{:#x}", module.path, module.base, module.end()));
+
+        self.modules.modules.retain(|loaded| !(loaded.base == module.base && loaded.path == module.path));
+        let index = self.modules.modules.partition_point(|loaded| loaded.base < module.base);
+        self.modules.modules.insert(index, module.clone());
+        self.run_load_callbacks(&module);
+    }
+
+    /// Go back to `state` like `ModuleRegistry::restore_state`, then run the load callbacks
+    /// for the modules that were not loaded before, e.g. the libraries of a snapshot file.
+    pub fn restore_modules(&mut self, state: &ModuleState) {
+        let before = self.modules.modules.clone();
+        self.modules.restore_state(state);
+
+        let new: Vec<Module> = self.modules.modules.iter()
+            .filter(|module| !before.iter().any(|loaded| loaded.base == module.base && loaded.path == module.path))
+            .cloned()
+            .collect();
+        for module in new.iter() {
+            self.debug_print(format!("module restored: {} {:#x}-{:#x}", module.path, module.base, module.end()));
+            self.run_load_callbacks(module);
+        }
+    }
+
+    fn run_load_callbacks(&mut self, module: &Module) {
+        // callbacks registered while these run are kept, they already saw the module
+        let mut callbacks = std::mem::take(&mut self.modules.load_callbacks);
+        for (_, callback) in callbacks.iter_mut() {
+            callback(self, module);
+        }
+        callbacks.append(&mut self.modules.load_callbacks);
+        self.modules.load_callbacks = callbacks;
+        self.forget_removed_callbacks();
+    }
+
+    fn forget_removed_callbacks(&mut self) {
+        let removed = std::mem::take(&mut self.modules.removed);
+        self.modules.load_callbacks.retain(|(id, _)| !removed.contains(id));
+        self.modules.unload_callbacks.retain(|(id, _)| !removed.contains(id));
+    }
+
+    pub fn module_at(&self, address: u64) -> Option<&Module> {
+        self.modules.find(address)
+    }
+
+    pub fn find_module(&self, name: &str) -> Option<&Module> {
+        self.modules.by_name(name)
+    }
+
+    /// Run `callback` for every module loaded from now on and, right away, for the ones
+    /// already loaded. Returns an id for `remove_module_callback`.
+    pub fn on_module_load<F>(&mut self, mut callback: F) -> usize
+        where F: FnMut(&mut Emulator<D>, &Module) + 'static
+    {
+        let loaded: Vec<Module> = self.modules.modules.clone();
+        for module in loaded.iter() {
+            callback(self, module);
+        }
+
+        let id = self.modules.next_callback;
+        self.modules.next_callback += 1;
+        self.modules.load_callbacks.push((id, Box::new(callback)));
+        id
+    }
+
+    /// Run `callback` for every module unloaded from now on.
+    pub fn on_module_unload<F>(&mut self, callback: F) -> usize
+        where F: FnMut(&mut Emulator<D>, &Module) + 'static
+    {
+        let id = self.modules.next_callback;
+        self.modules.next_callback += 1;
+        self.modules.unload_callbacks.push((id, Box::new(callback)));
+        id
+    }
+
+    /// Safe to call from inside a module callback, the callback itself included.
+    pub fn remove_module_callback(&mut self, id: usize) {
+        let count = self.modules.load_callbacks.len() + self.modules.unload_callbacks.len();
+        self.modules.load_callbacks.retain(|(callback, _)| *callback != id);
+        self.modules.unload_callbacks.retain(|(callback, _)| *callback != id);
+
+        // not found, it is running right now and gets dropped once it returns
+        if self.modules.load_callbacks.len() + self.modules.unload_callbacks.len() == count {
+            self.modules.removed.push(id);
+        }
+    }
+}
@@ -1,10 +1,22 @@
 
 use xmas_elf::header;
 use xmas_elf::ElfFile;
-use std::collections::HashMap;
+use std::collections::{HashMap, VecDeque};
 
 use super::mmu;
+use super::crash;
+use super::debugger;
+use super::trace;
+use super::symbols;
+use super::modules;
+use super::interceptor;
+use super::fuzz;
+use super::snapshot;
+use super::heap;
 use super::android::fs;
+use super::android::jni;
+use super::android::apk;
+use super::android::syscalls::SyscallRecord;
 use super::unicorn::ffi;
 
 use super::unicorn::unicorn_const::{Arch, Mode, uc_error};
@@ -33,10 +45,12 @@ pub struct Emulator<D>  {
     pub entry_point         : u64,
     pub elf_entry           : u64,
     pub brk_address         : u64,
+    pub call_trampoline     : Option<u64>,
 
     //elf arguments
     pub args                : Vec<String>,
     pub env                 : Vec<String>,
+    pub auxv                : Vec<(u64, u64)>,
 
     pub map_infos           : HashMap<u64, mmu::MapInfo>,
 
@@ -47,9 +61,34 @@ pub struct Emulator<D>  {
     pub insn_in_hooks       : HashMap<*mut libc::c_void, Box<ffi::InstructionInHook<D>>>,
     pub insn_out_hooks      : HashMap<*mut libc::c_void, Box<ffi::InstructionOutHook<D>>>,
     pub insn_sys_hooks      : HashMap<*mut libc::c_void, Box<ffi::InstructionSysHook<D>>>,
+    // hook callbacks running right now, nested when one starts emulation
+    pub hook_depth          : usize,
+    pub removed_hooks       : Vec<ffi::RemovedHook<D>>,
 
     // syscalls stuff
     pub sigmap              : HashMap<u64, Vec<u8>>,
+    pub syscall_history     : VecDeque<SyscallRecord>,
+
+    // crash reporting
+    pub last_fault          : Option<crash::Fault>,
+    pub tombstone_dir       : String,
+    pub core_dump_on_crash  : bool,
+
+    pub modules             : modules::ModuleRegistry<D>,
+    pub interceptor         : interceptor::Interceptor<D>,
+    pub jni                 : Option<jni::Jni<D>>,
+    pub apk                 : Option<apk::Apk>,
+    pub symbolizer          : symbols::Symbolizer,
+    pub debugger            : debugger::Debugger<D>,
+    pub tracer              : Option<trace::Tracer>,
+    pub coverage            : Option<trace::drcov::Coverage>,
+    pub tenet               : Option<trace::tenet::TenetRecorder>,
+    pub ltrace              : Option<trace::ltrace::Ltrace>,
+    pub edges               : Option<fuzz::coverage::EdgeMap>,
+    pub cmplog              : Option<fuzz::cmplog::CmpLog>,
+    pub dirty               : Option<snapshot::DirtyPages>,
+    pub heap                : Option<heap::GuardedHeap>,
+    pub alloc_stats         : Option<heap::stats::AllocStats>,
 
     _pin                    : std::marker::PhantomPinned,
 }
@@ -77,6 +116,7 @@ impl<D> Emulator<D>
             elf_path        : String::from(elf_path),
             args            : args,
             env             : env,
+            auxv            : Vec::new(),
             
             uc              : handle,
             uc_type         : data,
@@ -89,6 +129,7 @@ impl<D> Emulator<D>
             entry_point     : 0,
             elf_entry       : 0,
             brk_address     : 0,
+            call_trampoline : None,
             mmap_address    : 0,
             interp_address  : 0,
             new_stack       : 0,
@@ -101,11 +142,34 @@ impl<D> Emulator<D>
             insn_in_hooks   : HashMap::new(),
             insn_out_hooks  : HashMap::new(),
             insn_sys_hooks  : HashMap::new(),
+            hook_depth      : 0,
+            removed_hooks   : Vec::new(),
 
             _pin            : std::marker::PhantomPinned,
 
             filesystem      : fs::FsScheme::new(String::from(rootfs)),
             sigmap          : HashMap::new(),
+            syscall_history : VecDeque::new(),
+
+            last_fault      : None,
+            tombstone_dir   : String::from("tombstones"),
+            core_dump_on_crash  : false,
+
+            modules         : modules::ModuleRegistry::new(),
+            interceptor     : interceptor::Interceptor::new(),
+            jni             : None,
+            apk             : None,
+            symbolizer      : symbols::Symbolizer::new(),
+            debugger        : debugger::Debugger::new(),
+            tracer          : None,
+            coverage        : None,
+            tenet           : None,
+            ltrace          : None,
+            edges           : None,
+            cmplog          : None,
+            dirty           : None,
+            heap            : None,
+            alloc_stats     : None,
         };
         
         emu.load(elf);
@@ -0,0 +1,630 @@
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Write};
+
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+
+use crate::utilities;
+use crate::core::interceptor::InterceptorState;
+use crate::core::mmu::MapInfo;
+use crate::core::modules::{Module, ModuleState, Segment};
+use crate::core::rudroid::Emulator;
+use crate::core::unicorn::arch::arm64::RegisterARM64;
+use crate::core::unicorn::unicorn_const::Protection;
+
+use super::{fd_in_use, Region, PAGE_SIZE};
+
+const MAGIC         : &[u8; 8] = b"RUDSNAP\0";
+pub const VERSION   : u32 = 1;
+
+// every section is a tag, a payload length and the payload, so readers skip what they do not know
+const SECTION_END       : u32 = 0;
+const SECTION_REGISTERS : u32 = 1;
+const SECTION_MAPS      : u32 = 2;
+const SECTION_REGION    : u32 = 3;
+const SECTION_KERNEL    : u32 = 4;
+const SECTION_MODULES   : u32 = 5;
+
+// the arm64 user address space, regions past it come from a corrupt file
+const ADDRESS_LIMIT     : u64 = 1 << 48;
+
+fn invalid(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+fn write_string(out: &mut Vec<u8>, value: &str) {
+    out.write_u32::<LittleEndian>(value.len() as u32).unwrap();
+    out.extend_from_slice(value.as_bytes());
+}
+
+fn read_string(input: &mut &[u8]) -> io::Result<String> {
+    let len = input.read_u32::<LittleEndian>()? as usize;
+    let bytes = read_bytes(input, len)?;
+    String::from_utf8(bytes).map_err(|_| invalid(String::from("string is not utf-8")))
+}
+
+fn read_bytes(input: &mut &[u8], len: usize) -> io::Result<Vec<u8>> {
+    if input.len() < len {
+        return Err(io::Error::from(io::ErrorKind::UnexpectedEof));
+    }
+    let (bytes, rest) = input.split_at(len);
+    *input = rest;
+    Ok(bytes.to_vec())
+}
+
+fn write_section<W: Write>(out: &mut W, tag: u32, payload: &[u8]) -> io::Result<()> {
+    out.write_u32::<LittleEndian>(tag)?;
+    out.write_u64::<LittleEndian>(payload.len() as u64)?;
+    out.write_all(payload)
+}
+
+/// General purpose and system registers kept as 64 bit values.
+fn plain_registers() -> Vec<i32> {
+    let mut registers: Vec<i32> = (0..31).map(RegisterARM64::x).collect();
+    registers.extend_from_slice(&[
+        RegisterARM64::SP as i32,
+        RegisterARM64::PC as i32,
+        RegisterARM64::NZCV as i32,
+        RegisterARM64::TPIDR_EL0 as i32,
+        RegisterARM64::TPIDRRO_EL0 as i32,
+        RegisterARM64::CPACR_EL1 as i32,
+    ]);
+    registers
+}
+
+/// The brk and mmap cursors, signal handlers and open files.
+struct Kernel {
+    brk_address     : u64,
+    mmap_address    : u64,
+    trampoline      : u64,
+    sigmap          : HashMap<u64, Vec<u8>>,
+    // fd, path, open flags and offset
+    files           : Vec<(i32, String, i32, i64)>,
+}
+
+/// What a snapshot file holds, read in full and checked before any of it is applied.
+struct Image {
+    // register id and its value, 8 or 16 bytes
+    registers   : Vec<(i32, Vec<u8>)>,
+    map_infos   : HashMap<u64, MapInfo>,
+    regions     : Vec<Region>,
+    kernel      : Kernel,
+    modules     : Vec<Module>,
+}
+
+impl Image {
+    fn write<W: Write>(&self, out: &mut W) -> io::Result<()> {
+        out.write_all(MAGIC)?;
+        out.write_u32::<LittleEndian>(VERSION)?;
+
+        let mut payload = Vec::new();
+        for (register, value) in self.registers.iter() {
+            payload.write_u32::<LittleEndian>(*register as u32)?;
+            payload.write_u8(value.len() as u8)?;
+            payload.extend_from_slice(value);
+        }
+        write_section(out, SECTION_REGISTERS, &payload)?;
+
+        let mut payload = Vec::new();
+        let mut maps: Vec<&MapInfo> = self.map_infos.values().collect();
+        maps.sort_by_key(|info| info.memory_start);
+        for info in maps {
+            payload.write_u64::<LittleEndian>(info.memory_start)?;
+            payload.write_u64::<LittleEndian>(info.memory_end)?;
+            payload.write_u32::<LittleEndian>(info.memory_perms.bits())?;
+            write_string(&mut payload, &info.description);
+        }
+        write_section(out, SECTION_MAPS, &payload)?;
+
+        // one section per region, zero pages left out
+        for region in self.regions.iter() {
+            let data = &region.data;
+            let mut payload = Vec::new();
+            payload.write_u64::<LittleEndian>(region.begin)?;
+            payload.write_u64::<LittleEndian>(region.end)?;
+            payload.write_u32::<LittleEndian>(region.perms.bits())?;
+
+            let mut offset = 0;
+            while offset < data.len() {
+                let page_end = (offset + PAGE_SIZE as usize).min(data.len());
+                if data[offset..page_end].iter().all(|byte| *byte == 0) {
+                    offset = page_end;
+                    continue;
+                }
+
+                // extend the run over the following non-zero pages
+                let start = offset;
+                offset = page_end;
+                while offset < data.len() {
+                    let page_end = (offset + PAGE_SIZE as usize).min(data.len());
+                    if data[offset..page_end].iter().all(|byte| *byte == 0) {
+                        break;
+                    }
+                    offset = page_end;
+                }
+
+                payload.write_u64::<LittleEndian>(start as u64)?;
+                payload.write_u64::<LittleEndian>((offset - start) as u64)?;
+                payload.extend_from_slice(&data[start..offset]);
+            }
+            write_section(out, SECTION_REGION, &payload)?;
+        }
+
+        let kernel = &self.kernel;
+        let mut payload = Vec::new();
+        payload.write_u64::<LittleEndian>(kernel.brk_address)?;
+        payload.write_u64::<LittleEndian>(kernel.mmap_address)?;
+        payload.write_u64::<LittleEndian>(kernel.trampoline)?;
+
+        payload.write_u32::<LittleEndian>(kernel.sigmap.len() as u32)?;
+        for (signal, action) in kernel.sigmap.iter() {
+            payload.write_u64::<LittleEndian>(*signal)?;
+            payload.write_u32::<LittleEndian>(action.len() as u32)?;
+            payload.extend_from_slice(action);
+        }
+
+        payload.write_u32::<LittleEndian>(kernel.files.len() as u32)?;
+        for (fd, path, flags, offset) in kernel.files.iter() {
+            payload.write_i32::<LittleEndian>(*fd)?;
+            write_string(&mut payload, path);
+            payload.write_i32::<LittleEndian>(*flags)?;
+            payload.write_i64::<LittleEndian>(*offset)?;
+        }
+        write_section(out, SECTION_KERNEL, &payload)?;
+
+        let mut payload = Vec::new();
+        payload.write_u32::<LittleEndian>(self.modules.len() as u32)?;
+        for module in self.modules.iter() {
+            write_string(&mut payload, &module.name);
+            write_string(&mut payload, &module.path);
+            payload.write_u64::<LittleEndian>(module.base)?;
+            payload.write_u64::<LittleEndian>(module.size)?;
+            payload.write_u64::<LittleEndian>(module.bias)?;
+            payload.write_u32::<LittleEndian>(module.segments.len() as u32)?;
+            for segment in module.segments.iter() {
+                payload.write_u64::<LittleEndian>(segment.start)?;
+                payload.write_u64::<LittleEndian>(segment.end)?;
+                payload.write_u32::<LittleEndian>(segment.perms.bits())?;
+                payload.write_u64::<LittleEndian>(segment.offset)?;
+            }
+        }
+        write_section(out, SECTION_MODULES, &payload)?;
+
+        write_section(out, SECTION_END, &[])
+    }
+
+    /// Parse a whole file, lengths in it are checked against what is left so a truncated or
+    /// corrupt one is InvalidData rather than a panic.
+    fn read(input: &[u8]) -> io::Result<Image> {
+        Image::parse(&mut &input[..]).map_err(|error| match error.kind() {
+            io::ErrorKind::UnexpectedEof => invalid(String::from("truncated snapshot")),
+            _ => error,
+        })
+    }
+
+    fn parse(input: &mut &[u8]) -> io::Result<Image> {
+        if read_bytes(input, MAGIC.len())? != MAGIC {
+            return Err(invalid(String::from("not a snapshot")));
+        }
+        let version = input.read_u32::<LittleEndian>()?;
+        if version != VERSION {
+            return Err(invalid(format!("snapshot version {}, expected {}", version, VERSION)));
+        }
+
+        let mut registers = Vec::new();
+        let mut map_infos = HashMap::new();
+        let mut regions = Vec::new();
+        let mut kernel = None;
+        let mut modules = Vec::new();
+
+        loop {
+            let tag = input.read_u32::<LittleEndian>()?;
+            let len = input.read_u64::<LittleEndian>()?;
+            if len > input.len() as u64 {
+                return Err(io::Error::from(io::ErrorKind::UnexpectedEof));
+            }
+            let (section, rest) = input.split_at(len as usize);
+            *input = rest;
+            let payload = &mut &section[..];
+
+            match tag {
+                SECTION_END => break,
+                SECTION_REGISTERS => {
+                    while !payload.is_empty() {
+                        let register = payload.read_u32::<LittleEndian>()? as i32;
+                        let size = payload.read_u8()? as usize;
+                        if size != 8 && size != 16 {
+                            return Err(invalid(format!("register {}: {} bytes", register, size)));
+                        }
+                        registers.push((register, read_bytes(payload, size)?));
+                    }
+                },
+                SECTION_MAPS => {
+                    while !payload.is_empty() {
+                        let memory_start = payload.read_u64::<LittleEndian>()?;
+                        let memory_end = payload.read_u64::<LittleEndian>()?;
+                        let memory_perms = Protection::from_bits_truncate(payload.read_u32::<LittleEndian>()?);
+                        let description = read_string(payload)?;
+                        map_infos.insert(memory_start, MapInfo {
+                            memory_start    : memory_start,
+                            memory_end      : memory_end,
+                            memory_perms    : memory_perms,
+                            description     : description,
+                        });
+                    }
+                },
+                SECTION_REGION => regions.push(read_region(payload)?),
+                SECTION_KERNEL => {
+                    let brk_address = payload.read_u64::<LittleEndian>()?;
+                    let mmap_address = payload.read_u64::<LittleEndian>()?;
+                    let trampoline = payload.read_u64::<LittleEndian>()?;
+
+                    let mut sigmap = HashMap::new();
+                    for _ in 0..payload.read_u32::<LittleEndian>()? {
+                        let signal = payload.read_u64::<LittleEndian>()?;
+                        let size = payload.read_u32::<LittleEndian>()? as usize;
+                        sigmap.insert(signal, read_bytes(payload, size)?);
+                    }
+
+                    let mut files = Vec::new();
+                    for _ in 0..payload.read_u32::<LittleEndian>()? {
+                        let fd = payload.read_i32::<LittleEndian>()?;
+                        let path = read_string(payload)?;
+                        let flags = payload.read_i32::<LittleEndian>()?;
+                        let offset = payload.read_i64::<LittleEndian>()?;
+                        files.push((fd, path, flags, offset));
+                    }
+                    kernel = Some(Kernel {
+                        brk_address     : brk_address,
+                        mmap_address    : mmap_address,
+                        trampoline      : trampoline,
+                        sigmap          : sigmap,
+                        files           : files,
+                    });
+                },
+                SECTION_MODULES => {
+                    for _ in 0..payload.read_u32::<LittleEndian>()? {
+                        let name = read_string(payload)?;
+                        let path = read_string(payload)?;
+                        let base = payload.read_u64::<LittleEndian>()?;
+                        let size = payload.read_u64::<LittleEndian>()?;
+                        let bias = payload.read_u64::<LittleEndian>()?;
+                        let mut segments = Vec::new();
+                        for _ in 0..payload.read_u32::<LittleEndian>()? {
+                            segments.push(Segment {
+                                start   : payload.read_u64::<LittleEndian>()?,
+                                end     : payload.read_u64::<LittleEndian>()?,
+                                perms   : Protection::from_bits_truncate(payload.read_u32::<LittleEndian>()?),
+                                offset  : payload.read_u64::<LittleEndian>()?,
+                            });
+                        }
+                        modules.push(Module {
+                            name        : name,
+                            path        : path,
+                            base        : base,
+                            size        : size,
+                            bias        : bias,
+                            segments    : segments,
+                        });
+                    }
+                },
+                // written by a newer version, nothing we need
+                _ => {},
+            }
+        }
+
+        // unicorn refuses overlapping maps, restoring them would fail half way
+        regions.sort_by_key(|region| region.begin);
+        if let Some(pair) = regions.windows(2).find(|pair| pair[0].end > pair[1].begin) {
+            return Err(invalid(format!("regions {:#x} and {:#x} overlap", pair[0].begin, pair[1].begin)));
+        }
+
+        Ok(Image {
+            registers   : registers,
+            map_infos   : map_infos,
+            regions     : regions,
+            kernel      : kernel.ok_or_else(|| invalid(String::from("no kernel state")))?,
+            modules     : modules,
+        })
+    }
+}
+
+/// A page aligned region and its non-zero runs.
+fn read_region(payload: &mut &[u8]) -> io::Result<Region> {
+    let begin = payload.read_u64::<LittleEndian>()?;
+    let end = payload.read_u64::<LittleEndian>()?;
+    let perms = Protection::from_bits_truncate(payload.read_u32::<LittleEndian>()?);
+
+    let size = match end.checked_sub(begin) {
+        Some(size) if size != 0 && end <= ADDRESS_LIMIT && (begin | end) % PAGE_SIZE == 0 => size as usize,
+        _ => return Err(invalid(format!("bad region {:#x}-{:#x}", begin, end))),
+    };
+    let mut data = Vec::new();
+    data.try_reserve_exact(size).map_err(|_| invalid(format!("region {:#x}-{:#x} does not fit in memory", begin, end)))?;
+    data.resize(size, 0);
+
+    while !payload.is_empty() {
+        let offset = payload.read_u64::<LittleEndian>()?;
+        let len = payload.read_u64::<LittleEndian>()?;
+        let run = offset.checked_add(len)
+            .filter(|run_end| *run_end <= size as u64)
+            .map(|run_end| &mut data[offset as usize..run_end as usize])
+            .ok_or_else(|| invalid(format!("region {:#x}: data past its end", begin)))?;
+        run.copy_from_slice(&read_bytes(payload, len as usize)?);
+    }
+
+    Ok(Region {
+        begin   : begin,
+        end     : end,
+        perms   : perms,
+        data    : data,
+    })
+}
+
+impl<D> Emulator<D> {
+    /// Write the current state to `path` so a later run can `load_snapshot` it instead of
+    /// loading and linking again. Registers, memory with its map descriptions, the brk and
+    /// mmap cursors, signal handlers, open files and loaded modules are kept. JNI objects, the
+    /// guarded heap's bookkeeping and modules still being mapped are not.
+    pub fn save_snapshot(&mut self, path: &str) -> io::Result<()> {
+        let mut registers = Vec::new();
+        for register in plain_registers() {
+            let value = self.reg_read(register).map_err(|error| invalid(format!("reading register {}: {:?}", register, error)))?;
+            registers.push((register, value.to_le_bytes().to_vec()));
+        }
+        for n in 0..32 {
+            let value = self.reg_read_long(RegisterARM64::v(n)).map_err(|error| invalid(format!("reading v{}: {:?}", n, error)))?;
+            registers.push((RegisterARM64::v(n), value.to_vec()));
+        }
+
+        let mut regions = Vec::new();
+        for region in self.mem_regions().unwrap() {
+            let size = (region.end - region.begin + 1) as usize;
+            regions.push(Region {
+                begin   : region.begin,
+                end     : region.end + 1,
+                perms   : region.perms,
+                data    : self.mem_read_as_vec(region.begin, size).unwrap(),
+            });
+        }
+
+        // the host's stdio is not ours to reopen
+        let files = self.filesystem.open_files.values()
+            .filter(|file| !file.shared && file.fd > 2)
+            .map(|file| (file.fd, file.path.clone(), file.flags, unsafe { libc::lseek(file.fd, 0, libc::SEEK_CUR) }))
+            .collect();
+
+        let image = Image {
+            registers   : registers,
+            map_infos   : self.map_infos.clone(),
+            regions     : regions,
+            kernel      : Kernel {
+                brk_address     : self.brk_address,
+                mmap_address    : self.mmap_address,
+                trampoline      : self.call_trampoline.unwrap_or(0),
+                sigmap          : self.sigmap.clone(),
+                files           : files,
+            },
+            modules     : self.modules.save_state().modules().to_vec(),
+        };
+
+        let mut out = BufWriter::new(File::create(path)?);
+        image.write(&mut out)?;
+        out.flush()
+    }
+
+    /// Replace the current state with one written by `save_snapshot`. The emulator only needs
+    /// to be created, nothing has to be loaded first. Nothing changes when the file is not a
+    /// complete snapshot.
+    pub fn load_snapshot(&mut self, path: &str) -> io::Result<()> {
+        let image = Image::read(&fs::read(path)?).map_err(|error| invalid(format!("{}: {}", path, error)))?;
+        let Image { registers, map_infos, regions, kernel, modules } = image;
+
+        // the pages tracked so far belong to a state that is gone
+        self.stop_dirty_tracking();
+        self.jni_restore(None);
+
+        self.restore_regions(&regions, None);
+
+        for (register, value) in registers {
+            if value.len() == 8 {
+                let mut bytes = [0u8; 8];
+                bytes.copy_from_slice(&value);
+                self.reg_write(register, u64::from_le_bytes(bytes))
+            }
+            else {
+                self.reg_write_long(register, value.into_boxed_slice())
+            }.map_err(|error| invalid(format!("writing register {}: {:?}", register, error)))?;
+        }
+
+        let open: Vec<i32> = self.filesystem.open_files.values().filter(|file| !file.shared && file.fd > 2).map(|file| file.fd).collect();
+        for fd in open {
+            self.filesystem.close(fd);
+        }
+        for (fd, path, flags, offset) in kernel.files {
+            // the file is there already, creating or truncating it again would lose its contents
+            let host = self.filesystem.open(&path, flags & !(libc::O_CREAT | libc::O_TRUNC | libc::O_EXCL));
+            if host < 0 {
+                self.debug_print(format!("snapshot: could not reopen {} as fd {}", path, fd));
+                continue;
+            }
+
+            let mut file = self.filesystem.open_files.remove(&host).unwrap();
+            if host != fd && fd_in_use(fd) && !self.filesystem.open_files.contains_key(&fd) {
+                utilities::log(&format!("snapshot: fd {} is taken on the host, {} is not restored", fd, path), utilities::DebugLevel::ERROR);
+                unsafe { libc::close(host) };
+                continue;
+            }
+            if host != fd {
+                unsafe {
+                    libc::dup2(host, fd);
+                    libc::close(host);
+                }
+            }
+            unsafe { libc::lseek(fd, offset, libc::SEEK_SET) };
+            file.fd = fd;
+            self.filesystem.open_files.insert(fd, file);
+        }
+
+        self.map_infos          = map_infos;
+        self.sigmap             = kernel.sigmap;
+        self.brk_address        = kernel.brk_address;
+        self.mmap_address       = kernel.mmap_address;
+        self.call_trampoline    = if kernel.trampoline == 0 { None } else { Some(kernel.trampoline) };
+        self.syscall_history.clear();
+        self.last_fault = None;
+        self.symbolizer.clear();
+        // calls in flight belong to the state being replaced
+        self.restore_interceptor_state(&InterceptorState::new());
+
+        // hooks set up before loading, the guarded heap or ltrace, attach to the restored libraries
+        self.restore_modules(&ModuleState::new(modules));
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn image() -> Image {
+        let mut data = vec![0u8; 3 * PAGE_SIZE as usize];
+        data[0x10..0x14].copy_from_slice(b"\x7fELF");
+        data[2 * PAGE_SIZE as usize + 1] = 0xaa;
+
+        let mut map_infos = HashMap::new();
+        map_infos.insert(0x40_0000, MapInfo {
+            memory_start    : 0x40_0000,
+            memory_end      : 0x40_3000,
+            memory_perms    : Protection::READ | Protection::EXEC,
+            description     : String::from("/system/bin/app"),
+        });
+
+        let mut sigmap = HashMap::new();
+        sigmap.insert(11, vec![1, 2, 3, 4]);
+
+        Image {
+            registers   : vec![(RegisterARM64::PC as i32, 0x40_0010u64.to_le_bytes().to_vec()), (RegisterARM64::v(0), vec![7; 16])],
+            map_infos   : map_infos,
+            regions     : vec![Region { begin: 0x40_0000, end: 0x40_3000, perms: Protection::READ | Protection::EXEC, data: data }],
+            kernel      : Kernel {
+                brk_address     : 0x50_0000,
+                mmap_address    : 0x7f00_0000,
+                trampoline      : 0,
+                sigmap          : sigmap,
+                files           : vec![(3, String::from("/data/local/tmp/out"), libc::O_RDWR, 12)],
+            },
+            modules     : vec![Module {
+                name        : String::from("app"),
+                path        : String::from("/system/bin/app"),
+                base        : 0x40_0000,
+                size        : 0x3000,
+                bias        : 0x40_0000,
+                segments    : vec![Segment { start: 0x40_0000, end: 0x40_3000, perms: Protection::READ | Protection::EXEC, offset: 0 }],
+            }],
+        }
+    }
+
+    fn bytes(image: &Image) -> Vec<u8> {
+        let mut out = Vec::new();
+        image.write(&mut out).unwrap();
+        out
+    }
+
+    fn is_invalid(result: io::Result<Image>) -> bool {
+        match result {
+            Err(error) => error.kind() == io::ErrorKind::InvalidData,
+            Ok(_) => false,
+        }
+    }
+
+    // a file holding just a region section with `payload`, and an empty kernel section
+    fn with_region(payload: &[u8]) -> Vec<u8> {
+        let mut out = Vec::new();
+        out.extend_from_slice(MAGIC);
+        out.write_u32::<LittleEndian>(VERSION).unwrap();
+        write_section(&mut out, SECTION_REGION, payload).unwrap();
+        write_section(&mut out, SECTION_KERNEL, &[0; 32]).unwrap();
+        write_section(&mut out, SECTION_END, &[]).unwrap();
+        out
+    }
+
+    #[test]
+    fn round_trip() {
+        let saved = image();
+        let file = bytes(&saved);
+        // the zero page in the middle is left out
+        assert!(file.len() < 2 * PAGE_SIZE as usize + 0x200);
+
+        let loaded = Image::read(&file).unwrap();
+        assert_eq!(loaded.registers, saved.registers);
+        assert_eq!(loaded.map_infos, saved.map_infos);
+        assert_eq!(loaded.regions.len(), 1);
+        assert_eq!((loaded.regions[0].begin, loaded.regions[0].end, loaded.regions[0].perms), (0x40_0000, 0x40_3000, Protection::READ | Protection::EXEC));
+        assert_eq!(loaded.regions[0].data, saved.regions[0].data);
+        assert_eq!(loaded.kernel.brk_address, 0x50_0000);
+        assert_eq!(loaded.kernel.mmap_address, 0x7f00_0000);
+        assert_eq!(loaded.kernel.sigmap, saved.kernel.sigmap);
+        assert_eq!(loaded.kernel.files, saved.kernel.files);
+        assert_eq!(loaded.modules.len(), 1);
+        assert_eq!((loaded.modules[0].path.as_str(), loaded.modules[0].base, loaded.modules[0].segments.len()), ("/system/bin/app", 0x40_0000, 1));
+    }
+
+    #[test]
+    fn truncated_files() {
+        let file = bytes(&image());
+        for len in 0..file.len() {
+            assert!(is_invalid(Image::read(&file[..len])), "{} bytes", len);
+        }
+    }
+
+    #[test]
+    fn corrupt_files() {
+        let mut file = bytes(&image());
+        file[0] = b'X';
+        assert!(is_invalid(Image::read(&file)));
+
+        // the first section claims more than the file has
+        let mut file = bytes(&image());
+        file[16..24].copy_from_slice(&u64::MAX.to_le_bytes());
+        assert!(is_invalid(Image::read(&file)));
+
+        // a register that is not 8 or 16 bytes
+        let mut file = bytes(&image());
+        file[28] = 200;
+        assert!(is_invalid(Image::read(&file)));
+
+        let region = |begin: u64, end: u64, runs: &[(u64, u64)]| {
+            let mut payload = Vec::new();
+            payload.write_u64::<LittleEndian>(begin).unwrap();
+            payload.write_u64::<LittleEndian>(end).unwrap();
+            payload.write_u32::<LittleEndian>(Protection::READ.bits()).unwrap();
+            for (offset, len) in runs {
+                payload.write_u64::<LittleEndian>(*offset).unwrap();
+                payload.write_u64::<LittleEndian>(*len).unwrap();
+            }
+            with_region(&payload)
+        };
+        assert!(Image::read(&region(0x1000, 0x2000, &[])).is_ok());
+        // end before begin, empty, unaligned and past the address space
+        assert!(is_invalid(Image::read(&region(0x2000, 0x1000, &[]))));
+        assert!(is_invalid(Image::read(&region(0x1000, 0x1000, &[]))));
+        assert!(is_invalid(Image::read(&region(0x1000, 0x1800, &[]))));
+        assert!(is_invalid(Image::read(&region(0, u64::MAX & !0xfff, &[]))));
+        // runs overflowing or past the end of the region
+        assert!(is_invalid(Image::read(&region(0x1000, 0x2000, &[(u64::MAX, 2)]))));
+        assert!(is_invalid(Image::read(&region(0x1000, 0x2000, &[(0xff0, 0x20)]))));
+        // the run's data is missing
+        assert!(is_invalid(Image::read(&region(0x1000, 0x2000, &[(0, 0x10)]))));
+    }
+
+    #[test]
+    fn overlapping_regions() {
+        let mut image = image();
+        let mut other = image.regions[0].clone();
+        other.begin += PAGE_SIZE;
+        other.end += PAGE_SIZE;
+        image.regions.push(other);
+        assert!(is_invalid(Image::read(&bytes(&image))));
+    }
+}
@@ -0,0 +1,285 @@
+pub mod file;
+pub mod zygote;
+
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::os::unix::io::RawFd;
+
+use crate::utilities;
+use super::heap::GuardedHeap;
+use super::interceptor::InterceptorState;
+use super::mmu::MapInfo;
+use super::modules::ModuleState;
+use super::rudroid::Emulator;
+use super::unicorn::{ffi, Context};
+use super::unicorn::unicorn_const::{HookType, Protection};
+use super::android::fs::oFile;
+use super::android::jni::JniState;
+use super::android::syscalls::SyscallRecord;
+
+const PAGE_SIZE: u64 = 0x1000;
+
+/// Pages written since the snapshot `since` was taken or last restored, by guest code or by the
+/// syscalls writing into guest memory. Unmapped pages count as written.
+pub struct DirtyPages {
+    pub pages   : HashSet<u64>,
+    since       : u64,
+    next_id     : u64,
+    hook        : Option<ffi::uc_hook>,
+}
+
+impl DirtyPages {
+    pub fn mark(&mut self, address: u64, len: u64) {
+        if len == 0 {
+            return;
+        }
+
+        let mut page = address & !(PAGE_SIZE - 1);
+        while page < address.saturating_add(len) {
+            self.pages.insert(page);
+            page += PAGE_SIZE;
+        }
+    }
+}
+
+/// Whether `fd` is open on the host. Guest fds are host fds, one the guest does not have
+/// belongs to Rudroid: a trace, a crash report or the corpus.
+pub(super) fn fd_in_use(fd: RawFd) -> bool {
+    unsafe { libc::fcntl(fd, libc::F_GETFD) != -1 }
+}
+
+/// Contents of one unicorn memory region, `end` exclusive.
+#[derive(Debug, Clone)]
+pub struct Region {
+    pub begin   : u64,
+    pub end     : u64,
+    pub perms   : Protection,
+    pub data    : Vec<u8>,
+}
+
+/// An open guest fd. The host fd is duplicated so the file survives the guest closing it.
+#[derive(Debug)]
+struct SavedFile {
+    file    : oFile,
+    offset  : i64,
+    dup     : RawFd,
+}
+
+impl Drop for SavedFile {
+    fn drop(&mut self) {
+        unsafe { libc::close(self.dup) };
+    }
+}
+
+/// The emulator at one point in time: cpu, memory and the kernel side the syscalls keep.
+/// Hooks, traces and the debugger are configuration and stay as they are on restore.
+pub struct Snapshot {
+    id              : u64,
+    context         : Context,
+    pub regions     : Vec<Region>,
+    map_infos       : HashMap<u64, MapInfo>,
+    files           : Vec<SavedFile>,
+    sigmap          : HashMap<u64, Vec<u8>>,
+    brk_address     : u64,
+    mmap_address    : u64,
+    modules         : ModuleState,
+    syscall_history : VecDeque<SyscallRecord>,
+    jni             : Option<JniState>,
+    heap            : Option<GuardedHeap>,
+    interceptor     : InterceptorState,
+}
+
+impl Snapshot {
+    /// Bytes of guest memory held.
+    pub fn size(&self) -> usize {
+        self.regions.iter().map(|region| region.data.len()).sum()
+    }
+}
+
+impl<D> Emulator<D> {
+    /// Capture the current state, e.g. once the linker is done or at a breakpoint. Writes are
+    /// tracked from now on so restoring it only copies back what changed.
+    pub fn take_snapshot(&mut self) -> Snapshot {
+        let id = self.track_dirty_pages();
+
+        let mut regions = Vec::new();
+        for region in self.mem_regions().unwrap() {
+            // unicorn's end is inclusive
+            let size = (region.end - region.begin + 1) as usize;
+            regions.push(Region {
+                begin   : region.begin,
+                end     : region.end + 1,
+                perms   : region.perms,
+                data    : self.mem_read_as_vec(region.begin, size).unwrap(),
+            });
+        }
+
+        let mut files = Vec::new();
+        for (fd, file) in self.filesystem.open_files.iter() {
+            let dup = unsafe { libc::dup(*fd) };
+            if dup < 0 {
+                continue;
+            }
+            files.push(SavedFile {
+                file    : file.clone(),
+                offset  : unsafe { libc::lseek(*fd, 0, libc::SEEK_CUR) },
+                dup     : dup,
+            });
+        }
+
+        regions.sort_by_key(|region| region.begin);
+
+        Snapshot {
+            id              : id,
+            context         : self.context_init().expect("failed to save the cpu context"),
+            regions         : regions,
+            map_infos       : self.map_infos.clone(),
+            files           : files,
+            sigmap          : self.sigmap.clone(),
+            brk_address     : self.brk_address,
+            mmap_address    : self.mmap_address,
+            modules         : self.modules.save_state(),
+            syscall_history : self.syscall_history.clone(),
+            jni             : self.jni.as_ref().map(|jni| jni.save_state()),
+            heap            : self.heap.clone(),
+            interceptor     : self.save_interceptor_state(),
+        }
+    }
+
+    /// Put the emulator back to `snapshot`. The snapshot stays valid and can be restored again.
+    pub fn restore_snapshot(&mut self, snapshot: &Snapshot) {
+        // before memory, it may map the jni region that the memory restore then overwrites
+        self.jni_restore(snapshot.jni.as_ref());
+
+        // the pages written since, when the tracking started with this snapshot
+        let dirty = match self.dirty.as_mut() {
+            Some(dirty) if dirty.since == snapshot.id => Some(std::mem::take(&mut dirty.pages)),
+            _ => None,
+        };
+        self.restore_regions(&snapshot.regions, dirty.as_ref());
+
+        if let Some(dirty) = self.dirty.as_mut() {
+            dirty.pages.clear();
+            dirty.since = snapshot.id;
+        }
+
+        self.context_restore(&snapshot.context).unwrap();
+        self.restore_files(&snapshot.files);
+
+        self.map_infos          = snapshot.map_infos.clone();
+        self.sigmap             = snapshot.sigmap.clone();
+        self.brk_address        = snapshot.brk_address;
+        self.mmap_address       = snapshot.mmap_address;
+        self.syscall_history    = snapshot.syscall_history.clone();
+        self.modules.restore_state(&snapshot.modules);
+        // its hooks stay installed, only the bookkeeping goes back
+        if let Some(heap) = &snapshot.heap {
+            self.heap = Some(heap.clone());
+        }
+        self.restore_interceptor_state(&snapshot.interceptor);
+
+        self.last_fault = None;
+        self.symbolizer.clear();
+    }
+
+    /// Start tracking writes for a new snapshot and return its id.
+    fn track_dirty_pages(&mut self) -> u64 {
+        if self.dirty.is_none() {
+            let hook = self.add_mem_hook(HookType::MEM_WRITE, 1, 0, |emu: &mut Emulator<D>, _, address: u64, size: usize, _| {
+                if let Some(dirty) = emu.dirty.as_mut() {
+                    dirty.mark(address, size as u64);
+                }
+            }).expect("failed to add dirty page hook");
+
+            self.dirty = Some(DirtyPages {
+                pages   : HashSet::new(),
+                since   : 0,
+                next_id : 1,
+                hook    : Some(hook),
+            });
+        }
+
+        let dirty = self.dirty.as_mut().unwrap();
+        let id = dirty.next_id;
+        dirty.next_id += 1;
+        dirty.since = id;
+        dirty.pages.clear();
+        id
+    }
+
+    /// Stop tracking writes, restores copy whole regions again.
+    pub fn stop_dirty_tracking(&mut self) {
+        if let Some(mut dirty) = self.dirty.take() {
+            if let Some(hook) = dirty.hook.take() {
+                self.remove_hook(hook).unwrap();
+            }
+        }
+    }
+
+    /// Unmap regions the snapshot does not have and map the ones it has. Their data is copied
+    /// back whole, or only the `dirty` pages for regions that were there all along.
+    fn restore_regions(&mut self, regions: &[Region], dirty: Option<&HashSet<u64>>) {
+        for region in self.mem_regions().unwrap() {
+            let end = region.end + 1;
+            if !regions.iter().any(|saved| saved.begin == region.begin && saved.end == end) {
+                self.mem_unmap(region.begin, (end - region.begin) as usize).unwrap();
+            }
+        }
+
+        let current = self.mem_regions().unwrap();
+        let mut whole = vec![dirty.is_none(); regions.len()];
+        for (index, saved) in regions.iter().enumerate() {
+            let size = (saved.end - saved.begin) as usize;
+            match current.iter().find(|region| region.begin == saved.begin && region.end + 1 == saved.end) {
+                Some(region) if region.perms != saved.perms => self.mem_protect(saved.begin, size, saved.perms).unwrap(),
+                Some(_) => {},
+                None => {
+                    self.mem_map(saved.begin, size, saved.perms).unwrap();
+                    whole[index] = true;
+                },
+            }
+        }
+
+        for (saved, _) in regions.iter().zip(whole.iter()).filter(|(_, whole)| **whole) {
+            self.mem_write(saved.begin, &saved.data).unwrap();
+        }
+
+        for page in dirty.into_iter().flatten() {
+            let index = regions.partition_point(|region| region.end <= *page);
+            match regions.get(index) {
+                Some(saved) if saved.begin <= *page && !whole[index] => {
+                    let offset = (*page - saved.begin) as usize;
+                    let end = (offset + PAGE_SIZE as usize).min(saved.data.len());
+                    self.mem_write(*page, &saved.data[offset..end]).unwrap();
+                },
+                // written memory the snapshot did not have is unmapped by now
+                _ => {},
+            }
+        }
+    }
+
+    /// Close fds opened since the snapshot, bring back closed ones and rewind the offsets.
+    fn restore_files(&mut self, files: &[SavedFile]) {
+        let opened: Vec<RawFd> = self.filesystem.open_files.keys()
+            .filter(|fd| !files.iter().any(|saved| saved.file.fd == **fd))
+            .cloned()
+            .collect();
+        for fd in opened {
+            self.filesystem.close(fd);
+        }
+
+        // what is left open is the guest's, the numbers it closed may have been reused since
+        let guest: HashSet<RawFd> = self.filesystem.open_files.keys().cloned().collect();
+        self.filesystem.open_files.clear();
+        for saved in files.iter() {
+            if !guest.contains(&saved.file.fd) && fd_in_use(saved.file.fd) {
+                utilities::log(&format!("snapshot: fd {} is taken on the host, {} is not restored", saved.file.fd, saved.file.path), utilities::DebugLevel::ERROR);
+                continue;
+            }
+            unsafe {
+                libc::dup2(saved.dup, saved.file.fd);
+                libc::lseek(saved.file.fd, saved.offset, libc::SEEK_SET);
+            }
+            self.filesystem.open_files.insert(saved.file.fd, saved.file.clone());
+        }
+    }
+}
@@ -0,0 +1,179 @@
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+
+use crate::utilities;
+use crate::utilities::Fnv;
+use crate::core::rudroid::Emulator;
+use crate::core::unicorn::arch::arm64::RegisterARM64;
+
+use super::file::VERSION;
+
+const CACHE_ENV_VAR : &str = "RUDROID_CACHE";
+
+/// Names, sizes and modification times of everything below `root`, in a stable order. Contents
+/// are left out, a rootfs is too big to read on every start.
+fn hash_tree(hash: &mut Fnv, root: &Path) -> io::Result<()> {
+    let metadata = match fs::symlink_metadata(root) {
+        Ok(metadata) => metadata,
+        // a mount of something that is not there yet
+        Err(error) if error.kind() == io::ErrorKind::NotFound => {
+            hash.write_str("missing");
+            return Ok(());
+        },
+        Err(error) => return Err(error),
+    };
+
+    if metadata.file_type().is_symlink() {
+        hash.write_str(&fs::read_link(root)?.to_string_lossy());
+        return Ok(());
+    }
+
+    if !metadata.is_dir() {
+        let modified = metadata.modified()?.duration_since(UNIX_EPOCH).unwrap_or_default();
+        hash.write_u64(metadata.len());
+        hash.write_u64(modified.as_secs());
+        hash.write_u64(modified.subsec_nanos() as u64);
+        return Ok(());
+    }
+
+    let mut entries: Vec<PathBuf> = fs::read_dir(root)?.filter_map(|entry| entry.ok()).map(|entry| entry.path()).collect();
+    entries.sort();
+    for entry in entries {
+        hash.write_str(&entry.file_name().unwrap_or_default().to_string_lossy());
+        hash_tree(hash, &entry)?;
+    }
+    hash.write_str("..");
+    Ok(())
+}
+
+/// `$RUDROID_CACHE`, else `$XDG_CACHE_HOME/rudroid` or `~/.cache/rudroid`.
+pub fn default_cache_dir() -> PathBuf {
+    if let Ok(dir) = std::env::var(CACHE_ENV_VAR) {
+        return PathBuf::from(dir);
+    }
+    if let Ok(dir) = std::env::var("XDG_CACHE_HOME") {
+        return PathBuf::from(dir).join("rudroid");
+    }
+    match std::env::var("HOME") {
+        Ok(home) => PathBuf::from(home).join(".cache").join("rudroid"),
+        Err(_) => std::env::temp_dir().join("rudroid-cache"),
+    }
+}
+
+/// Where the state right after the linker is kept between runs, like zygote keeps a preloaded
+/// process around. Entries are named after the ELF and the hash of everything the linker's
+/// work depends on, so a changed input misses and its stale entry is replaced.
+pub struct ZygoteCache {
+    pub dir     : PathBuf,
+    prefix      : String,
+    key         : u64,
+}
+
+impl ZygoteCache {
+    pub fn path(&self) -> PathBuf {
+        self.dir.join(format!("{}-{:016x}.snap", self.prefix, self.key))
+    }
+
+    /// Entries of the same ELF made from other inputs.
+    fn stale_entries(&self) -> Vec<PathBuf> {
+        let current = self.path();
+        let entries = match fs::read_dir(&self.dir) {
+            Ok(entries) => entries,
+            Err(_) => return Vec::new(),
+        };
+
+        entries.filter_map(|entry| entry.ok()).map(|entry| entry.path())
+            .filter(|path| *path != current && path.extension().map(|extension| extension == "snap").unwrap_or(false))
+            .filter(|path| path.file_name().map(|name| name.to_string_lossy().starts_with(&format!("{}-", self.prefix))).unwrap_or(false))
+            .collect()
+    }
+}
+
+impl<D> Emulator<D> {
+    /// The cache entry for this emulator: keyed by the ELF's contents, the rootfs, the APK and
+    /// mounts, arguments, environment, rudroid's own binary and the caller's `config`,
+    /// anything else that changes what the linker does.
+    pub fn zygote_cache(&self, dir: &Path, config: &[String]) -> io::Result<ZygoteCache> {
+        let mut hash = Fnv::new();
+        hash.write_u64(VERSION as u64);
+
+        hash.write(&fs::read(&self.elf_path)?);
+
+        hash.write_str(&self.rootfs);
+        hash_tree(&mut hash, Path::new(&self.rootfs))?;
+
+        // mounted directories are extracted from the APK again on every run and hold the app's
+        // data, the APK stands in for them
+        let mut mounts: Vec<_> = self.filesystem.mounts.iter().collect();
+        mounts.sort();
+        for (guest, host) in mounts {
+            hash.write_str(guest);
+            hash.write_str(host);
+        }
+        if let Some(apk) = &self.apk {
+            hash_tree(&mut hash, Path::new(&apk.path))?;
+        }
+
+        for value in self.args.iter().chain(self.env.iter()).chain(config.iter()) {
+            hash.write_str(value);
+        }
+
+        // syscalls and hooks changing is a different process too
+        if let Ok(exe) = std::env::current_exe() {
+            hash_tree(&mut hash, &exe)?;
+        }
+
+        let name = Path::new(&self.elf_path).file_name().map(|name| name.to_string_lossy().into_owned()).unwrap_or_default();
+        let mut name_hash = Fnv::new();
+        name_hash.write_str(&self.elf_path);
+
+        Ok(ZygoteCache {
+            dir     : dir.to_path_buf(),
+            prefix  : format!("zygote-{}-{:08x}", name, name_hash.state as u32),
+            key     : hash.state,
+        })
+    }
+
+    /// `run_linker`, or the state it left behind on an earlier run with the same inputs.
+    /// Returns true when the cache was used.
+    pub fn run_linker_cached(&mut self, cache: &ZygoteCache) -> bool {
+        let path = cache.path();
+        if path.is_file() {
+            match self.load_snapshot(&path.to_string_lossy()) {
+                Ok(()) => {
+                    self.debug_print(format!("warm start from {}", path.display()));
+                    return true;
+                },
+                // anything short of a complete state is no use, start over
+                Err(error) => utilities::log(&format!("ignoring zygote cache {}: {}", path.display(), error), utilities::DebugLevel::INFO),
+            }
+        }
+
+        self.run_linker();
+
+        // only a linker that got all the way to the entry point is worth keeping
+        if self.reg_read(RegisterARM64::PC as i32).unwrap_or(0) != self.elf_entry {
+            return false;
+        }
+
+        for stale in cache.stale_entries() {
+            let _ = fs::remove_file(stale);
+        }
+
+        // written aside and renamed so a concurrent run never loads half a file
+        let partial = path.with_extension(format!("{}.tmp", std::process::id()));
+        let saved = fs::create_dir_all(&cache.dir)
+            .and_then(|_| self.save_snapshot(&partial.to_string_lossy()))
+            .and_then(|_| fs::rename(&partial, &path));
+        match saved {
+            Ok(()) => self.debug_print(format!("cached the linked process in {}", path.display())),
+            Err(error) => {
+                let _ = fs::remove_file(&partial);
+                utilities::log(&format!("failed to write zygote cache {}: {}", path.display(), error), utilities::DebugLevel::INFO);
+            },
+        }
+        false
+    }
+}
@@ -0,0 +1,98 @@
+use std::collections::HashMap;
+
+use gimli::{EndianSlice, LittleEndian};
+use xmas_elf::ElfFile;
+
+/// Address to source line table built from `.debug_line`.
+pub struct LineTable {
+    files   : Vec<String>,
+    // (address, index into files, line), sorted by address. u32::MAX as file index marks the
+    // end of a sequence
+    rows    : Vec<(u64, u32, u32)>,
+}
+
+impl LineTable {
+    /// None when the ELF carries no (uncompressed) DWARF line information.
+    pub fn load(elf: &ElfFile) -> Option<LineTable> {
+        elf.find_section_by_name(".debug_line")?;
+
+        let loader = |id: gimli::SectionId| -> Result<EndianSlice<LittleEndian>, gimli::Error> {
+            let data = elf.find_section_by_name(id.name()).map(|section| section.raw_data(elf)).unwrap_or(&[]);
+            Ok(EndianSlice::new(data, LittleEndian))
+        };
+        let dwarf = gimli::Dwarf::load(loader).ok()?;
+
+        let mut table = LineTable {
+            files   : Vec::new(),
+            rows    : Vec::new(),
+        };
+        let mut file_ids: HashMap<String, u32> = HashMap::new();
+
+        let mut units = dwarf.units();
+        while let Ok(Some(header)) = units.next() {
+            let unit = match dwarf.unit(header) {
+                Ok(unit) => unit,
+                Err(_) => continue,
+            };
+            let program = match unit.line_program.clone() {
+                Some(program) => program,
+                None => continue,
+            };
+
+            let mut rows = program.rows();
+            while let Ok(Some((header, row))) = rows.next_row() {
+                if row.end_sequence() {
+                    table.rows.push((row.address(), u32::MAX, 0));
+                    continue;
+                }
+
+                let file = match row.file(header) {
+                    Some(file) => file,
+                    None => continue,
+                };
+
+                let mut path = String::new();
+                if let Some(directory) = file.directory(header) {
+                    if let Ok(directory) = dwarf.attr_string(&unit, directory) {
+                        path.push_str(&directory.to_string_lossy());
+                        path.push('/');
+                    }
+                }
+                if let Ok(name) = dwarf.attr_string(&unit, file.path_name()) {
+                    path.push_str(&name.to_string_lossy());
+                }
+
+                let next_id = file_ids.len() as u32;
+                let id = *file_ids.entry(path.clone()).or_insert(next_id);
+                if id == next_id {
+                    table.files.push(path);
+                }
+
+                let line = row.line().map(|line| line.get()).unwrap_or(0);
+                table.rows.push((row.address(), id, line as u32));
+            }
+        }
+
+        if table.rows.is_empty() {
+            return None;
+        }
+
+        // a sequence may start where another one ended, the start has to win
+        table.rows.sort_by_key(|row| (row.0, row.1 != u32::MAX));
+        Some(table)
+    }
+
+    /// Source file and line of the unrelocated address `address`.
+    pub fn find(&self, address: u64) -> Option<(&str, u32)> {
+        let index = self.rows.partition_point(|row| row.0 <= address);
+        if index == 0 {
+            return None;
+        }
+
+        let (_, file, line) = self.rows[index - 1];
+        if file == u32::MAX {
+            return None;
+        }
+        Some((&self.files[file as usize], line))
+    }
+}
@@ -0,0 +1,289 @@
+pub mod dwarf;
+pub mod unwind;
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::rc::Rc;
+
+use xmas_elf::ElfFile;
+use xmas_elf::header;
+use xmas_elf::program;
+use xmas_elf::sections::SectionData;
+use xmas_elf::symbol_table::{Entry, Type};
+
+use super::rudroid::Emulator;
+use super::modules::Module;
+
+struct Symbol {
+    name    : String,
+    start   : u64,
+    size    : u64,
+}
+
+/// Symbols of one ELF, with addresses as found in the file.
+pub struct ModuleSymbols {
+    executable  : bool,
+    first_vaddr : u64,
+    symbols     : Vec<Symbol>,
+    lines       : Option<dwarf::LineTable>,
+    pub unwind  : Option<unwind::UnwindTable>,
+}
+
+impl ModuleSymbols {
+    pub fn load(host_path: &str) -> Option<ModuleSymbols> {
+        let data = std::fs::read(host_path).ok()?;
+        let elf = ElfFile::new(&data).ok()?;
+
+        let first_vaddr = elf.program_iter()
+            .filter(|ph| ph.get_type() == Ok(program::Type::Load))
+            .map(|ph| ph.virtual_addr() & !0xfff)
+            .min()
+            .unwrap_or(0);
+
+        let mut symbols = Vec::new();
+        for name in [".dynsym", ".symtab"].iter() {
+            let section = match elf.find_section_by_name(name) {
+                Some(section) => section,
+                None => continue,
+            };
+
+            match section.get_data(&elf) {
+                Ok(SectionData::DynSymbolTable64(entries)) => collect_symbols(&elf, entries, &mut symbols),
+                Ok(SectionData::SymbolTable64(entries)) => collect_symbols(&elf, entries, &mut symbols),
+                _ => {}
+            }
+        }
+
+        symbols.sort_by_key(|symbol| symbol.start);
+        symbols.dedup_by(|a, b| a.start == b.start && a.name == b.name);
+
+        Some(ModuleSymbols {
+            executable  : elf.header.pt2.type_().as_type() == header::Type::Executable,
+            first_vaddr : first_vaddr,
+            symbols     : symbols,
+            lines       : dwarf::LineTable::load(&elf),
+            unwind      : unwind::UnwindTable::load(&elf),
+        })
+    }
+
+    /// Difference between guest addresses and the addresses in the file, for a module whose
+    /// lowest mapping starts at `base`.
+    pub fn bias(&self, base: u64) -> u64 {
+        if self.executable { 0 } else { base - self.first_vaddr }
+    }
+
+    /// Symbol containing the unrelocated `address`, and the offset into it.
+    fn find(&self, address: u64) -> Option<(&str, u64)> {
+        let index = self.symbols.partition_point(|symbol| symbol.start <= address);
+        if index == 0 {
+            return None;
+        }
+
+        // sized symbols may nest (local labels inside functions), look back a little for one
+        // covering the address before giving up
+        let closest = &self.symbols[index - 1];
+        let symbol = if closest.size == 0 || address < closest.start + closest.size {
+            closest
+        }
+        else {
+            self.symbols[..index].iter().rev().take(16).find(|symbol| symbol.size != 0 && address < symbol.start + symbol.size)?
+        };
+
+        Some((&symbol.name, address - symbol.start))
+    }
+
+    fn lookup(&self, name: &str) -> Option<u64> {
+        self.symbols.iter().find(|symbol| symbol.name == name).map(|symbol| symbol.start)
+    }
+}
+
+fn collect_symbols<E: Entry>(elf: &ElfFile, entries: &[E], symbols: &mut Vec<Symbol>) {
+    for entry in entries {
+        let kind = entry.get_type();
+        if entry.shndx() == 0 || entry.value() == 0 || !(kind == Ok(Type::Func) || kind == Ok(Type::Object) || kind == Ok(Type::NoType)) {
+            continue;
+        }
+
+        let name = match entry.get_name(elf) {
+            // $x and $d only mark code and data
+            Ok(name) if !name.is_empty() && !name.starts_with('$') => name,
+            _ => continue,
+        };
+
+        symbols.push(Symbol {
+            name    : String::from(name),
+            start   : entry.value(),
+            size    : entry.size(),
+        });
+    }
+}
+
+/// Where a guest address lives, e.g. `libfoo.so!func+0x1c (foo.c:42)`.
+#[derive(Debug, Clone)]
+pub struct SymbolInfo {
+    pub module          : String,
+    pub module_offset   : u64,
+    pub symbol          : Option<(String, u64)>,
+    pub line            : Option<(String, u32)>,
+}
+
+impl SymbolInfo {
+    pub fn module_name(&self) -> &str {
+        self.module.rsplit('/').next().unwrap()
+    }
+}
+
+impl std::fmt::Display for SymbolInfo {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match &self.symbol {
+            Some((name, 0)) => write!(f, "{}!{}", self.module_name(), name)?,
+            Some((name, offset)) => write!(f, "{}!{}+{:#x}", self.module_name(), name, offset)?,
+            None => write!(f, "{}+{:#x}", self.module_name(), self.module_offset)?,
+        }
+        if let Some((file, line)) = &self.line {
+            write!(f, " ({}:{})", file.rsplit('/').next().unwrap(), line)?;
+        }
+        Ok(())
+    }
+}
+
+/// Symbol tables of every ELF mapped into the guest, parsed the first time an address inside
+/// them is looked up.
+pub struct Symbolizer {
+    modules     : RefCell<HashMap<String, Option<Rc<ModuleSymbols>>>>,
+    // mapping the last lookup hit, (start, end, path, base, bias, symbols)
+    last        : RefCell<Option<(u64, u64, String, u64, u64, Rc<ModuleSymbols>)>>,
+}
+
+impl Symbolizer {
+    pub fn new() -> Symbolizer {
+        Symbolizer {
+            modules : RefCell::new(HashMap::new()),
+            last    : RefCell::new(None),
+        }
+    }
+
+    fn module(&self, path: &str, host_path: &str) -> Option<Rc<ModuleSymbols>> {
+        self.modules.borrow_mut()
+            .entry(String::from(path))
+            .or_insert_with(|| ModuleSymbols::load(host_path).map(Rc::new))
+            .clone()
+    }
+
+    /// Forget everything, e.g. after the guest remapped its libraries.
+    pub fn clear(&self) {
+        self.modules.borrow_mut().clear();
+        self.last.borrow_mut().take();
+    }
+}
+
+impl<D> Emulator<D> {
+    /// Host path of a module the memory map knows by its guest path.
+    pub fn module_host_path(&self, description: &str) -> String {
+        if description == self.elf_path {
+            return String::from(description);
+        }
+        self.filesystem.change_path_if_special(description)
+    }
+
+    /// Module mapped at `address`: its path, its base and, when the file could be parsed, its
+    /// symbols with the load bias.
+    fn locate_module(&self, address: u64) -> Option<(String, u64, Option<(u64, Rc<ModuleSymbols>)>)> {
+        if let Some((start, end, path, base, bias, symbols)) = &*self.symbolizer.last.borrow() {
+            if address >= *start && address < *end {
+                return Some((path.clone(), *base, Some((*bias, symbols.clone()))));
+            }
+        }
+
+        // anonymous regions may have file mappings placed inside them, so only module mappings
+        // end up in the cache
+        let map_info = self.find_mapping(address)?;
+        let path = map_info.description.clone();
+        if path.starts_with('[') {
+            return None;
+        }
+
+        let (path, base) = match self.module_at(address) {
+            // libraries the linker mapped straight out of an APK
+            Some(module) if module.path.starts_with(&format!("{}!/", path)) => (module.path.clone(), module.base),
+            _ => {
+                let base = self.module_base(&path);
+                (path, base)
+            }
+        };
+        let symbols = match self.symbolizer.module(&path, &self.module_host_path(&path)) {
+            Some(symbols) => symbols,
+            None => return Some((path, base, None)),
+        };
+        let bias = symbols.bias(base);
+
+        *self.symbolizer.last.borrow_mut() = Some((map_info.memory_start, map_info.memory_end, path.clone(), base, bias, symbols.clone()));
+        Some((path, base, Some((bias, symbols))))
+    }
+
+    /// Parsed module mapped at `address` and its load bias.
+    pub fn module_symbols(&self, address: u64) -> Option<(Rc<ModuleSymbols>, u64)> {
+        self.locate_module(address)?.2.map(|(bias, symbols)| (symbols, bias))
+    }
+
+    pub fn symbol_info(&self, address: u64) -> Option<SymbolInfo> {
+        let (path, base, symbols) = self.locate_module(address)?;
+
+        let mut info = SymbolInfo {
+            module_offset   : address - base,
+            module          : path,
+            symbol          : None,
+            line            : None,
+        };
+
+        if let Some((bias, symbols)) = symbols {
+            let file_address = address - bias;
+            info.symbol = symbols.find(file_address).map(|(name, offset)| (String::from(name), offset));
+            info.line = symbols.lines.as_ref().and_then(|lines| lines.find(file_address)).map(|(file, line)| (String::from(file), line));
+        }
+
+        Some(info)
+    }
+
+    /// `libfoo.so!func+0x1c`, `libfoo.so+0x1234` without symbols, or the bare address.
+    pub fn symbolize(&self, address: u64) -> String {
+        match self.symbol_info(address) {
+            Some(info) => info.to_string(),
+            None => format!("{:#x}", address),
+        }
+    }
+
+    /// Guest address of `name` in a loaded module.
+    pub fn module_symbol(&self, module: &Module, name: &str) -> Option<u64> {
+        let symbols = self.symbolizer.module(&module.path, &self.module_host_path(&module.path))?;
+        symbols.lookup(name).map(|value| symbols.bias(module.base) + value)
+    }
+
+    /// Guest address of `name` or `libfoo.so!name` in the mapped modules.
+    pub fn resolve_symbol(&self, name: &str) -> Option<u64> {
+        let (module, name) = match name.find('!') {
+            Some(index) => (Some(&name[..index]), &name[index + 1..]),
+            None => (None, name),
+        };
+
+        let mut paths: Vec<String> = Vec::new();
+        for map_info in self.sorted_mappings() {
+            if !map_info.description.starts_with('[') && !paths.contains(&map_info.description) {
+                paths.push(map_info.description);
+            }
+        }
+
+        for path in paths.iter().filter(|path| module.map_or(true, |module| path.ends_with(module))) {
+            let symbols = match self.symbolizer.module(path, &self.module_host_path(path)) {
+                Some(symbols) => symbols,
+                None => continue,
+            };
+
+            if let Some(value) = symbols.lookup(name) {
+                return Some(symbols.bias(self.module_base(path)) + value);
+            }
+        }
+
+        None
+    }
+}
@@ -0,0 +1,205 @@
+use gimli::{BaseAddresses, CfaRule, EhFrame, EhFrameHdr, EndianSlice, LittleEndian, RegisterRule, UnwindContext, UnwindSection};
+use xmas_elf::ElfFile;
+
+use super::SymbolInfo;
+use crate::core::rudroid::Emulator;
+use crate::core::unicorn::arch::arm64::RegisterARM64;
+
+// x0-x30 and sp, numbered as in the AArch64 DWARF register mapping
+const DWARF_REGS    : usize = 32;
+const DWARF_FP      : usize = 29;
+const DWARF_LR      : usize = 30;
+const DWARF_SP      : usize = 31;
+
+pub const MAX_FRAMES: usize = 64;
+
+/// Call frame information of one module, `.eh_frame` and its `.eh_frame_hdr` search table.
+pub struct UnwindTable {
+    eh_frame        : Vec<u8>,
+    eh_frame_hdr    : Option<Vec<u8>>,
+    bases           : BaseAddresses,
+}
+
+impl UnwindTable {
+    pub fn load(elf: &ElfFile) -> Option<UnwindTable> {
+        let eh_frame = elf.find_section_by_name(".eh_frame")?;
+        let mut bases = BaseAddresses::default().set_eh_frame(eh_frame.address());
+
+        if let Some(text) = elf.find_section_by_name(".text") {
+            bases = bases.set_text(text.address());
+        }
+
+        let eh_frame_hdr = elf.find_section_by_name(".eh_frame_hdr").map(|hdr| {
+            bases = bases.clone().set_eh_frame_hdr(hdr.address());
+            hdr.raw_data(elf).to_vec()
+        });
+
+        Some(UnwindTable {
+            eh_frame        : eh_frame.raw_data(elf).to_vec(),
+            eh_frame_hdr    : eh_frame_hdr,
+            bases           : bases,
+        })
+    }
+
+    /// Caller's registers for a frame at the unrelocated `pc`, None without CFI for it.
+    fn unwind(&self, pc: u64, regs: &[Option<u64>; DWARF_REGS], read: &dyn Fn(u64) -> Option<u64>) -> Option<[Option<u64>; DWARF_REGS]> {
+        let mut eh_frame = EhFrame::new(&self.eh_frame, LittleEndian);
+        eh_frame.set_address_size(8);
+
+        let mut ctx = UnwindContext::new();
+
+        // the search table avoids walking every FDE, but it is optional
+        let hdr_table = self.eh_frame_hdr.as_ref()
+            .and_then(|hdr| EhFrameHdr::new(hdr, LittleEndian).parse(&self.bases, 8).ok());
+
+        let row = match hdr_table.as_ref().and_then(|hdr| hdr.table()) {
+            Some(table) => table.unwind_info_for_address(&eh_frame, &self.bases, &mut ctx, pc, EhFrame::cie_from_offset).ok()?,
+            None => eh_frame.unwind_info_for_address(&self.bases, &mut ctx, pc, EhFrame::cie_from_offset).ok()?,
+        };
+
+        let cfa = match row.cfa() {
+            CfaRule::RegisterAndOffset { register, offset } => {
+                let value = (*regs.get(register.0 as usize)?)?;
+                (value as i64 + offset) as u64
+            },
+            // DWARF expressions are not evaluated
+            _ => return None,
+        };
+
+        let mut caller = [None; DWARF_REGS];
+        for n in 0..DWARF_SP {
+            caller[n] = match row.register(gimli::Register(n as u16)) {
+                // registers without a rule keep their value (lr in leaf functions), an explicit
+                // undefined lr marks the outermost frame
+                RegisterRule::Undefined => if self.has_rule(row, n) { None } else { regs[n] },
+                RegisterRule::SameValue => regs[n],
+                RegisterRule::Offset(offset) => read((cfa as i64 + offset) as u64),
+                RegisterRule::ValOffset(offset) => Some((cfa as i64 + offset) as u64),
+                RegisterRule::Register(register) => *regs.get(register.0 as usize)?,
+                _ => None,
+            };
+        }
+
+        caller[DWARF_SP] = Some(cfa);
+        Some(caller)
+    }
+
+    fn has_rule(&self, row: &gimli::UnwindTableRow<EndianSlice<LittleEndian>>, register: usize) -> bool {
+        row.registers().any(|(r, _)| r.0 as usize == register)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum UnwindMethod {
+    Context,
+    Cfi,
+    FramePointer,
+    LinkRegister,
+}
+
+/// One frame of a guest backtrace. `pc` is the call site for every frame but the first.
+#[derive(Debug, Clone)]
+pub struct Frame {
+    pub pc      : u64,
+    pub sp      : u64,
+    pub method  : UnwindMethod,
+    pub info    : Option<SymbolInfo>,
+}
+
+impl std::fmt::Display for Frame {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match &self.info {
+            Some(info) => write!(f, "{:#018x} {}", self.pc, info),
+            None => write!(f, "{:#018x}", self.pc),
+        }
+    }
+}
+
+impl<D> Emulator<D> {
+    /// Whether `a` and `b` are in the same function, false when neither has a symbol.
+    fn same_function(&self, a: u64, b: u64) -> bool {
+        let start = |address: u64| self.symbol_info(address)?.symbol.map(|(_, offset)| address - offset);
+        let (a, b) = (start(a), start(b));
+        a.is_some() && a == b
+    }
+
+    /// Walk the guest stack from the current context using `.eh_frame` CFI, falling back to the
+    /// frame record chain (x29 -> [fp, lr]) for code without unwind information.
+    pub fn backtrace(&self, max_frames: usize) -> Vec<Frame> {
+        let mut regs = [None; DWARF_REGS];
+        for n in 0..29 {
+            regs[n] = self.reg_read(RegisterARM64::x(n)).ok();
+        }
+        regs[DWARF_FP] = self.reg_read(RegisterARM64::FP as i32).ok();
+        regs[DWARF_LR] = self.reg_read(RegisterARM64::LR as i32).ok();
+        regs[DWARF_SP] = self.reg_read(RegisterARM64::SP as i32).ok();
+
+        let read = |address: u64| -> Option<u64> {
+            self.mem_read_as_vec(address, 8).ok().map(|data| self.unpack_64(&data))
+        };
+
+        let mut pc = self.reg_read(RegisterARM64::PC as i32).unwrap_or(0);
+        let mut method = UnwindMethod::Context;
+        let mut frames: Vec<Frame> = Vec::new();
+
+        while frames.len() < max_frames && pc != 0 && self.find_mapping(pc).is_some() {
+            let sp = regs[DWARF_SP].unwrap_or(0);
+            frames.push(Frame {
+                pc      : pc,
+                sp      : sp,
+                method  : method,
+                info    : self.symbol_info(pc),
+            });
+
+            let cfi = self.module_symbols(pc).and_then(|(symbols, bias)| {
+                symbols.unwind.as_ref().and_then(|table| table.unwind(pc - bias, &regs, &read))
+            });
+
+            let caller = match cfi {
+                Some(caller) => {
+                    method = UnwindMethod::Cfi;
+                    caller
+                },
+                None => {
+                    let mut caller = regs;
+                    let fp = regs[DWARF_FP].unwrap_or(0);
+                    let record = if fp != 0 && fp % 16 == 0 && fp >= sp { read(fp).zip(read(fp + 8)) } else { None };
+
+                    match (frames.len(), record) {
+                        // without CFI the first frame may be a leaf that never saved lr. A function
+                        // that made a call has lr pointing back into itself, its record is right.
+                        (1, record) if record.map_or(true, |(_, ret)| Some(ret) != regs[DWARF_LR] && !self.same_function(pc, regs[DWARF_LR].unwrap_or(0))) => {
+                            method = UnwindMethod::LinkRegister;
+                            caller[DWARF_LR] = regs[DWARF_LR];
+                        },
+                        (_, Some((next_fp, ret))) => {
+                            method = UnwindMethod::FramePointer;
+                            caller[DWARF_FP] = Some(next_fp);
+                            caller[DWARF_LR] = Some(ret);
+                            caller[DWARF_SP] = Some(fp + 16);
+                        },
+                        _ => break,
+                    }
+                    caller
+                }
+            };
+
+            let ret = match caller[DWARF_LR] {
+                Some(ret) if ret != 0 => ret,
+                _ => break,
+            };
+
+            // the stack only grows down, a caller below its callee means garbage
+            let caller_sp = caller[DWARF_SP].unwrap_or(0);
+            if caller_sp < sp || (caller_sp == sp && ret.saturating_sub(4) == pc && method != UnwindMethod::LinkRegister) {
+                break;
+            }
+
+            regs = caller;
+            // report the call instruction, not the return address
+            pc = ret.saturating_sub(4);
+        }
+
+        frames
+    }
+}
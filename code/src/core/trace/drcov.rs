@@ -0,0 +1,274 @@
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::io::{self, Write};
+
+use byteorder::{ByteOrder, LittleEndian};
+
+use crate::utilities;
+use crate::core::rudroid::Emulator;
+use crate::core::unicorn::ffi;
+
+const BB_ENTRY_SIZE: usize = 8;
+
+#[derive(Debug, Clone)]
+struct Module {
+    path    : String,
+    base    : u64,
+    end     : u64,
+}
+
+/// Basic block coverage per module, written as drcov v2 for Lighthouse and bncov.
+pub struct Coverage {
+    pub output  : String,
+    // union with the blocks already in `output` instead of overwriting it
+    pub merge   : bool,
+
+    hook        : Option<ffi::uc_hook>,
+    seen        : HashSet<u64>,
+    modules     : Vec<Module>,
+    // (module index, offset from the module base) -> block size
+    blocks      : HashMap<(usize, u32), u16>,
+}
+
+impl Coverage {
+    pub fn new(output: &str, merge: bool) -> Coverage {
+        Coverage {
+            output  : String::from(output),
+            merge   : merge,
+            hook    : None,
+            seen    : HashSet::new(),
+            modules : Vec::new(),
+            blocks  : HashMap::new(),
+        }
+    }
+
+    pub fn block_count(&self) -> usize {
+        self.blocks.len()
+    }
+
+    fn record<D>(&mut self, emu: &Emulator<D>, address: u64, size: u32) {
+        if !self.seen.insert(address) {
+            return;
+        }
+
+        let path = match emu.find_mapping(address) {
+            // blocks in anonymous memory (jit, trampolines) have no module to belong to
+            Some(map_info) if !map_info.description.starts_with('[') && map_info.description != "munmap" => {
+                map_info.description.clone()
+            },
+            _ => return,
+        };
+
+        let index = match self.modules.iter().position(|module| module.path == path) {
+            Some(index) => index,
+            None => {
+                self.modules.push(Module {
+                    base    : emu.module_base(&path),
+                    end     : 0,
+                    path    : path,
+                });
+                self.modules.len() - 1
+            }
+        };
+
+        let offset = (address - self.modules[index].base) as u32;
+        self.blocks.insert((index, offset), size as u16);
+    }
+
+    /// Modules and blocks from an earlier drcov file, so several runs add up.
+    fn merge_file(&mut self, data: &[u8]) -> Option<()> {
+        let mut lines = Vec::new();
+        let mut position = 0;
+        while !lines.last().map_or(false, |line: &String| line.starts_with("BB Table:")) {
+            let end = position + data[position..].iter().position(|c| *c == b'\n')?;
+            lines.push(String::from_utf8_lossy(&data[position..end]).to_string());
+            position = end + 1;
+        }
+
+        let mut ids: HashMap<usize, usize> = HashMap::new();
+        for line in lines.iter() {
+            let columns: Vec<&str> = line.trim().splitn(7, ", ").collect();
+            if columns.len() != 7 {
+                continue;
+            }
+
+            let id = match columns[0].parse::<usize>() {
+                Ok(id) => id,
+                Err(_) => continue,
+            };
+            let base = u64::from_str_radix(columns[1].trim_start_matches("0x"), 16).ok()?;
+            let end  = u64::from_str_radix(columns[2].trim_start_matches("0x"), 16).ok()?;
+            let path = columns[6].to_string();
+
+            let index = match self.modules.iter().position(|module| module.path == path) {
+                Some(index) => index,
+                None => {
+                    self.modules.push(Module { path: path, base: base, end: end });
+                    self.modules.len() - 1
+                }
+            };
+            ids.insert(id, index);
+        }
+
+        for entry in data[position..].chunks_exact(BB_ENTRY_SIZE) {
+            let offset = LittleEndian::read_u32(&entry[0..4]);
+            let size   = LittleEndian::read_u16(&entry[4..6]);
+            let id     = LittleEndian::read_u16(&entry[6..8]) as usize;
+
+            if let Some(index) = ids.get(&id) {
+                self.blocks.entry((*index, offset)).or_insert(size);
+            }
+        }
+
+        Some(())
+    }
+
+    fn write<D>(&mut self, emu: &Emulator<D>) -> io::Result<()> {
+        if self.merge {
+            if let Ok(data) = fs::read(&self.output) {
+                if self.merge_file(&data).is_none() {
+                    return Err(io::Error::new(io::ErrorKind::InvalidData, format!("{} is not a drcov file", self.output)));
+                }
+            }
+        }
+
+        // modules may have grown since their first block ran
+        for module in self.modules.iter_mut() {
+            let end = emu.map_infos.values()
+                .filter(|map_info| map_info.description == module.path)
+                .map(|map_info| map_info.memory_end)
+                .max();
+            if let Some(end) = end {
+                module.end = end;
+            }
+        }
+
+        fs::write(&self.output, self.encode())
+    }
+
+    /// The drcov v2 file.
+    fn encode(&self) -> Vec<u8> {
+        let mut out = Vec::new();
+        writeln!(out, "DRCOV VERSION: 2").unwrap();
+        writeln!(out, "DRCOV FLAVOR: rudroid").unwrap();
+        writeln!(out, "Module Table: version 2, count {}", self.modules.len()).unwrap();
+        writeln!(out, "Columns: id, base, end, entry, checksum, timestamp, path").unwrap();
+        for (id, module) in self.modules.iter().enumerate() {
+            writeln!(out, "{:>3}, {:#018x}, {:#018x}, 0x0000000000000000, 0x00000000, 0x00000000, {}", id, module.base, module.end, module.path).unwrap();
+        }
+
+        let mut blocks: Vec<(&(usize, u32), &u16)> = self.blocks.iter().collect();
+        blocks.sort();
+
+        writeln!(out, "BB Table: {} bbs", blocks.len()).unwrap();
+        for ((index, offset), size) in blocks {
+            let mut entry = [0u8; BB_ENTRY_SIZE];
+            LittleEndian::write_u32(&mut entry[0..4], *offset);
+            LittleEndian::write_u16(&mut entry[4..6], *size);
+            LittleEndian::write_u16(&mut entry[6..8], *index as u16);
+            out.extend_from_slice(&entry);
+        }
+
+        out
+    }
+}
+
+impl<D> Emulator<D> {
+    /// Collect basic block coverage, written to `output` when the guest exits.
+    pub fn start_coverage(&mut self, output: &str, merge: bool) {
+        self.stop_coverage();
+
+        self.coverage = Some(Coverage::new(output, merge));
+
+        let hook = self.add_block_hook(1, 0, |emu: &mut Emulator<D>, address: u64, size: u32| {
+            if let Some(mut coverage) = emu.coverage.take() {
+                coverage.record(emu, address, size);
+                emu.coverage = Some(coverage);
+            }
+        }).expect("failed to add coverage hook");
+
+        self.coverage.as_mut().unwrap().hook = Some(hook);
+    }
+
+    /// Stop collecting and write the drcov file.
+    pub fn stop_coverage(&mut self) {
+        if let Some(mut coverage) = self.coverage.take() {
+            if let Some(hook) = coverage.hook.take() {
+                self.remove_hook(hook).unwrap();
+            }
+
+            match coverage.write(self) {
+                Ok(_) => self.debug_print(format!("{} blocks written to {}", coverage.block_count(), coverage.output)),
+                Err(e) => utilities::log(&format!("failed to write coverage to {}: {}", coverage.output, e), utilities::DebugLevel::ERROR),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn coverage(modules: &[(&str, u64, u64)], blocks: &[(usize, u32, u16)]) -> Coverage {
+        let mut coverage = Coverage::new("unused", true);
+        for (path, base, end) in modules {
+            coverage.modules.push(Module { path: String::from(*path), base: *base, end: *end });
+        }
+        for (index, offset, size) in blocks {
+            coverage.blocks.insert((*index, *offset), *size);
+        }
+        coverage
+    }
+
+    #[test]
+    fn merge_round_trip() {
+        let written = coverage(&[("/system/lib64/libc.so", 0x7000_0000, 0x7010_0000), ("/data/local/tmp/a b, c.so", 0x4000_0000, 0x4000_2000)],
+                               &[(0, 0x10, 4), (1, 0x20, 8), (1, 0x1000, 12)]);
+
+        let mut merged = Coverage::new("unused", true);
+        merged.merge_file(&written.encode()).unwrap();
+        assert_eq!(merged.modules.len(), 2);
+        // a ", " in the path is kept
+        assert_eq!(merged.modules[1].path, "/data/local/tmp/a b, c.so");
+        assert_eq!(merged.modules[1].base, 0x4000_0000);
+        assert_eq!(merged.modules[1].end, 0x4000_2000);
+        assert_eq!(merged.blocks, written.blocks);
+    }
+
+    #[test]
+    fn merge_maps_ids_to_known_modules() {
+        let written = coverage(&[("/a.so", 0x1000, 0x2000), ("/b.so", 0x3000, 0x4000)], &[(0, 0x10, 4), (1, 0x20, 8)]);
+
+        // b.so seen by this run already, the file's id 1 has to land on index 0
+        let mut merged = coverage(&[("/b.so", 0x5000, 0x6000)], &[(0, 0x30, 2)]);
+        merged.merge_file(&written.encode()).unwrap();
+        assert_eq!(merged.modules.len(), 2);
+        assert_eq!(merged.modules[1].path, "/a.so");
+        assert_eq!(merged.blocks.get(&(0, 0x20)), Some(&8));
+        assert_eq!(merged.blocks.get(&(0, 0x30)), Some(&2));
+        assert_eq!(merged.blocks.get(&(1, 0x10)), Some(&4));
+    }
+
+    #[test]
+    fn merge_rejects_garbage() {
+        let mut merged = Coverage::new("unused", true);
+        assert!(merged.merge_file(b"").is_none());
+        assert!(merged.merge_file(b"DRCOV VERSION: 2\nno table").is_none());
+        assert!(merged.merge_file(b"  0, 0xzz, 0x10, 0x0, 0x0, 0x0, /a.so\nBB Table: 0 bbs\n").is_none());
+    }
+
+    #[test]
+    fn merge_ignores_unknown_ids_and_partial_entries() {
+        let mut data = b"  0, 0x1000, 0x2000, 0x0, 0x0, 0x0, /a.so\r\nBB Table: 2 bbs\n".to_vec();
+        // id 0, then id 5 which is not in the module table, then a cut off entry
+        data.extend_from_slice(&[0x10, 0, 0, 0, 4, 0, 0, 0]);
+        data.extend_from_slice(&[0x20, 0, 0, 0, 4, 0, 5, 0]);
+        data.extend_from_slice(&[0x30, 0, 0]);
+
+        let mut merged = Coverage::new("unused", true);
+        merged.merge_file(&data).unwrap();
+        assert_eq!(merged.modules[0].path, "/a.so");
+        assert_eq!(merged.blocks.len(), 1);
+        assert_eq!(merged.blocks.get(&(0, 0x10)), Some(&4));
+    }
+}
@@ -0,0 +1,416 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::rc::Rc;
+
+use xmas_elf::ElfFile;
+use xmas_elf::sections::SectionData;
+use xmas_elf::symbol_table::Entry;
+
+use crate::utilities;
+use crate::core::interceptor::Invocation;
+use crate::core::modules::Module;
+use crate::core::rudroid::Emulator;
+use crate::core::unicorn::arch::arm64::RegisterARM64;
+
+const R_AARCH64_JUMP_SLOT   : u32 = 1026;
+const PLT_HEADER_SIZE       : u64 = 32;
+const PLT_ENTRY_SIZE        : u64 = 16;
+const MAX_STRING            : usize = 64;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Arg {
+    Int,
+    Long,
+    Size,
+    Hex,
+    Ptr,
+    Str,
+    Fd,
+    Char,
+    Double,
+    Void,
+}
+
+use Arg::*;
+
+// name, arguments, return value
+const PROTOTYPES: &[(&str, &[Arg], Arg)] = &[
+    ("strlen",              &[Str], Size),
+    ("strnlen",             &[Str, Size], Size),
+    ("strcmp",              &[Str, Str], Int),
+    ("strncmp",             &[Str, Str, Size], Int),
+    ("strcasecmp",          &[Str, Str], Int),
+    ("strcpy",              &[Ptr, Str], Ptr),
+    ("strncpy",             &[Ptr, Str, Size], Ptr),
+    ("strcat",              &[Ptr, Str], Ptr),
+    ("strchr",              &[Str, Char], Ptr),
+    ("strrchr",             &[Str, Char], Ptr),
+    ("strstr",              &[Str, Str], Ptr),
+    ("strdup",              &[Str], Ptr),
+    ("strtol",              &[Str, Ptr, Int], Long),
+    ("strtoul",             &[Str, Ptr, Int], Long),
+    ("atoi",                &[Str], Int),
+    ("memcpy",              &[Ptr, Ptr, Size], Ptr),
+    ("memmove",             &[Ptr, Ptr, Size], Ptr),
+    ("memset",              &[Ptr, Char, Size], Ptr),
+    ("memcmp",              &[Ptr, Ptr, Size], Int),
+    ("memchr",              &[Ptr, Char, Size], Ptr),
+    ("malloc",              &[Size], Ptr),
+    ("calloc",              &[Size, Size], Ptr),
+    ("realloc",             &[Ptr, Size], Ptr),
+    ("free",                &[Ptr], Void),
+    ("open",                &[Str, Hex, Hex], Fd),
+    ("openat",              &[Fd, Str, Hex, Hex], Fd),
+    ("close",               &[Fd], Int),
+    ("read",                &[Fd, Ptr, Size], Long),
+    ("write",               &[Fd, Ptr, Size], Long),
+    ("lseek",               &[Fd, Long, Int], Long),
+    ("fopen",               &[Str, Str], Ptr),
+    ("fdopen",              &[Fd, Str], Ptr),
+    ("fclose",              &[Ptr], Int),
+    ("fread",               &[Ptr, Size, Size, Ptr], Size),
+    ("fwrite",              &[Ptr, Size, Size, Ptr], Size),
+    ("fgets",               &[Ptr, Int, Ptr], Ptr),
+    ("fputs",               &[Str, Ptr], Int),
+    ("puts",                &[Str], Int),
+    ("putchar",             &[Char], Int),
+    ("printf",              &[Str], Int),
+    ("fprintf",             &[Ptr, Str], Int),
+    ("sprintf",             &[Ptr, Str], Int),
+    ("snprintf",            &[Ptr, Size, Str], Int),
+    ("getenv",              &[Str], Str),
+    ("setenv",              &[Str, Str, Int], Int),
+    ("dlopen",              &[Str, Hex], Ptr),
+    ("dlsym",               &[Ptr, Str], Ptr),
+    ("dlclose",             &[Ptr], Int),
+    ("mmap",                &[Ptr, Size, Hex, Hex, Fd, Long], Ptr),
+    ("munmap",              &[Ptr, Size], Int),
+    ("mprotect",            &[Ptr, Size, Hex], Int),
+    ("exit",                &[Int], Void),
+    ("abort",               &[], Void),
+    ("__android_log_print", &[Int, Str, Str], Int),
+    ("__android_log_write", &[Int, Str, Str], Int),
+    ("pthread_mutex_lock",  &[Ptr], Int),
+    ("pthread_mutex_unlock",&[Ptr], Int),
+    ("sin",                 &[Double], Double),
+    ("cos",                 &[Double], Double),
+    ("tan",                 &[Double], Double),
+    ("sqrt",                &[Double], Double),
+    ("pow",                 &[Double, Double], Double),
+    ("exp",                 &[Double], Double),
+    ("log",                 &[Double], Double),
+    ("floor",               &[Double], Double),
+    ("ceil",                &[Double], Double),
+    ("fabs",                &[Double], Double),
+];
+
+#[derive(Debug, Clone)]
+pub struct LtraceOptions {
+    // imports of every loaded library, not only the main executable
+    pub all_modules : bool,
+    // `lib!symbol`, `symbol` or `lib!*`, `*` matches anything. everything when empty
+    pub include     : Vec<String>,
+    pub exclude     : Vec<String>,
+    // stdout when not set
+    pub output      : Option<String>,
+}
+
+impl LtraceOptions {
+    pub fn new() -> LtraceOptions {
+        LtraceOptions {
+            all_modules : false,
+            include     : Vec::new(),
+            exclude     : Vec::new(),
+            output      : None,
+        }
+    }
+}
+
+/// A PLT slot of a traced module.
+struct Import {
+    symbol  : String,
+    // module the call comes from
+    from    : String,
+    got     : u64,
+}
+
+/// Writes one line per call made through a PLT:
+///
+/// `hello -> libc.so!strcmp("abc", "abd") = -1`
+///
+/// A call that makes traced calls of its own is split into `<unfinished ...>` and
+/// `<... strcmp resumed>` lines around them.
+pub struct Ltrace {
+    options         : LtraceOptions,
+    out             : Box<dyn Write>,
+    module_callback : Option<usize>,
+    listeners       : Vec<usize>,
+
+    // plt slot -> library the GOT points to, None when filtered out
+    resolved        : HashMap<u64, Option<String>>,
+    depth           : usize,
+    // line of the innermost call, until it returns or calls something traced
+    open_line       : Option<String>,
+
+    pub count       : u64,
+}
+
+impl Ltrace {
+    pub fn new(options: LtraceOptions) -> io::Result<Ltrace> {
+        let out: Box<dyn Write> = match &options.output {
+            Some(path) => Box::new(BufWriter::new(File::create(path)?)),
+            None => Box::new(BufWriter::new(io::stdout())),
+        };
+
+        Ok(Ltrace {
+            options         : options,
+            out             : out,
+            module_callback : None,
+            listeners       : Vec::new(),
+            resolved        : HashMap::new(),
+            depth           : 0,
+            open_line       : None,
+            count           : 0,
+        })
+    }
+
+    fn traced(&self, library: &str, symbol: &str) -> bool {
+        let matches = |pattern: &String| -> bool {
+            match pattern.find('!') {
+                Some(index) => glob(&pattern[..index], library) && glob(&pattern[index + 1..], symbol),
+                None => glob(pattern, symbol),
+            }
+        };
+
+        (self.options.include.is_empty() || self.options.include.iter().any(matches)) && !self.options.exclude.iter().any(matches)
+    }
+
+    /// Library the call goes to, None when it should not be traced.
+    fn library<D>(&mut self, emu: &Emulator<D>, import: &Import, plt: u64) -> Option<String> {
+        if let Some(library) = self.resolved.get(&plt) {
+            return library.clone();
+        }
+
+        // bionic binds everything at load time, the GOT already holds the target
+        let target = emu.mem_read_as_vec(import.got, 8).ok().map(|data| emu.unpack_64(&data))?;
+        let library = match emu.module_at(target) {
+            Some(module) => module.name.clone(),
+            None => return None,
+        };
+
+        let library = if self.traced(&library, &import.symbol) { Some(library) } else { None };
+        self.resolved.insert(plt, library.clone());
+        library
+    }
+
+    fn on_enter<D>(&mut self, emu: &Emulator<D>, import: &Import, invocation: &Invocation) {
+        let library = match self.library(emu, import, invocation.function) {
+            Some(library) => library,
+            None => return,
+        };
+        self.count += 1;
+
+        if let Some(line) = self.open_line.take() {
+            writeln!(self.out, "{} <unfinished ...>", line).expect("failed to write ltrace");
+        }
+
+        let args = match prototype(&import.symbol) {
+            Some((args, _)) => {
+                let (mut int, mut float) = (0, 0);
+                args.iter().map(|arg| {
+                    if *arg == Double {
+                        float += 1;
+                        format!("{}", read_double(emu, float - 1))
+                    }
+                    else {
+                        int += 1;
+                        format_value(emu, *arg, invocation.args[int - 1])
+                    }
+                }).collect::<Vec<String>>()
+            },
+            None => invocation.args[..4].iter().map(|arg| format!("{:#x}", arg)).collect(),
+        };
+
+        self.open_line = Some(format!("{}{} -> {}!{}({})", "  ".repeat(self.depth), import.from, library, import.symbol, args.join(", ")));
+        self.depth += 1;
+    }
+
+    fn on_leave<D>(&mut self, emu: &Emulator<D>, import: &Import, invocation: &Invocation) {
+        if self.resolved.get(&invocation.function).map_or(true, |library| library.is_none()) {
+            return;
+        }
+        self.depth = self.depth.saturating_sub(1);
+
+        let retval = match prototype(&import.symbol) {
+            Some((_, Void)) => String::from("<void>"),
+            Some((_, Double)) => format!("{}", read_double(emu, 0)),
+            Some((_, kind)) => format_value(emu, kind, invocation.retval),
+            None => format!("{:#x}", invocation.retval),
+        };
+
+        match self.open_line.take() {
+            Some(line) => writeln!(self.out, "{} = {}", line, retval),
+            None => writeln!(self.out, "{}<... {} resumed> = {}", "  ".repeat(self.depth), import.symbol, retval),
+        }.expect("failed to write ltrace");
+    }
+
+    fn finish(&mut self) -> io::Result<()> {
+        if let Some(line) = self.open_line.take() {
+            writeln!(self.out, "{} <unfinished ...>", line)?;
+        }
+        self.out.flush()
+    }
+}
+
+fn prototype(symbol: &str) -> Option<(&'static [Arg], Arg)> {
+    PROTOTYPES.iter().find(|(name, _, _)| *name == symbol).map(|(_, args, retval)| (*args, *retval))
+}
+
+fn format_value<D>(emu: &Emulator<D>, kind: Arg, value: u64) -> String {
+    match kind {
+        Int | Fd => format!("{}", value as i32),
+        Long => format!("{}", value as i64),
+        Size => format!("{}", value),
+        Char => format!("{:?}", (value as u8) as char),
+        Ptr | Str if value == 0 => String::from("NULL"),
+        Str => match emu.read_c_string(value, MAX_STRING + 1) {
+            Some(string) if string.len() > MAX_STRING => format!("{:?}...", &string[..MAX_STRING]),
+            Some(string) => format!("{:?}", string),
+            None => format!("{:#x}", value),
+        },
+        _ => format!("{:#x}", value),
+    }
+}
+
+fn read_double<D>(emu: &Emulator<D>, n: usize) -> f64 {
+    match emu.reg_read_long(RegisterARM64::v(n)) {
+        Ok(value) if value.len() >= 8 => f64::from_le_bytes([value[0], value[1], value[2], value[3], value[4], value[5], value[6], value[7]]),
+        _ => 0.0,
+    }
+}
+
+/// `*` matches any run of characters, everything else itself.
+fn glob(pattern: &str, text: &str) -> bool {
+    match pattern.find('*') {
+        None => pattern == text,
+        Some(index) => {
+            let (prefix, rest) = (&pattern[..index], &pattern[index + 1..]);
+            if !text.starts_with(prefix) {
+                return false;
+            }
+            let text = &text[prefix.len()..];
+            (0..=text.len()).filter(|i| text.is_char_boundary(*i)).any(|i| glob(rest, &text[i..]))
+        }
+    }
+}
+
+/// PLT slots of `module` as (plt entry, symbol, GOT slot), from `.rela.plt` in file order.
+fn plt_imports<D>(emu: &Emulator<D>, module: &Module) -> Vec<(u64, String, u64)> {
+    let data = match std::fs::read(emu.module_host_path(&module.path)) {
+        Ok(data) => data,
+        Err(_) => return Vec::new(),
+    };
+    let elf = match ElfFile::new(&data) {
+        Ok(elf) => elf,
+        Err(_) => return Vec::new(),
+    };
+
+    let (plt, rela_plt, dynsym) = match (elf.find_section_by_name(".plt"), elf.find_section_by_name(".rela.plt"), elf.find_section_by_name(".dynsym")) {
+        (Some(plt), Some(rela_plt), Some(dynsym)) => (plt, rela_plt, dynsym),
+        _ => return Vec::new(),
+    };
+
+    let symbols = match dynsym.get_data(&elf) {
+        Ok(SectionData::DynSymbolTable64(entries)) => entries,
+        _ => return Vec::new(),
+    };
+
+    let relocations = match rela_plt.get_data(&elf) {
+        Ok(SectionData::Rela64(relocations)) => relocations,
+        _ => return Vec::new(),
+    };
+
+    let mut imports = Vec::new();
+    let mut slot = 0;
+    for relocation in relocations.iter().filter(|relocation| relocation.get_type() == R_AARCH64_JUMP_SLOT) {
+        let name = symbols.get(relocation.get_symbol_table_index() as usize).and_then(|symbol| symbol.get_name(&elf).ok());
+        if let Some(name) = name {
+            let entry = module.bias + plt.address() + PLT_HEADER_SIZE + PLT_ENTRY_SIZE * slot;
+            imports.push((entry, String::from(name), module.bias + relocation.get_offset()));
+        }
+        slot += 1;
+    }
+    imports
+}
+
+impl<D> Emulator<D> {
+    /// Trace calls made through the PLT of the main executable, or of every module, as they
+    /// get loaded.
+    pub fn start_ltrace(&mut self, options: LtraceOptions) -> io::Result<()> {
+        self.stop_ltrace();
+
+        self.ltrace = Some(Ltrace::new(options)?);
+
+        let callback = self.on_module_load(|emu: &mut Emulator<D>, module: &Module| {
+            let all_modules = match &emu.ltrace {
+                Some(ltrace) => ltrace.options.all_modules,
+                None => return,
+            };
+            if !all_modules && module.path != emu.elf_path {
+                return;
+            }
+
+            let imports = plt_imports(emu, module);
+            emu.debug_print(format!("ltrace: {} imports in {}", imports.len(), module.name));
+
+            for (entry, symbol, got) in imports {
+                let import = Rc::new(Import {
+                    symbol  : symbol,
+                    from    : module.name.clone(),
+                    got     : got,
+                });
+                let leave_import = import.clone();
+
+                let listener = emu.hook_address(entry, move |emu: &mut Emulator<D>, invocation: &mut Invocation| {
+                    if let Some(mut ltrace) = emu.ltrace.take() {
+                        ltrace.on_enter(emu, &import, invocation);
+                        emu.ltrace = Some(ltrace);
+                    }
+                }, move |emu: &mut Emulator<D>, invocation: &mut Invocation| {
+                    if let Some(mut ltrace) = emu.ltrace.take() {
+                        ltrace.on_leave(emu, &leave_import, invocation);
+                        emu.ltrace = Some(ltrace);
+                    }
+                });
+
+                if let Some(ltrace) = emu.ltrace.as_mut() {
+                    ltrace.listeners.push(listener);
+                }
+            }
+        });
+
+        // the callback ran for the modules already loaded, the tracer is still in place
+        if let Some(ltrace) = self.ltrace.as_mut() {
+            ltrace.module_callback = Some(callback);
+        }
+        Ok(())
+    }
+
+    /// Stop tracing calls and flush the output.
+    pub fn stop_ltrace(&mut self) {
+        if let Some(mut ltrace) = self.ltrace.take() {
+            if let Some(callback) = ltrace.module_callback.take() {
+                self.remove_module_callback(callback);
+            }
+            for listener in ltrace.listeners.drain(..) {
+                self.unhook_symbol(listener);
+            }
+
+            match ltrace.finish() {
+                Ok(_) => self.debug_print(format!("{} library calls traced", ltrace.count)),
+                Err(e) => utilities::log(&format!("failed to write ltrace output: {}", e), utilities::DebugLevel::ERROR),
+            }
+        }
+    }
+}
@@ -0,0 +1,303 @@
+pub mod drcov;
+pub mod tenet;
+pub mod ltrace;
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+
+use capstone::prelude::*;
+
+use super::rudroid::Emulator;
+use super::unicorn::ffi;
+use super::unicorn::arch::arm64::RegisterARM64;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TraceMode {
+    Instruction,
+    Block,
+}
+
+#[derive(Debug, Clone)]
+pub struct TraceOptions {
+    pub mode        : TraceMode,
+    pub disassemble : bool,
+    // append the registers each traced instruction or block changed
+    pub registers   : bool,
+    // only trace addresses inside one of these [start, end) ranges
+    pub ranges      : Vec<(u64, u64)>,
+    // only trace modules whose path ends with one of these names
+    pub modules     : Vec<String>,
+    // stdout when not set
+    pub output      : Option<String>,
+}
+
+impl TraceOptions {
+    pub fn new(mode: TraceMode) -> TraceOptions {
+        TraceOptions {
+            mode        : mode,
+            disassemble : false,
+            registers   : false,
+            ranges      : Vec::new(),
+            modules     : Vec::new(),
+            output      : None,
+        }
+    }
+}
+
+struct Location {
+    start       : u64,
+    end         : u64,
+    path        : String,
+}
+
+/// Address range and module filter shared by the tracers.
+struct Scope {
+    ranges      : Vec<(u64, u64)>,
+    modules     : Vec<String>,
+    location    : Option<Location>,
+}
+
+impl Scope {
+    fn new(ranges: &[(u64, u64)], modules: &[String]) -> Scope {
+        Scope {
+            ranges      : ranges.to_vec(),
+            modules     : modules.to_vec(),
+            location    : None,
+        }
+    }
+
+    fn contains<D>(&mut self, emu: &Emulator<D>, address: u64) -> bool {
+        if !self.ranges.is_empty() && !self.ranges.iter().any(|(start, end)| address >= *start && address < *end) {
+            return false;
+        }
+
+        if self.modules.is_empty() {
+            return true;
+        }
+
+        self.locate(emu, address);
+        match &self.location {
+            Some(location) => self.modules.iter().any(|module| location.path.ends_with(module.as_str())),
+            None => false,
+        }
+    }
+
+    fn locate<D>(&mut self, emu: &Emulator<D>, address: u64) -> Option<&Location> {
+        // anonymous regions may have file mappings placed inside them, only modules are cached
+        let cached = match &self.location {
+            Some(location) => address >= location.start && address < location.end && location.path.starts_with('/'),
+            None => false,
+        };
+
+        if !cached {
+            self.location = emu.find_mapping(address).map(|map_info| {
+                Location {
+                    start   : map_info.memory_start,
+                    end     : map_info.memory_end,
+                    path    : map_info.description.clone(),
+                }
+            });
+        }
+
+        self.location.as_ref()
+    }
+}
+
+/// Writes one line per instruction or basic block:
+///
+/// `libc.so!strlen+0x14 ldr x0, [x1, #8] ; x0=0x7fffb7e01000`
+///
+/// Addresses outside any module are written as is.
+pub struct Tracer {
+    options     : TraceOptions,
+    out         : Box<dyn Write>,
+    hook        : Option<ffi::uc_hook>,
+    cs          : Capstone,
+
+    registers   : Vec<(String, i32)>,
+    last_values : Vec<u64>,
+    // line of the last traced instruction, waiting for the registers it changed
+    pending     : Option<String>,
+
+    disassembly : HashMap<u64, String>,
+    scope       : Scope,
+
+    pub count   : u64,
+}
+
+impl Tracer {
+    pub fn new(options: TraceOptions) -> io::Result<Tracer> {
+        let out: Box<dyn Write> = match &options.output {
+            Some(path) => Box::new(BufWriter::new(File::create(path)?)),
+            None => Box::new(BufWriter::new(io::stdout())),
+        };
+
+        let cs = Capstone::new()
+            .arm64()
+            .mode(arch::arm64::ArchMode::Arm)
+            .build()
+            .expect("failed to create capstone for ARM64");
+
+        let mut registers: Vec<(String, i32)> = (0..29).map(|n| (format!("x{}", n), RegisterARM64::x(n))).collect();
+        registers.push((String::from("fp"), RegisterARM64::FP as i32));
+        registers.push((String::from("lr"), RegisterARM64::LR as i32));
+        registers.push((String::from("sp"), RegisterARM64::SP as i32));
+        registers.push((String::from("nzcv"), RegisterARM64::NZCV as i32));
+
+        Ok(Tracer {
+            scope       : Scope::new(&options.ranges, &options.modules),
+            options     : options,
+            out         : out,
+            hook        : None,
+            cs          : cs,
+            last_values : vec![0; registers.len()],
+            registers   : registers,
+            pending     : None,
+            disassembly : HashMap::new(),
+            count       : 0,
+        })
+    }
+
+    fn record<D>(&mut self, emu: &Emulator<D>, address: u64, size: u32) {
+        if self.pending.is_some() {
+            self.flush_pending(emu);
+        }
+
+        if !self.scope.contains(emu, address) {
+            return;
+        }
+        self.count += 1;
+
+        let mut line = emu.symbolize(address);
+
+        if self.options.disassemble {
+            match self.options.mode {
+                TraceMode::Instruction => {
+                    line.push(' ');
+                    line.push_str(&self.disassemble(emu, address));
+                },
+                TraceMode::Block => {
+                    line.push_str(&format!(" [{:#x}]", size));
+                    for insn in (address..address + size as u64).step_by(4) {
+                        line.push_str("\n    ");
+                        line.push_str(&self.disassemble(emu, insn));
+                    }
+                },
+            }
+        }
+        else if self.options.mode == TraceMode::Block {
+            line.push_str(&format!(" [{:#x}]", size));
+        }
+
+        if self.options.registers {
+            for (i, (_, register)) in self.registers.iter().enumerate() {
+                self.last_values[i] = emu.reg_read(*register).unwrap_or(0);
+            }
+            self.pending = Some(line);
+        }
+        else {
+            writeln!(self.out, "{}", line).expect("failed to write trace");
+        }
+    }
+
+    fn flush_pending<D>(&mut self, emu: &Emulator<D>) {
+        let mut line = match self.pending.take() {
+            Some(line) => line,
+            None => return,
+        };
+
+        let mut changes = Vec::new();
+        for (i, (name, register)) in self.registers.iter().enumerate() {
+            let value = emu.reg_read(*register).unwrap_or(0);
+            if value != self.last_values[i] {
+                changes.push(format!("{}={:#x}", name, value));
+                self.last_values[i] = value;
+            }
+        }
+
+        if !changes.is_empty() {
+            line.push_str(" ; ");
+            line.push_str(&changes.join(" "));
+        }
+        writeln!(self.out, "{}", line).expect("failed to write trace");
+    }
+
+    fn disassemble<D>(&mut self, emu: &Emulator<D>, address: u64) -> String {
+        if let Some(text) = self.disassembly.get(&address) {
+            return text.clone();
+        }
+
+        let text = match emu.mem_read_as_vec(address, 4) {
+            Ok(code) => {
+                match self.cs.disasm_count(&code, address, 1) {
+                    Ok(insns) => {
+                        insns.iter().next().map(|insn| {
+                            format!("{} {}", insn.mnemonic().unwrap_or(""), insn.op_str().unwrap_or("")).trim_end().to_string()
+                        }).unwrap_or_else(|| String::from("(bad)"))
+                    },
+                    Err(_) => String::from("(bad)"),
+                }
+            },
+            Err(_) => String::from("(unreadable)"),
+        };
+
+        self.disassembly.insert(address, text.clone());
+        text
+    }
+
+    fn finish<D>(&mut self, emu: &Emulator<D>) {
+        self.flush_pending(emu);
+        self.out.flush().expect("failed to write trace");
+    }
+}
+
+impl<D> Emulator<D> {
+    /// Start tracing instructions or basic blocks as described by `options`.
+    pub fn start_trace(&mut self, options: TraceOptions) -> io::Result<()> {
+        self.stop_trace();
+
+        // a single range can be left to unicorn, anything else is filtered in the hook. register
+        // changes are taken at the next instruction, so that hook has to see every instruction
+        let (begin, end) = match (options.ranges.as_slice(), options.modules.is_empty() && !options.registers) {
+            ([(start, end)], true) => (*start, end - 1),
+            _ => (1, 0),
+        };
+        let mode = options.mode;
+
+        self.tracer = Some(Tracer::new(options)?);
+
+        let callback = |emu: &mut Emulator<D>, address: u64, size: u32| {
+            if let Some(mut tracer) = emu.tracer.take() {
+                tracer.record(emu, address, size);
+                emu.tracer = Some(tracer);
+            }
+        };
+
+        let hook = match mode {
+            TraceMode::Instruction => self.add_code_hook(begin, end, callback),
+            TraceMode::Block => self.add_block_hook(begin, end, callback),
+        }.expect("failed to add trace hook");
+
+        self.tracer.as_mut().unwrap().hook = Some(hook);
+        Ok(())
+    }
+
+    /// Stop tracing and flush the trace output.
+    pub fn stop_trace(&mut self) {
+        if let Some(mut tracer) = self.tracer.take() {
+            if let Some(hook) = tracer.hook.take() {
+                self.remove_hook(hook).unwrap();
+            }
+            tracer.finish(self);
+        }
+    }
+
+    /// Flush all trace and coverage output before the emulated process goes away.
+    pub fn finish_traces(&mut self) {
+        self.stop_trace();
+        self.stop_coverage();
+        self.stop_tenet();
+        self.stop_ltrace();
+    }
+}
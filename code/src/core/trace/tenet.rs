@@ -0,0 +1,145 @@
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+
+use super::Scope;
+use crate::utilities;
+use crate::core::rudroid::Emulator;
+use crate::core::unicorn::ffi;
+use crate::core::unicorn::arch::arm64::RegisterARM64;
+use crate::core::unicorn::unicorn_const::{HookType, MemType};
+
+/// Records a trace for the Tenet IDA plugin, one line per executed instruction:
+///
+/// `x0=0x1,sp=0x4ffffffde000,pc=0x7fffb7dd6004,mr=0x4ffffffde000:0100000000000000`
+///
+/// A line holds the registers changed since the previous line, the pc of the instruction and
+/// the memory it read and wrote. The first line holds every register.
+pub struct TenetRecorder {
+    out         : BufWriter<File>,
+    scope       : Scope,
+    hooks       : Vec<ffi::uc_hook>,
+
+    registers   : Vec<(String, i32)>,
+    last_values : Option<Vec<u64>>,
+    // line of the instruction being executed, its memory accesses are appended until the next one
+    pending     : Option<String>,
+
+    pub count   : u64,
+}
+
+impl TenetRecorder {
+    pub fn new(output: &str, ranges: &[(u64, u64)], modules: &[String]) -> io::Result<TenetRecorder> {
+        let mut registers: Vec<(String, i32)> = (0..29).map(|n| (format!("x{}", n), RegisterARM64::x(n))).collect();
+        registers.push((String::from("x29"), RegisterARM64::FP as i32));
+        registers.push((String::from("x30"), RegisterARM64::LR as i32));
+        registers.push((String::from("sp"), RegisterARM64::SP as i32));
+
+        Ok(TenetRecorder {
+            out         : BufWriter::new(File::create(output)?),
+            scope       : Scope::new(ranges, modules),
+            hooks       : Vec::new(),
+            registers   : registers,
+            last_values : None,
+            pending     : None,
+            count       : 0,
+        })
+    }
+
+    fn on_instruction<D>(&mut self, emu: &Emulator<D>, address: u64) {
+        self.flush_pending();
+
+        if !self.scope.contains(emu, address) {
+            return;
+        }
+        self.count += 1;
+
+        let values: Vec<u64> = self.registers.iter().map(|(_, register)| emu.reg_read(*register).unwrap_or(0)).collect();
+
+        let mut fields = Vec::new();
+        for (i, (name, _)) in self.registers.iter().enumerate() {
+            let changed = match &self.last_values {
+                Some(last_values) => last_values[i] != values[i],
+                None => true,
+            };
+            if changed {
+                fields.push(format!("{}={:#x}", name, values[i]));
+            }
+        }
+        fields.push(format!("pc={:#x}", address));
+
+        self.last_values = Some(values);
+        self.pending = Some(fields.join(","));
+    }
+
+    fn on_memory<D>(&mut self, emu: &Emulator<D>, mem_type: MemType, address: u64, size: usize, value: i64) {
+        let line = match self.pending.as_mut() {
+            Some(line) => line,
+            None => return,
+        };
+
+        let (kind, data) = match mem_type {
+            MemType::WRITE => ("mw", value.to_le_bytes()[..std::cmp::min(size, 8)].to_vec()),
+            _ => ("mr", emu.mem_read_as_vec(address, size).unwrap_or_default()),
+        };
+
+        let hex: String = data.iter().map(|b| format!("{:02x}", b)).collect();
+        line.push_str(&format!(",{}={:#x}:{}", kind, address, hex));
+    }
+
+    fn flush_pending(&mut self) {
+        if let Some(line) = self.pending.take() {
+            writeln!(self.out, "{}", line).expect("failed to write tenet trace");
+        }
+    }
+
+    fn finish(&mut self) -> io::Result<()> {
+        self.flush_pending();
+        self.out.flush()
+    }
+}
+
+impl<D> Emulator<D> {
+    /// Record a Tenet trace of the instructions inside `ranges` and `modules`, everything when
+    /// both are empty.
+    pub fn start_tenet(&mut self, output: &str, ranges: &[(u64, u64)], modules: &[String]) -> io::Result<()> {
+        self.stop_tenet();
+
+        self.tenet = Some(TenetRecorder::new(output, ranges, modules)?);
+
+        // every instruction has to be seen, it ends the line of the previous one
+        let code_hook = self.add_code_hook(1, 0, |emu: &mut Emulator<D>, address: u64, _size: u32| {
+            if let Some(mut tenet) = emu.tenet.take() {
+                tenet.on_instruction(emu, address);
+                emu.tenet = Some(tenet);
+            }
+        }).expect("failed to add tenet code hook");
+
+        let mut hooks = vec![code_hook];
+        for hook_type in [HookType::MEM_READ, HookType::MEM_WRITE].iter() {
+            let hook = self.add_mem_hook(*hook_type, 1, 0, |emu: &mut Emulator<D>, mem_type: MemType, address: u64, size: usize, value: i64| {
+                if let Some(mut tenet) = emu.tenet.take() {
+                    tenet.on_memory(emu, mem_type, address, size, value);
+                    emu.tenet = Some(tenet);
+                }
+            }).expect("failed to add tenet memory hook");
+            hooks.push(hook);
+        }
+
+        self.tenet.as_mut().unwrap().hooks = hooks;
+        Ok(())
+    }
+
+    /// Stop recording and flush the Tenet trace.
+    pub fn stop_tenet(&mut self) {
+        if let Some(mut tenet) = self.tenet.take() {
+            for hook in tenet.hooks.drain(..) {
+                self.remove_hook(hook).unwrap();
+            }
+
+            match tenet.finish() {
+                Ok(_) => self.debug_print(format!("{} instructions written to the tenet trace", tenet.count)),
+                Err(e) => utilities::log(&format!("failed to write tenet trace: {}", e), utilities::DebugLevel::ERROR),
+            }
+        }
+    }
+}
@@ -266,6 +266,31 @@ pub enum RegisterARM64 {
     // pseudo registers
     PC = 260,
     CPACR_EL1 =  261,
+
+    // thread registers
+    TPIDR_EL0 = 262,
+    TPIDRRO_EL0 = 263,
+    TPIDR_EL1 = 264,
+    PSTATE = 265,
 }
 
-pub const PAGE_ALIGN: u64 = 0x1000;
\ No newline at end of file
+pub const PAGE_ALIGN: u64 = 0x1000;
+impl RegisterARM64 {
+    /// Unicorn register id of the general purpose register `Xn`.
+    ///
+    /// X0..X28 are laid out contiguously, X29 and X30 are only exposed as FP and LR.
+    pub fn x(n: usize) -> i32 {
+        match n {
+            0..=28 => RegisterARM64::X0 as i32 + n as i32,
+            29 => RegisterARM64::FP as i32,
+            30 => RegisterARM64::LR as i32,
+            _ => panic!("there is no x{} on aarch64", n),
+        }
+    }
+
+    /// Unicorn register id of the SIMD register `Vn`.
+    pub fn v(n: usize) -> i32 {
+        assert!(n < 32, "there is no v{} on aarch64", n);
+        RegisterARM64::V0 as i32 + n as i32
+    }
+}
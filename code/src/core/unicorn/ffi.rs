@@ -90,6 +90,13 @@ pub struct InterruptHook<D> {
     pub callback: Box<dyn FnMut(&mut rudroid::Emulator<D>, u32)>
 }
 
+/// A removed hook, kept until no hook callback runs since it may be the one removing itself.
+pub enum RemovedHook<D> {
+    Code(Box<CodeHook<D>>),
+    Mem(Box<MemHook<D>>),
+    Interrupt(Box<InterruptHook<D>>),
+}
+
 pub struct InstructionInHook<D> {
     pub unicorn: *mut rudroid::Emulator<D>,
     pub callback: Box<dyn FnMut(&mut rudroid::Emulator<D>, u32, usize)>
@@ -110,7 +117,9 @@ pub extern "C" fn code_hook_proxy<D>(uc: uc_handle, address: u64, size: u32, use
     let mut unicorn = unsafe { &mut *(*user_data).unicorn };
     let callback = &mut unsafe { &mut *(*user_data).callback };
     assert_eq!(uc, unicorn.uc);
+    unicorn.hook_depth += 1;
     callback(&mut unicorn , address, size);
+    finish_hook(unicorn);
 }
 
 pub extern "C" fn mem_hook_proxy<D>(uc: uc_handle, 
@@ -123,12 +132,24 @@ pub extern "C" fn mem_hook_proxy<D>(uc: uc_handle,
     let mut unicorn = unsafe { &mut *(*user_data).unicorn };
     let callback = &mut unsafe { &mut *(*user_data).callback };
     assert_eq!(uc, unicorn.uc);
+    unicorn.hook_depth += 1;
     callback(&mut unicorn , mem_type, address, size as usize, value);
+    finish_hook(unicorn);
 }
 
 pub extern "C" fn intr_hook_proxy<D>(uc: uc_handle, value: u32, user_data: *mut InterruptHook<D>) {
     let mut unicorn = unsafe { &mut *(*user_data).unicorn };
     let callback = &mut unsafe { &mut *(*user_data).callback };
     assert_eq!(uc, unicorn.uc);
+    unicorn.hook_depth += 1;
     callback(&mut unicorn , value);
+    finish_hook(unicorn);
+}
+
+// after a callback returned, the hooks removed meanwhile can go once the outermost one did
+fn finish_hook<D>(unicorn: &mut rudroid::Emulator<D>) {
+    unicorn.hook_depth -= 1;
+    if unicorn.hook_depth == 0 {
+        unicorn.removed_hooks.clear();
+    }
 }
\ No newline at end of file
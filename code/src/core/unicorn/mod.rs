@@ -78,6 +78,10 @@ impl<D> Emulator<D> {
     pub fn mem_write(&mut self, address: u64, bytes: &[u8]) -> Result<(), uc_error> {
         let err = unsafe { ffi::uc_mem_write(self.uc, address, bytes.as_ptr(), bytes.len()) };
         if err == uc_error::OK {
+            // the write hook only sees guest code, snapshots need these too
+            if let Some(dirty) = self.dirty.as_mut() {
+                dirty.mark(address, bytes.len() as u64);
+            }
             Ok(())
         } else {
             Err(err)
@@ -136,6 +140,11 @@ impl<D> Emulator<D> {
     ) -> Result<(), uc_error> {
         let err = unsafe { ffi::uc_mem_unmap(self.uc, address, size) };
         if err == uc_error::OK {
+            // mapped again with the same bounds the region is zeroed without a single write,
+            // snapshot restores have to copy these pages back
+            if let Some(dirty) = self.dirty.as_mut() {
+                dirty.mark(address, size as u64);
+            }
             Ok(())
         } else {
             Err(err)
@@ -285,6 +294,40 @@ impl<D> Emulator<D> {
         }
     }
 
+    /// Add a basic block hook.
+    pub fn add_block_hook<F: 'static,>(
+        &mut self,
+        begin: u64,
+        end: u64,
+        callback: F,
+    ) -> Result<ffi::uc_hook, uc_error>
+    where F: FnMut(&mut Emulator<D>, u64, u32)
+    {
+        let mut hook_ptr = std::ptr::null_mut();
+        let mut user_data = Box::new(ffi::CodeHook {
+            unicorn: self,
+            callback: Box::new(callback),
+        });
+
+        let err = unsafe {
+            ffi::uc_hook_add(
+                self.uc,
+                &mut hook_ptr,
+                HookType::BLOCK,
+                ffi::code_hook_proxy::<D> as _,
+                user_data.as_mut() as *mut _ as _,
+                begin,
+                end,
+            )
+        };
+        if err == uc_error::OK {
+            unsafe { self }.code_hooks.insert(hook_ptr, user_data);
+            Ok(hook_ptr)
+        } else {
+            Err(err)
+        }
+    }
+
     /// Add a memory hook.
     pub fn add_mem_hook<F: 'static>(
         &mut self,
@@ -360,25 +403,26 @@ impl<D> Emulator<D> {
     ///
     /// `hook` is the value returned by `add_*_hook` functions.
     pub fn remove_hook(&mut self, hook: ffi::uc_hook) -> Result<(), uc_error> {
-        let handle = unsafe { self };
-        let err: uc_error;
-        // if handle.code_hooks.contains_key(&hook) || 
-        //     handle.mem_hooks.contains_key(&hook) ||
-        //     handle.intr_hooks.contains_key(&hook) ||
-        //     handle.insn_in_hooks.contains_key(&hook) ||
-        //     handle.insn_out_hooks.contains_key(&hook) ||
-        //     handle.insn_sys_hooks.contains_key(&hook) {
-            err = unsafe { ffi::uc_hook_del(handle.uc, hook) };
-            // handle.mem_hooks.remove(&hook);
-        // } else {
-        //     err = uc_error::HOOK;
-        // }
+        let err = unsafe { ffi::uc_hook_del(self.uc, hook) };
+        if err != uc_error::OK {
+            return Err(err);
+        }
 
-        if err == uc_error::OK {
-            Ok(())
+        let removed = if let Some(data) = self.code_hooks.remove(&hook) {
+            Some(ffi::RemovedHook::Code(data))
+        } else if let Some(data) = self.mem_hooks.remove(&hook) {
+            Some(ffi::RemovedHook::Mem(data))
         } else {
-            Err(err)
+            self.intr_hooks.remove(&hook).map(ffi::RemovedHook::Interrupt)
+        };
+
+        // a callback removing its own hook is still running, its closure goes when it returns
+        if let Some(removed) = removed {
+            if self.hook_depth > 0 {
+                self.removed_hooks.push(removed);
+            }
         }
+        Ok(())
     }
 
     /// Allocate and return an empty Unicorn context.
@@ -8,48 +8,475 @@ mod utilities;
 mod core;
 
 use std::env;
+use std::path::PathBuf;
 use xmas_elf::ElfFile;
+use xmas_elf::header;
 
 use crate::utilities::context_title;
+use crate::core::trace::{TraceMode, TraceOptions};
+use crate::core::trace::ltrace::LtraceOptions;
+use crate::core::android::jni::JValue;
+use crate::core::android::apk::Apk;
+use crate::core::fuzz::{FuzzArg, FuzzOptions};
+use crate::core::snapshot::zygote;
 
-fn parse_args() -> env::Args {
+struct Options {
+    elf_filename    : String,
+    rootfs          : String,
+    core_dump       : bool,
+    gdb             : Option<String>,
+    repl            : bool,
+    trace           : Option<TraceOptions>,
+    drcov           : Option<String>,
+    drcov_merge     : bool,
+    tenet           : Option<String>,
+    trace_ranges    : Vec<(u64, u64)>,
+    trace_modules   : Vec<String>,
+    ltrace          : Option<LtraceOptions>,
+    jni_trace       : bool,
+    jni_on_load     : bool,
+    jni_calls       : Vec<(String, Vec<JValue>)>,
+    apk_lib         : Option<String>,
+    save_snapshot   : Option<String>,
+    load_snapshot   : Option<String>,
+    zygote          : bool,
+    guard_heap      : bool,
+    alloc_stats     : Option<Option<String>>,
+    zygote_cache    : Option<String>,
+    fuzz            : Option<FuzzOptions>,
+    triage          : Option<String>,
+}
+
+fn parse_args() -> Options {
     //! Parse Command line arguments
-    let mut args = env::args();
+    //! usage: rudroid [debug] [--core-dump] [--gdb <port|unix-socket>]
+    //!                [--trace <insn|block>] [--trace-file <path>] [--trace-disasm] [--trace-regs]
+    //!                [--trace-range <start-end>] [--trace-module <name>]
+    //!                [--drcov <path>] [--drcov-merge] [--tenet <path>]
+    //!                [--ltrace] [--ltrace-all] [--ltrace-include <[lib!]symbol>] [--ltrace-exclude <[lib!]symbol>]
+    //!                [--ltrace-file <path>] [--jni-trace] [--jni-onload]
+    //!                [--jni-call <Java_symbol|com/pkg/Class.method>[:arg,...]] [--apk-lib <libname.so>]
+    //!                [--save-snapshot <path>] [--load-snapshot <path>] [--no-zygote] [--zygote-cache <dir>]
+    //!                [--guard-heap] [--alloc-stats] [--alloc-stats-json <path>]
+    //!                <elf|apk> <rootfs>
+    //!        rudroid fuzz --target <symbol|lib.so!symbol|0xaddr> [--args <template>] [--corpus <dir>]
+    //!                [--crashes <dir>] [--dict <file>] [--max-len <n>] [--timeout <ms>] [--runs <n>]
+    //!                [--seed <n>] [--afl [--afl-input <file|@@>]] [--triage <dir>] [--triage-frames <n>]
+    //!                [--cmplog]
+    //!                [options above] <elf|apk> <rootfs>
+    //! the fuzz argument template has one entry per register: input (or @@), cstr, len, env or a
+    //! number, input,len by default
+    //! with --afl afl-fuzz generates the inputs: afl-fuzz -i in -o out -- rudroid fuzz --afl --afl-input @@ ...
+    //! --cmplog records the operands of comparisons and memcmp/strcmp calls for every new corpus
+    //! entry, tries the inputs that would satisfy them and adds them to the dictionary
+    //! --triage buckets the crashing inputs of a directory instead of fuzzing, with a minimized
+    //! reproducer per bucket and triage.txt written to --crashes
+    //! --jni-call arguments are typed: z:true i:42 j:42 f:1.5 d:1.5 s:text b:<hex bytes>
+    //! with --jni-onload or --jni-call the JNI calls run instead of the ELF entry point
+    //! for an APK the library to run is picked with --apk-lib, it may be left out if there is only one
+    //! --save-snapshot writes the state once the linker is done, --load-snapshot starts from it
+    //! instead of running the linker again
+    //! the state after the linker is cached in --zygote-cache (default $RUDROID_CACHE or ~/.cache/rudroid)
+    //! and reused while the ELF, rootfs and options stay the same; tracing, gdb and debug runs skip it
+    //! --guard-heap serves malloc and friends from guarded chunks and reports heap misuse ASan style
+    //! --alloc-stats reports the allocations left at exit by call site, with peak usage and size
+    //! classes, --alloc-stats-json writes the same as JSON
+    //! --trace-range and --trace-module also select what --tenet records
+    let mut positional: Vec<String> = Vec::new();
+    let mut core_dump = false;
+    let mut gdb = None;
+    let mut tracing = false;
+    let mut trace = TraceOptions::new(TraceMode::Instruction);
+    let mut drcov = None;
+    let mut drcov_merge = false;
+    let mut tenet = None;
+    let mut ltracing = false;
+    let mut ltrace = LtraceOptions::new();
+    let mut jni_trace = false;
+    let mut jni_on_load = false;
+    let mut jni_calls = Vec::new();
+    let mut apk_lib = None;
+    let mut save_snapshot = None;
+    let mut load_snapshot = None;
+    let mut zygote = true;
+    let mut guard_heap = false;
+    let mut alloc_stats = None;
+    let mut zygote_cache = None;
+    let mut fuzz = FuzzOptions::new("");
+    let mut triage = None;
+
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--core-dump" => {
+                core_dump = true;
+            },
+            "--gdb" => {
+                gdb = Some(args.next().expect("--gdb needs a port or a unix socket path"));
+            },
+            "--trace" => {
+                tracing = true;
+                trace.mode = match args.next().as_deref() {
+                    Some("insn")  => TraceMode::Instruction,
+                    Some("block") => TraceMode::Block,
+                    _ => panic!("--trace needs insn or block"),
+                };
+            },
+            "--trace-file" => {
+                trace.output = Some(args.next().expect("--trace-file needs a path"));
+            },
+            "--trace-disasm" => {
+                trace.disassemble = true;
+            },
+            "--trace-regs" => {
+                trace.registers = true;
+            },
+            "--trace-range" => {
+                let range = args.next().expect("--trace-range needs <start-end>");
+                trace.ranges.push(parse_range(&range).expect("--trace-range needs <start-end>"));
+            },
+            "--trace-module" => {
+                trace.modules.push(args.next().expect("--trace-module needs a module name"));
+            },
+            "--drcov" => {
+                drcov = Some(args.next().expect("--drcov needs a path"));
+            },
+            "--drcov-merge" => {
+                drcov_merge = true;
+            },
+            "--tenet" => {
+                tenet = Some(args.next().expect("--tenet needs a path"));
+            },
+            "--ltrace" => {
+                ltracing = true;
+            },
+            "--ltrace-all" => {
+                ltracing = true;
+                ltrace.all_modules = true;
+            },
+            "--ltrace-include" => {
+                ltrace.include.push(args.next().expect("--ltrace-include needs a [lib!]symbol pattern"));
+            },
+            "--ltrace-exclude" => {
+                ltrace.exclude.push(args.next().expect("--ltrace-exclude needs a [lib!]symbol pattern"));
+            },
+            "--ltrace-file" => {
+                ltrace.output = Some(args.next().expect("--ltrace-file needs a path"));
+            },
+            "--jni-trace" => {
+                jni_trace = true;
+            },
+            "--jni-onload" => {
+                jni_on_load = true;
+            },
+            "--jni-call" => {
+                let call = args.next().expect("--jni-call needs a native method");
+                jni_calls.push(parse_jni_call(&call).expect("--jni-call arguments look like i:42,s:text"));
+            },
+            "--target" => {
+                fuzz.target = args.next().expect("--target needs a symbol or an address");
+            },
+            "--args" => {
+                let template = args.next().expect("--args needs an argument template");
+                fuzz.args = FuzzArg::parse_template(&template).expect("--args looks like input,len,0x10");
+            },
+            "--corpus" => {
+                fuzz.corpus = args.next().expect("--corpus needs a directory");
+            },
+            "--crashes" => {
+                fuzz.crashes = args.next().expect("--crashes needs a directory");
+            },
+            "--dict" => {
+                fuzz.dictionary = Some(args.next().expect("--dict needs a dictionary file"));
+            },
+            "--max-len" => {
+                fuzz.max_len = args.next().and_then(|n| n.parse().ok()).expect("--max-len needs a size");
+            },
+            "--timeout" => {
+                fuzz.timeout = args.next().and_then(|n| n.parse().ok()).expect("--timeout needs milliseconds");
+            },
+            "--runs" => {
+                fuzz.iterations = args.next().and_then(|n| n.parse().ok()).expect("--runs needs a count");
+            },
+            "--seed" => {
+                fuzz.seed = Some(args.next().and_then(|n| n.parse().ok()).expect("--seed needs a number"));
+            },
+            "--afl" => {
+                fuzz.afl = true;
+            },
+            "--afl-input" => {
+                fuzz.input_file = Some(args.next().expect("--afl-input needs a file, usually @@"));
+            },
+            "--triage" => {
+                triage = Some(args.next().expect("--triage needs a directory of crashing inputs"));
+            },
+            "--triage-frames" => {
+                fuzz.triage_frames = args.next().and_then(|n| n.parse().ok()).expect("--triage-frames needs a count");
+            },
+            "--cmplog" => {
+                fuzz.cmplog = true;
+            },
+            "--apk-lib" => {
+                apk_lib = Some(args.next().expect("--apk-lib needs a library name"));
+            },
+            "--save-snapshot" => {
+                save_snapshot = Some(args.next().expect("--save-snapshot needs a path"));
+            },
+            "--load-snapshot" => {
+                load_snapshot = Some(args.next().expect("--load-snapshot needs a path"));
+            },
+            "--guard-heap" => {
+                guard_heap = true;
+            },
+            "--alloc-stats" => {
+                alloc_stats = Some(None);
+            },
+            "--alloc-stats-json" => {
+                alloc_stats = Some(Some(args.next().expect("--alloc-stats-json needs a path")));
+            },
+            "--no-zygote" => {
+                zygote = false;
+            },
+            "--zygote-cache" => {
+                zygote_cache = Some(args.next().expect("--zygote-cache needs a directory"));
+            },
+            _ => {
+                positional.push(arg);
+            }
+        }
+    }
+
+    let repl = positional.len() == 3 && positional[0] == "debug";
+    let fuzzing = positional.len() == 3 && positional[0] == "fuzz";
+    if repl || fuzzing {
+        positional.remove(0);
+    }
+
+    if fuzzing && fuzz.target.is_empty() {
+        panic!("rudroid fuzz needs a --target function");
+    }
+
+    if positional.len() != 2 {
+        panic!("Please provide an ELF library or APK and rootfs folder");
+    }
+
+    // the linker has to really run for anything watching it
+    let zygote = zygote && !guard_heap && alloc_stats.is_none() && gdb.is_none() && !repl && !tracing && drcov.is_none() && tenet.is_none() && !ltracing;
+
+    Options {
+        elf_filename    : positional[0].clone(),
+        rootfs          : positional[1].clone(),
+        core_dump       : core_dump,
+        gdb             : gdb,
+        repl            : repl,
+        tenet           : tenet,
+        trace           : if tracing { Some(trace.clone()) } else { None },
+        drcov           : drcov,
+        drcov_merge     : drcov_merge,
+        trace_ranges    : trace.ranges,
+        trace_modules   : trace.modules,
+        ltrace          : if ltracing { Some(ltrace) } else { None },
+        jni_trace       : jni_trace,
+        jni_on_load     : jni_on_load,
+        jni_calls       : jni_calls,
+        apk_lib         : apk_lib,
+        save_snapshot   : save_snapshot,
+        load_snapshot   : load_snapshot,
+        zygote          : zygote,
+        guard_heap      : guard_heap,
+        alloc_stats     : alloc_stats,
+        zygote_cache    : zygote_cache,
+        fuzz            : if fuzzing { Some(fuzz) } else { None },
+        triage          : triage,
+    }
+}
 
-    if args.len() != 3 {
-        panic!("Please provide an ELF library and rootfs folder");
+fn parse_range(range: &str) -> Option<(u64, u64)> {
+    let mut parts = range.splitn(2, '-');
+    let start = u64::from_str_radix(parts.next()?.trim_start_matches("0x"), 16).ok()?;
+    let end   = u64::from_str_radix(parts.next()?.trim_start_matches("0x"), 16).ok()?;
+    Some((start, end))
+}
+
+fn parse_jni_call(call: &str) -> Option<(String, Vec<JValue>)> {
+    let mut parts = call.splitn(2, ':');
+    let method = String::from(parts.next()?);
+
+    let mut args = Vec::new();
+    for arg in parts.next().map(|args| args.split(',').collect()).unwrap_or_else(Vec::new) {
+        let mut arg = arg.splitn(2, ':');
+        let (kind, value) = (arg.next()?, arg.next()?);
+        args.push(match kind {
+            "z" => JValue::Boolean(value == "true" || value == "1"),
+            "i" => JValue::Int(value.parse().ok()?),
+            "j" => JValue::Long(value.parse().ok()?),
+            "f" => JValue::Float(value.parse().ok()?),
+            "d" => JValue::Double(value.parse().ok()?),
+            "s" => JValue::String(String::from(value)),
+            "b" => JValue::Bytes((0..value.len()).step_by(2).map(|i| u8::from_str_radix(value.get(i..i + 2)?, 16).ok()).collect::<Option<Vec<u8>>>()?),
+            _ => return None,
+        });
     }
-    args
+    Some((method, args))
 }
 
 fn main()
 {
     utilities::context_title(Some("Hello, world!"));
-    let mut args = parse_args();
-    let mut elf_filename = args.nth(1).unwrap();
-    let rootfs       = args.next().unwrap();
-    
-    let mut elf_data    = std::fs::read(&mut elf_filename).unwrap();
-    let mut elf: ElfFile        = ElfFile::new(&mut elf_data).unwrap();
+    let options = parse_args();
+    let mut elf_filename = options.elf_filename.clone();
+    let rootfs       = options.rootfs.clone();
 
     //our hello world program takes no arguments or environment variables
     let program_args: Vec<String>   = vec![];
-    let program_env: Vec<String>    = Vec::new();
+    let mut program_env: Vec<String>    = Vec::new();
+
+    // run one of an APK's libraries, extracted next to the app's data directory
+    let apk = if elf_filename.ends_with(".apk") {
+        let apk = Apk::open(&elf_filename, header::Machine::AArch64).expect("failed to read the APK");
+        apk.extract().expect("failed to extract the APK's libraries");
+
+        let names: Vec<&str> = apk.libraries.iter().map(|library| library.name.as_str()).collect();
+        let library = match &options.apk_lib {
+            Some(name) => apk.library(name).unwrap_or_else(|| panic!("{} is not in the APK: {}", name, names.join(" "))),
+            None if apk.libraries.len() == 1 => &apk.libraries[0],
+            None => panic!("pick a library with --apk-lib: {}", names.join(" ")),
+        };
+
+        println!("package {} ({}), running {}", apk.package_name(), apk.abi, library.entry);
+        elf_filename = apk.scratch_dir().join("lib").join(&library.name).to_string_lossy().into_owned();
+        program_env.push(format!("LD_LIBRARY_PATH={}", apk.library_path()));
+        Some(apk)
+    }
+    else {
+        None
+    };
+
+    let mut elf_data    = std::fs::read(&mut elf_filename).unwrap();
+    let mut elf: ElfFile        = ElfFile::new(&mut elf_data).unwrap();
     
     let endian =  elf.header.pt1.data();
     let mut emu = core::rudroid::Emulator::new( &elf_filename, &rootfs, &mut elf, endian, program_args, program_env, 0, true).expect("Emulator initialisation failed");
       
-    context_title(Some("Emulator created"))
+    context_title(Some("Emulator created"));
+    emu.core_dump_on_crash = options.core_dump;
+
+    if let Some(apk) = &apk {
+        emu.install_apk(apk);
+    }
     
     //set up hooks
     core::hooks::add_hooks(&mut emu);
 
-    context_title(Some("Running linker..."))
-    //run linker to load dependencies of ELF and then run the main from ELF
-    emu.run_linker();
+    if let Some(address) = &options.gdb {
+        context_title(Some("Waiting for gdb..."));
+        emu.attach_gdb(address).expect("failed to start gdb stub");
+    }
+    else if options.repl {
+        emu.attach_debugger(Box::new(core::debugger::repl::Repl::new()));
+    }
+
+    if let Some(trace) = options.trace.clone() {
+        emu.start_trace(trace).expect("failed to open trace output");
+    }
+
+    if let Some(path) = &options.drcov {
+        emu.start_coverage(path, options.drcov_merge);
+    }
+
+    if let Some(path) = &options.tenet {
+        emu.start_tenet(path, &options.trace_ranges, &options.trace_modules).expect("failed to open tenet trace");
+    }
+
+    if let Some(ltrace) = options.ltrace.clone() {
+        emu.start_ltrace(ltrace).expect("failed to open ltrace output");
+    }
+
+    // before the linker, libc's hooks go in as it is loaded
+    if options.guard_heap {
+        emu.enable_guarded_heap();
+    }
+    if let Some(output) = &options.alloc_stats {
+        emu.start_alloc_stats(output.as_deref());
+    }
+
+    match &options.load_snapshot {
+        Some(path) => {
+            context_title(Some("Loading snapshot..."));
+            emu.load_snapshot(path).expect("failed to load the snapshot");
+        },
+        None if options.zygote => {
+            let dir = options.zygote_cache.as_ref().map(PathBuf::from).unwrap_or_else(zygote::default_cache_dir);
+            let config = vec![options.apk_lib.clone().unwrap_or_default()];
+            match emu.zygote_cache(&dir, &config) {
+                Ok(cache) => {
+                    context_title(Some("Running linker..."));
+                    if emu.run_linker_cached(&cache) {
+                        context_title(Some("Warm start from the zygote cache"));
+                    }
+                },
+                Err(e) => {
+                    utilities::log(&format!("zygote cache disabled: {}", e), utilities::DebugLevel::INFO);
+                    context_title(Some("Running linker..."));
+                    emu.run_linker();
+                },
+            }
+        },
+        None => {
+            context_title(Some("Running linker..."));
+            //run linker to load dependencies of ELF and then run the main from ELF
+            emu.run_linker();
+        },
+    }
+
+    if let Some(path) = &options.save_snapshot {
+        emu.save_snapshot(path).expect("failed to save the snapshot");
+        println!("snapshot saved to {}", path);
+    }
     
-    context_title(Some("Executing target ELF..."))
-    emu.run_elf();
+    if options.jni_trace {
+        emu.set_jni_trace(true);
+    }
+
+    if let (Some(fuzz), Some(dir)) = (&options.fuzz, &options.triage) {
+        if let Err(e) = emu.triage_crashes(fuzz, dir) {
+            panic!("triage failed: {}", e);
+        }
+    }
+    else if let Some(fuzz) = options.fuzz.as_ref().filter(|fuzz| fuzz.afl) {
+        emu.afl_fuzz_target(fuzz).expect("afl forkserver failed");
+    }
+    else if let Some(fuzz) = &options.fuzz {
+        match emu.fuzz(fuzz) {
+            Ok(stats) => println!("[fuzz] done, {}", stats),
+            Err(e) => panic!("fuzzing failed: {}", e),
+        }
+    }
+    else if options.jni_on_load || !options.jni_calls.is_empty() {
+        let module = String::from(elf_filename.rsplit('/').next().unwrap());
+
+        if options.jni_on_load {
+            context_title(Some("Calling JNI_OnLoad..."));
+            match emu.jni_on_load(&module) {
+                Some(version) => println!("JNI_OnLoad = {:#x}", version),
+                None => println!("{} has no JNI_OnLoad", module),
+            }
+        }
+
+        for (method, args) in options.jni_calls.iter() {
+            context_title(Some(&format!("Calling {}...", method)));
+            match emu.call_native(&module, method, None, args) {
+                Some(value) => println!("{} = {:#x} {}", method, value, emu.java_string(value).map(|value| format!("{:?}", value)).unwrap_or_default()),
+                None => println!("{} not found in {}", method, module),
+            }
+        }
+    }
+    else {
+        context_title(Some("Executing target ELF..."));
+        emu.run_elf();
+    }
+    emu.finish_traces();
+    emu.stop_alloc_stats();
     
-    context_title(Some("The End"))
+    context_title(Some("The End"));
 }
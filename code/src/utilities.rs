@@ -175,4 +175,32 @@ pub fn to_uc_permissions(perms: program::Flags) -> Protection {
 	}
 	
 	uc_perms
-}
\ No newline at end of file
+}
+
+/// FNV-1a, stable across builds unlike std's hasher.
+pub struct Fnv {
+	pub state : u64,
+}
+
+impl Fnv {
+	pub fn new() -> Fnv {
+		Fnv { state: 0xcbf2_9ce4_8422_2325 }
+	}
+
+	pub fn write(&mut self, bytes: &[u8]) {
+		for byte in bytes {
+			self.state ^= *byte as u64;
+			self.state = self.state.wrapping_mul(0x0100_0000_01b3);
+		}
+	}
+
+	// length prefixed so "ab" + "c" and "a" + "bc" differ
+	pub fn write_str(&mut self, value: &str) {
+		self.write(&(value.len() as u64).to_le_bytes());
+		self.write(value.as_bytes());
+	}
+
+	pub fn write_u64(&mut self, value: u64) {
+		self.write(&value.to_le_bytes());
+	}
+}